@@ -1,12 +1,15 @@
-use std::io::Write;
+use std::{io::Write, path::Path};
 
 use cake_core::{
-    cake::{Context, Master, Mode, Worker},
-    Args,
+    cake::{
+        api, CancellationToken, ClusterStatus, ConnectionOptions, Context, LogFormat, Master, Mode,
+        OutputFormat, Topology, Worker,
+    },
+    utils, Args,
 };
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -17,26 +20,112 @@ async fn main() -> Result<()> {
         std::env::set_var("RUST_LOG", "info,tokenizers=error");
     }
 
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
-        .format_module_path(false)
-        .format_target(false)
-        .init();
+    logger(&args)?.init();
+
+    if matches!(args.mode, Mode::Status) {
+        // the model isn't needed to query the workers
+        let topology = Topology::from_path(&args.topology)?;
+        let status = ClusterStatus::query(&topology, &ConnectionOptions::from_args(&args)?).await;
+        print!("{status}");
+        return Ok(());
+    }
+
+    if matches!(args.mode, Mode::Topology) {
+        // only the configuration of the model is needed to validate the topology
+        let config = Context::load_model_config(&args)?;
+        let topology = Topology::from_path(&args.topology)?;
+        topology.validate(&config)?;
+        let dot = topology.to_dot(&config);
+        match &args.topology_out {
+            Some(path) => std::fs::write(path, dot)?,
+            None => print!("{dot}"),
+        }
+        return Ok(());
+    }
+
+    if matches!(args.mode, Mode::Count) {
+        // only the tokenizer and the configuration of the model are needed
+        print!("{}", Master::count_prompt(&args)?);
+        return Ok(());
+    }
+
+    if matches!(args.mode, Mode::Shard) {
+        let output = args
+            .shard_out
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("--shard-out is required in shard mode"))?;
+        let config = Context::load_model_config(&args)?;
+        let topology = Topology::from_path(&args.topology)?;
+        topology.validate(&config)?;
+        utils::shard_checkpoint(Path::new(&args.model), &topology, Path::new(output))?;
+        return Ok(());
+    }
 
     let ctx = Context::from_args(args)?;
 
     match ctx.args.mode {
         Mode::Master => {
-            Master::new(ctx)
-                .await?
-                .generate(|data| {
-                    if data.is_empty() {
-                        println!();
-                    } else {
-                        print!("{data}")
+            let output = ctx.args.output;
+            let interactive = ctx.args.interactive;
+            let mut master = Master::new(ctx).await?;
+
+            if interactive {
+                let stdin = tokio::io::BufReader::new(tokio::io::stdin());
+                return Ok(master.interactive(stdin, std::io::stdout()).await?);
+            }
+
+            // ctrl-c stops the generation at the next token
+            let cancel = CancellationToken::default();
+            tokio::spawn({
+                let cancel = cancel.clone();
+                async move {
+                    if tokio::signal::ctrl_c().await.is_ok() {
+                        cancel.cancel();
                     }
-                    std::io::stdout().flush().unwrap();
-                })
-                .await?;
+                }
+            });
+
+            match output {
+                OutputFormat::Text => {
+                    master
+                        .generate(&cancel, |data| {
+                            if data.is_empty() {
+                                println!();
+                            } else {
+                                print!("{data}")
+                            }
+                            std::io::stdout().flush().unwrap();
+                        })
+                        .await?;
+                }
+                OutputFormat::Jsonl => {
+                    master
+                        .generate_events(&cancel, |event| {
+                            let line = event.to_jsonl();
+                            println!("{line}");
+                            std::io::stdout().flush().unwrap();
+                        })
+                        .await?;
+                }
+            }
+        }
+        Mode::Embeddings => {
+            let prompt = ctx.args.prompt.clone();
+            let embedding = Master::new(ctx).await?.embed(&prompt).await?;
+            let values: Vec<String> = embedding.iter().map(|v| v.to_string()).collect();
+            println!("[{}]", values.join(", "));
+        }
+        Mode::Bench => {
+            let json = ctx.args.bench_json.clone();
+            let report = Master::new(ctx).await?.bench().await?;
+            print!("{report}");
+            if let Some(path) = json {
+                std::fs::write(path, report.to_json()?)?;
+            }
+        }
+        Mode::Status | Mode::Topology | Mode::Shard | Mode::Count => unreachable!(),
+        Mode::Api => {
+            api::serve(Master::new(ctx).await?).await?;
         }
         Mode::Worker => {
             Worker::new(ctx).await?.run().await?;
@@ -45,3 +134,152 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Adds the structured fields of a record to its JSON object, numbers and booleans as such.
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        let value = if let Some(value) = value.to_u64() {
+            value.into()
+        } else if let Some(value) = value.to_i64() {
+            value.into()
+        } else if let Some(value) = value.to_f64() {
+            value.into()
+        } else if let Some(value) = value.to_bool() {
+            value.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Sets up a logger with the format and destination of the arguments, the levels are read from
+/// RUST_LOG.
+fn logger(args: &Args) -> Result<env_logger::Builder> {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+
+    match args.log_format {
+        LogFormat::Text => {
+            builder.format_module_path(false).format_target(false);
+        }
+        LogFormat::Json => {
+            let role = args
+                .mode
+                .to_possible_value()
+                .map(|value| value.get_name().to_string());
+            let name = args.name.clone();
+
+            builder.format(move |buf, record| {
+                let mut line = serde_json::Map::new();
+                line.insert(
+                    "timestamp".into(),
+                    buf.timestamp_millis().to_string().into(),
+                );
+                line.insert("level".into(), record.level().as_str().into());
+                line.insert("module".into(), record.module_path().into());
+                line.insert("message".into(), record.args().to_string().into());
+                line.insert("role".into(), role.clone().into());
+                if let Some(name) = &name {
+                    line.insert("name".into(), name.clone().into());
+                }
+                record
+                    .key_values()
+                    .visit(&mut JsonFields(&mut line))
+                    .map_err(std::io::Error::other)?;
+
+                writeln!(buf, "{}", serde_json::Value::Object(line))
+            });
+        }
+    }
+
+    if let Some(path) = &args.log_file {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("can't open {path}: {e}"))?;
+        builder
+            .target(env_logger::Target::Pipe(Box::new(file)))
+            .write_style(env_logger::WriteStyle::Never);
+    }
+
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Log;
+
+    use super::*;
+
+    #[test]
+    fn json_logs_of_the_forwards() {
+        let path = std::env::temp_dir().join(format!("cake-json-logs-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let args = Args::parse_from([
+            "cake",
+            "--mode",
+            "worker",
+            "--name",
+            "worker-0",
+            "--log-format",
+            "json",
+            "--log-file",
+            &path.display().to_string(),
+        ]);
+
+        let logger = logger(&args).unwrap().build();
+        // as logged by the workers after every forward
+        let fields = [
+            ("layer_name", log::kv::Value::from("model.layers.2")),
+            ("duration_ms", log::kv::Value::from(1.5)),
+        ];
+        logger.log(
+            &log::Record::builder()
+                .args(format_args!("model.layers.2 forward took 1.50ms"))
+                .level(log::Level::Info)
+                .module_path(Some(module_path!()))
+                .key_values(&fields)
+                .build(),
+        );
+        logger.flush();
+
+        let logs = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = logs
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1);
+        let line = lines[0].as_object().unwrap();
+        let mut keys: Vec<&str> = line.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                "duration_ms",
+                "layer_name",
+                "level",
+                "message",
+                "module",
+                "name",
+                "role",
+                "timestamp"
+            ]
+        );
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["module"], module_path!());
+        assert_eq!(line["message"], "model.layers.2 forward took 1.50ms");
+        assert_eq!(line["role"], "worker");
+        assert_eq!(line["name"], "worker-0");
+        assert_eq!(line["layer_name"], "model.layers.2");
+        assert_eq!(line["duration_ms"], 1.5);
+    }
+}
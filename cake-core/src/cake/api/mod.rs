@@ -0,0 +1,458 @@
+use std::{collections::HashMap, convert::Infallible, path::Path, sync::Arc};
+
+use anyhow::Result;
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{
+        sse::{Event, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+
+use super::{
+    CakeError, CancellationToken, ClusterStatus, ConnectionOptions, FinishReason, Master, Topology,
+};
+use crate::{prompt::ChatMessage, utils, Args};
+
+/// Chunks a streamed completion gets ahead of the client reading it.
+const STREAM_BUFFER: usize = 16;
+
+type SharedMaster = Arc<Mutex<Master>>;
+// copy of the master topology, readable while it's generating
+type SharedTopology = Arc<std::sync::Mutex<Topology>>;
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Generation parameters shared by chat and text completion requests.
+#[derive(Debug, Deserialize)]
+struct GenerationParams {
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    max_tokens: Option<usize>,
+    seed: Option<u64>,
+    stop: Option<Stop>,
+    logit_bias: Option<HashMap<u32, f32>>,
+    /// Dtype of the activations for this request, see --activation-dtype.
+    dtype: Option<String>,
+    #[serde(default)]
+    stream: bool,
+}
+
+impl GenerationParams {
+    /// Overrides the master arguments with the parameters of the request.
+    fn apply(self, args: &Args) -> Args {
+        let mut args = args.clone();
+        if self.temperature.is_some() {
+            args.temperature = self.temperature;
+        }
+        if self.top_p.is_some() {
+            args.top_p = self.top_p;
+        }
+        if self.max_tokens.is_some() {
+            args.max_tokens = self.max_tokens;
+        }
+        if self.seed.is_some() {
+            args.seed = self.seed;
+        }
+        if self.dtype.is_some() {
+            args.activation_dtype = self.dtype;
+        }
+        if let Some(logit_bias) = self.logit_bias {
+            args.logit_bias = logit_bias;
+        }
+        match self.stop {
+            Some(Stop::One(stop)) => args.stop = vec![stop],
+            Some(Stop::Many(stop)) => args.stop = stop,
+            None => {}
+        }
+        args
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletionRequest {
+    messages: Vec<ChatMessage>,
+    #[serde(flatten)]
+    params: GenerationParams,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionRequest {
+    prompt: String,
+    #[serde(flatten)]
+    params: GenerationParams,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Endpoint {
+    Chat,
+    Completion,
+}
+
+impl Endpoint {
+    fn object(&self, stream: bool) -> &'static str {
+        match (self, stream) {
+            (Self::Chat, false) => "chat.completion",
+            (Self::Chat, true) => "chat.completion.chunk",
+            (Self::Completion, _) => "text_completion",
+        }
+    }
+
+    fn choice(&self, text: &str, finish_reason: Option<&str>, stream: bool) -> serde_json::Value {
+        match (self, stream) {
+            (Self::Chat, false) => json!({
+                "index": 0,
+                "message": { "role": "assistant", "content": text },
+                "finish_reason": finish_reason,
+            }),
+            (Self::Chat, true) => json!({
+                "index": 0,
+                "delta": if text.is_empty() { json!({}) } else { json!({ "content": text }) },
+                "finish_reason": finish_reason,
+            }),
+            (Self::Completion, _) => json!({
+                "index": 0,
+                "text": text,
+                "finish_reason": finish_reason,
+            }),
+        }
+    }
+}
+
+/// Identifies a single completion in the responses.
+struct Completion {
+    endpoint: Endpoint,
+    id: String,
+    created: u64,
+    model: String,
+}
+
+impl Completion {
+    fn new(endpoint: Endpoint, args: &Args) -> Self {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let model = Path::new(&args.model)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| args.model.clone());
+
+        Self {
+            endpoint,
+            id: format!("cmpl-{}", now.as_nanos()),
+            created: now.as_secs(),
+            model,
+        }
+    }
+
+    fn body(&self, text: &str, finish_reason: Option<&str>, stream: bool) -> serde_json::Value {
+        json!({
+            "id": &self.id,
+            "object": self.endpoint.object(stream),
+            "created": self.created,
+            "model": &self.model,
+            "choices": [self.endpoint.choice(text, finish_reason, stream)],
+        })
+    }
+}
+
+/// Returns the OpenAI finish reason, which doesn't tell the end of sequence token and the stop
+/// sequences apart.
+fn finish_reason(reason: FinishReason) -> &'static str {
+    match reason {
+        FinishReason::Length | FinishReason::TimeLimit => "length",
+        _ => "stop",
+    }
+}
+
+/// OpenAI error object of a generation that failed.
+fn error_body(e: &CakeError) -> serde_json::Value {
+    let kind = match e {
+        CakeError::PromptTooLong { .. } => "invalid_request_error",
+        _ => "server_error",
+    };
+    json!({ "error": { "message": e.to_string(), "type": kind } })
+}
+
+fn error_response(status: StatusCode, e: anyhow::Error) -> Response {
+    log::error!("{}", &e);
+    (
+        status,
+        Json(json!({ "error": { "message": e.to_string() } })),
+    )
+        .into_response()
+}
+
+async fn complete(
+    master: SharedMaster,
+    endpoint: Endpoint,
+    tokens: Vec<u32>,
+    params: GenerationParams,
+) -> Response {
+    let stream = params.stream;
+    let args = params.apply(master.lock().await.args());
+    let args = Args {
+        chat: matches!(endpoint, Endpoint::Chat),
+        ..args
+    };
+    let completion = Completion::new(endpoint, &args);
+
+    if let Some(Err(e)) = args.activation_dtype.as_deref().map(utils::parse_dtype) {
+        return error_response(StatusCode::BAD_REQUEST, e);
+    }
+
+    if !stream {
+        let mut text = String::new();
+        let mut master = master.lock().await;
+        return match master
+            .generate_with(&args, tokens, &CancellationToken::default(), |data| {
+                text.push_str(data)
+            })
+            .await
+        {
+            Ok(reason) => {
+                Json(completion.body(&text, Some(finish_reason(reason)), false)).into_response()
+            }
+            Err(e @ CakeError::PromptTooLong { .. }) => {
+                error_response(StatusCode::BAD_REQUEST, e.into())
+            }
+            Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e.into()),
+        };
+    }
+
+    // the generation waits for a client reading slower than it generates
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+
+    tokio::spawn(async move {
+        let mut master = master.lock().await;
+        // the receiver is dropped once the client disconnects
+        let cancel = CancellationToken::default();
+        let res = master
+            .generate_with_async(&args, tokens, &cancel, |data| {
+                let (tx, cancel) = (tx.clone(), cancel.clone());
+                async move {
+                    if !data.is_empty() && tx.send(Ok((data, None))).await.is_err() {
+                        cancel.cancel();
+                    }
+                }
+            })
+            .await;
+        let _ = match res {
+            Ok(reason) => tx.send(Ok((String::new(), Some(finish_reason(reason))))),
+            Err(e) => {
+                log::error!("generation failed: {e}");
+                tx.send(Err(e))
+            }
+        }
+        .await;
+    });
+
+    // the last chunk has the finish reason, or the error that ended the generation
+    let events = ReceiverStream::new(rx)
+        .map(move |chunk| {
+            let body = match chunk {
+                Ok((text, reason)) => completion.body(&text, reason, true),
+                Err(e) => error_body(&e),
+            };
+            Event::default().data(body.to_string())
+        })
+        .chain(tokio_stream::iter([Event::default().data("[DONE]")]))
+        .map(Ok::<_, Infallible>);
+
+    Sse::new(events).into_response()
+}
+
+async fn chat_completions(
+    State(master): State<SharedMaster>,
+    Json(req): Json<ChatCompletionRequest>,
+) -> Response {
+    let tokens = match master.lock().await.encode_chat(&req.messages) {
+        Ok(tokens) => tokens,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+
+    complete(master, Endpoint::Chat, tokens, req.params).await
+}
+
+async fn completions(
+    State(master): State<SharedMaster>,
+    Json(req): Json<CompletionRequest>,
+) -> Response {
+    let tokens = {
+        let master = master.lock().await;
+        let args = Args {
+            prompt: req.prompt,
+            chat: false,
+            ..master.args().clone()
+        };
+        match master.encode_prompt(&args) {
+            Ok(tokens) => tokens,
+            Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+        }
+    };
+
+    complete(master, Endpoint::Completion, tokens, req.params).await
+}
+
+/// Reports the status of the workers. The master is locked while generating, the liveness of its
+/// connections is only reported when it's idle.
+async fn status(
+    master: SharedMaster,
+    topology: SharedTopology,
+    options: ConnectionOptions,
+) -> Json<ClusterStatus> {
+    let liveness = master
+        .try_lock()
+        .map(|master| master.worker_status())
+        .unwrap_or_default();
+    let topology = topology.lock().unwrap().clone();
+    Json(
+        ClusterStatus::query(&topology, &options)
+            .await
+            .with_liveness(&liveness),
+    )
+}
+
+/// Reloads the topology of the master on every SIGHUP, once the generation in progress is over.
+#[cfg(unix)]
+async fn reload_on_sighup(master: SharedMaster, topology: SharedTopology) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            log::error!("can't listen for SIGHUP, the topology won't be reloaded: {e}");
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        let mut master = master.lock().await;
+        match master.reload_topology().await {
+            Ok(()) => *topology.lock().unwrap() = master.topology().clone(),
+            Err(e) => log::error!("can't reload the topology, keeping the current one: {e}"),
+        }
+    }
+}
+
+/// Serves the OpenAI compatible completion endpoints on the address from the arguments.
+pub async fn serve(master: Master) -> Result<()> {
+    let address = master.args().address.clone();
+    let topology = Arc::new(std::sync::Mutex::new(master.topology().clone()));
+    let options = ConnectionOptions::from_args(master.args())?;
+    let master = Arc::new(Mutex::new(master));
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(master.clone(), topology.clone()));
+
+    let app = Router::new()
+        .route("/v1/chat/completions", post(chat_completions))
+        .route("/v1/completions", post(completions))
+        .route(
+            "/status",
+            get(move |State(master): State<SharedMaster>| status(master, topology, options)),
+        )
+        .with_state(master);
+
+    let listener = tokio::net::TcpListener::bind(&address).await?;
+
+    log::info!("api listening on http://{} ...", &address);
+
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    /// Serves the tiny test model and returns its address.
+    async fn server(extra: &[&str]) -> String {
+        let address = test_utils::free_address();
+        let mut args = vec!["--address", &address];
+        args.extend_from_slice(extra);
+        let master = test_utils::master(test_utils::args(&args)).await;
+        tokio::spawn(serve(master));
+        test_utils::wait_listening(&address).await;
+        address
+    }
+
+    /// Posts a streamed completion request and returns the data of the server sent events.
+    async fn stream(address: &str, request: serde_json::Value) -> Vec<String> {
+        let url = format!("http://{address}/v1/completions");
+        let body = tokio::task::spawn_blocking(move || {
+            let response = ureq::post(&url)
+                .set("Content-Type", "application/json")
+                .send_string(&request.to_string())
+                .unwrap();
+            assert_eq!(response.content_type(), "text/event-stream");
+            response.into_string().unwrap()
+        })
+        .await
+        .unwrap();
+
+        body.lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn streamed_completion() {
+        let address = server(&[]).await;
+        let events = stream(
+            &address,
+            json!({ "prompt": "the cat sat", "max_tokens": 5, "stream": true }),
+        )
+        .await;
+
+        let (done, chunks) = events.split_last().unwrap();
+        assert_eq!(done, "[DONE]");
+        let chunks: Vec<serde_json::Value> = chunks
+            .iter()
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        let (last, text) = chunks.split_last().unwrap();
+        assert!(!text.is_empty());
+        for chunk in text {
+            assert_eq!(chunk["object"], "text_completion");
+            assert_eq!(
+                chunk["choices"][0]["finish_reason"],
+                serde_json::Value::Null
+            );
+        }
+        assert_eq!(last["choices"][0]["text"], "");
+        assert_eq!(last["choices"][0]["finish_reason"], "length");
+    }
+
+    #[tokio::test]
+    async fn failed_streamed_completion() {
+        // more tokens than the context holds, with the default --truncate error
+        let address = server(&[]).await;
+        let events = stream(
+            &address,
+            json!({ "prompt": "the cat sat", "max_tokens": 1000, "stream": true }),
+        )
+        .await;
+
+        assert_eq!(events.len(), 2);
+        let error: serde_json::Value = serde_json::from_str(&events[0]).unwrap();
+        assert_eq!(error["error"]["type"], "invalid_request_error");
+        assert!(error["error"]["message"].as_str().is_some());
+        assert_eq!(events[1], "[DONE]");
+    }
+}
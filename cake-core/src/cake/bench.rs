@@ -0,0 +1,242 @@
+use std::{fmt, time::Duration};
+
+use serde::Serialize;
+
+/// Forward latencies of every layer, along with the node serving it.
+#[derive(Debug, Clone, Default)]
+pub struct LayerTimings {
+    layers: Vec<(String, String, Vec<Duration>)>,
+}
+
+impl LayerTimings {
+    /// Creates the timings of the given (layer name, node) pairs, indexed by block.
+    pub fn new(layers: Vec<(String, String)>) -> Self {
+        Self {
+            layers: layers
+                .into_iter()
+                .map(|(layer, node)| (layer, node, vec![]))
+                .collect(),
+        }
+    }
+
+    pub fn record(&mut self, block_idx: usize, elapsed: Duration) {
+        if let Some((_, _, samples)) = self.layers.get_mut(block_idx) {
+            samples.push(elapsed);
+        }
+    }
+
+    pub fn latencies(&self) -> Vec<LayerLatency> {
+        self.layers
+            .iter()
+            .map(|(layer, node, samples)| {
+                let mut ms: Vec<f64> = samples.iter().map(|d| d.as_secs_f64() * 1000.).collect();
+                ms.sort_by(f64::total_cmp);
+
+                let mean_ms = if ms.is_empty() {
+                    0.
+                } else {
+                    ms.iter().sum::<f64>() / ms.len() as f64
+                };
+                // nearest rank
+                let p95_ms = match ms.len() {
+                    0 => 0.,
+                    n => ms[((n as f64 * 0.95).ceil() as usize).clamp(1, n) - 1],
+                };
+
+                LayerLatency {
+                    layer: layer.clone(),
+                    node: node.clone(),
+                    samples: ms.len(),
+                    mean_ms,
+                    p95_ms,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Forward latency of a layer, in milliseconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct LayerLatency {
+    pub layer: String,
+    /// Address of the worker serving the layer, or "local".
+    pub node: String,
+    pub samples: usize,
+    pub mean_ms: f64,
+    pub p95_ms: f64,
+}
+
+/// Time spent by a node on every generated token, in milliseconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeLatency {
+    pub node: String,
+    pub layers: usize,
+    pub mean_ms: f64,
+}
+
+/// Results of a benchmark run.
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchReport {
+    pub prompt_tokens: usize,
+    pub generated_tokens: usize,
+    pub prompt_tokens_per_sec: f64,
+    pub generation_tokens_per_sec: f64,
+    /// Latencies of the layers while generating, the prompt evaluation is not included.
+    pub layers: Vec<LayerLatency>,
+    pub nodes: Vec<NodeLatency>,
+}
+
+impl BenchReport {
+    pub fn new(
+        prompt_tokens: usize,
+        prompt_elapsed: Duration,
+        generated_tokens: usize,
+        generation_elapsed: Duration,
+        layers: Vec<LayerLatency>,
+    ) -> Self {
+        let mut nodes: Vec<NodeLatency> = vec![];
+        for layer in &layers {
+            if let Some(node) = nodes.iter_mut().find(|n| n.node == layer.node) {
+                node.layers += 1;
+                node.mean_ms += layer.mean_ms;
+            } else {
+                nodes.push(NodeLatency {
+                    node: layer.node.clone(),
+                    layers: 1,
+                    mean_ms: layer.mean_ms,
+                });
+            }
+        }
+
+        Self {
+            prompt_tokens,
+            generated_tokens,
+            prompt_tokens_per_sec: prompt_tokens as f64 / prompt_elapsed.as_secs_f64(),
+            generation_tokens_per_sec: generated_tokens as f64 / generation_elapsed.as_secs_f64(),
+            layers,
+            nodes,
+        }
+    }
+
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| anyhow!(e))
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "prompt:     {} tokens, {:.2} token/s",
+            self.prompt_tokens, self.prompt_tokens_per_sec
+        )?;
+        writeln!(
+            f,
+            "generation: {} tokens, {:.2} token/s",
+            self.generated_tokens, self.generation_tokens_per_sec
+        )?;
+
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<24} {:<24} {:>10} {:>10}",
+            "layer", "node", "mean (ms)", "p95 (ms)"
+        )?;
+        for layer in &self.layers {
+            writeln!(
+                f,
+                "{:<24} {:<24} {:>10.3} {:>10.3}",
+                layer.layer, layer.node, layer.mean_ms, layer.p95_ms
+            )?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:<24} {:>6} {:>15}", "node", "layers", "ms/token")?;
+        for node in &self.nodes {
+            writeln!(
+                f,
+                "{:<24} {:>6} {:>15.3}",
+                node.node, node.layers, node.mean_ms
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn mean_and_p95_of_each_layer() {
+        let mut timings = LayerTimings::new(vec![
+            ("model.layers.0".into(), "local".into()),
+            ("model.layers.1".into(), "127.0.0.1:1".into()),
+        ]);
+        for ms in 1..=20 {
+            timings.record(0, Duration::from_millis(ms));
+        }
+        // out of the layers
+        timings.record(2, Duration::from_millis(5));
+
+        let latencies = timings.latencies();
+        assert_eq!(latencies.len(), 2);
+        assert_eq!(latencies[0].samples, 20);
+        assert!((latencies[0].mean_ms - 10.5).abs() < 1e-9);
+        assert!((latencies[0].p95_ms - 19.).abs() < 1e-9);
+        assert_eq!(
+            (
+                latencies[1].samples,
+                latencies[1].mean_ms,
+                latencies[1].p95_ms
+            ),
+            (0, 0., 0.)
+        );
+    }
+
+    #[tokio::test]
+    async fn bench_report_json() {
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "bench.yml",
+            &format!("bench-worker: {{ host: '{address}', layers: [2, 3] }}"),
+        );
+        let _worker = test_utils::worker("bench-worker", &topology, &[]).await;
+        let mut args = test_utils::args(&["--bench-prompt-len", "8", "--max-tokens", "4"]);
+        args.topology = topology;
+        let report = test_utils::master(args).await.bench().await.unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        assert_eq!(json["prompt_tokens"], 8);
+        assert_eq!(json["generated_tokens"], 4);
+        assert!(json["prompt_tokens_per_sec"].as_f64().unwrap() > 0.);
+        assert!(json["generation_tokens_per_sec"].as_f64().unwrap() > 0.);
+
+        let layers = json["layers"].as_array().unwrap();
+        assert_eq!(layers.len(), 4);
+        for (idx, layer) in layers.iter().enumerate() {
+            assert_eq!(layer["layer"], format!("model.layers.{idx}"));
+            assert_eq!(layer["samples"], 4);
+            assert!(layer["mean_ms"].as_f64().unwrap() >= 0.);
+            assert!(layer["p95_ms"].as_f64().unwrap() >= 0.);
+        }
+        assert_eq!(layers[0]["node"], "local");
+        assert_eq!(layers[3]["node"], address);
+
+        // by worker
+        let nodes = json["nodes"].as_array().unwrap();
+        let nodes: Vec<_> = nodes
+            .iter()
+            .map(|node| {
+                assert!(node["mean_ms"].as_f64().unwrap() >= 0.);
+                (
+                    node["node"].as_str().unwrap(),
+                    node["layers"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(nodes, [("local", 2), (address.as_str(), 2)]);
+    }
+}
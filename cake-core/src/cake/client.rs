@@ -1,29 +1,250 @@
+use std::{
+    collections::HashSet,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
 use anyhow::Result;
 use async_trait::async_trait;
-use candle_core::{Device, Tensor};
-use tokio::net::TcpStream;
+use candle_core::{DType, Device, Tensor};
+use futures::FutureExt;
+use rustls::ClientConfig;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{model::Cache, Args};
+
+use super::{
+    tls::{self, Stream},
+    ChecksumMismatch, Message, RateLimiter, RawTensor, Registry, WorkerInfo, WorkerState,
+    REGISTERED_HOST_PREFIX,
+};
+
+/// Liveness of a worker as seen by the master.
+#[derive(Debug, Clone)]
+pub struct WorkerStatus {
+    pub address: String,
+    pub healthy: bool,
+    /// Last time a message has been received from the worker.
+    pub last_seen: Instant,
+}
+
+/// Settings of the connections to the workers.
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    /// Time a busy worker can go without sending anything, no heartbeat is expected if zero.
+    pub heartbeat_timeout: Duration,
+    /// Number of reconnection attempts once the connection to a worker drops.
+    pub reconnect_attempts: usize,
+    /// Delay before the first reconnection attempt, doubled at every attempt.
+    pub backoff_base: Duration,
+    /// Maximum delay between two reconnection attempts.
+    pub backoff_max: Duration,
+    /// Connect to the workers over TLS if set.
+    pub tls: Option<Arc<ClientConfig>>,
+    /// Compress the messages sent to the workers at this zstd level if set.
+    pub compression: Option<i32>,
+    /// Time a forward request can take before failing, no limit if zero.
+    pub rpc_timeout: Duration,
+    /// Send the tensors with their checksums.
+    pub verify_checksums: bool,
+    /// Token sent to the workers in the hello.
+    pub auth_token: Option<String>,
+    /// Id of the master sent to the workers in the hello.
+    pub master_id: Option<String>,
+    /// Rate each connection sends its messages at, in megabits per second, unlimited if not set.
+    pub max_bandwidth_mbps: Option<f64>,
+    /// Workers that registered to the master, connected to through it.
+    pub registry: Option<Arc<Registry>>,
+    /// Size above which the messages of the workers are rejected.
+    pub max_message_bytes: u32,
+}
 
-use crate::model::Cache;
+impl ConnectionOptions {
+    pub fn from_args(args: &Args) -> Result<Self> {
+        Ok(Self {
+            heartbeat_timeout: Duration::from_millis(args.heartbeat_interval)
+                * args.heartbeat_misses,
+            reconnect_attempts: args.reconnect_attempts,
+            backoff_base: Duration::from_millis(args.reconnect_backoff_base),
+            backoff_max: Duration::from_millis(args.reconnect_backoff_max),
+            tls: tls::client_config(args)?,
+            compression: args.compress,
+            rpc_timeout: Duration::from_millis(args.rpc_timeout_ms),
+            verify_checksums: args.verify_checksums,
+            auth_token: args.auth_token.clone(),
+            master_id: args.master_id.clone(),
+            max_bandwidth_mbps: match args.max_bandwidth_mbps {
+                Some(mbps) if mbps <= 0. || !mbps.is_finite() => {
+                    bail!("--max-bandwidth-mbps must be a positive number")
+                }
+                mbps => mbps,
+            },
+            // set by the master accepting registrations
+            registry: None,
+            max_message_bytes: args.max_message_bytes(),
+        })
+    }
+}
+
+/// Errors of the connection to a worker.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The worker could not be reached again after the connection dropped.
+    Unreachable {
+        address: String,
+        layers: String,
+        reason: String,
+    },
+    /// The connection has been restored but the worker lost the cache of the current sequences,
+    /// or the replica holding it failed, or the worker dropped it after failing a forward.
+    CacheLost {
+        address: String,
+        reason: Option<String>,
+    },
+    /// The worker didn't answer a forward request in time.
+    Timeout {
+        address: String,
+        layers: String,
+        timeout: Duration,
+    },
+    /// The worker rejected the auth token of the master.
+    Unauthorized { address: String },
+    /// The worker kept rejecting a request because too many others were waiting to be processed.
+    Overloaded {
+        address: String,
+        layers: String,
+        attempts: usize,
+    },
+    /// The tensors of a request or of its response kept being corrupted.
+    Corrupted {
+        address: String,
+        layers: String,
+        attempts: usize,
+    },
+    /// None of the workers serving the same layers could be reached.
+    NoHealthyReplica { layers: String, reason: String },
+    /// The forward pass failed or panicked on the worker, which is still serving the connection.
+    Internal { address: String, message: String },
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unreachable {
+                address,
+                layers,
+                reason,
+            } => write!(
+                f,
+                "worker {address} serving {layers} is unreachable: {reason}"
+            ),
+            Self::CacheLost { address, reason } => {
+                write!(
+                    f,
+                    "worker {address} lost the cache of the current sequences"
+                )?;
+                match reason {
+                    Some(reason) => write!(f, ": {reason}"),
+                    None => Ok(()),
+                }
+            }
+            Self::Timeout {
+                address,
+                layers,
+                timeout,
+            } => write!(
+                f,
+                "worker {address} serving {layers} didn't answer within {timeout:?}"
+            ),
+            Self::Unauthorized { address } => {
+                write!(f, "worker {address} rejected the auth token")
+            }
+            Self::Corrupted {
+                address,
+                layers,
+                attempts,
+            } => write!(
+                f,
+                "request to {address} serving {layers} was corrupted {attempts} times"
+            ),
+            Self::NoHealthyReplica { layers, reason } => {
+                write!(f, "no replica serving {layers} is healthy: {reason}")
+            }
+            Self::Overloaded {
+                address,
+                layers,
+                attempts,
+            } => write!(
+                f,
+                "worker {address} serving {layers} was overloaded for {attempts} attempts"
+            ),
+            Self::Internal { address, message } => {
+                write!(f, "worker {address} failed the request: {message}")
+            }
+        }
+    }
+}
 
-use super::{Message, WorkerInfo};
+impl std::error::Error for ClientError {}
 
 #[derive(Debug)]
 pub struct Client {
     device: Device,
     address: String,
     layer_name: String,
-    stream: TcpStream,
+    stream: Box<dyn Stream>,
     worker_info: WorkerInfo,
+    // dtype of the worker layers, known after the handshake
+    dtype: Option<DType>,
+    options: ConnectionOptions,
+    last_seen: Instant,
+    healthy: bool,
+    // set when the worker cache holds the state of the current sequences
+    stateful: bool,
+    // caches the worker processes the requests with, see Message::SelectCache
+    cache_id: u64,
+    // other ids whose caches the worker keeps with the state of their sequences
+    stashed: HashSet<u64>,
+    // other ids whose state was dropped along with a previous connection
+    lost: HashSet<u64>,
+    // throttles the messages sent on this connection if set
+    limiter: Option<RateLimiter>,
+    // forward started by start_batch, its response is read by finish_batch
+    pending: Option<PendingForward>,
+    // set when the request of the next call has already been sent
+    sent: bool,
+    // set from the write of a request until its response is read, still set if the caller
+    // dropped the call in between
+    in_call: bool,
+}
+
+/// Forward request sent to the worker, waiting for its response to be read.
+#[derive(Debug)]
+struct PendingForward {
+    req: Message,
+    layers: String,
+    dtype: DType,
 }
 
 impl Client {
-    pub async fn new(device: Device, address: &str, layer_name: &str) -> Result<Self> {
+    pub async fn new(
+        device: Device,
+        address: &str,
+        layer_name: &str,
+        options: ConnectionOptions,
+    ) -> Result<Self> {
         let address = address.to_string();
         let layer_name = layer_name.to_string();
-        let stream = TcpStream::connect(&address).await?;
-        let worker_info = WorkerInfo {
-            device: String::new(),
-        };
+        let stream =
+            Self::connect(&address, &options)
+                .await
+                .map_err(|e| ClientError::Unreachable {
+                    address: address.clone(),
+                    layers: layer_name.clone(),
+                    reason: e.to_string(),
+                })?;
+        let worker_info = WorkerInfo::default();
 
         let mut client = Self {
             address,
@@ -31,30 +252,486 @@ impl Client {
             stream,
             layer_name,
             worker_info,
+            dtype: None,
+            last_seen: Instant::now(),
+            healthy: true,
+            stateful: false,
+            cache_id: 0,
+            stashed: HashSet::new(),
+            lost: HashSet::new(),
+            limiter: options.max_bandwidth_mbps.map(RateLimiter::from_mbps),
+            pending: None,
+            sent: false,
+            in_call: false,
+            options,
         };
 
-        let resp = client.request(Message::Hello).await?;
-        client.worker_info = if let Message::WorkerInfo(info) = resp {
-            info
+        client.handshake().await?;
+
+        Ok(client)
+    }
+
+    /// Opens a connection to the worker, through the registry if it registered to the master.
+    async fn connect(address: &str, options: &ConnectionOptions) -> Result<Box<dyn Stream>> {
+        match (
+            address.strip_prefix(REGISTERED_HOST_PREFIX),
+            &options.registry,
+        ) {
+            (Some(name), Some(registry)) => {
+                // the worker is the one dialing, it still serves the connection
+                let stream = registry.connect(name).await?;
+                tls::connect_stream(stream, name, options.tls.as_ref()).await
+            }
+            (Some(name), None) => bail!("{name} registers to the master, it can't be dialed"),
+            (None, _) => tls::connect(address, options.tls.as_ref()).await,
+        }
+    }
+
+    /// Information the worker has advertised when connecting.
+    pub fn worker_info(&self) -> &WorkerInfo {
+        &self.worker_info
+    }
+
+    /// Queries the layers and the activity of the worker.
+    pub async fn state(&mut self) -> Result<WorkerState> {
+        match self.request(Message::StatusRequest).await? {
+            Message::StatusResponse(state) => Ok(state),
+            resp => Err(anyhow!("unexpected response {:?}", &resp)),
+        }
+    }
+
+    /// Liveness of the worker this client is connected to.
+    pub fn status(&self) -> WorkerStatus {
+        WorkerStatus {
+            address: self.address.clone(),
+            healthy: self.healthy,
+            last_seen: self.last_seen,
+        }
+    }
+
+    async fn handshake(&mut self) -> Result<()> {
+        // the worker compresses its responses if the hello is compressed
+        Message::Hello {
+            auth_token: self.options.auth_token.clone(),
+            master_id: self.options.master_id.clone(),
+        }
+        .to_writer_compressed(&mut self.stream, self.options.compression)
+        .await?;
+        let resp = self.read().await?;
+        self.worker_info = if let Message::WorkerInfo(info) = resp {
+            WorkerInfo {
+                host: self.address.clone(),
+                ..info
+            }
+        } else if let Message::Unauthorized = resp {
+            return Err(ClientError::Unauthorized {
+                address: self.address.clone(),
+            }
+            .into());
         } else {
             return Err(anyhow!("unexpected worker info message: {:?}", &resp));
         };
+        self.dtype = Some(
+            DType::from_str(&self.worker_info.dtype)
+                .map_err(|e| anyhow!("invalid dtype from {}: {:?}", &self.address, e))?,
+        );
 
-        Ok(client)
+        Ok(())
+    }
+
+    /// Reads the next message, skipping the heartbeats sent while the worker is busy.
+    async fn read(&mut self) -> Result<Message> {
+        let timeout = self.options.heartbeat_timeout;
+        let max_size = self.options.max_message_bytes;
+        loop {
+            let msg = if timeout.is_zero() {
+                Message::from_reader(&mut self.stream, max_size).await?
+            } else {
+                tokio::time::timeout(timeout, Message::from_reader(&mut self.stream, max_size))
+                    .await
+                    .map_err(|_| {
+                        anyhow!(
+                            "worker {} missed its heartbeats for {:?}",
+                            &self.address,
+                            timeout
+                        )
+                    })??
+            };
+
+            self.last_seen = Instant::now();
+            match msg {
+                Message::Heartbeat => {}
+                Message::Shutdown => {
+                    // the connection is dropped and the worker treated as unavailable until it
+                    // can be reached again
+                    log::warn!("worker {} is shutting down", &self.address);
+                    self.healthy = false;
+                    return Err(anyhow!("worker {} has shut down", &self.address));
+                }
+                msg => {
+                    // the message has been read completely, the stream is still usable
+                    msg.verify_checksums()?;
+                    return Ok(msg);
+                }
+            }
+        }
+    }
+
+    /// Connects again to the worker, waiting exponentially longer between attempts.
+    async fn reconnect(&mut self, layers: &str) -> Result<()> {
+        let mut delay = self.options.backoff_base;
+        let mut reason = "reconnection is disabled".to_string();
+
+        for attempt in 1..=self.options.reconnect_attempts {
+            log::warn!(
+                "reconnecting to {} in {:?} ({attempt}/{}) ...",
+                &self.address,
+                delay,
+                self.options.reconnect_attempts
+            );
+
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(self.options.backoff_max);
+
+            let res = match Self::connect(&self.address, &self.options).await {
+                Ok(stream) => {
+                    self.stream = stream;
+                    match self.handshake().await {
+                        // the connection starts with the caches of id 0
+                        Ok(()) if self.cache_id != 0 => {
+                            Message::SelectCache { id: self.cache_id }
+                                .to_writer_throttled(
+                                    &mut self.stream,
+                                    self.options.compression,
+                                    self.limiter.as_mut(),
+                                )
+                                .await
+                        }
+                        res => res,
+                    }
+                }
+                Err(e) => Err(e),
+            };
+
+            match res {
+                Ok(()) => {
+                    log::info!("reconnected to {}", &self.address);
+                    self.healthy = true;
+                    self.stateful = false;
+                    self.lost.extend(self.stashed.drain());
+                    return Ok(());
+                }
+                Err(e) => reason = e.to_string(),
+            }
+        }
+
+        Err(ClientError::Unreachable {
+            address: self.address.clone(),
+            layers: layers.to_string(),
+            reason,
+        }
+        .into())
+    }
+
+    /// Flags the connection unhealthy if a call was dropped before its response was read, which
+    /// may still be on its way and would be read in place of the next one.
+    fn check_abandoned_call(&mut self) {
+        if self.in_call && !self.sent && self.healthy {
+            log::warn!(
+                "a request to {} was abandoned before its response, reconnecting",
+                &self.address
+            );
+            self.healthy = false;
+        }
+    }
+
+    /// Sends a message and reads its response if one is expected, reconnecting and retrying if
+    /// the connection drops.
+    async fn call(&mut self, msg: &Message, layers: &str, reply: bool) -> Result<Option<Message>> {
+        // the response of an abandoned forward would be read in place of this one
+        if self.pending.take().is_some() {
+            self.healthy = false;
+        }
+        self.check_abandoned_call();
+
+        self.in_call = true;
+        let res = self.send_and_read(msg, layers, reply).await;
+        self.in_call = false;
+        res
+    }
+
+    async fn send_and_read(
+        &mut self,
+        msg: &Message,
+        layers: &str,
+        reply: bool,
+    ) -> Result<Option<Message>> {
+        let mut attempts = 0;
+        loop {
+            let sent = std::mem::take(&mut self.sent) && self.healthy;
+            if !self.healthy {
+                let lost = self.stateful;
+
+                self.reconnect(layers).await?;
+
+                // resetting, replacing or switching the cache doesn't depend on its previous state
+                if lost
+                    && !matches!(
+                        msg,
+                        Message::ResetCache { .. }
+                            | Message::SetCache(_)
+                            | Message::SelectCache { .. }
+                            | Message::DropCache { .. }
+                    )
+                {
+                    return Err(ClientError::CacheLost {
+                        address: self.address.clone(),
+                        reason: None,
+                    }
+                    .into());
+                }
+            }
+
+            let written = if sent {
+                Ok(())
+            } else {
+                msg.to_writer_throttled(
+                    &mut self.stream,
+                    self.options.compression,
+                    self.limiter.as_mut(),
+                )
+                .await
+            };
+            let res = match written {
+                Ok(()) if reply => self.read().await.map(Some),
+                Ok(()) => Ok(None),
+                Err(e) => Err(e),
+            };
+
+            match res {
+                Ok(Some(Message::ChecksumMismatch)) => {
+                    attempts += 1;
+                    log::warn!(
+                        "request to {} was corrupted, sending it again",
+                        &self.address
+                    );
+                    if attempts > self.options.reconnect_attempts {
+                        return Err(self.corrupted(layers, attempts));
+                    }
+                }
+                Ok(Some(Message::WorkerError(message))) => {
+                    // the worker drops the cache of the connection, which may be half-written,
+                    // so the sequences are processed again
+                    if std::mem::take(&mut self.stateful) {
+                        return Err(ClientError::CacheLost {
+                            address: self.address.clone(),
+                            reason: Some(message),
+                        }
+                        .into());
+                    }
+                    // sending the request again would fail the same way
+                    return Err(ClientError::Internal {
+                        address: self.address.clone(),
+                        message,
+                    }
+                    .into());
+                }
+                Ok(Some(Message::Overloaded)) => {
+                    attempts += 1;
+                    if attempts > self.options.reconnect_attempts {
+                        return Err(ClientError::Overloaded {
+                            address: self.address.clone(),
+                            layers: layers.to_string(),
+                            attempts,
+                        }
+                        .into());
+                    }
+
+                    // the request has been dropped, give the worker time to drain its queue
+                    let delay = self
+                        .options
+                        .backoff_base
+                        .saturating_mul(2u32.saturating_pow(attempts as u32 - 1))
+                        .min(self.options.backoff_max);
+                    log::warn!(
+                        "worker {} is overloaded, sending the request again in {:?}",
+                        &self.address,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) if e.is::<ChecksumMismatch>() => {
+                    // a forward request can be sent again, the worker overwrites the cache entries
+                    // it has written for the same positions
+                    attempts += 1;
+                    log::warn!(
+                        "response from {} was corrupted, sending the request again: {}",
+                        &self.address,
+                        e
+                    );
+                    if attempts > self.options.reconnect_attempts {
+                        return Err(self.corrupted(layers, attempts));
+                    }
+                }
+                Ok(resp) => {
+                    match msg {
+                        Message::ResetCache { .. } => self.stateful = false,
+                        Message::TransformerOp { .. } | Message::Batch { .. } => {
+                            self.stateful = true
+                        }
+                        Message::SetCache(_) => self.stateful = true,
+                        _ => {}
+                    }
+                    return Ok(resp);
+                }
+                Err(e) => {
+                    // the stream can't be trusted anymore
+                    self.healthy = false;
+                    attempts += 1;
+
+                    log::warn!("request to {} failed: {}", &self.address, e);
+
+                    if attempts > self.options.reconnect_attempts {
+                        return Err(ClientError::Unreachable {
+                            address: self.address.clone(),
+                            layers: layers.to_string(),
+                            reason: e.to_string(),
+                        }
+                        .into());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Flags the connection unhealthy if the worker closed it or sent anything while no request
+    /// was running, so that it's opened again before it's used.
+    fn check_connection(&mut self) {
+        if !self.healthy {
+            return;
+        }
+        let mut byte = [0u8; 1];
+        let reason = match self.stream.read(&mut byte).now_or_never() {
+            // nothing to read, the connection is idle
+            None => return,
+            Some(Ok(0)) => "closed by the worker".to_string(),
+            Some(Ok(_)) => "unexpected data from the worker".to_string(),
+            Some(Err(e)) => e.to_string(),
+        };
+        log::warn!("connection to {} is unusable: {}", &self.address, reason);
+        self.healthy = false;
+    }
+
+    fn corrupted(&self, layers: &str, attempts: usize) -> anyhow::Error {
+        ClientError::Corrupted {
+            address: self.address.clone(),
+            layers: layers.to_string(),
+            attempts,
+        }
+        .into()
+    }
+
+    fn with_checksums(&self, msg: Message) -> Message {
+        if self.options.verify_checksums {
+            msg.with_checksums()
+        } else {
+            msg
+        }
+    }
+
+    async fn send(&mut self, msg: Message) -> Result<()> {
+        let layers = self.layer_name.clone();
+        let msg = self.with_checksums(msg);
+        self.call(&msg, &layers, false).await.map(|_| ())
     }
 
     async fn request(&mut self, req: Message) -> Result<Message> {
-        req.to_writer(&mut self.stream).await?;
-        super::Message::from_reader(&mut self.stream).await
+        let layers = self.layer_name.clone();
+        self.request_for(req, &layers).await
     }
 
-    async fn forward_request(&mut self, req: Message) -> Result<Tensor> {
-        let resp = self.request(req).await?;
-        match resp {
+    async fn request_for(&mut self, req: Message, layers: &str) -> Result<Message> {
+        let req = self.with_checksums(req);
+        self.call(&req, layers, true)
+            .await?
+            .ok_or_else(|| anyhow!("no response from {}", &self.address))
+    }
+
+    /// Casts a tensor to the dtype the worker runs its layers in. Activations cast to another
+    /// dtype than the one of the model for the generation are sent as they are, the worker casts
+    /// them at its end.
+    fn to_worker_dtype(&self, x: &Tensor, cache: &Cache) -> Result<Tensor> {
+        Ok(match self.dtype {
+            Some(dtype) if x.dtype() == cache.cos.dtype() => x.to_dtype(dtype)?,
+            _ => x.clone(),
+        })
+    }
+
+    /// Builds the request for a batch of ops, with the layers it runs.
+    fn batch_request(
+        &self,
+        x: &Tensor,
+        batch: Vec<(String, usize, usize)>,
+        cache: &Cache,
+    ) -> Result<(Message, String)> {
+        let layers = match (batch.first(), batch.last()) {
+            (Some((first, _, _)), Some((last, _, _))) if first != last => {
+                format!("{first} to {last}")
+            }
+            _ => self.layer_name.clone(),
+        };
+        let x = self.to_worker_dtype(x, cache)?;
+        Ok((Message::from_batch(&x, batch), layers))
+    }
+
+    async fn forward_request(&mut self, req: Message, layers: &str) -> Result<Tensor> {
+        match self.forward_call(req, layers).await? {
             Message::Tensor(raw) => Ok(raw.to_tensor(&self.device)?),
-            _ => Err(anyhow!("unexpected response {:?}", &resp)),
+            resp => Err(anyhow!("unexpected response {:?}", &resp)),
         }
     }
+
+    /// Sends a forward request and returns its response, within --rpc-timeout-ms if set.
+    async fn forward_call(&mut self, req: Message, layers: &str) -> Result<Message> {
+        let timeout = self.options.rpc_timeout;
+        let resp = if timeout.is_zero() {
+            self.request_for(req, layers).await?
+        } else {
+            match tokio::time::timeout(timeout, self.request_for(req, layers)).await {
+                Ok(resp) => resp?,
+                Err(_) => {
+                    // the response could still come, the stream can't be trusted anymore
+                    self.healthy = false;
+                    return Err(ClientError::Timeout {
+                        address: self.address.clone(),
+                        layers: layers.to_string(),
+                        timeout,
+                    }
+                    .into());
+                }
+            }
+        };
+        Ok(resp)
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // tells the worker the connection is over, so that it releases its cache right away
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        if !self.healthy {
+            return;
+        }
+        let (closed, _) = tokio::io::duplex(1);
+        let mut stream = std::mem::replace(&mut self.stream, Box::new(closed));
+        runtime.spawn(async move {
+            if Message::Shutdown.to_writer(&mut stream).await.is_ok() {
+                let _ = stream.shutdown().await;
+            }
+        });
+    }
 }
 
 impl std::fmt::Display for Client {
@@ -74,25 +751,202 @@ impl super::Forwarder for Client {
         x: &Tensor,
         index_pos: usize,
         block_idx: usize,
-        _: &mut Cache,
+        cache: &mut Cache,
     ) -> Result<Tensor> {
-        self.forward_request(super::Message::transformer_op(
-            &self.layer_name,
-            x,
+        let layers = self.layer_name.clone();
+        // the response is cast back for the next block, which can run in another dtype
+        let dtype = x.dtype();
+        let x = self.to_worker_dtype(x, cache)?;
+        self.forward_request(
+            super::Message::transformer_op(&self.layer_name, &x, index_pos, block_idx),
+            &layers,
+        )
+        .await?
+        .to_dtype(dtype)
+        .map_err(|e| anyhow!(e))
+    }
+
+    async fn forward_attention(
+        &mut self,
+        x: &Tensor,
+        index_pos: usize,
+        block_idx: usize,
+        cache: &mut Cache,
+    ) -> Result<(Tensor, Tensor)> {
+        let layers = self.layer_name.clone();
+        let dtype = x.dtype();
+        let req = Message::AttentionOp {
+            layer_name: self.layer_name.clone(),
+            x: RawTensor::from_tensor(&self.to_worker_dtype(x, cache)?),
             index_pos,
             block_idx,
-        ))
-        .await
+        };
+        match self.forward_call(req, &layers).await? {
+            Message::Attention { x, attention } => Ok((
+                x.to_tensor(&self.device)?.to_dtype(dtype)?,
+                attention.to_tensor(&self.device)?,
+            )),
+            resp => Err(anyhow!("unexpected response {:?}", &resp)),
+        }
     }
 
     async fn forward_batch(
         &mut self,
         x: &Tensor,
         batch: Vec<(String, usize, usize)>,
-        _: &mut Cache,
+        cache: &mut Cache,
     ) -> Result<Tensor> {
-        self.forward_request(super::Message::from_batch(x, batch))
-            .await
+        let dtype = x.dtype();
+        let (req, layers) = self.batch_request(x, batch, cache)?;
+        self.forward_request(req, &layers)
+            .await?
+            .to_dtype(dtype)
+            .map_err(|e| anyhow!(e))
+    }
+
+    async fn start_batch(
+        &mut self,
+        x: &Tensor,
+        batch: Vec<(String, usize, usize)>,
+        cache: &Cache,
+    ) -> Result<()> {
+        if self.pending.is_some() {
+            return Err(anyhow!("a forward is already running on {}", &self.address));
+        }
+
+        let (req, layers) = self.batch_request(x, batch, cache)?;
+        let req = self.with_checksums(req);
+        self.check_abandoned_call();
+        // finish_batch reads the response
+        self.in_call = true;
+        // if it can't be sent now, the request is sent again once finish_batch reconnects
+        if self.healthy {
+            match req
+                .to_writer_throttled(
+                    &mut self.stream,
+                    self.options.compression,
+                    self.limiter.as_mut(),
+                )
+                .await
+            {
+                Ok(()) => self.sent = true,
+                Err(e) => {
+                    log::warn!("request to {} failed: {}", &self.address, e);
+                    self.healthy = false;
+                }
+            }
+        }
+        self.pending = Some(PendingForward {
+            req,
+            layers,
+            dtype: x.dtype(),
+        });
+        Ok(())
+    }
+
+    async fn finish_batch(&mut self) -> Result<Tensor> {
+        let PendingForward { req, layers, dtype } = self
+            .pending
+            .take()
+            .ok_or_else(|| anyhow!("no forward is running on {}", &self.address))?;
+        self.forward_request(req, &layers)
+            .await?
+            .to_dtype(dtype)
+            .map_err(|e| anyhow!(e))
+    }
+
+    async fn reset(&mut self, left_padding: &[usize]) -> Result<()> {
+        // new sequences are about to reuse the connection, it's opened again if it dropped since
+        // the previous ones
+        self.check_connection();
+        // no response is expected for this message
+        self.send(Message::ResetCache {
+            left_padding: left_padding.to_vec(),
+        })
+        .await
+    }
+
+    async fn select_cache(&mut self, id: u64) -> Result<()> {
+        if id == self.cache_id {
+            return Ok(());
+        }
+        self.check_connection();
+        if self.stateful {
+            self.stashed.insert(self.cache_id);
+        }
+        // a reconnection selects the id again and loses the state of every id
+        self.stateful = self.stashed.remove(&id);
+        let kept = self.stateful;
+        let lost = self.lost.remove(&id);
+        self.cache_id = id;
+        // no response is expected for this message
+        self.send(Message::SelectCache { id }).await?;
+
+        if lost || (kept && !self.stateful) {
+            return Err(ClientError::CacheLost {
+                address: self.address.clone(),
+                reason: None,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    async fn drop_cache(&mut self, id: u64) -> Result<()> {
+        if id == self.cache_id {
+            self.stateful = false;
+        }
+        self.stashed.remove(&id);
+        self.lost.remove(&id);
+        self.send(Message::DropCache { id }).await
+    }
+
+    async fn get_kv_cache(
+        &mut self,
+        block_idxs: &[usize],
+        cache: &Cache,
+    ) -> Result<Vec<(usize, Tensor, Tensor)>> {
+        let dtype = cache.cos.dtype();
+        let resp = self
+            .request(Message::GetCache {
+                blocks: block_idxs.to_vec(),
+            })
+            .await?;
+        match resp {
+            Message::Cache(kvs) => kvs
+                .into_iter()
+                .map(|(idx, k, v)| {
+                    Ok((
+                        idx,
+                        k.to_tensor(&self.device)?.to_dtype(dtype)?,
+                        v.to_tensor(&self.device)?.to_dtype(dtype)?,
+                    ))
+                })
+                .collect(),
+            _ => Err(anyhow!("unexpected response {:?}", &resp)),
+        }
+    }
+
+    async fn set_kv_cache(
+        &mut self,
+        kvs: Vec<(usize, Tensor, Tensor)>,
+        cache: &mut Cache,
+    ) -> Result<()> {
+        let kvs = kvs
+            .iter()
+            .map(|(idx, k, v)| {
+                Ok((
+                    *idx,
+                    RawTensor::from_tensor(&self.to_worker_dtype(k, cache)?),
+                    RawTensor::from_tensor(&self.to_worker_dtype(v, cache)?),
+                ))
+            })
+            .collect::<Result<_>>()?;
+        self.send(Message::SetCache(kvs)).await
+    }
+
+    fn status(&self) -> Vec<WorkerStatus> {
+        vec![Client::status(self)]
     }
 
     fn ident(&self) -> &str {
@@ -103,3 +957,445 @@ impl super::Forwarder for Client {
         &self.layer_name
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cake::{Context, Forwarder},
+        model::Block,
+        test_utils::{self, MockWorker, Reply},
+    };
+
+    /// Answers the forward requests with their input.
+    fn echo(msg: Message) -> Reply {
+        match msg {
+            Message::TransformerOp { x, .. } => Reply::Message(Message::Tensor(x)),
+            _ => Reply::Nothing,
+        }
+    }
+
+    async fn client(worker: &MockWorker, options: ConnectionOptions) -> Client {
+        Client::new(Device::Cpu, &worker.address, "model.layers.0", options)
+            .await
+            .unwrap()
+    }
+
+    fn input() -> (Tensor, Cache) {
+        let config = test_utils::model_config();
+        let cache = Cache::new(true, DType::F32, &config, &Device::Cpu).unwrap();
+        (
+            Tensor::ones((1, 2, 64), DType::F32, &Device::Cpu).unwrap(),
+            cache,
+        )
+    }
+
+    #[tokio::test]
+    async fn silent_worker_is_flagged_unhealthy() {
+        let worker = MockWorker::start(|_, msg| match msg {
+            Message::TransformerOp { .. } => Reply::Silent,
+            msg => echo(msg),
+        })
+        .await;
+        let options = ConnectionOptions {
+            heartbeat_timeout: Duration::from_millis(200),
+            reconnect_attempts: 0,
+            ..test_utils::connection_options()
+        };
+        let mut client = client(&worker, options).await;
+        assert!(client.status().healthy);
+
+        let (x, mut cache) = input();
+        let start = Instant::now();
+        let e = client.forward(&x, 0, 0, &mut cache).await.unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(!client.status().healthy);
+        match e.downcast_ref::<ClientError>() {
+            Some(ClientError::Unreachable {
+                address, reason, ..
+            }) => {
+                assert_eq!(address, &worker.address);
+                assert!(reason.contains("missed its heartbeats"), "{reason}");
+            }
+            _ => panic!("unexpected error {e}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn busy_worker_sending_heartbeats_is_healthy() {
+        let worker = MockWorker::start(|_, msg| match msg {
+            // twice the heartbeat timeout to process the request
+            Message::TransformerOp { x, .. } => Reply::Busy(
+                Duration::from_millis(50),
+                Duration::from_millis(400),
+                Message::Tensor(x),
+            ),
+            msg => echo(msg),
+        })
+        .await;
+        let options = ConnectionOptions {
+            heartbeat_timeout: Duration::from_millis(200),
+            reconnect_attempts: 0,
+            ..test_utils::connection_options()
+        };
+        let mut client = client(&worker, options).await;
+
+        let (x, mut cache) = input();
+        let before = client.status().last_seen;
+        let y = client.forward(&x, 0, 0, &mut cache).await.unwrap();
+        assert_eq!(y.dims(), x.dims());
+        let status = client.status();
+        assert!(status.healthy);
+        assert!(status.last_seen > before);
+    }
+
+    #[tokio::test]
+    async fn response_of_a_dropped_call_isnt_read_by_the_next_one() {
+        // answers with the position of the request, the first one once busy for a while
+        let worker = MockWorker::start(|connection, msg| match msg {
+            Message::TransformerOp { index_pos, .. } => {
+                let y = Tensor::full(index_pos as f32, (1, 2, 64), &Device::Cpu).unwrap();
+                let reply = Message::from_tensor(&y);
+                match (connection, index_pos) {
+                    (0, 1) => {
+                        Reply::Busy(Duration::from_millis(20), Duration::from_millis(300), reply)
+                    }
+                    _ => Reply::Message(reply),
+                }
+            }
+            msg => echo(msg),
+        })
+        .await;
+        let mut client = client(&worker, test_utils::connection_options()).await;
+        let (x, mut cache) = input();
+        let position = |y: Tensor| y.flatten_all().unwrap().to_vec1::<f32>().unwrap()[0];
+
+        let y = client.forward(&x, 0, 0, &mut cache).await.unwrap();
+        assert_eq!(position(y), 0.);
+
+        // like a request handler whose client disconnected
+        let call = client.forward(&x, 1, 0, &mut cache);
+        assert!(tokio::time::timeout(Duration::from_millis(50), call)
+            .await
+            .is_err());
+
+        // on a new connection, the worker having lost the first position
+        let e = client.forward(&x, 2, 0, &mut cache).await.unwrap_err();
+        assert!(
+            matches!(e.downcast_ref(), Some(ClientError::CacheLost { .. })),
+            "{e}"
+        );
+        let y = client.forward(&x, 2, 0, &mut cache).await.unwrap();
+        assert_eq!(position(y), 2.);
+        assert!(client.status().healthy);
+    }
+
+    /// Worker sending heartbeats for the duration before answering the forward requests.
+    async fn slow_worker(duration: Duration) -> MockWorker {
+        MockWorker::start(move |_, msg| match msg {
+            Message::TransformerOp { x, .. } => {
+                Reply::Busy(Duration::from_millis(20), duration, Message::Tensor(x))
+            }
+            msg => echo(msg),
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn forward_times_out() {
+        let worker = slow_worker(Duration::from_secs(5)).await;
+        let options = ConnectionOptions {
+            rpc_timeout: Duration::from_millis(200),
+            reconnect_attempts: 0,
+            ..test_utils::connection_options()
+        };
+        let mut client = client(&worker, options).await;
+
+        let (x, mut cache) = input();
+        let start = Instant::now();
+        let e = client.forward(&x, 0, 0, &mut cache).await.unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(!client.status().healthy);
+        match e.downcast_ref::<ClientError>() {
+            Some(ClientError::Timeout {
+                address,
+                layers,
+                timeout,
+            }) => {
+                assert_eq!(address, &worker.address);
+                assert!(layers.contains("model.layers.0"), "{layers}");
+                assert_eq!(*timeout, Duration::from_millis(200));
+            }
+            _ => panic!("unexpected error {e}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn timeout_is_per_forward() {
+        let worker = slow_worker(Duration::from_millis(120)).await;
+        let options = ConnectionOptions {
+            rpc_timeout: Duration::from_millis(300),
+            ..test_utils::connection_options()
+        };
+        let mut client = client(&worker, options).await;
+
+        // longer than the timeout altogether
+        let (x, mut cache) = input();
+        let start = Instant::now();
+        for _ in 0..4 {
+            client.forward(&x, 0, 0, &mut cache).await.unwrap();
+        }
+        assert!(start.elapsed() > Duration::from_millis(300));
+        assert!(client.status().healthy);
+    }
+
+    #[tokio::test]
+    async fn corrupted_request_is_sent_again() {
+        let mut mismatches = 0;
+        let worker = MockWorker::start(move |_, msg| match msg {
+            Message::TransformerOp { x, .. } => {
+                assert!(x.checksum.is_some());
+                if mismatches < 2 {
+                    mismatches += 1;
+                    Reply::Message(Message::ChecksumMismatch)
+                } else {
+                    Reply::Message(Message::Tensor(x))
+                }
+            }
+            msg => echo(msg),
+        })
+        .await;
+        let options = ConnectionOptions {
+            verify_checksums: true,
+            reconnect_attempts: 2,
+            ..test_utils::connection_options()
+        };
+        let mut retried = client(&worker, options).await;
+
+        let (x, mut cache) = input();
+        let y = retried.forward(&x, 0, 0, &mut cache).await.unwrap();
+        assert_eq!(y.dims(), x.dims());
+
+        // every attempt is corrupted
+        let worker = MockWorker::start(|_, msg| match msg {
+            Message::TransformerOp { .. } => Reply::Message(Message::ChecksumMismatch),
+            msg => echo(msg),
+        })
+        .await;
+        let options = ConnectionOptions {
+            verify_checksums: true,
+            reconnect_attempts: 2,
+            ..test_utils::connection_options()
+        };
+        let mut corrupted = client(&worker, options).await;
+        assert!(corrupted.forward(&x, 0, 0, &mut cache).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reconnects_once_the_connection_drops() {
+        let worker = MockWorker::start(|connection, msg| match msg {
+            Message::TransformerOp { .. } if connection == 0 => Reply::Drop,
+            msg => echo(msg),
+        })
+        .await;
+        let options = ConnectionOptions {
+            reconnect_attempts: 2,
+            ..test_utils::connection_options()
+        };
+        let mut client = client(&worker, options).await;
+
+        let (x, mut cache) = input();
+        let y = client.forward(&x, 0, 0, &mut cache).await.unwrap();
+        assert_eq!(
+            y.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+            x.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+        );
+        assert!(client.status().healthy);
+    }
+
+    #[tokio::test]
+    async fn reconnection_loses_the_kept_caches() {
+        // the first connection drops at its second forward
+        let selected = Arc::new(std::sync::Mutex::new(vec![]));
+        let worker = MockWorker::start({
+            let selected = selected.clone();
+            let mut forwards = 0;
+            move |connection, msg| match msg {
+                Message::SelectCache { id } => {
+                    selected.lock().unwrap().push((connection, id));
+                    Reply::Nothing
+                }
+                Message::TransformerOp { .. } if connection == 0 => {
+                    forwards += 1;
+                    if forwards == 2 {
+                        Reply::Drop
+                    } else {
+                        echo(msg)
+                    }
+                }
+                msg => echo(msg),
+            }
+        })
+        .await;
+        let mut client = client(&worker, test_utils::connection_options()).await;
+        let (x, mut cache) = input();
+        let is_lost = |res: Result<()>| {
+            matches!(
+                res.unwrap_err().downcast_ref(),
+                Some(ClientError::CacheLost { .. })
+            )
+        };
+
+        // the state of id 0 is kept by the worker while id 1 is processed
+        client.forward(&x, 0, 0, &mut cache).await.unwrap();
+        client.select_cache(1).await.unwrap();
+        client.select_cache(1).await.unwrap();
+        // id 1 had no state to lose, the new connection processes it
+        client.forward(&x, 0, 0, &mut cache).await.unwrap();
+        assert_eq!(*selected.lock().unwrap(), [(0, 1), (1, 1)]);
+
+        // the state of id 0 was kept by the dropped connection
+        assert!(is_lost(client.select_cache(0).await));
+        client.forward(&x, 0, 0, &mut cache).await.unwrap();
+        client.select_cache(1).await.unwrap();
+        client.select_cache(0).await.unwrap();
+        client.forward(&x, 1, 0, &mut cache).await.unwrap();
+        assert_eq!(selected.lock().unwrap()[2..], [(1, 0), (1, 1), (1, 0)]);
+    }
+
+    #[tokio::test]
+    async fn unreachable_once_the_attempts_are_exhausted() {
+        let worker = MockWorker::start(|_, msg| match msg {
+            Message::TransformerOp { .. } => Reply::Drop,
+            msg => echo(msg),
+        })
+        .await;
+        let options = ConnectionOptions {
+            reconnect_attempts: 2,
+            ..test_utils::connection_options()
+        };
+        let mut client = client(&worker, options.clone()).await;
+        let (x, mut cache) = input();
+        let e = client.forward(&x, 0, 0, &mut cache).await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<ClientError>(),
+            Some(ClientError::Unreachable { address, layers, .. })
+                if address == &worker.address && layers == "model.layers.0"
+        ));
+
+        // the worker is gone
+        let mut client = Client::new(Device::Cpu, &worker.address, "model.layers.0", options)
+            .await
+            .unwrap();
+        let address = worker.address.clone();
+        drop(worker);
+        let e = client.forward(&x, 0, 0, &mut cache).await.unwrap_err();
+        assert!(matches!(
+            crate::cake::CakeError::from(e),
+            crate::cake::CakeError::WorkerUnreachable { addr, layers, .. }
+                if addr == address && layers == "model.layers.0"
+        ));
+    }
+
+    #[tokio::test]
+    async fn mixed_dtype_workers() {
+        let (f16, f32) = (test_utils::free_address(), test_utils::free_address());
+        let topology = test_utils::topology(
+            "mixed-dtypes.yml",
+            &format!(
+                "w-f16: {{ host: '{f16}', layers: [0], dtype: f16 }}\n\
+                 w-f32: {{ host: '{f32}', layers: [1], dtype: f32 }}"
+            ),
+        );
+        let _workers = (
+            test_utils::worker("w-f16", &topology, &[]).await,
+            test_utils::worker("w-f32", &topology, &[]).await,
+        );
+        let options = test_utils::connection_options();
+        let mut first = Client::new(Device::Cpu, &f16, "model.layers.0", options.clone())
+            .await
+            .unwrap();
+        let mut second = Client::new(Device::Cpu, &f32, "model.layers.1", options)
+            .await
+            .unwrap();
+        assert_eq!(first.worker_info().dtype, "f16");
+        assert_eq!(second.worker_info().dtype, "f32");
+
+        let x = Tensor::randn(0f32, 1., (1, 3, 64), &Device::Cpu).unwrap();
+        let (_, mut cache) = input();
+        let y = first.forward(&x, 0, 0, &mut cache).await.unwrap();
+        assert_eq!(y.dtype(), DType::F32);
+        let y = second.forward(&y, 0, 1, &mut cache).await.unwrap();
+        assert_eq!(y.dtype(), DType::F32);
+
+        // the workers are started with --dtype f32, each block runs in the dtype of its node
+        let mut expected = x;
+        for (block_idx, dtype) in [(0, "f16"), (1, "f32")] {
+            let args = Args {
+                dtype: Some(dtype.to_string()),
+                ..test_utils::args(&[])
+            };
+            let ctx = Context::from_args(args).unwrap();
+            let name = format!("model.layers.{block_idx}");
+            let block = Block::load(&name, ctx.var_builder.pp(&name), &ctx.config).unwrap();
+            let mut cache = ctx.cache.as_new();
+            let x = expected.to_dtype(ctx.cache.cos.dtype()).unwrap();
+            expected = block
+                .forward_imm(&x, 0, block_idx, &mut cache)
+                .await
+                .unwrap()
+                .to_dtype(DType::F32)
+                .unwrap();
+        }
+        assert_eq!(
+            y.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+            expected.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+        );
+    }
+
+    /// Connects to a worker of the address with the auth token.
+    async fn connect_with_token(address: &str, token: Option<&str>) -> Result<Client> {
+        let options = ConnectionOptions {
+            auth_token: token.map(str::to_string),
+            reconnect_attempts: 0,
+            ..test_utils::connection_options()
+        };
+        Client::new(Device::Cpu, address, "model.layers.0", options).await
+    }
+
+    #[tokio::test]
+    async fn auth_token_of_the_worker() {
+        let (address, open) = (test_utils::free_address(), test_utils::free_address());
+        let topology = test_utils::topology(
+            "auth.yml",
+            &format!(
+                "auth: {{ host: '{address}', layers: [0] }}\n\
+                 open: {{ host: '{open}', layers: [1] }}"
+            ),
+        );
+        let _workers = (
+            test_utils::worker("auth", &topology, &["--auth-token", "secret"]).await,
+            test_utils::worker("open", &topology, &[]).await,
+        );
+
+        let mut authorized = connect_with_token(&address, Some("secret")).await.unwrap();
+        let (x, mut cache) = input();
+        let y = authorized.forward(&x, 0, 0, &mut cache).await.unwrap();
+        assert_eq!(y.dims(), x.dims());
+
+        for token in [Some("secrets"), Some("terces"), None] {
+            let e = connect_with_token(&address, token).await.unwrap_err();
+            match e.downcast_ref::<ClientError>() {
+                Some(ClientError::Unauthorized { address: rejected }) => {
+                    assert_eq!(rejected, &address)
+                }
+                _ => panic!("unexpected error {e}"),
+            }
+        }
+
+        // a worker without token accepts any master
+        for token in [Some("secret"), None] {
+            connect_with_token(&open, token).await.unwrap();
+        }
+    }
+}
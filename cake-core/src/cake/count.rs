@@ -0,0 +1,35 @@
+use std::fmt;
+
+/// Size of a prompt and the room it leaves in the context, see Master::count_prompt.
+#[derive(Debug, Clone)]
+pub struct PromptCount {
+    pub prompt_tokens: usize,
+    pub context_size: usize,
+    /// --max-tokens, if set.
+    pub max_tokens: Option<usize>,
+}
+
+impl PromptCount {
+    /// Number of tokens that can be generated after the prompt before the context is full.
+    pub fn remaining(&self) -> usize {
+        self.context_size.saturating_sub(self.prompt_tokens)
+    }
+}
+
+impl fmt::Display for PromptCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "prompt:       {} tokens", self.prompt_tokens)?;
+        writeln!(f, "context size: {} tokens", self.context_size)?;
+        writeln!(f, "remaining:    {} tokens", self.remaining())?;
+        if let Some(max_tokens) = self.max_tokens {
+            if max_tokens > self.remaining() {
+                writeln!(
+                    f,
+                    "the prompt leaves room for {} of the {max_tokens} tokens of --max-tokens, see --truncate",
+                    self.remaining()
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,392 @@
+use std::{path::PathBuf, time::Duration};
+
+use super::ClientError;
+
+/// Errors of the public entry points of the library, so that the callers can tell the main
+/// failure classes apart. Anything else is reported as Other.
+#[derive(Debug, thiserror::Error)]
+pub enum CakeError {
+    /// A worker of the topology couldn't be connected to, or reconnected to once the connection
+    /// dropped.
+    #[error("worker {addr} serving {layers} is unreachable: {reason}")]
+    WorkerUnreachable {
+        addr: String,
+        layers: String,
+        reason: String,
+    },
+    /// The topology can't be read or doesn't match the model.
+    #[error("invalid topology: {reason}")]
+    TopologyInvalid { reason: String },
+    /// An argument has a value that can't be used, or a required one is missing.
+    #[error("invalid argument: {reason}")]
+    InvalidArgument { reason: String },
+    /// The configuration of the model can't be read or parsed.
+    #[error("can't parse {}: {reason}", path.display())]
+    ConfigParse { path: PathBuf, reason: String },
+    /// The configuration of the model describes an architecture that isn't implemented, such as
+    /// a mixture of experts.
+    #[error("unsupported model architecture, {field} is {value}: {reason}")]
+    ArchitectureUnsupported {
+        field: String,
+        value: String,
+        reason: String,
+    },
+    /// A dtype other than f16, bf16 or f32 has been requested.
+    #[error("unsupported dtype {got}, expected f16, bf16 or f32")]
+    DtypeUnsupported { got: String },
+    /// The tensors exchanged with a worker kept being corrupted, see --verify-checksums.
+    #[error("tensors exchanged with worker {addr} were corrupted {attempts} times")]
+    ChecksumMismatch { addr: String, attempts: usize },
+    /// A worker kept rejecting the requests of the master, see --worker-queue.
+    #[error("worker {addr} serving {layers} was overloaded for {attempts} attempts")]
+    WorkerOverloaded {
+        addr: String,
+        layers: String,
+        attempts: usize,
+    },
+    /// None of the workers a layer is replicated on could be reached.
+    #[error("no replica serving {layers} is healthy: {reason}")]
+    NoHealthyReplica { layers: String, reason: String },
+    /// The layers assigned to a worker won't fit in the memory of one of its devices, see
+    /// --skip-memory-check.
+    #[error(
+        "{layers} layers need about {} on {device} but only {} is available, assign fewer layers to this worker or pass --skip-memory-check",
+        human_bytes::human_bytes(*required as f64),
+        human_bytes::human_bytes(*available as f64)
+    )]
+    InsufficientMemory {
+        device: String,
+        layers: usize,
+        required: u64,
+        available: u64,
+    },
+    /// A worker didn't answer a forward request within --rpc-timeout-ms.
+    #[error("worker {addr} serving {layers} didn't answer within {timeout:?}")]
+    Timeout {
+        addr: String,
+        layers: String,
+        timeout: Duration,
+    },
+    /// The forward pass failed or panicked on a worker, such as on a shape mismatch.
+    #[error("worker {addr} failed the request: {message}")]
+    WorkerInternal { addr: String, message: String },
+    /// The prompt doesn't leave room in the context for the tokens to generate, see --truncate.
+    #[error(
+        "the prompt has {prompt_tokens} tokens but the context size of {context_size} only leaves room for {} with {reserved} tokens to generate, see --truncate",
+        context_size.saturating_sub(*reserved)
+    )]
+    PromptTooLong {
+        prompt_tokens: usize,
+        reserved: usize,
+        context_size: usize,
+    },
+    /// The model produced a NaN or infinite logit, from a worker returning corrupted activations
+    /// for instance, see --sanitize-logits.
+    #[error("non-finite logit {value} for token {token} at position {position}")]
+    NonFiniteLogits {
+        position: usize,
+        token: u32,
+        value: f32,
+    },
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for CakeError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<CakeError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+
+        match e.downcast_ref::<ClientError>() {
+            Some(ClientError::Unreachable {
+                address,
+                layers,
+                reason,
+            }) => {
+                return Self::WorkerUnreachable {
+                    addr: address.clone(),
+                    layers: layers.clone(),
+                    reason: reason.clone(),
+                }
+            }
+            Some(ClientError::Timeout {
+                address,
+                layers,
+                timeout,
+            }) => {
+                return Self::Timeout {
+                    addr: address.clone(),
+                    layers: layers.clone(),
+                    timeout: *timeout,
+                }
+            }
+            Some(ClientError::Corrupted {
+                address, attempts, ..
+            }) => {
+                return Self::ChecksumMismatch {
+                    addr: address.clone(),
+                    attempts: *attempts,
+                }
+            }
+            Some(ClientError::Overloaded {
+                address,
+                layers,
+                attempts,
+            }) => {
+                return Self::WorkerOverloaded {
+                    addr: address.clone(),
+                    layers: layers.clone(),
+                    attempts: *attempts,
+                }
+            }
+            Some(ClientError::Internal { address, message }) => {
+                return Self::WorkerInternal {
+                    addr: address.clone(),
+                    message: message.clone(),
+                }
+            }
+            Some(ClientError::NoHealthyReplica { layers, reason }) => {
+                return Self::NoHealthyReplica {
+                    layers: layers.clone(),
+                    reason: reason.clone(),
+                }
+            }
+            _ => {}
+        }
+
+        Self::Other(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        cake::{Context, Master, Message, Worker},
+        test_utils::{self, MockWorker, Reply},
+        Args,
+    };
+
+    async fn master(args: Args) -> Result<Master, CakeError> {
+        Master::new(Context::from_args(args)?).await
+    }
+
+    async fn worker(args: Args) -> Result<Worker, CakeError> {
+        Worker::new(Context::from_args(args)?).await
+    }
+
+    /// Arguments of a master whose layers 2 and 3 are served by the worker at address.
+    fn remote_args(name: &str, address: &str, extra: &[&str]) -> Args {
+        let topology = test_utils::topology(
+            &format!("{name}.yml"),
+            &format!("{name}: {{ host: '{address}', layers: [2, 3] }}"),
+        );
+        Args {
+            topology,
+            ..test_utils::args(extra)
+        }
+    }
+
+    /// Error of a generation through a worker answering the forward requests with reply.
+    async fn generation_error<F>(name: &str, reply: F, extra: &[&str]) -> (String, CakeError)
+    where
+        F: Fn(Message) -> Reply + Send + 'static,
+    {
+        let worker = MockWorker::start(move |_, msg| match msg {
+            Message::TransformerOp { .. } | Message::Batch { .. } => reply(msg),
+            _ => Reply::Nothing,
+        })
+        .await;
+        let args = remote_args(name, &worker.address, extra);
+        let mut master = master(args.clone()).await.unwrap();
+        let tokens = master.encode("the cat sat").unwrap();
+        let e = master
+            .generate_with(&args, tokens, &Default::default(), |_| {})
+            .await
+            .unwrap_err();
+        (worker.address.clone(), e)
+    }
+
+    #[tokio::test]
+    async fn worker_unreachable() {
+        let address = test_utils::free_address();
+        let args = remote_args("unreachable", &address, &["--reconnect-attempts", "0"]);
+        match master(args).await {
+            Err(CakeError::WorkerUnreachable { addr, layers, .. }) => {
+                assert_eq!(addr, address);
+                assert!(layers.contains("model.layers.2"), "{layers}");
+            }
+            res => panic!("unexpected result {:?}", res.err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn topology_invalid() {
+        let args = Args {
+            topology: "/nonexistent/topology.yml".to_string(),
+            ..test_utils::args(&[])
+        };
+        assert!(matches!(
+            Context::from_args(args),
+            Err(CakeError::TopologyInvalid { .. })
+        ));
+
+        // a worker isn't in the topology, or its layers are served by the master
+        let topology = test_utils::topology(
+            "local-worker.yml",
+            "local-0: { host: local, layers: [2, 3] }",
+        );
+        for name in ["missing-0", "local-0"] {
+            let args = Args {
+                topology: topology.clone(),
+                ..test_utils::args(&["--mode", "worker", "--name", name])
+            };
+            match worker(args).await {
+                Err(CakeError::TopologyInvalid { reason }) => {
+                    assert!(reason.contains(name), "{reason}")
+                }
+                res => panic!("unexpected result {:?}", res.err()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_worker_arguments() {
+        let topology = test_utils::topology(
+            "invalid-arguments.yml",
+            &format!(
+                "invalid-0: {{ host: '{}', layers: [2, 3] }}",
+                test_utils::free_address()
+            ),
+        );
+        for (extra, expected) in [
+            (&[][..], "no --name provided for worker"),
+            (
+                &["--name", "invalid-0", "--worker-concurrency", "0"],
+                "--worker-concurrency must be at least 1",
+            ),
+            (
+                &["--name", "invalid-0", "--cpu-offload-layers", "3"],
+                "--cpu-offload-layers 3 exceeds the 2 layers of invalid-0",
+            ),
+        ] {
+            let args = Args {
+                topology: topology.clone(),
+                ..test_utils::args(&[&["--mode", "worker"], extra].concat())
+            };
+            match worker(args).await {
+                Err(CakeError::InvalidArgument { reason }) => assert_eq!(reason, expected),
+                res => panic!("unexpected result {:?}", res.err()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn insufficient_memory() {
+        // layers far larger than any memory, the weights not matching isn't noticed before
+        let dir = test_utils::model_dir_with("oversized", serde_json::json!({}));
+        let mut config = test_utils::config();
+        config["intermediate_size"] = serde_json::json!(1u64 << 40);
+        std::fs::write(dir.join("config.json"), config.to_string()).unwrap();
+        let topology = dir.join("topology.yml");
+        std::fs::write(
+            &topology,
+            format!(
+                "oversized-0: {{ host: '{}', layers: [1, 2, 3] }}",
+                test_utils::free_address()
+            ),
+        )
+        .unwrap();
+
+        let args = test_utils::args_for(&dir, &["--mode", "worker", "--name", "oversized-0"]);
+        match worker(args.clone()).await {
+            Err(CakeError::InsufficientMemory {
+                device,
+                layers,
+                required,
+                available,
+            }) => {
+                assert_eq!(device, "Cpu");
+                assert_eq!(layers, 3);
+                assert!(required > available);
+            }
+            res => panic!("unexpected result {:?}", res.err()),
+        }
+
+        // loading goes on when skipping the check, failing at the weights instead
+        let args = Args {
+            skip_memory_check: true,
+            ..args
+        };
+        match worker(args).await {
+            Err(CakeError::InsufficientMemory { .. }) => panic!("memory checked"),
+            Err(e) => assert!(e.to_string().contains("shape"), "{e}"),
+            Ok(_) => panic!("loaded the oversized layers"),
+        }
+    }
+
+    #[test]
+    fn config_parse() {
+        let dir = test_utils::model_dir_with("invalid-config", serde_json::json!({}));
+        std::fs::write(dir.join("config.json"), "{ not json").unwrap();
+        match Context::from_args(test_utils::args_for(&dir, &[])) {
+            Err(CakeError::ConfigParse { path, .. }) => {
+                assert_eq!(path, dir.join("config.json"))
+            }
+            res => panic!("unexpected result {:?}", res.err()),
+        }
+    }
+
+    #[test]
+    fn dtype_unsupported() {
+        let args = Args {
+            dtype: Some("f64".to_string()),
+            ..test_utils::args(&[])
+        };
+        match Context::from_args(args) {
+            Err(CakeError::DtypeUnsupported { got }) => assert_eq!(got, "f64"),
+            res => panic!("unexpected result {:?}", res.err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn checksum_mismatch() {
+        let (address, e) = generation_error(
+            "checksum-mismatch",
+            |_| Reply::Message(Message::ChecksumMismatch),
+            &["--verify-checksums", "--reconnect-attempts", "1"],
+        )
+        .await;
+        match e {
+            CakeError::ChecksumMismatch { addr, .. } => assert_eq!(addr, address),
+            e => panic!("unexpected error {e}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let (address, e) = generation_error(
+            "timeout",
+            |_| {
+                Reply::Busy(
+                    Duration::from_millis(20),
+                    Duration::from_secs(5),
+                    Message::ChecksumMismatch,
+                )
+            },
+            &["--rpc-timeout-ms", "200", "--reconnect-attempts", "0"],
+        )
+        .await;
+        match e {
+            CakeError::Timeout { addr, timeout, .. } => {
+                assert_eq!(addr, address);
+                assert_eq!(timeout, Duration::from_millis(200));
+            }
+            e => panic!("unexpected error {e}"),
+        }
+    }
+}
@@ -0,0 +1,1934 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use super::{
+    sampling::sampling_seed,
+    sequence::{Sequence, TimeBudget, TokenChooser},
+    Master, TokenLogprob,
+};
+use crate::{
+    cake::{CakeError, ClientError, Metrics},
+    model::CacheState,
+    utils::GrammarState,
+    Args,
+};
+
+use anyhow::Result;
+use candle_core::Tensor;
+use tokio::sync::mpsc;
+
+/// Pieces of text the generations streaming to an async callback get ahead of it.
+const MAX_PENDING_TEXT: usize = 8;
+
+/// Why a generation stopped. When several conditions are met by the same token, cancellation
+/// takes precedence over a stop sequence, which takes precedence over the end of sequence token,
+/// which takes precedence over the token limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The generation has been cancelled.
+    Cancelled,
+    /// A stop sequence has been generated, or the grammar is complete.
+    Stop,
+    /// One of the end of sequence tokens has been generated.
+    Eos,
+    /// The token limit or the end of the context has been reached.
+    Length,
+    /// The time allowed by --max-time-ms has passed.
+    TimeLimit,
+}
+
+impl FinishReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Cancelled => "cancelled",
+            Self::Stop => "stop",
+            Self::Eos => "eos",
+            Self::Length => "length",
+            Self::TimeLimit => "time_limit",
+        }
+    }
+}
+
+impl fmt::Display for FinishReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Passed to the stream callback for every generated token, then once with the text left when
+/// the generation is over and once more with no text to signal its end.
+#[derive(Debug, Clone)]
+pub struct TokenEvent<'a> {
+    /// Text that can be streamed after this token, often empty while the tokens of a word or of
+    /// a possible stop sequence are buffered.
+    pub text: &'a str,
+    /// Generated token, None for the event echoing the prefill and for the events sent once the
+    /// generation is over.
+    pub token_id: Option<u32>,
+    /// Log-probability of the token, set by generate_events and generate_with_events.
+    pub logprob: Option<f32>,
+    /// Time spent generating since the prompt started being processed, the time spent in the
+    /// stream callback excluded.
+    pub elapsed_since_start: Duration,
+    pub prompt_tokens: usize,
+    pub generated_tokens: usize,
+    /// Why the generation stopped, set on the last event only.
+    pub finish_reason: Option<FinishReason>,
+}
+
+impl<'a> TokenEvent<'a> {
+    /// Returns the text of the event for the text only callbacks, which get no empty text before
+    /// the end of the generation.
+    pub(super) fn streamed_text(&self) -> Option<&'a str> {
+        if self.token_id.is_some() && self.text.is_empty() {
+            None
+        } else {
+            Some(self.text)
+        }
+    }
+
+    /// Line of the event printed by --output jsonl: the token, or the statistics of the
+    /// generation once it's over.
+    pub fn to_jsonl(&self) -> serde_json::Value {
+        if self.token_id.is_some() || !self.text.is_empty() {
+            return serde_json::json!({
+                "token": self.text,
+                "id": self.token_id,
+                "logprob": self.logprob,
+            });
+        }
+
+        let elapsed = self.elapsed_since_start.as_secs_f64();
+        serde_json::json!({
+            "done": true,
+            "finish_reason": self.finish_reason.map(|reason| reason.as_str()),
+            "stats": {
+                "prompt_tokens": self.prompt_tokens,
+                "generated_tokens": self.generated_tokens,
+                "elapsed_ms": self.elapsed_since_start.as_millis() as u64,
+                "tokens_per_second": self.generated_tokens as f64 / elapsed,
+            },
+        })
+    }
+}
+
+/// Sends the text streamed by a generation to an async consumer through a bounded channel. The
+/// pieces of text of a step are sent before the next forward pass, which waits for the consumer
+/// once the channel is full.
+#[derive(Debug)]
+pub(super) struct Pace {
+    texts: mpsc::Sender<String>,
+    // streamed since the last wait
+    step: std::sync::Mutex<Vec<String>>,
+}
+
+impl Pace {
+    fn new(capacity: usize) -> (Self, mpsc::Receiver<String>) {
+        let (texts, pending) = mpsc::channel(capacity);
+        let pace = Self {
+            texts,
+            step: Default::default(),
+        };
+        (pace, pending)
+    }
+
+    fn push(&self, text: &str) {
+        self.step.lock().unwrap().push(text.to_string());
+    }
+
+    pub(super) async fn wait(&self) {
+        let step = std::mem::take(&mut *self.step.lock().unwrap());
+        for text in step {
+            // the consumer is gone, the generation runs to its end anyway
+            if self.texts.send(text).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Sequences generated together, advanced a forward pass at a time by Master::step_generation.
+pub(super) struct Generation {
+    pub(super) sequences: Vec<Sequence>,
+    left_padding: Vec<usize>,
+    // length of the longest prompt, the others being padded to it
+    prompt_len: usize,
+    // positions the caches hold
+    index_pos: usize,
+    // forward passes that sampled the next tokens
+    sampled: usize,
+    max_tokens: usize,
+    // prompt positions processed per forward pass, all of them if None
+    chunk_size: Option<usize>,
+    eos_token_ids: HashSet<u32>,
+    pad_token_id: u32,
+    budget: TimeBudget,
+    // since the first token, which is the warmup
+    start_gen: Instant,
+    pub(super) done: bool,
+}
+
+impl Generation {
+    /// Returns the tokens of every row at the positions from start to end, padding included.
+    fn input(&self, start: usize, end: usize) -> Vec<u32> {
+        let mut input = Vec::with_capacity(self.sequences.len() * (end - start));
+        for (sequence, pad) in self.sequences.iter().zip(&self.left_padding) {
+            let row =
+                std::iter::repeat_n(self.pad_token_id, *pad).chain(sequence.tokens.iter().copied());
+            input.extend(row.skip(start).take(end - start));
+        }
+        input
+    }
+
+    /// Returns the tokens the caches of a single sequence have been computed from.
+    pub(super) fn cached_tokens(&self) -> &[u32] {
+        &self.sequences[0].tokens[..self.index_pos]
+    }
+
+    /// Processes the sequences again from their start at the next step, once their caches have
+    /// been lost.
+    pub(super) fn restart(&mut self) {
+        self.index_pos = 0;
+    }
+}
+
+/// Wraps stream into the callback of the generation steps, which get the sequence along with its
+/// text and whether a token has just been generated, false for the events sent once it's over.
+pub(super) fn token_events<'a, S>(
+    metrics: Arc<Metrics>,
+    cancel: &'a CancellationToken,
+    mut stream: S,
+) -> impl FnMut(usize, &Sequence, bool, &str) + 'a
+where
+    S: FnMut(usize, &TokenEvent) + 'a,
+{
+    let started = Instant::now();
+    let mut in_callback = Duration::ZERO;
+    move |i: usize, sequence: &Sequence, token: bool, text: &str| {
+        if token {
+            metrics.add_generated_tokens(1);
+        }
+        let last = !token && text.is_empty();
+        // only the end of a cancelled generation is signaled
+        if cancel.is_cancelled() && !last {
+            return;
+        }
+        let event = TokenEvent {
+            text,
+            token_id: if token {
+                sequence.tokens.last().copied()
+            } else {
+                None
+            },
+            logprob: match (token, &sequence.logprobs) {
+                (true, Some(logprobs)) => logprobs.last().map(|logprob| logprob.logprob),
+                _ => None,
+            },
+            elapsed_since_start: started.elapsed() - in_callback,
+            prompt_tokens: sequence.prompt_len,
+            generated_tokens: sequence.generated,
+            finish_reason: if last { sequence.finish_reason } else { None },
+        };
+        let callback = Instant::now();
+        stream(i, &event);
+        in_callback += callback.elapsed();
+    }
+}
+
+/// Stops a generation at the next token once cancelled, from any thread or task holding a
+/// clone of it.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Master {
+    /// Generates text from the prompt and sampling parameters the master has been started with,
+    /// until done or cancelled, and returns why it stopped. The stream gets the prompt first, or
+    /// the fim prefix in fim mode, then the prefill if any.
+    pub async fn generate<S>(
+        &mut self,
+        cancel: &CancellationToken,
+        mut stream: S,
+    ) -> Result<FinishReason, CakeError>
+    where
+        S: FnMut(&str),
+    {
+        let args = &self.ctx.args;
+        stream(if args.fim {
+            &args.fim_prefix
+        } else {
+            &args.prompt
+        });
+
+        Ok(self
+            .generate_from_args(cancel, false, |event| {
+                if let Some(text) = event.streamed_text() {
+                    stream(text)
+                }
+            })
+            .await?)
+    }
+
+    /// Same as generate, awaiting stream with every piece of text so that the generation keeps
+    /// pace with a slow consumer, getting at most a few pieces ahead of it.
+    pub async fn generate_async<S, F>(
+        &mut self,
+        cancel: &CancellationToken,
+        mut stream: S,
+    ) -> Result<FinishReason, CakeError>
+    where
+        S: FnMut(String) -> F,
+        F: Future<Output = ()>,
+    {
+        let args = self.ctx.args.clone();
+        stream(if args.fim {
+            args.fim_prefix.clone()
+        } else {
+            args.prompt.clone()
+        })
+        .await;
+
+        let tokens = self.encode_prompt(&args)?;
+        if let Some(prefill) = args.prefill.clone().filter(|prefill| !prefill.is_empty()) {
+            stream(prefill).await;
+        }
+
+        self.load_cache_in(&args).await?;
+        let finish_reason = self
+            .generate_with_async(&args, tokens, cancel, &mut stream)
+            .await?;
+        self.save_cache_out(&args, cancel).await?;
+
+        Ok(finish_reason)
+    }
+
+    /// Same as generate, passing an event with the timing of the generation and the
+    /// log-probability of every token rather than the text alone.
+    pub async fn generate_events<S>(
+        &mut self,
+        cancel: &CancellationToken,
+        stream: S,
+    ) -> Result<FinishReason, CakeError>
+    where
+        S: FnMut(&TokenEvent),
+    {
+        Ok(self.generate_from_args(cancel, true, stream).await?)
+    }
+
+    async fn generate_from_args<S>(
+        &mut self,
+        cancel: &CancellationToken,
+        logprobs: bool,
+        mut stream: S,
+    ) -> Result<FinishReason>
+    where
+        S: FnMut(&TokenEvent),
+    {
+        let args = self.ctx.args.clone();
+        let tokens = self.encode_prompt(&args)?;
+
+        if let Some(prefill) = args
+            .prefill
+            .as_deref()
+            .filter(|prefill| !prefill.is_empty())
+        {
+            stream(&TokenEvent {
+                text: prefill,
+                token_id: None,
+                logprob: None,
+                elapsed_since_start: Duration::ZERO,
+                prompt_tokens: tokens.len(),
+                generated_tokens: 0,
+                finish_reason: None,
+            });
+        }
+
+        self.load_cache_in(&args).await?;
+        let finish_reason = self
+            .generate_tokens(&args, tokens, cancel, logprobs, None, stream)
+            .await?;
+        self.save_cache_out(&args, cancel).await?;
+
+        Ok(finish_reason)
+    }
+
+    /// Loads the cache of --cache-in, if set.
+    async fn load_cache_in(&mut self, args: &Args) -> Result<()> {
+        if let Some(path) = &args.cache_in {
+            self.load_cache(path).await?;
+        }
+        Ok(())
+    }
+
+    /// Saves the cache to --cache-out if set, unless the generation has been cancelled.
+    async fn save_cache_out(&mut self, args: &Args, cancel: &CancellationToken) -> Result<()> {
+        if let Some(path) = &args.cache_out {
+            if !cancel.is_cancelled() {
+                self.save_cache(path).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Generates text from the given prompt tokens, using the sampling parameters from args. Once
+    /// cancel is triggered the generation stops at the next token and the stream gets no more
+    /// text.
+    pub async fn generate_with<S>(
+        &mut self,
+        args: &Args,
+        tokens: Vec<u32>,
+        cancel: &CancellationToken,
+        mut stream: S,
+    ) -> Result<FinishReason, CakeError>
+    where
+        S: FnMut(&str),
+    {
+        Ok(self
+            .generate_tokens(args, tokens, cancel, false, None, |event| {
+                if let Some(text) = event.streamed_text() {
+                    stream(text)
+                }
+            })
+            .await?)
+    }
+
+    /// Same as generate_with, awaiting stream with every piece of text like generate_async.
+    pub async fn generate_with_async<S, F>(
+        &mut self,
+        args: &Args,
+        tokens: Vec<u32>,
+        cancel: &CancellationToken,
+        mut stream: S,
+    ) -> Result<FinishReason, CakeError>
+    where
+        S: FnMut(String) -> F,
+        F: Future<Output = ()>,
+    {
+        let (pace, mut pending) = Pace::new(MAX_PENDING_TEXT);
+        let generation = async move {
+            let res = self
+                .generate_tokens(args, tokens, cancel, false, Some(&pace), |event| {
+                    if let Some(text) = event.streamed_text() {
+                        pace.push(text);
+                    }
+                })
+                .await;
+            // the text of the last step, then the consumer is done once the sender is dropped
+            pace.wait().await;
+            drop(pace);
+            res
+        };
+        let consume = async {
+            while let Some(text) = pending.recv().await {
+                stream(text).await;
+            }
+        };
+        let (res, ()) = futures::join!(generation, consume);
+        Ok(res?)
+    }
+
+    /// Same as generate_with, passing an event for every token rather than the text alone.
+    pub async fn generate_with_events<S>(
+        &mut self,
+        args: &Args,
+        tokens: Vec<u32>,
+        cancel: &CancellationToken,
+        stream: S,
+    ) -> Result<FinishReason, CakeError>
+    where
+        S: FnMut(&TokenEvent),
+    {
+        Ok(self
+            .generate_tokens(args, tokens, cancel, true, None, stream)
+            .await?)
+    }
+
+    /// Generates a single sequence, paced by pace if set. The candidates of best_of are streamed
+    /// once all of them are generated, whatever the pace.
+    async fn generate_tokens<S>(
+        &mut self,
+        args: &Args,
+        tokens: Vec<u32>,
+        cancel: &CancellationToken,
+        logprobs: bool,
+        pace: Option<&Pace>,
+        mut stream: S,
+    ) -> Result<FinishReason>
+    where
+        S: FnMut(&TokenEvent),
+    {
+        if args.best_of > 1 {
+            return self.generate_best_of(args, tokens, cancel, stream).await;
+        }
+
+        let sequences = self
+            .generate_sequences_with(
+                args,
+                vec![tokens],
+                logprobs,
+                cancel,
+                None,
+                pace,
+                |_, event| stream(event),
+            )
+            .await?;
+        Ok(sequences[0].finish_reason.unwrap_or(FinishReason::Length))
+    }
+
+    /// Generates args.best_of candidates one after the other, with consecutive seeds, and streams
+    /// the events of the one whose tokens have the highest mean log-probability once they're all
+    /// generated. The candidates after the first reuse the caches of the prompt if the prefix
+    /// cache is enabled.
+    async fn generate_best_of<S>(
+        &mut self,
+        args: &Args,
+        tokens: Vec<u32>,
+        cancel: &CancellationToken,
+        mut stream: S,
+    ) -> Result<FinishReason>
+    where
+        S: FnMut(&TokenEvent),
+    {
+        let seed = sampling_seed(args);
+        let mut best: Option<(f32, Vec<(String, TokenEvent<'static>)>)> = None;
+
+        for candidate in 0..args.best_of {
+            let args = Args {
+                seed: Some(seed.wrapping_add(candidate as u64)),
+                ..args.clone()
+            };
+
+            let mut events = vec![];
+            let mut logprobs = vec![];
+            self.generate_sequences(&args, vec![tokens.clone()], true, cancel, |_, event| {
+                logprobs.extend(event.logprob);
+                events.push((event.text.to_string(), TokenEvent { text: "", ..*event }));
+            })
+            .await?;
+
+            if cancel.is_cancelled() {
+                // only the end of the generation is signaled
+                if let Some((text, event)) = events.last() {
+                    stream(&TokenEvent { text, ..*event });
+                }
+                return Ok(FinishReason::Cancelled);
+            }
+
+            let score = if logprobs.is_empty() {
+                f32::NEG_INFINITY
+            } else {
+                logprobs.iter().sum::<f32>() / logprobs.len() as f32
+            };
+
+            log::info!(
+                "candidate {candidate}: mean log-probability {score:.4} over {} tokens",
+                logprobs.len()
+            );
+
+            if best.as_ref().is_none_or(|(best, _)| score > *best) {
+                best = Some((score, events));
+            }
+        }
+
+        let (_, events) = best.unwrap_or_default();
+        let mut finish_reason = FinishReason::Length;
+        for (text, event) in &events {
+            finish_reason = event.finish_reason.unwrap_or(finish_reason);
+            stream(&TokenEvent { text, ..*event });
+        }
+
+        Ok(finish_reason)
+    }
+
+    /// Same as generate_with, also returning the log-probability of every generated token along
+    /// with its args.top_logprobs most likely alternatives. The log-probabilities are the ones of
+    /// the distribution tokens are sampled from, before top-k and top-p filtering.
+    pub async fn generate_with_logprobs<S>(
+        &mut self,
+        args: &Args,
+        tokens: Vec<u32>,
+        mut stream: S,
+    ) -> Result<Vec<TokenLogprob>, CakeError>
+    where
+        S: FnMut(&str),
+    {
+        let mut sequences = self
+            .generate_sequences(
+                args,
+                vec![tokens],
+                true,
+                &CancellationToken::default(),
+                |_, event| {
+                    if let Some(text) = event.streamed_text() {
+                        stream(text)
+                    }
+                },
+            )
+            .await?;
+        Ok(sequences.remove(0).logprobs.unwrap_or_default())
+    }
+
+    /// Generates from the prompt tokens like generate_with, calling choose with the tokens of the
+    /// sequence so far, prompt included, and the logits of the next token, with shape
+    /// (vocab_size,), to pick every token instead of sampling it. The sampling parameters of args,
+    /// the repeat penalty and the logit bias are ignored, while the token limit, the end of
+    /// sequence tokens, the stop sequences and the time budget still end the generation. A token
+    /// that doesn't match the grammar, if any, fails it.
+    pub async fn generate_logits<C, S>(
+        &mut self,
+        args: &Args,
+        tokens: Vec<u32>,
+        cancel: &CancellationToken,
+        mut choose: C,
+        mut stream: S,
+    ) -> Result<FinishReason, CakeError>
+    where
+        C: FnMut(&[u32], &Tensor) -> Result<u32> + Send,
+        S: FnMut(&str),
+    {
+        let sequences = self
+            .generate_sequences_with(
+                args,
+                vec![tokens],
+                false,
+                cancel,
+                Some(&mut choose),
+                None,
+                |_, event| {
+                    if let Some(text) = event.streamed_text() {
+                        stream(text)
+                    }
+                },
+            )
+            .await?;
+        Ok(sequences[0].finish_reason.unwrap_or(FinishReason::Length))
+    }
+
+    /// Generates text for multiple prompts at once, with a single forward pass per token for the
+    /// whole batch. The stream callback receives the index of the prompt along with its text.
+    pub async fn generate_batch<S>(
+        &mut self,
+        prompts: Vec<String>,
+        mut stream: S,
+    ) -> Result<(), CakeError>
+    where
+        S: FnMut(usize, &str),
+    {
+        let args = self.ctx.args.clone();
+        let mut batch = vec![];
+        for prompt in prompts {
+            batch.push(self.encode_prompt(&Args {
+                prompt,
+                ..args.clone()
+            })?);
+        }
+
+        self.generate_sequences(
+            &args,
+            batch,
+            false,
+            &CancellationToken::default(),
+            |i, event| {
+                if let Some(text) = event.streamed_text() {
+                    stream(i, text)
+                }
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Returns true if the tokens extend the ones the caches have been computed from.
+    fn continues_history(&self, tokens: &[u32]) -> bool {
+        self.ctx.cache.use_kv_cache
+            && !self.history.is_empty()
+            && tokens.len() > self.history.len()
+            && tokens.starts_with(&self.history)
+    }
+
+    /// Restores the caches of the longest cached prefix of the tokens, if any.
+    async fn restore_prefix(&mut self, tokens: &[u32]) -> Result<()> {
+        if !self.ctx.cache.use_kv_cache {
+            return Ok(());
+        }
+
+        if let Some((len, state)) = self.prefix_cache.longest_match(tokens) {
+            // at least the last token must be processed to get the logits
+            let len = len.min(tokens.len() - 1);
+            if len == 0 {
+                return Ok(());
+            }
+
+            log::info!("restoring the cache of a {len} tokens prefix");
+
+            let mut cache = self.ctx.cache.as_contiguous();
+            state.narrow(len)?.restore(&mut cache)?;
+
+            self.reset().await?;
+            self.model.set_kv_cache(&cache, &mut self.ctx.cache).await?;
+            self.history = tokens[..len].to_vec();
+        }
+
+        Ok(())
+    }
+
+    /// Generates the sequences and returns them, along with the log-probabilities of their tokens
+    /// if requested. The caches of cancelled sequences are discarded, on the workers too.
+    pub(super) async fn generate_sequences<S>(
+        &mut self,
+        args: &Args,
+        prompts: Vec<Vec<u32>>,
+        logprobs: bool,
+        cancel: &CancellationToken,
+        stream: S,
+    ) -> Result<Vec<Sequence>>
+    where
+        S: FnMut(usize, &TokenEvent),
+    {
+        self.generate_sequences_with(args, prompts, logprobs, cancel, None, None, stream)
+            .await
+    }
+
+    /// Same as generate_sequences, letting choose pick the tokens instead of the sampler if set,
+    /// and waiting for pace before every forward pass if set.
+    #[allow(clippy::too_many_arguments)]
+    async fn generate_sequences_with<S>(
+        &mut self,
+        args: &Args,
+        prompts: Vec<Vec<u32>>,
+        logprobs: bool,
+        cancel: &CancellationToken,
+        mut choose: Option<&mut TokenChooser<'_>>,
+        pace: Option<&Pace>,
+        stream: S,
+    ) -> Result<Vec<Sequence>>
+    where
+        S: FnMut(usize, &TokenEvent),
+    {
+        let mut stream = token_events(self.model.metrics().clone(), cancel, stream);
+
+        log::info!(
+            "starting the inference loop (mem={})\n\n",
+            human_bytes::human_bytes(memory_stats::memory_stats().unwrap().physical_mem as f64)
+        );
+
+        if prompts.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut generation = self.start_generation(args, prompts, logprobs, None).await?;
+
+        // the draft tokens are verified against the sampled ones
+        if self.draft.is_some()
+            && generation.sequences.len() == 1
+            && self.ctx.cache.use_kv_cache
+            && choose.is_none()
+        {
+            generation.index_pos = self
+                .speculate(
+                    &mut generation.sequences[0],
+                    args,
+                    generation.index_pos,
+                    generation.max_tokens,
+                    &generation.eos_token_ids,
+                    cancel,
+                    pace,
+                    &mut generation.budget,
+                    &mut stream,
+                )
+                .await?;
+        } else {
+            while !generation.done {
+                self.step_generation(
+                    &mut generation,
+                    args,
+                    cancel,
+                    choose.as_deref_mut(),
+                    pace,
+                    &mut stream,
+                )
+                .await?;
+            }
+        }
+
+        self.finish_generation(generation, cancel, &mut stream)
+            .await
+    }
+
+    /// Prepares the caches and the sequences of the prompts for step_generation, which processes
+    /// the prompts chunk_size positions at a time if set, at once otherwise.
+    pub(super) async fn start_generation(
+        &mut self,
+        args: &Args,
+        prompts: Vec<Vec<u32>>,
+        logprobs: bool,
+        chunk_size: Option<usize>,
+    ) -> Result<Generation> {
+        let budget = TimeBudget::new(args);
+
+        self.model
+            .set_activation_dtype(Self::activation_dtype(args)?);
+
+        let prompts = prompts
+            .into_iter()
+            .map(|tokens| self.truncate_prompt(args, tokens))
+            .collect::<Result<Vec<_>>>()?;
+
+        // prompts are padded on the left so that the last tokens of every row are aligned
+        let prompt_len = prompts.iter().map(|tokens| tokens.len()).max().unwrap();
+        let left_padding: Vec<usize> = prompts.iter().map(|t| prompt_len - t.len()).collect();
+
+        if prompts.len() == 1 && !self.continues_history(&prompts[0]) {
+            self.restore_prefix(&prompts[0]).await?;
+        }
+
+        // a single prompt that continues the cached tokens only needs the new ones processed
+        let start_pos = if prompts.len() == 1 && self.continues_history(&prompts[0]) {
+            self.history.len()
+        } else {
+            0
+        };
+
+        if start_pos > 0 {
+            log::info!("reusing the cache of {start_pos} prompt tokens");
+        } else {
+            self.reset_batch(&left_padding).await?;
+        }
+        // set again once the caches are consistent with the sequence
+        self.history.clear();
+
+        let mut eos_token_ids = self.eos_token_ids.clone();
+        if args.chat {
+            // the assistant turn is terminated by the end of turn token
+            eos_token_ids.extend(self.tokenizer.token_to_id(&self.chat_tokens()?.eot));
+        }
+        if args.fim {
+            eos_token_ids.insert(self.fim_tokens()?.end);
+        }
+        // padding tokens are masked, their value is irrelevant
+        let pad_token_id = eos_token_ids.iter().min().copied().unwrap_or_default();
+        if args.ignore_eos {
+            eos_token_ids.clear();
+        }
+
+        let mut sequences = vec![];
+        for tokens in prompts {
+            log::info!("prompt tokens: {:?}", &tokens);
+
+            let mut sequence = Sequence::new(tokens, self.tokenizer.clone(), args);
+            if logprobs {
+                sequence.logprobs = Some(vec![]);
+            }
+            if let Some((grammar, trie)) = &self.grammar {
+                sequence.grammar = Some((GrammarState::new(grammar.clone()), trie.clone()));
+            }
+            sequences.push(sequence);
+        }
+
+        let max_tokens = args.max_tokens.unwrap_or_else(|| {
+            // fill whatever is left of the model context
+            self.ctx.config.context_size().saturating_sub(prompt_len)
+        });
+
+        log::debug!("max_tokens={max_tokens}");
+
+        Ok(Generation {
+            sequences,
+            left_padding,
+            prompt_len,
+            index_pos: start_pos,
+            sampled: 0,
+            max_tokens,
+            // the whole sequences are processed by every forward pass without the cache
+            chunk_size: chunk_size.filter(|_| self.ctx.cache.use_kv_cache),
+            eos_token_ids,
+            pad_token_id,
+            budget,
+            start_gen: Instant::now(),
+            done: max_tokens == 0,
+        })
+    }
+
+    /// Runs the next forward pass of the generation: the next chunk of the prompts or, once they
+    /// have been processed, the last tokens of the sequences, whose next ones are sampled. The
+    /// generation is done once every sequence is over, or it's cancelled or out of time.
+    pub(super) async fn step_generation<S>(
+        &mut self,
+        generation: &mut Generation,
+        args: &Args,
+        cancel: &CancellationToken,
+        mut choose: Option<&mut TokenChooser<'_>>,
+        pace: Option<&Pace>,
+        stream: &mut S,
+    ) -> Result<()>
+    where
+        S: FnMut(usize, &Sequence, bool, &str),
+    {
+        if let Some(pace) = pace {
+            pace.wait().await;
+        }
+        if cancel.is_cancelled() {
+            generation.done = true;
+            return Ok(());
+        }
+
+        // every row is as long, the finished sequences being padded
+        let len = generation.prompt_len + generation.sampled;
+        let (context_index, end) = match generation.chunk_size {
+            _ if !self.ctx.cache.use_kv_cache => (0, len),
+            Some(size) => (generation.index_pos, (generation.index_pos + size).min(len)),
+            None => (generation.index_pos, len),
+        };
+        let rows = generation.sequences.len();
+
+        let input = Tensor::from_vec(
+            generation.input(context_index, end),
+            (rows, end - context_index),
+            &self.ctx.device,
+        )?;
+        let logits = match self
+            .model
+            .forward(&input, context_index, &mut self.ctx.cache)
+            .await
+        {
+            Err(e) if matches!(e.downcast_ref(), Some(ClientError::CacheLost { .. })) => {
+                log::warn!("{e}, processing the whole sequences again");
+
+                // rebuild every cache from scratch
+                self.reset_batch(&generation.left_padding).await?;
+                let input =
+                    Tensor::from_vec(generation.input(0, end), (rows, end), &self.ctx.device)?;
+                self.model.forward(&input, 0, &mut self.ctx.cache).await?
+            }
+            res => res?,
+        };
+        generation.index_pos = end;
+
+        // the rest of the prompts is processed by the next steps
+        if end < len {
+            return Ok(());
+        }
+
+        let eos_token_ids = &generation.eos_token_ids;
+        for (i, sequence) in generation.sequences.iter_mut().enumerate() {
+            if sequence.finished() {
+                // keep the rows aligned
+                sequence.tokens.push(generation.pad_token_id);
+            } else {
+                let logits = logits.get(i)?;
+                let text = match choose.as_mut() {
+                    Some(choose) => {
+                        let logits = sequence.check_logits(&logits, args, &[])?;
+                        let token = choose(&sequence.tokens, &logits)?;
+                        sequence.push(token, eos_token_ids)?
+                    }
+                    None => sequence.next(&logits, args, eos_token_ids)?,
+                };
+                stream(i, sequence, true, &text.unwrap_or_default());
+            }
+        }
+        generation.sampled += 1;
+        if generation.sampled == 1 {
+            // record start time again since the first token is the warmup
+            generation.start_gen = Instant::now();
+        }
+
+        if generation
+            .sequences
+            .iter()
+            .all(|sequence| sequence.finished())
+        {
+            generation.done = true;
+            return Ok(());
+        }
+
+        generation.budget.start();
+        if generation.budget.exceeded() {
+            for sequence in generation.sequences.iter_mut() {
+                if !sequence.finished() {
+                    sequence.finish_reason = Some(FinishReason::TimeLimit);
+                }
+            }
+            generation.done = true;
+        }
+        generation.done |= generation.sampled >= generation.max_tokens;
+
+        Ok(())
+    }
+
+    /// Ends the generation once done and returns its sequences, their end being streamed. The
+    /// caches are kept for the next generation, unless it's been cancelled.
+    pub(super) async fn finish_generation<S>(
+        &mut self,
+        mut generation: Generation,
+        cancel: &CancellationToken,
+        stream: &mut S,
+    ) -> Result<Vec<Sequence>>
+    where
+        S: FnMut(usize, &Sequence, bool, &str),
+    {
+        let (prompt_len, index_pos) = (generation.prompt_len, generation.index_pos);
+        let sequences = &mut generation.sequences;
+
+        if cancel.is_cancelled() {
+            log::info!("generation cancelled");
+
+            self.reset().await?;
+        } else if self.ctx.cache.use_kv_cache && sequences.len() == 1 {
+            self.history = sequences[0].tokens[..index_pos].to_vec();
+
+            // no decoding step computes the prompt entries when there's no room left for a token
+            if self.prefix_cache.is_enabled() && index_pos >= prompt_len {
+                let cache = self.model.kv_cache(&self.ctx.cache).await?;
+                let state = CacheState::from_cache(&cache)?.narrow(prompt_len)?;
+                self.prefix_cache
+                    .insert(sequences[0].tokens[..prompt_len].to_vec(), state);
+            }
+        }
+
+        let mut generated = 0;
+        for (i, sequence) in sequences.iter_mut().enumerate() {
+            sequence.finish_reason = if cancel.is_cancelled() {
+                Some(FinishReason::Cancelled)
+            } else {
+                sequence.finish_reason.or(Some(FinishReason::Length))
+            };
+            if let Some(text) = sequence.finish()? {
+                stream(i, sequence, false, &text);
+            }
+            // signal end of stream
+            stream(i, sequence, false, "");
+
+            generated += sequence.generated;
+        }
+
+        let dt = generation.start_gen.elapsed();
+
+        log::info!(
+            "{} tokens generated ({} token/s) - mem={}",
+            generated,
+            generated.saturating_sub(sequences.len()) as f64 / dt.as_secs_f64(),
+            human_bytes::human_bytes(memory_stats::memory_stats().unwrap().physical_mem as f64)
+        );
+
+        Ok(generation.sequences)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    use crate::{cake::Message, test_utils};
+
+    /// Text generated greedily from the prompt by a master of the arguments, with why it stopped.
+    async fn generate(master: &mut Master, args: &Args, prompt: &str) -> (String, FinishReason) {
+        let tokens = master.encode(prompt).unwrap();
+        let mut text = String::new();
+        let finish_reason = master
+            .generate_with(args, tokens, &CancellationToken::default(), |t| {
+                text.push_str(t)
+            })
+            .await
+            .unwrap();
+        (text, finish_reason)
+    }
+
+    #[tokio::test]
+    async fn stop_sequence_ends_the_generation() {
+        let args = test_utils::args(&["--max-tokens", "12", "--ignore-eos"]);
+        let mut master = test_utils::master(args.clone()).await;
+        let (text, finish_reason) = generate(&mut master, &args, "the cat").await;
+        assert_eq!(finish_reason, FinishReason::Length);
+
+        // two words spanning two tokens
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let stop = words[3..5].join(" ");
+        let args = Args {
+            stop: vec![stop.clone()],
+            ..args
+        };
+        let (stopped, finish_reason) = generate(&mut master, &args, "the cat").await;
+        assert_eq!(finish_reason, FinishReason::Stop);
+        assert_eq!(stopped, text[..text.find(&stop).unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn finish_reason_of_the_last_token() {
+        let args = test_utils::args(&["--max-tokens", "6", "--ignore-eos"]);
+        let mut master = test_utils::master(args.clone()).await;
+        let (text, finish_reason) = generate(&mut master, &args, "the cat").await;
+        assert_eq!(finish_reason, FinishReason::Length);
+        let expected = tokens(&mut master, &args, "the cat").await;
+
+        // the end of text beats the token limit
+        let eos_token_ids = std::mem::replace(&mut master.eos_token_ids, [expected[5]].into());
+        let stopping = Args {
+            ignore_eos: false,
+            ..args.clone()
+        };
+        let (_, finish_reason) = generate(&mut master, &stopping, "the cat").await;
+        assert_eq!(finish_reason, FinishReason::Eos);
+        master.eos_token_ids = eos_token_ids;
+
+        // a stop sequence completed by the last token beats it too
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let stop = words[1..].join(" ");
+        let stopped_args = Args {
+            stop: vec![stop],
+            ..args.clone()
+        };
+        let (stopped, finish_reason) = generate(&mut master, &stopped_args, "the cat").await;
+        assert_eq!(finish_reason, FinishReason::Stop);
+        assert_eq!(stopped.split_whitespace().collect::<Vec<_>>(), words[..1]);
+
+        // and the cancellation beats the stop sequence
+        let cancel = CancellationToken::default();
+        let prompt = master.encode("the cat").unwrap();
+        let mut generated = 0;
+        let finish_reason = master
+            .generate_with_events(&stopped_args, prompt, &cancel, |event| {
+                if event.token_id.is_some() {
+                    generated += 1;
+                    if generated == 6 {
+                        cancel.cancel();
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(generated, 6);
+        assert_eq!(finish_reason, FinishReason::Cancelled);
+
+        // --max-time-ms stops at the next token
+        let timed = Args {
+            max_time_ms: Some(0),
+            ..args.clone()
+        };
+        let (timed_out, finish_reason) = generate(&mut master, &timed, "the cat").await;
+        assert_eq!(finish_reason, FinishReason::TimeLimit);
+        assert!(text.starts_with(&timed_out) && timed_out.len() < text.len());
+
+        // whichever limit comes first wins
+        let timed = Args {
+            max_time_ms: Some(60_000),
+            ..args
+        };
+        assert_eq!(
+            generate(&mut master, &timed, "the cat").await,
+            (text, FinishReason::Length)
+        );
+    }
+
+    #[tokio::test]
+    async fn activation_dtype_per_generation() {
+        // the worker serves the last two layers as the identity, and records the dtype of the
+        // activations it gets
+        let dtypes = Arc::new(std::sync::Mutex::new(vec![]));
+        let worker = test_utils::MockWorker::start({
+            let dtypes = dtypes.clone();
+            move |_, msg| match msg {
+                Message::Batch { x, .. } | Message::TransformerOp { x, .. } => {
+                    dtypes.lock().unwrap().push(x.dtype.clone());
+                    test_utils::Reply::Message(Message::Tensor(x))
+                }
+                _ => test_utils::Reply::Nothing,
+            }
+        })
+        .await;
+        let topology = test_utils::topology(
+            "activation-dtype.yml",
+            &format!("mock: {{ host: '{}', layers: [2, 3] }}", worker.address),
+        );
+        let mut args = test_utils::args(&["--max-tokens", "6", "--ignore-eos"]);
+        args.topology = topology;
+        let mut master = test_utils::master(args.clone()).await;
+        let seen = |dtypes: &std::sync::Mutex<Vec<String>>| {
+            let mut seen = std::mem::take(&mut *dtypes.lock().unwrap());
+            seen.dedup();
+            seen
+        };
+
+        let (expected, _) = generate(&mut master, &args, "the cat").await;
+        assert_eq!(seen(&dtypes), ["f32"]);
+
+        // the same loaded model, its weights untouched
+        let half = Args {
+            activation_dtype: Some("f16".to_string()),
+            ..args.clone()
+        };
+        let (text, finish_reason) = generate(&mut master, &half, "the cat").await;
+        assert_eq!(finish_reason, FinishReason::Length);
+        assert!(text.len() > "the cat".len());
+        assert_eq!(seen(&dtypes), ["f16"]);
+
+        // the override only lasts for its generation
+        assert_eq!(generate(&mut master, &args, "the cat").await.0, expected);
+        assert_eq!(seen(&dtypes), ["f32"]);
+    }
+
+    #[tokio::test]
+    async fn async_generation_streams_like_the_sync_one() {
+        let extra = [
+            "--prompt",
+            "the cat",
+            "--prefill",
+            "sat",
+            "--max-tokens",
+            "12",
+        ];
+        let mut master = test_utils::master(test_utils::args(&extra)).await;
+        let cancel = CancellationToken::default();
+        let mut expected = vec![];
+        master
+            .generate(&cancel, |t| expected.push(t.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(expected[..2], ["the cat", "sat"]);
+
+        let mut pieces = vec![];
+        master
+            .generate_async(&cancel, |piece| {
+                pieces.push(piece);
+                tokio::time::sleep(Duration::from_millis(1))
+            })
+            .await
+            .unwrap();
+        assert_eq!(pieces, expected);
+    }
+
+    #[tokio::test]
+    async fn slow_consumer_paces_the_generation() {
+        let args = test_utils::args(&["--max-tokens", "40", "--ignore-eos"]);
+        let mut master = test_utils::master(args.clone()).await;
+        let (expected, _) = generate(&mut master, &args, "the cat").await;
+
+        let forwards = Arc::new(AtomicUsize::new(0));
+        let counted = forwards.clone();
+        master
+            .add_hook(
+                0,
+                crate::model::HookKind::Residual,
+                Box::new(move |_| {
+                    counted.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }),
+            )
+            .unwrap();
+
+        // forward passes ahead of the consumer whenever it gets a piece of text
+        let (mut text, mut consumed, mut ahead) = (String::new(), 0, vec![]);
+        let prompt = master.encode("the cat").unwrap();
+        master
+            .generate_with_async(&args, prompt, &CancellationToken::default(), |piece| {
+                text.push_str(&piece);
+                consumed += 1;
+                ahead.push(forwards.load(Ordering::SeqCst) - consumed);
+                tokio::time::sleep(Duration::from_millis(10))
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(text, expected);
+        // the generation would be over before the consumer got its first piece otherwise: it's
+        // ahead by the pending pieces, the forward in progress and the tokens buffered with the
+        // next ones rather than streamed as pieces of their own
+        let buffered = 40 - consumed.min(40);
+        assert!(ahead[0] <= MAX_PENDING_TEXT + 1, "{ahead:?}");
+        assert!(
+            ahead.iter().all(|a| *a <= MAX_PENDING_TEXT + 1 + buffered),
+            "{ahead:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn nan_activations_of_a_worker_fail_the_generation() {
+        // the worker serves the last two layers as the identity, until its third forward
+        let forwards = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let worker = test_utils::MockWorker::start({
+            let forwards = forwards.clone();
+            move |_, msg| match msg {
+                Message::Batch { x, .. } | Message::TransformerOp { x, .. } => {
+                    if forwards.fetch_add(1, std::sync::atomic::Ordering::SeqCst) < 2 {
+                        return test_utils::Reply::Message(Message::Tensor(x));
+                    }
+                    let nan =
+                        Tensor::full(f32::NAN, x.shape.clone(), &candle_core::Device::Cpu).unwrap();
+                    test_utils::Reply::Message(Message::from_tensor(&nan))
+                }
+                _ => test_utils::Reply::Nothing,
+            }
+        })
+        .await;
+        let topology = test_utils::topology(
+            "nan-activations.yml",
+            &format!("nan: {{ host: '{}', layers: [2, 3] }}", worker.address),
+        );
+        let mut args = test_utils::args(&["--max-tokens", "6", "--ignore-eos"]);
+        args.topology = topology;
+        let mut master = test_utils::master(args.clone()).await;
+
+        let prompt = master.encode("the cat").unwrap();
+        let mut text = String::new();
+        let e = master
+            .generate_with(&args, prompt.clone(), &CancellationToken::default(), |t| {
+                text.push_str(t)
+            })
+            .await
+            .unwrap_err();
+        // after the two tokens of the healthy forwards
+        match e {
+            CakeError::NonFiniteLogits {
+                position, value, ..
+            } => {
+                assert_eq!(position, prompt.len() + 2);
+                assert!(value.is_nan());
+            }
+            e => panic!("{e:?}"),
+        }
+        assert_eq!(text.split_whitespace().count(), 2, "{text}");
+    }
+
+    #[tokio::test]
+    async fn cancelled_generation_stops_at_the_next_token() {
+        // the worker serves the last two layers as the identity, and records its requests
+        let requests = Arc::new(std::sync::Mutex::new(vec![]));
+        let worker = test_utils::MockWorker::start({
+            let requests = requests.clone();
+            move |_, msg| {
+                let (request, reply) = match msg {
+                    Message::Batch { x, .. } | Message::TransformerOp { x, .. } => {
+                        ("forward", test_utils::Reply::Message(Message::Tensor(x)))
+                    }
+                    Message::ResetCache { .. } => ("reset", test_utils::Reply::Nothing),
+                    _ => ("other", test_utils::Reply::Nothing),
+                };
+                requests.lock().unwrap().push(request);
+                reply
+            }
+        })
+        .await;
+        let topology = test_utils::topology(
+            "cancel.yml",
+            &format!("cancel: {{ host: '{}', layers: [2, 3] }}", worker.address),
+        );
+        let mut args = test_utils::args(&["--max-tokens", "10", "--ignore-eos"]);
+        args.topology = topology;
+        let mut master = test_utils::master(args.clone()).await;
+
+        let cancel = CancellationToken::default();
+        let prompt = master.encode("the cat sat").unwrap();
+        let (mut pieces, mut after_cancel) = (0, String::new());
+        let finish_reason = master
+            .generate_with(&args, prompt, &cancel, |t| {
+                if cancel.is_cancelled() {
+                    after_cancel.push_str(t);
+                    return;
+                }
+                pieces += 1;
+                if pieces == 3 {
+                    cancel.cancel();
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(finish_reason, FinishReason::Cancelled);
+        assert_eq!(pieces, 3);
+        assert_eq!(after_cancel, "");
+
+        // one forward pass per token, then the caches of the worker are discarded, no response
+        // is awaited for the reset
+        let start = std::time::Instant::now();
+        while requests.lock().unwrap().last() != Some(&"reset") && start.elapsed().as_secs() < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let requests = requests.lock().unwrap();
+        let forwards = requests.iter().filter(|&&r| r == "forward").count();
+        assert_eq!(forwards, 3);
+        assert_eq!(requests.last(), Some(&"reset"), "{requests:?}");
+    }
+
+    /// Events of a generation from the prompt, with their text apart.
+    async fn events(
+        master: &mut Master,
+        args: &Args,
+        prompt: &str,
+    ) -> Vec<(String, TokenEvent<'static>)> {
+        let tokens = master.encode(prompt).unwrap();
+        let mut events = vec![];
+        master
+            .generate_with_events(args, tokens, &CancellationToken::default(), |event| {
+                events.push((event.text.to_string(), TokenEvent { text: "", ..*event }))
+            })
+            .await
+            .unwrap();
+        events
+    }
+
+    #[tokio::test]
+    async fn max_tokens_limits_the_generated_tokens() {
+        let args = test_utils::args(&["--max-tokens", "5", "--ignore-eos"]);
+        let mut master = test_utils::master(args.clone()).await;
+        let events = events(&mut master, &args, "the cat sat").await;
+
+        let tokens = events.iter().filter(|(_, event)| event.token_id.is_some());
+        assert_eq!(tokens.count(), 5);
+        let (text, last) = events.last().unwrap();
+        assert_eq!(text, "");
+        assert_eq!(last.token_id, None);
+        assert_eq!(last.generated_tokens, 5);
+        assert_eq!(last.finish_reason, Some(FinishReason::Length));
+    }
+
+    #[tokio::test]
+    async fn events_count_the_tokens_and_time_the_generation() {
+        let args = test_utils::args(&["--max-tokens", "6", "--ignore-eos"]);
+        let mut master = test_utils::master(args.clone()).await;
+        let prompt = master.encode("the cat sat").unwrap();
+        let prompt_len = prompt.len();
+
+        // the time spent in the callback isn't accounted
+        let mut events = vec![];
+        let start = std::time::Instant::now();
+        master
+            .generate_with_events(&args, prompt, &CancellationToken::default(), |event| {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                events.push((event.text.to_string(), TokenEvent { text: "", ..*event }))
+            })
+            .await
+            .unwrap();
+        let callbacks = start.elapsed();
+
+        let tokens: Vec<&TokenEvent> = events
+            .iter()
+            .map(|(_, event)| event)
+            .filter(|event| event.token_id.is_some())
+            .collect();
+        assert_eq!(tokens.len(), 6);
+        for (i, event) in tokens.iter().enumerate() {
+            assert_eq!(event.generated_tokens, i + 1);
+            assert_eq!(event.prompt_tokens, prompt_len);
+        }
+        for pair in events.windows(2) {
+            assert!(pair[0].1.elapsed_since_start <= pair[1].1.elapsed_since_start);
+        }
+        let last = &events.last().unwrap().1;
+        assert!(last.elapsed_since_start + std::time::Duration::from_millis(50 * 6) <= callbacks);
+
+        // the text callback gets the same text
+        let text: String = events.iter().map(|(text, _)| text.as_str()).collect();
+        assert_eq!(generate(&mut master, &args, "the cat sat").await.0, text);
+    }
+
+    #[tokio::test]
+    async fn jsonl_lines_of_the_events() {
+        let args = test_utils::args(&[
+            "--prompt",
+            "the cat sat",
+            "--max-tokens",
+            "4",
+            "--ignore-eos",
+        ]);
+        let mut master = test_utils::master(args).await;
+        let mut lines = vec![];
+        master
+            .generate_events(&CancellationToken::default(), |event| {
+                lines.push(event.to_jsonl().to_string())
+            })
+            .await
+            .unwrap();
+
+        let lines: Vec<serde_json::Value> = lines
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let (done, tokens) = lines.split_last().unwrap();
+        assert_eq!(tokens.len(), 4);
+        for token in tokens {
+            assert!(token["token"].is_string());
+            assert!(token["id"].is_u64());
+            assert!(token["logprob"].as_f64().unwrap() <= 0.);
+        }
+
+        assert_eq!(done["done"], true);
+        assert_eq!(done["finish_reason"], "length");
+        assert_eq!(done["stats"]["generated_tokens"], 4);
+        assert!(done["stats"]["prompt_tokens"].as_u64().unwrap() > 0);
+    }
+
+    /// Tokens generated from the prompt.
+    async fn tokens(master: &mut Master, args: &Args, prompt: &str) -> Vec<u32> {
+        events(master, args, prompt)
+            .await
+            .iter()
+            .filter_map(|(_, event)| event.token_id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn prefill_is_streamed_then_continued() {
+        let args = test_utils::args(&["--max-tokens", "4", "--ignore-eos", "--prompt", "the cat"]);
+        let prefilled = Args {
+            prefill: Some("sat on".to_string()),
+            ..args.clone()
+        };
+        let mut master = test_utils::master(prefilled).await;
+        let mut pieces = vec![];
+        master
+            .generate(&CancellationToken::default(), |t| {
+                pieces.push(t.to_string())
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(pieces[..2], ["the cat", "sat on"]);
+        let (continuation, _) = generate(&mut master, &args, "the cat sat on").await;
+        assert_eq!(pieces[2..].concat(), continuation);
+    }
+
+    #[tokio::test]
+    async fn prefill_follows_the_assistant_header() {
+        let args = test_utils::args_for(
+            test_utils::chat_model_dir(),
+            &["--chat", "--prompt", "the cat"],
+        );
+        let master = test_utils::master(args.clone()).await;
+        let prompt = master.encode_prompt(&args).unwrap();
+        assert_eq!(
+            prompt.last().copied(),
+            master
+                .tokenizer()
+                .token_to_id(&master.chat_tokens().unwrap().end_header)
+        );
+
+        let prefilled = Args {
+            prefill: Some("sat on".to_string()),
+            ..args
+        };
+        assert_eq!(
+            master.encode_prompt(&prefilled).unwrap(),
+            [prompt, vec![7, 8]].concat()
+        );
+    }
+
+    #[tokio::test]
+    async fn any_end_token_stops_the_generation() {
+        let args = test_utils::args(&["--max-tokens", "8", "--ignore-eos"]);
+        let mut master = test_utils::master(args.clone()).await;
+        let expected = tokens(&mut master, &args, "the cat sat").await;
+
+        let (first, second) = (expected[4], expected[2]);
+        let end = expected
+            .iter()
+            .position(|&id| id == first || id == second)
+            .unwrap();
+        let eos_ids = format!("{first},{second}");
+        let args = test_utils::args(&["--max-tokens", "8", "--eos-ids", &eos_ids]);
+        let mut master = test_utils::master(args.clone()).await;
+        assert_eq!(master.eos_token_ids, [first, second].into());
+        assert_eq!(
+            tokens(&mut master, &args, "the cat sat").await,
+            expected[..=end]
+        );
+        let (_, finish_reason) = generate(&mut master, &args, "the cat sat").await;
+        assert_eq!(finish_reason, FinishReason::Eos);
+    }
+
+    #[tokio::test]
+    async fn ignore_eos_continues_past_the_end_of_text() {
+        let args = test_utils::args(&["--max-tokens", "8", "--ignore-eos"]);
+        let mut master = test_utils::master(args.clone()).await;
+        let expected = tokens(&mut master, &args, "the cat sat").await;
+        let (text, _) = generate(&mut master, &args, "the cat sat").await;
+        assert_eq!(expected.len(), 8);
+
+        // the third token ends the text of the model, it's the last one generated
+        master.eos_token_ids = [expected[2]].into();
+        let stopping = Args {
+            ignore_eos: false,
+            ..args.clone()
+        };
+        let (_, finish_reason) = generate(&mut master, &stopping, "the cat sat").await;
+        assert_eq!(finish_reason, FinishReason::Eos);
+        assert_eq!(
+            tokens(&mut master, &stopping, "the cat sat").await,
+            expected[..3]
+        );
+
+        assert_eq!(tokens(&mut master, &args, "the cat sat").await, expected);
+        let (ignoring, finish_reason) = generate(&mut master, &args, "the cat sat").await;
+        assert_eq!(finish_reason, FinishReason::Length);
+        assert_eq!(ignoring, text);
+
+        // a stop sequence past the end of text token still stops the generation
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let stop = words[4..6].join(" ");
+        let args = Args {
+            stop: vec![stop.clone()],
+            ..args
+        };
+        let (stopped, finish_reason) = generate(&mut master, &args, "the cat sat").await;
+        assert_eq!(finish_reason, FinishReason::Stop);
+        assert_eq!(stopped, text[..text.find(&stop).unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn banned_token_is_never_sampled() {
+        let args = test_utils::args(&["--max-tokens", "1", "--top-logprobs", "2"]);
+        let mut master = test_utils::master(args.clone()).await;
+        let prompt = master.encode("the cat sat").unwrap();
+        let logprobs = master
+            .generate_with_logprobs(&args, prompt, |_| ())
+            .await
+            .unwrap();
+        let [(argmax, _), (second, _)] = logprobs[0].top[..] else {
+            panic!("no alternatives");
+        };
+
+        let banned = |extra: &[&str]| {
+            let bias = format!("{{\"{argmax}\": \"-inf\"}}");
+            test_utils::args(&[&["--logit-bias", &bias, "--ignore-eos"], extra].concat())
+        };
+        let args = banned(&["--max-tokens", "1"]);
+        assert_eq!(tokens(&mut master, &args, "the cat sat").await, [second]);
+
+        // whatever the temperature
+        let args = banned(&["--max-tokens", "40", "--temperature", "20", "--seed", "1"]);
+        let sampled = tokens(&mut master, &args, "the cat sat").await;
+        assert_eq!(sampled.len(), 40);
+        assert!(!sampled.contains(&argmax), "{sampled:?}");
+    }
+
+    #[tokio::test]
+    async fn logprobs_of_the_generated_tokens() {
+        let args = test_utils::args(&["--max-tokens", "6", "--ignore-eos", "--top-logprobs", "3"]);
+        let mut master = test_utils::master(args.clone()).await;
+        let prompt = master.encode("the cat sat").unwrap();
+        let logprobs = master
+            .generate_with_logprobs(&args, prompt.clone(), |_| ())
+            .await
+            .unwrap();
+        assert_eq!(logprobs.len(), 6);
+
+        // the log-softmax of the logits each greedy token is picked from
+        let mut expected = vec![];
+        master
+            .generate_logits(
+                &args,
+                prompt,
+                &CancellationToken::default(),
+                |_, logits| {
+                    let logprobs: Vec<f32> =
+                        candle_nn::ops::log_softmax(logits, candle_core::D::Minus1)?.to_vec1()?;
+                    let (token, logprob) = logprobs
+                        .iter()
+                        .enumerate()
+                        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                        .unwrap();
+                    expected.push((token as u32, *logprob));
+                    Ok(token as u32)
+                },
+                |_| (),
+            )
+            .await
+            .unwrap();
+
+        for (logprob, (token, expected)) in logprobs.iter().zip(expected) {
+            assert_eq!(logprob.token, token);
+            assert!((logprob.logprob - expected).abs() < 1e-5);
+            assert_eq!(logprob.top.len(), 3);
+            assert_eq!(logprob.top[0], (logprob.token, logprob.logprob));
+            assert!(logprob.top.windows(2).all(|w| w[0].1 >= w[1].1));
+            assert!(logprob.top.iter().map(|(_, lp)| lp.exp()).sum::<f32>() <= 1.);
+        }
+    }
+
+    #[tokio::test]
+    async fn greedy_choice_like_greedy_sampling() {
+        let args = test_utils::args(&["--max-tokens", "8", "--ignore-eos"]);
+        let mut master = test_utils::master(args.clone()).await;
+        let greedy = tokens(&mut master, &args, "the cat sat").await;
+        assert_eq!(greedy.len(), 8);
+
+        let prompt = master.encode("the cat sat").unwrap();
+        let mut chosen = vec![];
+        let reason = master
+            .generate_logits(
+                &args,
+                prompt.clone(),
+                &CancellationToken::default(),
+                |history, logits| {
+                    // the history is the prompt followed by the tokens chosen so far
+                    assert_eq!(history[..prompt.len()], prompt[..]);
+                    assert_eq!(history[prompt.len()..], chosen[..]);
+                    let token = logits.argmax(candle_core::D::Minus1)?.to_scalar::<u32>()?;
+                    chosen.push(token);
+                    Ok(token)
+                },
+                |_| (),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(reason, FinishReason::Length);
+        assert_eq!(chosen, greedy);
+    }
+
+    #[tokio::test]
+    async fn generation_fills_the_context_without_max_tokens() {
+        let dir = test_utils::model_dir_with(
+            "short-context",
+            serde_json::json!({ "max_position_embeddings": 16 }),
+        );
+        let args = test_utils::args_for(&dir, &["--ignore-eos"]);
+        let mut master = test_utils::master(args.clone()).await;
+        let events = events(&mut master, &args, "the cat sat").await;
+
+        // 4 prompt tokens with the bos token
+        let (_, last) = events.last().unwrap();
+        assert_eq!(last.prompt_tokens, 4);
+        assert_eq!(last.generated_tokens, 12);
+        assert_eq!(last.finish_reason, Some(FinishReason::Length));
+    }
+
+    #[tokio::test]
+    async fn batch_matches_sequential_generations() {
+        let args = test_utils::args(&["--max-tokens", "6", "--ignore-eos"]);
+        let mut master = test_utils::master(args.clone()).await;
+        // prompts of different lengths, the shorter one is padded
+        let prompts = ["the cat sat on the mat", "a dog", "hello world"];
+
+        let mut sequential = vec![];
+        for prompt in prompts {
+            sequential.push(generate(&mut master, &args, prompt).await.0);
+        }
+
+        let mut batched = vec![String::new(); prompts.len()];
+        master
+            .generate_batch(prompts.map(str::to_string).to_vec(), |i, text| {
+                batched[i].push_str(text)
+            })
+            .await
+            .unwrap();
+        assert_eq!(batched, sequential);
+    }
+
+    /// Text of a run of the master with the prompt and the flags of its arguments.
+    async fn run(args: Args) -> (Master, String) {
+        let mut master = test_utils::master(args).await;
+        let mut text = String::new();
+        master
+            .generate(&CancellationToken::default(), |t| text.push_str(t))
+            .await
+            .unwrap();
+        (master, text)
+    }
+
+    #[tokio::test]
+    async fn warm_start_from_a_saved_cache() {
+        let path = test_utils::temp_dir("warm-start").join("cache.safetensors");
+        let path = path.display().to_string();
+        let flags = ["--prompt", "the cat sat on the mat", "--ignore-eos"];
+        let args = |extra: &[&str]| test_utils::args(&[&flags[..], extra].concat());
+        let (_, baseline) = run(args(&["--max-tokens", "4"])).await;
+
+        let (saving, _) = run(args(&["--max-tokens", "1", "--cache-out", &path])).await;
+        let prompt_len = saving.encode("the cat sat on the mat").unwrap().len();
+        assert!(saving.history.len() >= prompt_len);
+
+        let (loading, text) = run(args(&["--max-tokens", "4", "--cache-in", &path])).await;
+        assert_eq!(text, baseline);
+        assert_eq!(loading.history[..prompt_len], saving.history[..prompt_len]);
+    }
+
+    /// Number of tokens processed by every forward pass of a generation from the prompt.
+    async fn forwarded(master: &mut Master, args: &Args, prompt: &str) -> (String, Vec<usize>) {
+        let lens = Arc::new(std::sync::Mutex::new(vec![]));
+        let recorded = lens.clone();
+        master
+            .add_hook(
+                0,
+                crate::model::HookKind::Residual,
+                Box::new(move |x| {
+                    recorded.lock().unwrap().push(x.dim(1)?);
+                    Ok(())
+                }),
+            )
+            .unwrap();
+        let (text, _) = generate(master, args, prompt).await;
+        master.clear_hooks();
+        let lens = lens.lock().unwrap().clone();
+        (text, lens)
+    }
+
+    const SYSTEM: &str = "the big red dog and the small blue cat sat on the mat \
+        and ran to the home of the dog";
+
+    #[tokio::test]
+    async fn shared_prefix_is_processed_once() {
+        let args = test_utils::args(&[
+            "--max-tokens",
+            "3",
+            "--ignore-eos",
+            "--prefix-cache-size",
+            "2",
+        ]);
+        let mut master = test_utils::master(args.clone()).await;
+        let first = format!("{SYSTEM} one two");
+        let second = format!("{SYSTEM} three four five");
+
+        let (_, lens) = forwarded(&mut master, &args, &first).await;
+        assert_eq!(lens[0], master.encode(&first).unwrap().len());
+
+        let (text, lens) = forwarded(&mut master, &args, &second).await;
+        let prompt_len = master.encode(&second).unwrap().len();
+        assert!(lens[0] < prompt_len, "{} of {prompt_len} tokens", lens[0]);
+
+        // the same text as without the cached prefix
+        let uncached = test_utils::args(&["--max-tokens", "3", "--ignore-eos"]);
+        let mut master = test_utils::master(uncached.clone()).await;
+        let (expected, lens) = forwarded(&mut master, &uncached, &second).await;
+        assert_eq!(lens[0], prompt_len);
+        assert_eq!(text, expected);
+    }
+
+    #[tokio::test]
+    async fn prompt_at_the_context_limit() {
+        let dir = test_utils::model_dir_with(
+            "prefix-context-limit",
+            serde_json::json!({ "max_position_embeddings": 16 }),
+        );
+        let args = test_utils::args_for(&dir, &["--prefix-cache-size", "2"]);
+        let mut master = test_utils::master(args.clone()).await;
+        // 15 words and the bos token fill the context
+        let prompt = test_utils::WORDS[3..18].join(" ");
+        assert_eq!(master.encode(&prompt).unwrap().len(), 16);
+
+        let (text, reason) = generate(&mut master, &args, &prompt).await;
+        assert_eq!((text.as_str(), reason), ("", FinishReason::Length));
+
+        let args = Args {
+            max_tokens: Some(0),
+            ..args
+        };
+        let (text, reason) = generate(&mut master, &args, "the cat").await;
+        assert_eq!((text.as_str(), reason), ("", FinishReason::Length));
+    }
+
+    #[tokio::test]
+    async fn generated_text_matches_the_grammar() {
+        let dir = test_utils::temp_dir("grammar");
+        let path = dir.join("sentence.gbnf");
+        std::fs::write(
+            &path,
+            r#"root ::= " the" (" cat" | " dog") (" sat" | " ran") (" on" " the" (" mat" | " home"))?"#,
+        )
+        .unwrap();
+        let grammar = path.display().to_string();
+
+        for seed in ["1", "2", "3"] {
+            let args = test_utils::args(&[
+                "--grammar",
+                &grammar,
+                "--temperature",
+                "1",
+                "--seed",
+                seed,
+                "--max-tokens",
+                "16",
+            ]);
+            let mut master = test_utils::master(args.clone()).await;
+            let (grammar, trie) = master.grammar.clone().unwrap();
+            let events = events(&mut master, &args, "a big dog").await;
+
+            // every prefix of the generated text is valid, up to a complete one
+            let mut state = GrammarState::new(grammar);
+            for (_, event) in &events {
+                match event.token_id {
+                    Some(id) if master.eos_token_ids.contains(&id) => {}
+                    Some(id) => assert!(state.accept(trie.text(id).unwrap()), "{id}"),
+                    None => {}
+                }
+            }
+            assert!(state.is_accepting());
+            let reason = events.last().unwrap().1.finish_reason.unwrap();
+            assert!(
+                matches!(reason, FinishReason::Stop | FinishReason::Eos),
+                "{reason:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_grammar_fails_at_load() {
+        let dir = test_utils::temp_dir("invalid-grammar");
+        let path = dir.join("invalid.gbnf");
+        std::fs::write(&path, "root ::= word").unwrap();
+        let args = test_utils::args(&["--grammar", &path.display().to_string()]);
+
+        let ctx = crate::cake::Context::from_args(args).unwrap();
+        let e = Master::new(ctx).await.err().unwrap();
+        assert!(e.to_string().contains("undefined rule word"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn best_of_keeps_the_most_likely_candidate() {
+        let args = test_utils::args(&[
+            "--max-tokens",
+            "8",
+            "--ignore-eos",
+            "--temperature",
+            "1.5",
+            "--seed",
+            "11",
+        ]);
+        let mut master = test_utils::master(args.clone()).await;
+        let prompt = master.encode("the cat sat").unwrap();
+
+        // the candidates, generated on their own with the seeds best_of uses
+        let mut candidates = vec![];
+        for candidate in 0..4 {
+            let args = Args {
+                seed: Some(11 + candidate),
+                ..args.clone()
+            };
+            let mut text = String::new();
+            let logprobs = master
+                .generate_with_logprobs(&args, prompt.clone(), |t| text.push_str(t))
+                .await
+                .unwrap();
+            let score = logprobs.iter().map(|l| l.logprob).sum::<f32>() / logprobs.len() as f32;
+            candidates.push((score, text));
+        }
+        let texts: std::collections::HashSet<&String> =
+            candidates.iter().map(|(_, text)| text).collect();
+        assert!(texts.len() > 1, "{candidates:?}");
+        let (_, expected) = candidates
+            .iter()
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))
+            .unwrap();
+
+        let best_of = Args { best_of: 4, ..args };
+        let (text, finish_reason) = generate(&mut master, &best_of, "the cat sat").await;
+        assert_eq!(&text, expected);
+        assert_eq!(finish_reason, FinishReason::Length);
+    }
+}
@@ -0,0 +1,726 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::Arc,
+};
+
+use super::{
+    BenchReport, CakeError, ClusterStatus, ConnectionOptions, Context, Registry, Topology,
+    WorkerStatus,
+};
+use crate::{
+    model::{Cache, Hook, HookKind, Llama, PrefixCache},
+    utils::{parse_dtype, Grammar, TokenTrie},
+    Args,
+};
+
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor, D};
+use tokenizers::Tokenizer;
+
+mod generation;
+mod prompt;
+mod sampling;
+mod sequence;
+mod session;
+mod speculative;
+
+pub use generation::*;
+pub use prompt::*;
+pub use sampling::*;
+pub use session::*;
+
+use sampling::sampling_temperature;
+use session::Session;
+use speculative::Draft;
+
+/// How the hidden states of the tokens are reduced to a single embedding.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum Pooling {
+    /// Average of the hidden states of all the tokens.
+    #[default]
+    Mean,
+    /// Hidden state of the last token.
+    Last,
+}
+
+pub struct Master {
+    ctx: Context,
+    model: Llama,
+    draft: Option<Draft>,
+    tokenizer: Tokenizer,
+    eos_token_ids: HashSet<u32>,
+    // tokens added before and after the tokenized prompts, see --add-bos and --add-eos
+    added_tokens: (Option<u32>, Option<u32>),
+    // grammar the generated text must match, with the text of every token
+    grammar: Option<(Arc<Grammar>, Arc<TokenTrie>)>,
+    // tokens the key-value caches have been computed from
+    history: Vec<u32>,
+    prefix_cache: PrefixCache,
+    sessions: HashMap<SessionId, Session>,
+    next_session_id: u64,
+    // session the local and remote caches belong to
+    active_session: Option<SessionId>,
+    // dropped sessions whose caches the workers still keep, discarded at the next switch
+    dropped_caches: Vec<u64>,
+    // workers registered with --registry-addr
+    registry: Option<Arc<Registry>>,
+}
+
+impl Master {
+    /// Returns the dtype the activations are cast to for a generation, see --activation-dtype.
+    fn activation_dtype(args: &Args) -> Result<Option<DType>> {
+        args.activation_dtype
+            .as_deref()
+            .map(parse_dtype)
+            .transpose()
+    }
+
+    fn load_grammar(path: &str, tokenizer: &Tokenizer) -> Result<(Arc<Grammar>, Arc<TokenTrie>)> {
+        log::info!("loading grammar from {path}");
+
+        let text =
+            std::fs::read_to_string(path).map_err(|e| anyhow!("can't read grammar {path}: {e}"))?;
+        let grammar = Grammar::parse(&text).map_err(|e| anyhow!("invalid grammar {path}: {e}"))?;
+
+        Ok((Arc::new(grammar), Arc::new(TokenTrie::new(tokenizer)?)))
+    }
+
+    /// Loads the model and connects to the workers of the topology. The connections are kept for
+    /// the lifetime of the master and reused by every generation, the ones that dropped being
+    /// opened again by the next request they carry, and closed once the master is dropped.
+    pub async fn new(ctx: Context) -> Result<Self, CakeError> {
+        Ok(Self::load(ctx).await?)
+    }
+
+    async fn load(mut ctx: Context) -> Result<Self> {
+        // the same for all the connections of the master
+        let master_id = ctx
+            .args
+            .master_id
+            .get_or_insert_with(|| format!("{:016x}", rand::random::<u64>()));
+        log::info!("master id: {master_id}");
+
+        if ctx.args.grammar.is_some() && ctx.args.draft_model.is_some() {
+            bail!("--grammar can't be used with --draft-model");
+        }
+        if let Some(min_p) = ctx.args.min_p.filter(|min_p| !(0. ..=1.).contains(min_p)) {
+            bail!("--min-p must be between 0 and 1, got {min_p}");
+        }
+        if ctx.args.top_k == Some(0) {
+            bail!("--top-k must be at least 1");
+        }
+        if ctx.args.best_of > 1 && sampling_temperature(&ctx.args) <= 0. {
+            log::warn!("--best-of generates the same completion every time with greedy decoding");
+        }
+
+        log::info!("loading master topology from {}", &ctx.args.topology);
+
+        let registry = match &ctx.args.registry_addr {
+            Some(address) => {
+                let registry = Registry::listen(address, ctx.args.max_message_bytes()).await?;
+                let topology = registry
+                    .wait_for_layers(&ctx.topology, ctx.config.num_hidden_layers)
+                    .await;
+                topology
+                    .validate(&ctx.config)
+                    .map_err(|e| CakeError::TopologyInvalid {
+                        reason: e.to_string(),
+                    })?;
+                // the master now serves fewer layers
+                ctx.var_builder = ctx.var_builder_with(&topology)?;
+                ctx.topology = topology;
+                Some(registry)
+            }
+            None => None,
+        };
+        let options = ConnectionOptions {
+            registry: registry.clone(),
+            ..ConnectionOptions::from_args(&ctx.args)?
+        };
+
+        let mut model = Llama::load(
+            &ctx.var_builder,
+            &ctx.config,
+            &ctx.device,
+            &ctx.topology,
+            &options,
+        )
+        .await?;
+        if let Some(dir) = &ctx.args.dump_activations {
+            log::info!("dumping activations to {dir}");
+            model.dump_activations_to(dir.into());
+        }
+        if let Some(chunks) = ctx.args.pipeline_chunks {
+            model.set_pipeline_chunks(chunks);
+        }
+        if ctx.args.fallback_local {
+            let var_builder = if ctx.device.is_cpu() {
+                ctx.var_builder.clone()
+            } else {
+                ctx.var_builder_for(&Device::Cpu)?
+            };
+            model.enable_local_fallback(var_builder, &ctx.config, &ctx.cache)?;
+        }
+
+        if let Some(address) = &ctx.args.metrics_addr {
+            model.metrics().clone().serve(address).await?;
+        }
+
+        let draft = match &ctx.args.draft_model {
+            Some(path) => Some(Self::load_draft(&ctx, path).await?),
+            None => None,
+        };
+
+        log::info!(
+            "model loaded - mem={}",
+            human_bytes::human_bytes(memory_stats::memory_stats().unwrap().physical_mem as f64)
+        );
+
+        let tokenizer = Self::create_tokenizer(&ctx.args, &ctx.data_path)?;
+        let eos_token_ids =
+            Self::eos_token_ids(&ctx.args, &ctx.data_path, &ctx.config, &tokenizer)?;
+        let mut sorted: Vec<_> = eos_token_ids.iter().collect();
+        sorted.sort();
+        log::info!("eos token ids: {sorted:?}");
+        let added_tokens = Self::added_tokens(
+            &ctx.args,
+            &ctx.data_path,
+            &ctx.config,
+            &tokenizer,
+            &eos_token_ids,
+        )?;
+        let grammar = match &ctx.args.grammar {
+            Some(path) => Some(Self::load_grammar(path, &tokenizer)?),
+            None => None,
+        };
+        let prefix_cache = PrefixCache::new(ctx.args.prefix_cache_size);
+
+        Ok(Self {
+            ctx,
+            model,
+            draft,
+            tokenizer,
+            eos_token_ids,
+            added_tokens,
+            grammar,
+            history: vec![],
+            prefix_cache,
+            sessions: HashMap::new(),
+            next_session_id: 0,
+            active_session: None,
+            dropped_caches: vec![],
+            registry,
+        })
+    }
+
+    /// The arguments this master has been started with.
+    pub fn args(&self) -> &Args {
+        &self.ctx.args
+    }
+
+    /// Address and liveness of every worker.
+    pub fn worker_status(&self) -> Vec<WorkerStatus> {
+        self.model.worker_status()
+    }
+
+    /// Queries the status of every worker of the topology, along with the liveness of the
+    /// connections to them.
+    pub async fn status(&self) -> Result<ClusterStatus> {
+        let options = self.connection_options()?;
+        Ok(ClusterStatus::query(&self.ctx.topology, &options)
+            .await
+            .with_liveness(&self.worker_status()))
+    }
+
+    /// Reads the topology file the master has been started with again and serves the layers as
+    /// it assigns them, connecting to the new workers and disconnecting from the dropped ones.
+    /// Generations can't be in progress, the caches are reset and the sessions keep their tokens
+    /// only. The current topology is kept if the new one is invalid or a worker can't be reached.
+    pub async fn reload_topology(&mut self) -> Result<(), CakeError> {
+        log::info!("reloading topology from {}", &self.ctx.args.topology);
+
+        let topology = if self.registry.is_some() && !Path::new(&self.ctx.args.topology).exists() {
+            Ok(Topology::default())
+        } else {
+            Topology::from_path(&self.ctx.args.topology)
+        };
+        let topology = topology
+            .and_then(|mut topology| {
+                if let Some(registry) = &self.registry {
+                    registry.extend(&mut topology);
+                }
+                topology.validate(&self.ctx.config).map(|_| topology)
+            })
+            .map_err(|e| CakeError::TopologyInvalid {
+                reason: e.to_string(),
+            })?;
+
+        // the layers it now serves may be in shards that aren't mapped yet
+        let var_builder = self.ctx.var_builder_with(&topology)?;
+        self.model
+            .set_topology(
+                &var_builder,
+                &self.ctx.config,
+                &self.ctx.device,
+                &topology,
+                &self.connection_options()?,
+            )
+            .await?;
+        self.ctx.topology = topology;
+        self.ctx.var_builder = var_builder;
+
+        // the caches of the sessions may have been on the workers
+        self.active_session = None;
+        for session in self.sessions.values_mut() {
+            session.forget_caches();
+        }
+        self.reset().await?;
+
+        Ok(())
+    }
+
+    /// The topology of the cluster.
+    pub fn topology(&self) -> &Topology {
+        &self.ctx.topology
+    }
+
+    /// Observes the attention probabilities or the output of a layer in the forward passes of the
+    /// generations, see Llama::add_hook. The tensors hold every sequence of a batched generation
+    /// and the draft model of --draft-model isn't hooked.
+    pub fn add_hook(&mut self, layer_idx: usize, kind: HookKind, hook: Hook) -> Result<()> {
+        self.model.add_hook(layer_idx, kind, hook)
+    }
+
+    /// Removes the hooks added with add_hook.
+    pub fn clear_hooks(&mut self) {
+        self.model.clear_hooks();
+    }
+
+    /// Options of the connections to the workers, the registered ones included.
+    fn connection_options(&self) -> Result<ConnectionOptions> {
+        Ok(ConnectionOptions {
+            registry: self.registry.clone(),
+            ..ConnectionOptions::from_args(&self.ctx.args)?
+        })
+    }
+
+    /// Clears the local and the remote key-value caches so that a new sequence can be generated.
+    pub async fn reset(&mut self) -> Result<()> {
+        self.reset_batch(&[]).await
+    }
+
+    async fn reset_batch(&mut self, left_padding: &[usize]) -> Result<()> {
+        self.ctx.cache = self.ctx.cache.as_new();
+        self.ctx.cache.left_padding = left_padding.to_vec();
+        self.history.clear();
+        self.model.reset(left_padding).await
+    }
+
+    /// Saves the local and the remote key-value caches, along with the tokens they have been
+    /// computed from, to a safetensors file.
+    pub async fn save_cache<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        if self.history.is_empty() {
+            bail!("there's no cache to save");
+        }
+
+        log::info!(
+            "saving cache of {} tokens to {}",
+            self.history.len(),
+            path.as_ref().display()
+        );
+
+        let cache = self.model.kv_cache(&self.ctx.cache).await?;
+        cache.save(path, &self.history)
+    }
+
+    /// Restores the key-value caches saved with save_cache, a prompt starting with the same
+    /// tokens will only process the new ones.
+    pub async fn load_cache<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let (cache, tokens) = Cache::load(&path, &self.ctx.config, &self.ctx.device)?;
+        if cache.cos.dtype() != self.ctx.cache.cos.dtype() {
+            bail!(
+                "cache {} has dtype {:?}, the model uses {:?}",
+                path.as_ref().display(),
+                cache.cos.dtype(),
+                self.ctx.cache.cos.dtype()
+            );
+        }
+
+        log::info!(
+            "loaded cache of {} tokens from {}",
+            tokens.len(),
+            path.as_ref().display()
+        );
+
+        self.reset().await?;
+        self.model.set_kv_cache(&cache, &mut self.ctx.cache).await?;
+        self.history = tokens;
+
+        Ok(())
+    }
+
+    /// Returns the embedding of the text, pooled from the final hidden states of its tokens.
+    pub async fn embed(&mut self, text: &str) -> Result<Vec<f32>> {
+        let tokens = self.encode(text)?;
+        if tokens.is_empty() {
+            bail!("can't embed an empty text");
+        }
+
+        // the caches must only hold this text
+        self.reset().await?;
+
+        let input = Tensor::new(tokens.as_slice(), &self.ctx.device)?.unsqueeze(0)?;
+        let hidden = self
+            .model
+            .hidden_states(&input, 0, &mut self.ctx.cache)
+            .await?
+            .squeeze(0)?
+            .to_dtype(DType::F32)?;
+
+        let embedding = match self.ctx.args.pooling {
+            Pooling::Mean => hidden.mean(0)?,
+            Pooling::Last => hidden.get(tokens.len() - 1)?,
+        };
+
+        // nothing can be continued from these caches
+        self.reset().await?;
+
+        Ok(embedding.to_vec1()?)
+    }
+
+    /// Generates args.max_tokens tokens, 128 if not set, from a synthetic prompt of
+    /// args.bench_prompt_len tokens and reports the throughput along with the latency of every
+    /// layer. Tokens are picked greedily and the end of sequence token is ignored.
+    pub async fn bench(&mut self) -> Result<BenchReport> {
+        let prompt_len = self.ctx.args.bench_prompt_len.max(1);
+        let max_tokens = self.ctx.args.max_tokens.unwrap_or(128).max(1);
+        let context_size = self.ctx.config.context_size();
+        if prompt_len + max_tokens > context_size {
+            bail!(
+                "{prompt_len} prompt tokens and {max_tokens} new tokens exceed the context size of {context_size}"
+            );
+        }
+
+        log::info!("bench: {prompt_len} prompt tokens, {max_tokens} new tokens");
+
+        // tokens cycling through the vocabulary
+        let tokens: Vec<u32> = (0..prompt_len)
+            .map(|i| (i % self.ctx.config.vocab_size) as u32)
+            .collect();
+
+        self.reset().await?;
+        self.model
+            .set_activation_dtype(Self::activation_dtype(&self.ctx.args)?);
+
+        let start = std::time::Instant::now();
+        let input = Tensor::new(tokens.as_slice(), &self.ctx.device)?.unsqueeze(0)?;
+        let mut logits = self.model.forward(&input, 0, &mut self.ctx.cache).await?;
+        let prompt_elapsed = start.elapsed();
+
+        // layers are only timed while generating
+        self.model.enable_timings();
+
+        let start = std::time::Instant::now();
+        for index in 0..max_tokens {
+            let next_token = logits.argmax(D::Minus1)?.to_vec1::<u32>()?[0];
+            let input = Tensor::new(&[next_token], &self.ctx.device)?.unsqueeze(0)?;
+            logits = self
+                .model
+                .forward(&input, prompt_len + index, &mut self.ctx.cache)
+                .await?;
+        }
+        let generation_elapsed = start.elapsed();
+
+        let layers = self
+            .model
+            .take_timings()
+            .map(|timings| timings.latencies())
+            .unwrap_or_default();
+
+        // nothing can be continued from these caches
+        self.reset().await?;
+
+        Ok(BenchReport::new(
+            prompt_len,
+            prompt_elapsed,
+            max_tokens,
+            generation_elapsed,
+            layers,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn embeddings_of_the_final_hidden_states() {
+        let mut master = test_utils::master(test_utils::args(&[])).await;
+        let hidden_size = master.ctx.config.hidden_size;
+        let mean = master.embed("the cat sat on the mat").await.unwrap();
+        assert_eq!(mean.len(), hidden_size);
+        assert!(mean.iter().all(|v| v.is_finite()));
+        assert_eq!(mean, master.embed("the cat sat on the mat").await.unwrap());
+        assert_ne!(mean, master.embed("the dog ran home").await.unwrap());
+
+        let mut master = test_utils::master(test_utils::args(&["--pooling", "last"])).await;
+        let last = master.embed("the cat sat on the mat").await.unwrap();
+        assert_eq!(last.len(), hidden_size);
+        assert_ne!(last, mean);
+        assert_eq!(last, master.embed("the cat sat on the mat").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn status_of_the_workers() {
+        let (first, second) = (test_utils::free_address(), test_utils::free_address());
+        let topology = test_utils::topology(
+            "status.yml",
+            &format!(
+                "status-0: {{ host: '{first}', layers: [0, 1] }}\n\
+                 status-1: {{ host: '{second}', layers: [2, 3] }}"
+            ),
+        );
+        let _workers = (
+            test_utils::worker("status-0", &topology, &[]).await,
+            test_utils::worker("status-1", &topology, &[]).await,
+        );
+        let mut args = test_utils::args(&[]);
+        args.topology = topology;
+        let master = test_utils::master(args).await;
+
+        let status = master.status().await.unwrap();
+        let names: Vec<&str> = status.workers.iter().map(|w| w.name.as_str()).collect();
+        assert_eq!(names, ["status-0", "status-1"]);
+        for (worker, (host, layers)) in status.workers.iter().zip([
+            (&first, ["model.layers.0", "model.layers.1"]),
+            (&second, ["model.layers.2", "model.layers.3"]),
+        ]) {
+            assert_eq!(&worker.host, host);
+            assert!(worker.reachable, "{:?}", worker.error);
+            assert_eq!(worker.layers, layers);
+            assert_eq!(worker.loaded_layers, layers);
+            assert_eq!(worker.dtype.as_deref(), Some("f32"));
+            assert_eq!(worker.in_flight, Some(0));
+            assert_eq!(worker.healthy, Some(true));
+            assert!(worker.last_seen_ms.is_some());
+        }
+
+        let table = status.to_string();
+        assert!(
+            table.contains("status-1") && table.contains(" up "),
+            "{table}"
+        );
+    }
+
+    async fn generate(master: &mut Master, args: &Args) -> String {
+        let tokens = master.encode("the cat sat").unwrap();
+        let mut text = String::new();
+        master
+            .generate_with(args, tokens, &Default::default(), |t| text.push_str(t))
+            .await
+            .unwrap();
+        text
+    }
+
+    #[tokio::test]
+    async fn reloaded_topology_connects_the_new_workers() {
+        let (first, second) = (test_utils::free_address(), test_utils::free_address());
+        let topology = test_utils::topology(
+            "reload.yml",
+            &format!("reload-0: {{ host: '{first}', layers: [2, 3] }}"),
+        );
+        let new_topology = format!("reload-1: {{ host: '{second}', layers: [0, 1] }}");
+        let first_worker = test_utils::worker("reload-0", &topology, &[]).await;
+        let _second_worker = test_utils::worker(
+            "reload-1",
+            &test_utils::topology("reload-1.yml", &new_topology),
+            &[],
+        )
+        .await;
+
+        let mut args = test_utils::args(&["--max-tokens", "4", "--ignore-eos"]);
+        args.topology = topology.clone();
+        let mut master = test_utils::master(args.clone()).await;
+        let hosts = |status: ClusterStatus| -> Vec<(String, String, bool)> {
+            status
+                .workers
+                .into_iter()
+                .map(|w| (w.name, w.host, w.reachable))
+                .collect()
+        };
+        let expected = generate(&mut master, &args).await;
+
+        // an invalid topology is rejected and the current one kept
+        std::fs::write(&topology, "reload-1: { host: local, layers: [9] }").unwrap();
+        assert!(matches!(
+            master.reload_topology().await,
+            Err(CakeError::TopologyInvalid { .. })
+        ));
+        assert_eq!(
+            hosts(master.status().await.unwrap()),
+            [("reload-0".to_string(), first.clone(), true)]
+        );
+
+        std::fs::write(&topology, &new_topology).unwrap();
+        master.reload_topology().await.unwrap();
+        assert_eq!(
+            hosts(master.status().await.unwrap()),
+            [("reload-1".to_string(), second, true)]
+        );
+
+        // the dropped worker isn't needed anymore
+        drop(first_worker);
+        assert_eq!(generate(&mut master, &args).await, expected);
+    }
+
+    #[tokio::test]
+    async fn layers_of_a_dead_worker_fall_back_to_the_cpu() {
+        let args = test_utils::args(&["--max-tokens", "8", "--ignore-eos"]);
+        let expected = generate(&mut test_utils::master(args.clone()).await, &args).await;
+
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "fallback.yml",
+            &format!("fallback: {{ host: '{address}', layers: [2, 3] }}"),
+        );
+        for fallback_local in [false, true] {
+            let mut worker = Some(test_utils::worker("fallback", &topology, &[]).await);
+            let args = Args {
+                topology: topology.clone(),
+                fallback_local,
+                reconnect_attempts: 1,
+                reconnect_backoff_base: 10,
+                ..args.clone()
+            };
+            let mut master = test_utils::master(args.clone()).await;
+            assert_eq!(master.worker_status().len(), 1);
+
+            // the worker dies for good after the third token
+            let tokens = master.encode("the cat sat").unwrap();
+            let (mut text, mut pieces) = (String::new(), 0);
+            let res = master
+                .generate_with(&args, tokens, &Default::default(), |t| {
+                    text.push_str(t);
+                    pieces += 1;
+                    if pieces == 3 {
+                        worker.take();
+                    }
+                })
+                .await;
+            assert!(pieces >= 3);
+            if !fallback_local {
+                assert!(res.is_err());
+                continue;
+            }
+
+            // the sequence processed again by the layers loaded on the cpu, once and for all
+            res.unwrap();
+            assert_eq!(text, expected);
+            assert!(master.worker_status().is_empty());
+            assert_eq!(generate(&mut master, &args).await, expected);
+        }
+    }
+
+    /// Connections of the master through a proxy.
+    #[derive(Default)]
+    struct Proxied {
+        accepted: std::sync::atomic::AtomicUsize,
+        // last bytes the master sent on each connection it closed
+        closed: std::sync::Mutex<Vec<Vec<u8>>>,
+    }
+
+    /// Proxy to target, closing every connection it holds whenever kill is notified.
+    async fn proxy(target: String) -> (String, Arc<Proxied>, tokio::sync::watch::Sender<()>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let proxied = Arc::new(Proxied::default());
+        let (kill, killed) = tokio::sync::watch::channel(());
+        let state = proxied.clone();
+        tokio::spawn(async move {
+            loop {
+                let (master, _) = listener.accept().await.unwrap();
+                state
+                    .accepted
+                    .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let worker = tokio::net::TcpStream::connect(&target).await.unwrap();
+                let (mut master_read, mut master_write) = master.into_split();
+                let (mut worker_read, mut worker_write) = worker.into_split();
+                let (state, mut killed) = (state.clone(), killed.clone());
+                killed.mark_unchanged();
+                tokio::spawn(async move {
+                    let upstream = async {
+                        let (mut buf, mut last) = (vec![0; 64 * 1024], vec![]);
+                        while let Ok(n @ 1..) = master_read.read(&mut buf).await {
+                            last = buf[..n].to_vec();
+                            if worker_write.write_all(&buf[..n]).await.is_err() {
+                                return;
+                            }
+                        }
+                        state.closed.lock().unwrap().push(last);
+                    };
+                    let downstream = tokio::io::copy(&mut worker_read, &mut master_write);
+                    tokio::select! {
+                        _ = async { tokio::join!(upstream, downstream) } => {}
+                        _ = killed.changed() => {}
+                    }
+                });
+            }
+        });
+        (address, proxied, kill)
+    }
+
+    #[tokio::test]
+    async fn connections_are_reused_and_revived() {
+        use std::sync::atomic::Ordering;
+
+        let address = test_utils::free_address();
+        let worker_topology = test_utils::topology(
+            "pool-worker.yml",
+            &format!("pool: {{ host: '{address}', layers: [2, 3] }}"),
+        );
+        let _worker = test_utils::worker("pool", &worker_topology, &[]).await;
+        let (proxy_address, proxied, kill) = proxy(address).await;
+        let mut args = test_utils::args(&["--max-tokens", "6", "--ignore-eos"]);
+        args.topology = test_utils::topology(
+            "pool-master.yml",
+            &format!("pool: {{ host: '{proxy_address}', layers: [2, 3] }}"),
+        );
+        let mut master = test_utils::master(args.clone()).await;
+        let connections = proxied.accepted.load(Ordering::SeqCst);
+        assert!(connections > 0);
+
+        // the connections opened by Master::new serve every generation
+        let expected = generate(&mut master, &args).await;
+        assert_eq!(generate(&mut master, &args).await, expected);
+        assert_eq!(proxied.accepted.load(Ordering::SeqCst), connections);
+
+        // dropped between two generations, they're opened again before being used
+        kill.send(()).unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(generate(&mut master, &args).await, expected);
+        assert_eq!(proxied.accepted.load(Ordering::SeqCst), 2 * connections);
+        assert_eq!(generate(&mut master, &args).await, expected);
+        assert_eq!(proxied.accepted.load(Ordering::SeqCst), 2 * connections);
+
+        // and closed with a shutdown once the master is dropped
+        drop(master);
+        let mut shutdown = vec![];
+        crate::cake::Message::Shutdown
+            .to_writer(&mut shutdown)
+            .await
+            .unwrap();
+        let start = std::time::Instant::now();
+        while proxied.closed.lock().unwrap().len() < connections {
+            assert!(start.elapsed().as_secs() < 2, "connections left open");
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        for last in proxied.closed.lock().unwrap().iter() {
+            assert!(last.ends_with(&shutdown), "{last:?}");
+        }
+    }
+}
@@ -0,0 +1,640 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use super::Master;
+use crate::{
+    cake::{CakeError, Context, PromptCount},
+    model::{Config, EosTokenIds, EOS_TOKEN},
+    prompt::{ChatMessage, ChatTokens, FimTokens},
+    utils::TokenOutputStream,
+    Args,
+};
+
+use anyhow::Result;
+use tokenizers::Tokenizer;
+
+/// What's done with a prompt that doesn't leave room in the context for the tokens to generate.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Truncation {
+    /// Drop its first tokens, keeping the bos token.
+    Left,
+    /// Drop its last tokens.
+    Right,
+    /// Fail with its token count.
+    #[default]
+    Error,
+}
+
+/// Whether the tokenizer adds a special token around the prompts.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddToken {
+    /// As set by the tokenizer config, or as the post-processor of the tokenizer does.
+    #[default]
+    Auto,
+    True,
+    False,
+}
+
+/// What the prompts are tokenized with, the tokenizer and the special tokens of a master.
+struct Prompter<'a> {
+    tokenizer: &'a Tokenizer,
+    // bos and eos tokens of --add-bos and --add-eos
+    added_tokens: (Option<u32>, Option<u32>),
+    bos_token_id: Option<u32>,
+    data_path: &'a Path,
+}
+
+impl Prompter<'_> {
+    /// Tokenizes the text, with the bos token of --add-bos first if bos is set and the eos token
+    /// of --add-eos last.
+    fn encode_with(&self, text: &str, bos: bool) -> Result<Vec<u32>> {
+        let (bos_token, eos_token) = self.added_tokens;
+        let mut tokens: Vec<u32> = bos_token.filter(|_| bos).into_iter().collect();
+        tokens.extend(
+            self.tokenizer
+                .encode(text, false)
+                .map_err(anyhow::Error::msg)?
+                .get_ids(),
+        );
+        tokens.extend(eos_token);
+        Ok(tokens)
+    }
+
+    fn chat_tokens(&self) -> Result<ChatTokens> {
+        ChatTokens::from_tokenizer(
+            self.tokenizer,
+            &self.data_path.join("tokenizer_config.json"),
+        )
+    }
+
+    fn fim_tokens(&self) -> Result<FimTokens> {
+        FimTokens::from_tokenizer(
+            self.tokenizer,
+            &self.data_path.join("tokenizer_config.json"),
+        )
+    }
+
+    fn encode_prompt(&self, args: &Args) -> Result<Vec<u32>> {
+        let mut tokens = if args.fim {
+            let tokens = self.fim_tokens()?.build_fim_prompt(
+                self.tokenizer,
+                self.bos_token_id,
+                &args.fim_prefix,
+                &args.fim_suffix,
+            )?;
+            log::debug!("fim prompt: {:?}", &tokens);
+            tokens
+        } else if args.chat {
+            let prompt = self
+                .chat_tokens()?
+                .build_chat_prompt(args.system.as_deref(), &args.prompt);
+            self.encode_chat_prompt(prompt)?
+        } else {
+            self.encode_with(&args.prompt, true)?
+        };
+
+        if let Some(prefill) = &args.prefill {
+            // tokenized on its own so that the generation starts right after it
+            tokens.extend_from_slice(
+                self.tokenizer
+                    .encode(prefill.as_str(), false)
+                    .map_err(anyhow::Error::msg)?
+                    .get_ids(),
+            );
+        }
+
+        Ok(tokens)
+    }
+
+    fn encode_chat_prompt(&self, prompt: String) -> Result<Vec<u32>> {
+        log::debug!("chat prompt: {:?}", &prompt);
+
+        // the template already starts with the bos token
+        Ok(self
+            .tokenizer
+            .encode(prompt, false)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec())
+    }
+}
+
+impl Master {
+    pub(super) fn create_tokenizer(args: &Args, data_path: &Path) -> Result<Tokenizer> {
+        let default_filename = data_path.join("tokenizer.json");
+        let tokenizer_filename = match &args.tokenizer {
+            Some(path) if Path::new(path).exists() => PathBuf::from(path),
+            Some(path) if default_filename.exists() => {
+                log::warn!(
+                    "tokenizer {path} not found, using {}",
+                    default_filename.display()
+                );
+                default_filename
+            }
+            Some(path) => bail!(
+                "tokenizer not found at {path} nor at {}",
+                default_filename.display()
+            ),
+            None if !default_filename.exists() => bail!(
+                "tokenizer not found at {}, set its path with --tokenizer",
+                default_filename.display()
+            ),
+            None => default_filename,
+        };
+
+        log::info!("loading tokenizer from {}", tokenizer_filename.display());
+
+        Tokenizer::from_file(tokenizer_filename).map_err(anyhow::Error::msg)
+    }
+
+    /// Returns the bos and the eos tokens the prompts are tokenized with, if any. In auto mode
+    /// they're added if the add_bos_token and add_eos_token of the tokenizer_config.json say so,
+    /// otherwise if the post-processor of the tokenizer adds them.
+    pub(super) fn added_tokens(
+        args: &Args,
+        data_path: &Path,
+        model_config: &Config,
+        tokenizer: &Tokenizer,
+        eos_token_ids: &HashSet<u32>,
+    ) -> Result<(Option<u32>, Option<u32>)> {
+        let mut config = serde_json::Value::Null;
+        let tokenizer_config = data_path.join("tokenizer_config.json");
+        if tokenizer_config.exists() {
+            let data = std::fs::read(&tokenizer_config)
+                .map_err(|e| anyhow!("can't read {}: {:?}", tokenizer_config.display(), e))?;
+            config = serde_json::from_slice(&data)
+                .map_err(|e| anyhow!("can't parse {}: {:?}", tokenizer_config.display(), e))?;
+        }
+        let token_id = |key: &str| {
+            config
+                .get(key)
+                .and_then(|token| token.as_str())
+                .and_then(|token| tokenizer.token_to_id(token))
+        };
+
+        let bos = model_config.bos_token_id.or_else(|| token_id("bos_token"));
+        let eos = token_id("eos_token").or_else(|| eos_token_ids.iter().min().copied());
+        // the special tokens the post-processor adds around the text
+        let template = tokenizer
+            .encode("", true)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec();
+
+        let added = |flag: &str, mode: AddToken, token: Option<u32>, at: Option<&u32>| {
+            let key = format!("{}_token", flag.replace('-', "_"));
+            let add = match mode {
+                AddToken::True => true,
+                AddToken::False => false,
+                AddToken::Auto => config
+                    .get(&key)
+                    .and_then(|add| add.as_bool())
+                    .unwrap_or_else(|| token.is_some() && at.copied() == token),
+            };
+            match (add, token) {
+                (true, None) => bail!("--{flag} but the model has no such token"),
+                (true, token) => Ok(token),
+                (false, _) => Ok(None),
+            }
+        };
+
+        let added_tokens = (
+            added("add-bos", args.add_bos, bos, template.first())?,
+            added("add-eos", args.add_eos, eos, template.last())?,
+        );
+        log::info!(
+            "prompts tokenized with bos {:?} and eos {:?}",
+            added_tokens.0,
+            added_tokens.1
+        );
+        Ok(added_tokens)
+    }
+
+    /// Returns the ids of the tokens ending a generation, the ones of --eos-ids if set, otherwise
+    /// the ones of the model config and of its generation_config.json, or the </s> token if they
+    /// have none.
+    pub(super) fn eos_token_ids(
+        args: &Args,
+        data_path: &Path,
+        config: &Config,
+        tokenizer: &Tokenizer,
+    ) -> Result<HashSet<u32>> {
+        if !args.eos_ids.is_empty() {
+            return Ok(args.eos_ids.iter().copied().collect());
+        }
+
+        let mut ids: HashSet<u32> = config.eos_token_ids.iter().copied().collect();
+
+        let generation_config = data_path.join("generation_config.json");
+        if generation_config.exists() {
+            let data = std::fs::read(&generation_config)
+                .map_err(|e| anyhow!("can't read {}: {:?}", generation_config.display(), e))?;
+            let config: serde_json::Value = serde_json::from_slice(&data)
+                .map_err(|e| anyhow!("can't parse {}: {:?}", generation_config.display(), e))?;
+            if let Some(eos_token_id) = config.get("eos_token_id") {
+                let eos_token_ids: EosTokenIds = serde_json::from_value(eos_token_id.clone())
+                    .map_err(|e| anyhow!("can't parse {}: {:?}", generation_config.display(), e))?;
+                ids.extend(eos_token_ids.into_vec());
+            }
+        }
+
+        if ids.is_empty() {
+            ids.extend(tokenizer.token_to_id(EOS_TOKEN));
+        }
+
+        Ok(ids)
+    }
+
+    /// The tokenizer of the model, along with its vocabulary.
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    /// Tokenizes a text as a prompt, with the special tokens of --add-bos and --add-eos.
+    pub fn encode(&self, text: &str) -> Result<Vec<u32>> {
+        self.encode_with(text, true)
+    }
+
+    pub(super) fn encode_with(&self, text: &str, bos: bool) -> Result<Vec<u32>> {
+        self.prompter().encode_with(text, bos)
+    }
+
+    fn prompter(&self) -> Prompter<'_> {
+        Prompter {
+            tokenizer: &self.tokenizer,
+            added_tokens: self.added_tokens,
+            bos_token_id: self.ctx.config.bos_token_id,
+            data_path: &self.ctx.data_path,
+        }
+    }
+
+    /// Decodes tokens the way the generated ones are streamed, skipping the special tokens. The
+    /// bytes of a character split across several tokens are only decoded once all of them are
+    /// there, a trailing incomplete character is decoded as U+FFFD.
+    pub fn decode(&self, tokens: &[u32]) -> Result<String> {
+        let mut stream = TokenOutputStream::new(self.tokenizer.clone());
+        let mut text = String::new();
+        for &token in tokens {
+            if let Some(t) = stream.next_token(token)? {
+                text += &t;
+            }
+        }
+        if let Some(rest) = stream.decode_rest()? {
+            text += &rest;
+        }
+        Ok(text)
+    }
+
+    pub(super) fn chat_tokens(&self) -> Result<ChatTokens> {
+        self.prompter().chat_tokens()
+    }
+
+    pub(super) fn fim_tokens(&self) -> Result<FimTokens> {
+        self.prompter().fim_tokens()
+    }
+
+    /// Tokenizes the prompt from the arguments, applying the chat template if enabled or laying
+    /// out the fim prefix and suffix in fim mode, followed by the prefill if any.
+    pub fn encode_prompt(&self, args: &Args) -> Result<Vec<u32>> {
+        self.prompter().encode_prompt(args)
+    }
+
+    /// Tokenizes the prompt from the arguments like a master started with them would, without
+    /// loading the model, and returns its size along with the room it leaves in the context.
+    pub fn count_prompt(args: &Args) -> Result<PromptCount, CakeError> {
+        let data_path = PathBuf::from(&args.model);
+        let config = Context::load_model_config(args)?;
+        let tokenizer = Self::create_tokenizer(args, &data_path)?;
+        let eos_token_ids = Self::eos_token_ids(args, &data_path, &config, &tokenizer)?;
+        let added_tokens =
+            Self::added_tokens(args, &data_path, &config, &tokenizer, &eos_token_ids)?;
+
+        let tokens = Prompter {
+            tokenizer: &tokenizer,
+            added_tokens,
+            bos_token_id: config.bos_token_id,
+            data_path: &data_path,
+        }
+        .encode_prompt(args)?;
+
+        Ok(PromptCount {
+            prompt_tokens: tokens.len(),
+            context_size: config.context_size(),
+            max_tokens: args.max_tokens,
+        })
+    }
+
+    /// Applies --truncate to a prompt that doesn't leave room in the context for the max_tokens
+    /// tokens to generate, if set.
+    pub(super) fn truncate_prompt(&self, args: &Args, mut tokens: Vec<u32>) -> Result<Vec<u32>> {
+        let context_size = self.ctx.config.context_size();
+        let reserved = args.max_tokens.unwrap_or(0);
+        let room = context_size.saturating_sub(reserved);
+        if tokens.len() <= room {
+            return Ok(tokens);
+        }
+
+        let prompt_tokens = tokens.len();
+        // kept when dropping the first tokens
+        let bos = usize::from(tokens.first().copied() == self.ctx.config.bos_token_id);
+        match args.truncate {
+            Truncation::Left if room > bos => {
+                tokens.drain(bos..bos + prompt_tokens - room);
+            }
+            Truncation::Right if room > 0 => tokens.truncate(room),
+            _ => {
+                return Err(CakeError::PromptTooLong {
+                    prompt_tokens,
+                    reserved,
+                    context_size,
+                }
+                .into())
+            }
+        }
+
+        log::warn!(
+            "prompt truncated from {prompt_tokens} to {} tokens to leave room for {reserved} new tokens in the context size of {context_size}",
+            tokens.len()
+        );
+
+        Ok(tokens)
+    }
+
+    /// Tokenizes a list of chat messages with the chat template.
+    pub fn encode_chat(&self, messages: &[ChatMessage]) -> Result<Vec<u32>> {
+        let prompt = self.chat_tokens()?.build_prompt(messages);
+        self.encode_chat_prompt(prompt)
+    }
+
+    pub(super) fn encode_chat_prompt(&self, prompt: String) -> Result<Vec<u32>> {
+        self.prompter().encode_chat_prompt(prompt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cake::FinishReason, test_utils};
+
+    #[tokio::test]
+    async fn prompt_count_without_the_weights() {
+        let text = "the cat sat on the mat";
+        let dir = test_utils::model_dir_with("count", serde_json::json!({}));
+        for weights in ["model.safetensors", "model.safetensors.index.json"] {
+            std::fs::remove_file(dir.join(weights)).unwrap();
+        }
+
+        let count = Master::count_prompt(&test_utils::args_for(&dir, &["--prompt", text])).unwrap();
+        let tokens = test_utils::tokenizer().encode(text, true).unwrap();
+        assert_eq!(count.prompt_tokens, tokens.len());
+        assert_eq!(count.prompt_tokens, 7);
+        assert_eq!((count.context_size, count.remaining()), (256, 249));
+        assert_eq!(
+            count.to_string(),
+            "prompt:       7 tokens\ncontext size: 256 tokens\nremaining:    249 tokens\n"
+        );
+
+        let args = test_utils::args_for(&dir, &["--prompt", text, "--max-tokens", "250"]);
+        let count = Master::count_prompt(&args).unwrap();
+        assert!(count
+            .to_string()
+            .ends_with("leaves room for 249 of the 250 tokens of --max-tokens, see --truncate\n"));
+
+        // like the master tokenizes it, the chat template applied
+        let args = test_utils::args_for(
+            test_utils::chat_model_dir(),
+            &["--prompt", text, "--chat", "--system", "be brief"],
+        );
+        let count = Master::count_prompt(&args).unwrap();
+        let master = test_utils::master(args.clone()).await;
+        assert_eq!(
+            count.prompt_tokens,
+            master.encode_prompt(&args).unwrap().len()
+        );
+        assert!(count.prompt_tokens > 7 + 4);
+    }
+
+    #[tokio::test]
+    async fn bos_and_eos_of_each_setting() {
+        async fn encode(dir: &Path, bos: &str, eos: &str) -> Vec<u32> {
+            let args = test_utils::args_for(dir, &["--add-bos", bos, "--add-eos", eos]);
+            test_utils::master(args).await.encode("the cat").unwrap()
+        }
+        let (bos, eos) = (1, 2);
+
+        // the tokenizer adds the bos token only
+        let dir = test_utils::model_dir();
+        assert_eq!(encode(dir, "auto", "auto").await, [bos, 3, 5]);
+        assert_eq!(encode(dir, "false", "auto").await, [3, 5]);
+        assert_eq!(encode(dir, "auto", "true").await, [bos, 3, 5, eos]);
+        assert_eq!(encode(dir, "true", "false").await, [bos, 3, 5]);
+
+        // the tokenizer config takes precedence
+        let dir = test_utils::model_dir_with("add-tokens", serde_json::json!({}));
+        std::fs::write(
+            dir.join("tokenizer_config.json"),
+            r#"{"add_bos_token": false, "add_eos_token": true}"#,
+        )
+        .unwrap();
+        assert_eq!(encode(&dir, "auto", "auto").await, [3, 5, eos]);
+        assert_eq!(encode(&dir, "true", "auto").await, [bos, 3, 5, eos]);
+        assert_eq!(encode(&dir, "auto", "false").await, [3, 5]);
+    }
+
+    #[tokio::test]
+    async fn fim_generation_stops_at_the_end_token() {
+        let fim = ["▁<PRE>", "▁<SUF>", "▁<MID>", "▁<EOT>"];
+        let dir = test_utils::model_dir_with(
+            "fim",
+            serde_json::json!({ "vocab_size": test_utils::WORDS.len() + fim.len() }),
+        );
+        let mut tokenizer = test_utils::tokenizer_json();
+        let added = tokenizer["added_tokens"].as_array_mut().unwrap();
+        for (i, token) in fim.iter().enumerate() {
+            added.push(serde_json::json!({
+                "id": test_utils::WORDS.len() + i, "content": token, "single_word": false,
+                "lstrip": false, "rstrip": false, "normalized": false, "special": true,
+            }));
+        }
+        std::fs::write(dir.join("tokenizer.json"), tokenizer.to_string()).unwrap();
+        let [pre, suf, mid, eot] = [32, 33, 34, 35];
+
+        let fim_args = |extra: &[&str]| {
+            let mut args = test_utils::args_for(&dir, &[&["--max-tokens", "8"], extra].concat());
+            args.fim = true;
+            args.fim_prefix = "the cat".to_string();
+            args.fim_suffix = "on the mat".to_string();
+            args
+        };
+        let mut master = test_utils::master(fim_args(&[])).await;
+        let tokens = master.encode_prompt(&fim_args(&[])).unwrap();
+        assert_eq!(tokens, [1, pre, 3, 5, suf, 8, 3, 9, mid]);
+
+        async fn generate(
+            master: &mut Master,
+            args: &Args,
+            tokens: &[u32],
+        ) -> (FinishReason, Vec<u32>) {
+            let mut generated = vec![];
+            let finish_reason = master
+                .generate_with_events(args, tokens.to_vec(), &Default::default(), |event| {
+                    generated.extend(event.token_id)
+                })
+                .await
+                .unwrap();
+            (finish_reason, generated)
+        }
+
+        // the end token ends the middle in fim mode only
+        let bias = format!("{{\"{eot}\": 100}}");
+        let ending = fim_args(&["--logit-bias", &bias]);
+        assert_eq!(
+            generate(&mut master, &ending, &tokens).await,
+            (FinishReason::Eos, vec![eot])
+        );
+        let continuing = Args {
+            fim: false,
+            ..ending
+        };
+        assert_eq!(
+            generate(&mut master, &continuing, &tokens).await,
+            (FinishReason::Length, vec![eot; 8])
+        );
+    }
+
+    #[tokio::test]
+    async fn tokenizer_of_another_path() {
+        let dir = test_utils::model_dir_with("no-tokenizer", serde_json::json!({}));
+        std::fs::remove_file(dir.join("tokenizer.json")).unwrap();
+        let shared = test_utils::temp_dir("shared-tokenizer").join("tokenizer.json");
+        std::fs::write(&shared, test_utils::tokenizer_json().to_string()).unwrap();
+
+        let ctx = Context::from_args(test_utils::args_for(&dir, &[])).unwrap();
+        let e = Master::new(ctx).await.err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "tokenizer not found at {}, set its path with --tokenizer",
+                dir.join("tokenizer.json").display()
+            )
+        );
+
+        let args = test_utils::args_for(&dir, &["--tokenizer", &shared.display().to_string()]);
+        let master = Master::new(Context::from_args(args).unwrap())
+            .await
+            .unwrap();
+        // <s> the cat sat
+        assert_eq!(master.encode("the cat sat").unwrap(), [1, 3, 5, 7]);
+    }
+
+    #[test]
+    fn missing_tokenizer_names_both_paths() {
+        let dir = test_utils::temp_dir("missing-tokenizer");
+        let missing = dir.join("elsewhere.json").display().to_string();
+        let args = test_utils::args_for(&dir, &["--tokenizer", &missing]);
+
+        let e = Master::create_tokenizer(&args, &dir).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "tokenizer not found at {missing} nor at {}",
+                dir.join("tokenizer.json").display()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn end_tokens_of_the_configs_or_of_the_arguments() {
+        let dir =
+            test_utils::model_dir_with("multi-eos", serde_json::json!({"eos_token_id": [2, 9]}));
+        std::fs::write(
+            dir.join("generation_config.json"),
+            serde_json::json!({"eos_token_id": 11}).to_string(),
+        )
+        .unwrap();
+
+        let master = Master::new(Context::from_args(test_utils::args_for(&dir, &[])).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(master.eos_token_ids, [2, 9, 11].into());
+
+        let args = test_utils::args_for(&dir, &["--eos-ids", "12,13"]);
+        let master = Master::new(Context::from_args(args).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(master.eos_token_ids, [12, 13].into());
+    }
+
+    #[tokio::test]
+    async fn byte_level_round_trip() {
+        let tokenizer = test_utils::byte_level_tokenizer_json();
+        let dir =
+            test_utils::model_dir_with("byte-level", serde_json::json!({ "vocab_size": 256 }));
+        std::fs::write(dir.join("tokenizer.json"), tokenizer.to_string()).unwrap();
+        let args = test_utils::args_for(&dir, &["--max-tokens", "16", "--ignore-eos"]);
+        let mut master = Master::new(Context::from_args(args.clone()).unwrap())
+            .await
+            .unwrap();
+
+        let text = "héllo wörld 😀🎂";
+        let tokens = master.encode(text).unwrap();
+        assert_eq!(tokens.len(), text.len());
+        assert_eq!(master.decode(&tokens).unwrap(), text);
+        // the last character is missing a byte
+        assert_eq!(
+            master.decode(&tokens[..tokens.len() - 1]).unwrap(),
+            "héllo wörld 😀\u{FFFD}"
+        );
+
+        // decoded like the generated tokens are streamed
+        let mut generated = vec![];
+        let mut streamed = String::new();
+        master
+            .generate_with_events(&args, tokens, &Default::default(), |event| {
+                generated.extend(event.token_id);
+                streamed += event.text;
+            })
+            .await
+            .unwrap();
+        assert_eq!(generated.len(), 16);
+        assert_eq!(master.decode(&generated).unwrap(), streamed);
+    }
+
+    #[tokio::test]
+    async fn overlong_prompt_of_each_truncation() {
+        let master = test_utils::master(test_utils::args(&[])).await;
+        assert_eq!(master.ctx.config.context_size(), 256);
+        // the bos token then 299 words
+        let prompt: Vec<u32> = std::iter::once(1)
+            .chain((0..299).map(|i| 3 + i % 20))
+            .collect();
+        let truncated = |truncate: &str, max_tokens: &str| {
+            let args = test_utils::args(&["--truncate", truncate, "--max-tokens", max_tokens]);
+            master.truncate_prompt(&args, prompt.clone())
+        };
+
+        // 16 tokens are reserved for the generation
+        let left = truncated("left", "16").unwrap();
+        assert_eq!(left.len(), 240);
+        assert_eq!(left[0], 1);
+        assert_eq!(left[1..], prompt[prompt.len() - 239..]);
+        let right = truncated("right", "16").unwrap();
+        assert_eq!(right, prompt[..240]);
+
+        let e = truncated("error", "16").unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(CakeError::PromptTooLong {
+                prompt_tokens: 300,
+                reserved: 16,
+                context_size: 256,
+            })
+        ));
+
+        // a prompt that fits is kept whatever the policy
+        let args = test_utils::args(&["--truncate", "left", "--max-tokens", "16"]);
+        assert_eq!(master.truncate_prompt(&args, left.clone()).unwrap(), left);
+    }
+}
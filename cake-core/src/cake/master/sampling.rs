@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+
+use crate::{
+    utils::{self, Sampler},
+    Args,
+};
+
+use anyhow::Result;
+use candle_core::{DType, Tensor, D};
+use candle_transformers::generation::Sampling;
+
+/// Log-probability of a generated token.
+#[derive(Debug, Clone)]
+pub struct TokenLogprob {
+    pub token: u32,
+    pub logprob: f32,
+    /// The most likely tokens at this step with their log-probabilities, most likely first.
+    pub top: Vec<(u32, f32)>,
+}
+
+pub(super) fn sampling_temperature(args: &Args) -> f64 {
+    // with no sampling parameters at all, keep greedy decoding
+    match (args.temperature, args.top_k, args.top_p, args.min_p) {
+        (None, None, None, None) => 0.,
+        (temperature, _, _, _) => temperature.unwrap_or(1.0),
+    }
+}
+
+/// The sampling of the logits processor, min-p aside.
+fn sampling(args: &Args) -> Sampling {
+    let temperature = sampling_temperature(args);
+    // temperature is applied to the logits before the softmax, top-p and top-k then both
+    // keep a prefix of the tokens sorted by probability, so applying them in either order
+    // selects the same candidates.
+    if temperature <= 0. {
+        Sampling::ArgMax
+    } else {
+        match (args.top_k, args.top_p) {
+            (None, None) => Sampling::All { temperature },
+            (Some(k), None) => Sampling::TopK { k, temperature },
+            (None, Some(p)) => Sampling::TopP { p, temperature },
+            (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+        }
+    }
+}
+
+pub(super) fn create_sampler(args: &Args) -> Sampler {
+    let sampling = sampling(args);
+    let seed = sampling_seed(args);
+
+    log::info!(
+        "sampling: {:?} min_p={:?} seed={seed}",
+        &sampling,
+        args.min_p
+    );
+
+    Sampler::new(seed, sampling)
+}
+
+pub(super) fn sampling_seed(args: &Args) -> u64 {
+    args.seed.unwrap_or_else(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|t| t.as_nanos() as u64)
+            .unwrap_or_default()
+    })
+}
+
+/// Adds the biases to the logits of their tokens, ids outside of the vocabulary are ignored.
+pub(super) fn apply_logit_bias(logits: Tensor, bias: &HashMap<u32, f32>) -> Result<Tensor> {
+    if bias.is_empty() {
+        return Ok(logits);
+    }
+
+    let mut values: Vec<f32> = logits.to_dtype(DType::F32)?.to_vec1()?;
+    for (token, bias) in bias {
+        if let Some(value) = values.get_mut(*token as usize) {
+            *value += bias;
+        }
+    }
+
+    Ok(Tensor::new(values, logits.device())?.to_dtype(logits.dtype())?)
+}
+
+/// Computes the log-probability of the token and of the most likely ones.
+pub(super) fn logprob(logits: &Tensor, token: u32, args: &Args) -> Result<TokenLogprob> {
+    // greedy decoding picks from the unscaled distribution
+    let temperature = match sampling_temperature(args) {
+        t if t <= 0. => 1.,
+        t => t,
+    };
+    let logits = (logits.to_dtype(DType::F32)? / temperature)?;
+    let logprobs: Vec<f32> = candle_nn::ops::log_softmax(&logits, D::Minus1)?.to_vec1()?;
+
+    let mut top: Vec<(u32, f32)> = logprobs
+        .iter()
+        .enumerate()
+        .map(|(token, logprob)| (token as u32, *logprob))
+        .collect();
+    let k = args.top_logprobs.min(top.len());
+    if k > 0 && k < top.len() {
+        top.select_nth_unstable_by(k - 1, |a, b| b.1.total_cmp(&a.1));
+    }
+    top.truncate(k);
+    top.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    Ok(TokenLogprob {
+        token,
+        logprob: logprobs[token as usize],
+        top,
+    })
+}
+
+/// Probabilities the next token is sampled with by the logits processor, once the
+/// temperature, min-p, top-k and top-p are applied.
+pub(super) fn sampling_probs(logits: &Tensor, args: &Args) -> Result<Vec<f32>> {
+    let probs = utils::filtered_probs(&apply_min_p(logits, args)?, &sampling(args))?;
+    let sum: f32 = probs.iter().sum();
+    Ok(probs.into_iter().map(|p| p / sum).collect())
+}
+
+/// Masks the logits of the tokens less than min_p times as likely as the most likely one once
+/// the temperature is applied, leaving the logits processor the top-k and top-p to apply on
+/// the remaining ones.
+pub(super) fn apply_min_p(logits: &Tensor, args: &Args) -> Result<Tensor> {
+    let temperature = sampling_temperature(args);
+    let min_p = match args.min_p {
+        // greedy decoding always picks a token above the threshold
+        Some(min_p) if temperature > 0. => min_p,
+        _ => return Ok(logits.clone()),
+    };
+
+    let mut values: Vec<f32> = logits.to_dtype(DType::F32)?.to_vec1()?;
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    // p >= min_p * p_max once exp(logit / temperature) is normalized
+    let threshold = max + (temperature * min_p.ln()) as f32;
+    for value in values.iter_mut() {
+        if *value < threshold {
+            *value = f32::NEG_INFINITY;
+        }
+    }
+
+    Ok(Tensor::new(values, logits.device())?.to_dtype(logits.dtype())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{cake::CancellationToken, test_utils};
+    use candle_core::Device;
+
+    /// Logits of tokens with the given probabilities.
+    fn logits(probs: &[f32]) -> Tensor {
+        let logits: Vec<f32> = probs.iter().map(|p| p.ln()).collect();
+        Tensor::new(logits, &Device::Cpu).unwrap()
+    }
+
+    fn args(temperature: Option<f64>, top_k: Option<usize>, top_p: Option<f64>) -> Args {
+        Args {
+            temperature,
+            top_k,
+            top_p,
+            seed: Some(42),
+            ..Default::default()
+        }
+    }
+
+    /// Samples n tokens with the sampler of args and returns how often each one was picked.
+    fn frequencies(args: &Args, logits: &Tensor, n: usize) -> Vec<f32> {
+        let mut sampler = create_sampler(args);
+        let mut counts = vec![0; logits.dim(0).unwrap()];
+        for _ in 0..n {
+            counts[sampler.sample(logits).unwrap() as usize] += 1;
+        }
+        counts.into_iter().map(|c| c as f32 / n as f32).collect()
+    }
+
+    #[test]
+    fn greedy_without_sampling_parameters() {
+        let logits = Tensor::new(&[1f32, 3., 2., -1.], &Device::Cpu).unwrap();
+        let mut sampler = create_sampler(&args(None, None, None));
+        for _ in 0..10 {
+            assert_eq!(sampler.sample(&logits).unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn temperature_scales_the_distribution() {
+        let logits = logits(&[0.75, 0.25]);
+
+        let freqs = frequencies(&args(Some(1.), None, None), &logits, 4000);
+        assert!((freqs[1] - 0.25).abs() < 0.03, "{freqs:?}");
+
+        // p^2 normalized: 0.9 and 0.1
+        let freqs = frequencies(&args(Some(0.5), None, None), &logits, 4000);
+        assert!((freqs[1] - 0.1).abs() < 0.03, "{freqs:?}");
+
+        // a temperature of zero is greedy
+        let freqs = frequencies(&args(Some(0.), None, None), &logits, 100);
+        assert_eq!(freqs, vec![1., 0.]);
+    }
+
+    #[test]
+    fn top_k_keeps_the_most_likely_tokens() {
+        let logits = logits(&[0.1, 0.4, 0.2, 0.3]);
+
+        let freqs = frequencies(&args(Some(1.), Some(1), None), &logits, 200);
+        assert_eq!(freqs, vec![0., 1., 0., 0.]);
+
+        // 0.4 and 0.3 renormalized
+        let freqs = frequencies(&args(Some(1.), Some(2), None), &logits, 4000);
+        assert_eq!((freqs[0], freqs[2]), (0., 0.));
+        assert!((freqs[1] - 4. / 7.).abs() < 0.03, "{freqs:?}");
+    }
+
+    #[test]
+    fn top_p_keeps_the_nucleus() {
+        let logits = logits(&[0.5, 0.3, 0.2]);
+
+        // 0.5 alone doesn't reach 0.6, 0.5 + 0.3 does
+        let freqs = frequencies(&args(Some(1.), None, Some(0.6)), &logits, 4000);
+        assert_eq!(freqs[2], 0.);
+        assert!((freqs[0] - 0.625).abs() < 0.03, "{freqs:?}");
+
+        let freqs = frequencies(&args(Some(1.), None, Some(0.4)), &logits, 200);
+        assert_eq!(freqs, vec![1., 0., 0.]);
+    }
+
+    #[test]
+    fn top_k_then_top_p() {
+        let logits = logits(&[0.1, 0.4, 0.2, 0.3]);
+
+        // 0.4 and 0.3 are kept by top-k, then 0.4 alone doesn't reach 0.6
+        let freqs = frequencies(&args(Some(1.), Some(2), Some(0.6)), &logits, 4000);
+        assert_eq!((freqs[0], freqs[2]), (0., 0.));
+        assert!(freqs[3] > 0.3, "{freqs:?}");
+
+        let freqs = frequencies(&args(Some(1.), Some(3), Some(0.4)), &logits, 200);
+        assert_eq!(freqs, vec![0., 1., 0., 0.]);
+    }
+
+    #[test]
+    fn sampling_probs_match_the_sampler() {
+        let logits = logits(&[0.1, 0.4, 0.2, 0.3]);
+
+        let probs = sampling_probs(&logits, &args(None, None, None)).unwrap();
+        assert_eq!(probs, vec![0., 1., 0., 0.]);
+
+        let probs = sampling_probs(&logits, &args(Some(1.), Some(2), None)).unwrap();
+        let expected = [0., 4. / 7., 0., 3. / 7.];
+        for (p, e) in probs.iter().zip(expected) {
+            assert!((p - e).abs() < 1e-5, "{probs:?}");
+        }
+
+        let probs = sampling_probs(&logits, &args(Some(1.), None, Some(0.6))).unwrap();
+        let expected = [0., 4. / 7., 0., 3. / 7.];
+        for (p, e) in probs.iter().zip(expected) {
+            assert!((p - e).abs() < 1e-5, "{probs:?}");
+        }
+    }
+
+    #[test]
+    fn sampling_probs_are_the_frequencies_of_the_draws() {
+        let logits = logits(&[0.3, 0.05, 0.25, 0.15, 0.2, 0.05]);
+        for (temperature, top_k, top_p, min_p) in [
+            (1., Some(4), Some(0.7), Some(0.2)),
+            (0.7, Some(3), None, Some(0.1)),
+            (1.3, None, Some(0.8), None),
+            (1., Some(6), Some(0.5), None),
+        ] {
+            let args = Args {
+                min_p,
+                ..args(Some(temperature), top_k, top_p)
+            };
+            let probs = sampling_probs(&logits, &args).unwrap();
+            assert!((probs.iter().sum::<f32>() - 1.).abs() < 1e-5, "{probs:?}");
+            let freqs = frequencies(&args, &apply_min_p(&logits, &args).unwrap(), 8000);
+            for (p, f) in probs.iter().zip(&freqs) {
+                assert!((p - f).abs() < 0.03, "{args:?}: {probs:?} {freqs:?}");
+                assert_eq!(*p == 0., *f == 0., "{probs:?} {freqs:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn top_k_of_zero_is_rejected() {
+        let args = test_utils::args(&["--top-k", "0", "--temperature", "1"]);
+        let Err(e) = crate::cake::Master::new(crate::cake::Context::from_args(args).unwrap()).await
+        else {
+            panic!("--top-k 0 accepted");
+        };
+        assert!(e.to_string().contains("--top-k must be at least 1"), "{e}");
+
+        // the filter keeps every token rather than none
+        let logits = logits(&[0.1, 0.4, 0.2, 0.3]);
+        let sampling = Sampling::TopK {
+            k: 0,
+            temperature: 1.,
+        };
+        let probs = utils::filtered_probs(&logits, &sampling).unwrap();
+        assert!(probs.iter().all(|p| *p > 0.), "{probs:?}");
+    }
+
+    #[test]
+    fn min_p_keeps_the_tokens_above_the_threshold() {
+        let logits = logits(&[0.5, 0.2, 0.1, 0.15, 0.05]);
+        let min_p = |min_p: Option<f64>, temperature: f64, top_k: Option<usize>| Args {
+            min_p,
+            ..args(Some(temperature), top_k, None)
+        };
+        // indices of the tokens that can still be sampled
+        let kept = |args: &Args| -> Vec<usize> {
+            let values: Vec<f32> = apply_min_p(&logits, args).unwrap().to_vec1().unwrap();
+            (0..values.len())
+                .filter(|&i| values[i].is_finite())
+                .collect()
+        };
+
+        // 0.25 * 0.5, 0.1 and 0.05 are below
+        let args = min_p(Some(0.25), 1., None);
+        assert_eq!(kept(&args), [0, 1, 3]);
+        let freqs = frequencies(&args, &apply_min_p(&logits, &args).unwrap(), 4000);
+        assert_eq!((freqs[2], freqs[4]), (0., 0.));
+        assert!((freqs[0] - 0.5 / 0.85).abs() < 0.03, "{freqs:?}");
+        let probs = sampling_probs(&logits, &args).unwrap();
+        for (p, e) in probs
+            .iter()
+            .zip([0.5 / 0.85, 0.2 / 0.85, 0., 0.15 / 0.85, 0.])
+        {
+            assert!((p - e).abs() < 1e-5, "{probs:?}");
+        }
+
+        // applied after the temperature, which flattens the distribution to sqrt(p)
+        assert_eq!(kept(&min_p(Some(0.25), 2., None)), [0, 1, 2, 3, 4]);
+        // then top-k among the remaining tokens
+        let args = min_p(Some(0.25), 1., Some(2));
+        let freqs = frequencies(&args, &apply_min_p(&logits, &args).unwrap(), 200);
+        assert_eq!((freqs[2], freqs[3], freqs[4]), (0., 0., 0.));
+        // a no-op when unset
+        assert_eq!(kept(&min_p(None, 1., None)), [0, 1, 2, 3, 4]);
+    }
+
+    /// Tokens generated from the prompt with the sampling parameters of extra.
+    async fn generate(extra: &[&str]) -> Vec<u32> {
+        let args = test_utils::args(extra);
+        let mut master = test_utils::master(args.clone()).await;
+        let prompt = master.encode("the cat sat on").unwrap();
+        let mut tokens = vec![];
+        master
+            .generate_with_events(&args, prompt, &CancellationToken::default(), |event| {
+                tokens.extend(event.token_id)
+            })
+            .await
+            .unwrap();
+        tokens
+    }
+
+    #[tokio::test]
+    async fn same_seed_same_tokens() {
+        let sampling = ["--temperature", "1.5", "--max-tokens", "16", "--ignore-eos"];
+        let with_seed = |seed: &'static str| [&sampling[..], &["--seed", seed]].concat();
+
+        let first = generate(&with_seed("42")).await;
+        assert_eq!(first.len(), 16);
+        assert_eq!(first, generate(&with_seed("42")).await);
+        assert_ne!(first, generate(&with_seed("43")).await);
+    }
+
+    #[test]
+    fn logprobs_of_the_sampled_distribution() {
+        let logits = logits(&[0.1, 0.4, 0.2, 0.3]);
+        let args = Args {
+            top_logprobs: 4,
+            ..args(Some(1.), None, None)
+        };
+        let logprob = logprob(&logits, 2, &args).unwrap();
+        assert_eq!(logprob.token, 2);
+        assert!((logprob.logprob - 0.2f32.ln()).abs() < 1e-5);
+
+        // the whole vocabulary sums to one, with the chosen token where the sampler puts it
+        let probs = sampling_probs(&logits, &args).unwrap();
+        let tokens: Vec<u32> = logprob.top.iter().map(|(token, _)| *token).collect();
+        assert_eq!(tokens, [1, 3, 2, 0]);
+        let sum: f32 = logprob.top.iter().map(|(_, lp)| lp.exp()).sum();
+        assert!((sum - 1.).abs() < 1e-5);
+        assert!((logprob.logprob.exp() - probs[2]).abs() < 1e-5);
+
+        // scaled by the temperature: p^2 normalized
+        let hot = logprob_of(&logits, 1, Some(0.5), 2);
+        assert!((hot.logprob.exp() - 0.16 / 0.3).abs() < 1e-5);
+        assert_eq!(hot.top.len(), 2);
+        assert_eq!(hot.top[0].0, 1);
+        assert!(hot.top[0].1 > hot.top[1].1);
+
+        // greedy decoding reports the unscaled distribution
+        let greedy = logprob_of(&logits, 1, None, 0);
+        assert!((greedy.logprob - 0.4f32.ln()).abs() < 1e-5);
+        assert!(greedy.top.is_empty());
+    }
+
+    fn logprob_of(
+        logits: &Tensor,
+        token: u32,
+        temperature: Option<f64>,
+        top_logprobs: usize,
+    ) -> TokenLogprob {
+        let args = Args {
+            top_logprobs,
+            ..args(temperature, None, None)
+        };
+        logprob(logits, token, &args).unwrap()
+    }
+}
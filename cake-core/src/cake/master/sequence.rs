@@ -0,0 +1,410 @@
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use super::{
+    sampling::{apply_logit_bias, apply_min_p, create_sampler, logprob},
+    FinishReason, TokenLogprob,
+};
+use crate::{
+    cake::CakeError,
+    utils::{GrammarState, Sampler, StopSequences, TokenOutputStream, TokenTrie},
+    Args,
+};
+
+use anyhow::Result;
+use candle_core::{DType, Tensor};
+use tokenizers::Tokenizer;
+
+/// Wall-clock time a generation is allowed, see --max-time-ms.
+pub(super) struct TimeBudget {
+    limit: Option<Duration>,
+    // when the budget started being spent, once the first token is generated unless the prompt
+    // processing counts
+    since: Option<Instant>,
+}
+
+impl TimeBudget {
+    pub(super) fn new(args: &Args) -> Self {
+        Self {
+            limit: args.max_time_ms.map(Duration::from_millis),
+            since: args.max_time_includes_prompt.then(Instant::now),
+        }
+    }
+
+    /// Starts spending the budget, unless it already is.
+    pub(super) fn start(&mut self) {
+        self.since.get_or_insert_with(Instant::now);
+    }
+
+    pub(super) fn exceeded(&self) -> bool {
+        match (self.limit, self.since) {
+            (Some(limit), Some(since)) => since.elapsed() >= limit,
+            _ => false,
+        }
+    }
+}
+
+/// Picks the next token of a sequence from its tokens and the logits of the next one, see
+/// Master::generate_logits.
+pub(super) type TokenChooser<'a> = dyn FnMut(&[u32], &Tensor) -> Result<u32> + Send + 'a;
+
+/// Generation state of a single sequence.
+pub(super) struct Sequence {
+    pub(super) tokens: Vec<u32>,
+    pub(super) prompt_len: usize,
+    pub(super) generated: usize,
+    // set once the sequence is over
+    pub(super) finish_reason: Option<FinishReason>,
+    tokenizer: TokenOutputStream,
+    sampler: Sampler,
+    stop: StopSequences,
+    // recorded for every generated token if set
+    pub(super) logprobs: Option<Vec<TokenLogprob>>,
+    // constrains the generated text if set
+    pub(super) grammar: Option<(GrammarState, Arc<TokenTrie>)>,
+}
+
+impl Sequence {
+    pub(super) fn new(tokens: Vec<u32>, tokenizer: Tokenizer, args: &Args) -> Self {
+        Self {
+            prompt_len: tokens.len(),
+            tokens,
+            generated: 0,
+            finish_reason: None,
+            tokenizer: TokenOutputStream::new(tokenizer),
+            sampler: create_sampler(args),
+            stop: StopSequences::new(args.stop.clone()),
+            logprobs: None,
+            grammar: None,
+        }
+    }
+
+    pub(super) fn finished(&self) -> bool {
+        self.finish_reason.is_some()
+    }
+
+    /// Checks that the logits of the token following the sequence extended with the pending
+    /// tokens are finite. With --sanitize-logits NaNs are replaced by -inf and infinities by the
+    /// largest finite values, otherwise the first non-finite logit fails the generation.
+    pub(super) fn check_logits(
+        &self,
+        logits: &Tensor,
+        args: &Args,
+        pending: &[u32],
+    ) -> Result<Tensor> {
+        let values: Vec<f32> = logits.to_dtype(DType::F32)?.to_vec1()?;
+        let Some(token) = values.iter().position(|value| !value.is_finite()) else {
+            return Ok(logits.clone());
+        };
+        let position = self.tokens.len() + pending.len();
+        if !args.sanitize_logits {
+            return Err(CakeError::NonFiniteLogits {
+                position,
+                token: token as u32,
+                value: values[token],
+            }
+            .into());
+        }
+
+        log::warn!(
+            "sanitizing {} non-finite logits at position {position}",
+            values.iter().filter(|value| !value.is_finite()).count()
+        );
+        let values: Vec<f32> = values
+            .into_iter()
+            .map(|value| {
+                if value.is_nan() {
+                    f32::NEG_INFINITY
+                } else {
+                    value.clamp(f32::MIN, f32::MAX)
+                }
+            })
+            .collect();
+        Ok(Tensor::new(values, logits.device())?.to_dtype(logits.dtype())?)
+    }
+
+    /// Applies the repeat penalty and the logit bias to the logits of the token following the
+    /// sequence extended with the pending tokens, once checked.
+    pub(super) fn adjust_logits(
+        &self,
+        logits: &Tensor,
+        args: &Args,
+        pending: &[u32],
+    ) -> Result<Tensor> {
+        let logits = &self.check_logits(logits, args, pending)?;
+        let logits = if args.repeat_penalty == 1. {
+            logits.clone()
+        } else {
+            let tokens: Vec<u32> = self.tokens.iter().chain(pending).copied().collect();
+            // unless asked otherwise, only penalize the generated tokens
+            let window_start = if args.repeat_penalty_prompt {
+                0
+            } else {
+                self.prompt_len
+            };
+            let start_at = tokens
+                .len()
+                .saturating_sub(args.repeat_last_n)
+                .max(window_start);
+            candle_transformers::utils::apply_repeat_penalty(
+                logits,
+                args.repeat_penalty,
+                &tokens[start_at..],
+            )?
+        };
+        // before the temperature, so that banned tokens are never sampled
+        apply_logit_bias(logits, &args.logit_bias)
+    }
+
+    /// Masks the logits of the tokens the grammar doesn't allow next, the end of sequence tokens
+    /// are only allowed once the text is complete.
+    fn apply_grammar(&self, logits: Tensor, eos_token_ids: &HashSet<u32>) -> Result<Tensor> {
+        let Some((state, trie)) = &self.grammar else {
+            return Ok(logits);
+        };
+
+        let mut allowed = state.allowed_tokens(trie);
+        if state.is_accepting() {
+            allowed.extend(eos_token_ids);
+        }
+        if allowed.is_empty() {
+            bail!("no token can continue the text according to the grammar");
+        }
+
+        let values: Vec<f32> = logits.to_dtype(DType::F32)?.to_vec1()?;
+        let mut masked = vec![f32::NEG_INFINITY; values.len()];
+        for token in allowed {
+            if let Some(value) = values.get(token as usize) {
+                masked[token as usize] = *value;
+            }
+        }
+
+        Ok(Tensor::new(masked, logits.device())?.to_dtype(logits.dtype())?)
+    }
+
+    /// Samples the next token from the logits and returns the text that can be streamed, if any.
+    pub(super) fn next(
+        &mut self,
+        logits: &Tensor,
+        args: &Args,
+        eos_token_ids: &HashSet<u32>,
+    ) -> Result<Option<String>> {
+        let logits = self.adjust_logits(logits, args, &[])?;
+        let logits = self.apply_grammar(logits, eos_token_ids)?;
+
+        let next_token = self.sampler.sample(&apply_min_p(&logits, args)?)?;
+        if let Some(logprobs) = &mut self.logprobs {
+            logprobs.push(logprob(&logits, next_token, args)?);
+        }
+        self.push(next_token, eos_token_ids)
+    }
+
+    /// Appends the token to the sequence and returns the text that can be streamed, if any.
+    pub(super) fn push(
+        &mut self,
+        next_token: u32,
+        eos_token_ids: &HashSet<u32>,
+    ) -> Result<Option<String>> {
+        self.tokens.push(next_token);
+        self.generated += 1;
+
+        if eos_token_ids.contains(&next_token) {
+            self.finish_reason = Some(FinishReason::Eos);
+            return Ok(None);
+        }
+
+        if let Some((state, trie)) = &mut self.grammar {
+            if !state.accept(trie.text(next_token).unwrap_or_default()) {
+                bail!("token {next_token} doesn't match the grammar");
+            }
+        }
+        // a complete grammar is handled as a stop sequence
+        if self
+            .grammar
+            .as_ref()
+            .is_some_and(|(state, _)| state.is_complete())
+        {
+            self.finish_reason = Some(FinishReason::Stop);
+        }
+
+        if let Some(t) = self.tokenizer.next_token(next_token)? {
+            let text = self.stop.push(&t);
+            if self.stop.matched() {
+                self.finish_reason = Some(FinishReason::Stop);
+            }
+            if !text.is_empty() {
+                return Ok(Some(text));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Returns whatever text is left to stream once the generation is over.
+    pub(super) fn finish(&mut self) -> Result<Option<String>> {
+        if self.stop.matched() {
+            return Ok(None);
+        }
+
+        let mut text = String::new();
+        if let Some(rest) = self.tokenizer.decode_rest().map_err(anyhow::Error::msg)? {
+            text += &self.stop.push(&rest);
+        }
+        text += &self.stop.finish();
+
+        Ok(if text.is_empty() { None } else { Some(text) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils;
+    use candle_core::Device;
+
+    /// Sequence of the prompt followed by the generated tokens.
+    fn sequence(prompt: &[u32], generated: &[u32], args: &Args) -> Sequence {
+        let mut sequence = Sequence::new(prompt.to_vec(), test_utils::tokenizer(), args);
+        for &token in generated {
+            sequence.push(token, &HashSet::new()).unwrap();
+        }
+        sequence
+    }
+
+    fn adjusted(sequence: &Sequence, logits: &[f32], args: &Args) -> Vec<f32> {
+        let logits = Tensor::new(logits, &Device::Cpu).unwrap();
+        sequence
+            .adjust_logits(&logits, args, &[])
+            .unwrap()
+            .to_vec1()
+            .unwrap()
+    }
+
+    #[test]
+    fn repeat_penalty_divides_the_logits_of_the_generated_tokens() {
+        let args = test_utils::args(&["--repeat-penalty", "2"]);
+        let logits = [2., 2., -2., 2., -2., 2., 2., 2.];
+        let sequence = sequence(&[3, 4], &[5, 4], &args);
+
+        // positive logits are divided, negative ones multiplied, the prompt is left as is
+        assert_eq!(
+            adjusted(&sequence, &logits, &args),
+            vec![2., 2., -2., 2., -4., 1., 2., 2.]
+        );
+    }
+
+    #[test]
+    fn repeat_penalty_window() {
+        let args = test_utils::args(&["--repeat-penalty", "2", "--repeat-last-n", "1"]);
+        let logits = [2.; 8];
+        let sequence = sequence(&[3], &[5, 6], &args);
+        assert_eq!(
+            adjusted(&sequence, &logits, &args),
+            vec![2., 2., 2., 2., 2., 2., 1., 2.]
+        );
+
+        // the pending tokens are part of the window
+        let logits = Tensor::new(&logits, &Device::Cpu).unwrap();
+        let adjusted: Vec<f32> = sequence
+            .adjust_logits(&logits, &args, &[7])
+            .unwrap()
+            .to_vec1()
+            .unwrap();
+        assert_eq!(adjusted, vec![2., 2., 2., 2., 2., 2., 2., 1.]);
+    }
+
+    #[test]
+    fn repeat_penalty_on_the_prompt() {
+        let args = test_utils::args(&["--repeat-penalty", "2", "--repeat-penalty-prompt"]);
+        let logits = [2.; 8];
+        let sequence = sequence(&[3, 1], &[5], &args);
+        assert_eq!(
+            adjusted(&sequence, &logits, &args),
+            vec![2., 1., 2., 1., 2., 1., 2., 2.]
+        );
+    }
+
+    #[test]
+    fn no_penalty_by_default() {
+        let args = test_utils::args(&[]);
+        let logits = [2., -1., 0.5, 3.];
+        let sequence = sequence(&[1, 3], &[3, 2], &args);
+        assert_eq!(adjusted(&sequence, &logits, &args), logits.to_vec());
+    }
+
+    #[test]
+    fn non_finite_logits_fail_unless_sanitized() {
+        let logits = Tensor::new(&[1., f32::NAN, 2., f32::INFINITY], &Device::Cpu).unwrap();
+        let args = test_utils::args(&[]);
+        let sequence = sequence(&[1, 3], &[5], &args);
+
+        let e = sequence.check_logits(&logits, &args, &[6]).unwrap_err();
+        match e.downcast_ref::<CakeError>() {
+            Some(CakeError::NonFiniteLogits {
+                position,
+                token,
+                value,
+            }) => {
+                // the first non-finite one, for the token after the pending ones
+                assert_eq!((*position, *token), (4, 1));
+                assert!(value.is_nan());
+            }
+            _ => panic!("{e:?}"),
+        }
+        // the repeat penalty and the logit bias are applied to checked logits only
+        assert!(sequence.adjust_logits(&logits, &args, &[]).is_err());
+
+        let args = test_utils::args(&["--sanitize-logits"]);
+        let sanitized: Vec<f32> = sequence
+            .check_logits(&logits, &args, &[])
+            .unwrap()
+            .to_vec1()
+            .unwrap();
+        assert_eq!(sanitized, [1., f32::NEG_INFINITY, 2., f32::MAX]);
+        assert_eq!(
+            adjusted(&sequence, &[1., f32::NAN], &args),
+            [1., f32::NEG_INFINITY]
+        );
+
+        // finite logits are left as they are
+        let finite = Tensor::new(&[1f32, -3.], &Device::Cpu).unwrap();
+        for args in [test_utils::args(&[]), args] {
+            let checked = sequence.check_logits(&finite, &args, &[]).unwrap();
+            assert_eq!(checked.to_vec1::<f32>().unwrap(), [1., -3.]);
+        }
+    }
+
+    #[test]
+    fn time_budget_starts_at_the_first_token() {
+        let budget = |includes_prompt: bool| {
+            TimeBudget::new(&Args {
+                max_time_ms: Some(50),
+                max_time_includes_prompt: includes_prompt,
+                ..Default::default()
+            })
+        };
+
+        // the prompt takes longer than the budget
+        let mut generation = budget(false);
+        let mut with_prompt = budget(true);
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!generation.exceeded());
+        assert!(with_prompt.exceeded());
+
+        generation.start();
+        with_prompt.start();
+        assert!(!generation.exceeded());
+        assert!(with_prompt.exceeded());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(generation.exceeded());
+
+        // unlimited without --max-time-ms
+        let mut unlimited = TimeBudget::new(&Args::default());
+        unlimited.start();
+        assert!(!unlimited.exceeded());
+    }
+}
@@ -0,0 +1,690 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt,
+    io::Write,
+};
+
+use super::{
+    generation::{token_events, Generation},
+    CancellationToken, FinishReason, Master, TokenEvent,
+};
+use crate::cake::{CakeError, ClientError};
+use crate::{model::Cache, Args};
+
+use anyhow::Result;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// Identifier of a session created with Master::create_session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionId(u64);
+
+impl SessionId {
+    /// Id the blocks keep the caches of the session under, see Llama::select_cache. The caches
+    /// of id 0 are the ones the connections start with.
+    fn cache_id(&self) -> u64 {
+        self.0 + 1
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Independent sequence of tokens, each generation in the session continues the previous ones.
+#[derive(Default)]
+pub(super) struct Session {
+    tokens: Vec<u32>,
+    // local caches of the session and the tokens they've been computed from, kept while other
+    // sessions are served, the workers keeping the remote ones
+    saved: Option<(Cache, Vec<u32>)>,
+}
+
+impl Session {
+    /// Discards the caches kept for the session, which are computed again by its next generation.
+    pub(super) fn forget_caches(&mut self) {
+        self.saved = None;
+    }
+}
+
+impl Master {
+    /// Creates an empty session, see generate_in.
+    pub fn create_session(&mut self) -> SessionId {
+        let session_id = SessionId(self.next_session_id);
+        self.next_session_id += 1;
+        self.sessions.insert(session_id, Session::default());
+        self.model
+            .metrics()
+            .set_active_sessions(self.sessions.len());
+
+        log::debug!("created session {session_id}");
+
+        session_id
+    }
+
+    /// Discards a session and the caches kept for it.
+    pub fn drop_session(&mut self, session_id: SessionId) -> Result<()> {
+        if self.sessions.remove(&session_id).is_none() {
+            bail!("unknown session {session_id}");
+        }
+        self.dropped_caches.push(session_id.cache_id());
+        self.model
+            .metrics()
+            .set_active_sessions(self.sessions.len());
+        if self.active_session == Some(session_id) {
+            // nothing will continue from the caches
+            self.active_session = None;
+            self.history.clear();
+        }
+
+        log::debug!("dropped session {session_id}");
+
+        Ok(())
+    }
+
+    /// Generates text from the prompt appended to the tokens of the session, prompted and
+    /// generated by the previous calls, with the sampling parameters the master has been started
+    /// with. Sessions only see their own tokens and keep their own caches, the master keeping the
+    /// ones of the local layers and the workers the ones of theirs, so that switching sessions
+    /// sends no cache over the network.
+    pub async fn generate_in<S>(
+        &mut self,
+        session_id: SessionId,
+        prompt: &str,
+        stream: S,
+    ) -> Result<FinishReason, CakeError>
+    where
+        S: FnMut(&str),
+    {
+        let tokens = self.session_prompt(session_id, prompt)?;
+        let args = self.ctx.args.clone();
+        Ok(self
+            .generate_in_session(session_id, &args, tokens, stream)
+            .await?)
+    }
+
+    /// Generates text in several sessions at once, from the prompt of each request appended to
+    /// the tokens of its session like generate_in, and returns why each generation stopped. The
+    /// stream gets the id of the session along with its text, then an empty text once its
+    /// generation is over.
+    ///
+    /// The generations advance in turns, round-robin: each turn runs a single forward pass for
+    /// every generation still in progress, in the order of the requests, which processes up to
+    /// --prefill-chunk-size tokens of its prompt or generates its next token. A generation that
+    /// is decoding therefore waits for at most one chunk of the prompt of each other request
+    /// between two of its tokens, however long the prompts are, and every generation advances at
+    /// every turn. Each turn selects the caches of its session, see generate_in.
+    pub async fn generate_sessions<S>(
+        &mut self,
+        requests: Vec<(SessionId, String)>,
+        mut stream: S,
+    ) -> Result<Vec<FinishReason>, CakeError>
+    where
+        S: FnMut(SessionId, &str),
+    {
+        Ok(self
+            .generate_sessions_in_turns(requests, &mut stream)
+            .await?)
+    }
+
+    async fn generate_sessions_in_turns<S>(
+        &mut self,
+        requests: Vec<(SessionId, String)>,
+        stream: &mut S,
+    ) -> Result<Vec<FinishReason>>
+    where
+        S: FnMut(SessionId, &str),
+    {
+        if !self.ctx.cache.use_kv_cache {
+            bail!("generating in several sessions at once needs the key-value cache");
+        }
+        let mut session_ids = HashSet::new();
+        if let Some((session_id, _)) = requests.iter().find(|(id, _)| !session_ids.insert(*id)) {
+            bail!("session {session_id} has several requests");
+        }
+
+        let args = self.ctx.args.clone();
+        let chunk_size = args.prefill_chunk_size.max(1);
+        // the generations of the sessions aren't cancelled
+        let cancel = CancellationToken::default();
+        let metrics = self.model.metrics().clone();
+
+        // the generations in progress, in turn order, with their index in the requests
+        let mut turns = VecDeque::new();
+        for (i, (session_id, prompt)) in requests.into_iter().enumerate() {
+            let tokens = self.session_prompt(session_id, &prompt)?;
+            self.activate_session(session_id).await?;
+            let generation = self
+                .start_generation(&args, vec![tokens], false, Some(chunk_size))
+                .await?;
+            self.record_turn(session_id, &generation);
+            turns.push_back((i, session_id, generation));
+        }
+        let mut finish_reasons = vec![FinishReason::Length; turns.len()];
+
+        while let Some((i, session_id, mut generation)) = turns.pop_front() {
+            self.activate_session(session_id).await?;
+            // the caches of the session have been lost since its previous turn
+            if self.history != generation.cached_tokens() {
+                generation.restart();
+            }
+
+            let mut events = token_events(metrics.clone(), &cancel, |_, event: &TokenEvent| {
+                if let Some(text) = event.streamed_text() {
+                    stream(session_id, text)
+                }
+            });
+            self.step_generation(&mut generation, &args, &cancel, None, None, &mut events)
+                .await?;
+
+            if generation.done {
+                let sequence = self
+                    .finish_generation(generation, &cancel, &mut events)
+                    .await?
+                    .remove(0);
+                finish_reasons[i] = sequence.finish_reason.unwrap_or(FinishReason::Length);
+                if let Some(session) = self.sessions.get_mut(&session_id) {
+                    session.tokens = sequence.tokens;
+                }
+            } else {
+                self.record_turn(session_id, &generation);
+                turns.push_back((i, session_id, generation));
+            }
+        }
+
+        Ok(finish_reasons)
+    }
+
+    /// Records the tokens of a generation in progress in its session, and the ones its caches
+    /// hold so that they're kept for it while the other sessions are served.
+    fn record_turn(&mut self, session_id: SessionId, generation: &Generation) {
+        self.history = generation.cached_tokens().to_vec();
+        if let Some(session) = self.sessions.get_mut(&session_id) {
+            session.tokens = generation.sequences[0].tokens.clone();
+        }
+    }
+
+    /// Returns the tokens of the session followed by the ones of the prompt.
+    fn session_prompt(&self, session_id: SessionId, prompt: &str) -> Result<Vec<u32>> {
+        let mut tokens = self.session_tokens(session_id)?;
+        // only the beginning of the session starts with the bos token
+        let bos = tokens.is_empty();
+        tokens.extend(self.encode_with(prompt, bos)?);
+        Ok(tokens)
+    }
+
+    /// Same as generate_in, formatting the message as the next user turn of a chat conversation
+    /// and generating the reply of the assistant. The first turn of the session starts with the
+    /// system prompt the master has been started with, if any.
+    pub async fn chat_in<S>(
+        &mut self,
+        session_id: SessionId,
+        message: &str,
+        stream: S,
+    ) -> Result<FinishReason, CakeError>
+    where
+        S: FnMut(&str),
+    {
+        let mut tokens = self.session_tokens(session_id)?;
+        let chat_tokens = self.chat_tokens()?;
+        let prompt = if tokens.is_empty() {
+            chat_tokens.build_chat_prompt(self.ctx.args.system.as_deref(), message)
+        } else {
+            // replies cut by the token limit or a stop sequence aren't terminated yet
+            let eot = self.tokenizer.token_to_id(&chat_tokens.eot);
+            if tokens.last().copied() != eot {
+                tokens.extend(eot);
+            }
+            chat_tokens.build_next_turn(message)
+        };
+        tokens.extend(self.encode_chat_prompt(prompt)?);
+
+        let args = Args {
+            chat: true,
+            ..self.ctx.args.clone()
+        };
+        Ok(self
+            .generate_in_session(session_id, &args, tokens, stream)
+            .await?)
+    }
+
+    /// Reads the user turns from input, a line each, and writes the replies to output until
+    /// /exit or the end of the input. The conversation is kept by a session so that every turn
+    /// only processes its own tokens, /reset starts a new one.
+    pub async fn interactive<R, W>(&mut self, input: R, output: W) -> Result<(), CakeError>
+    where
+        R: AsyncBufRead + Unpin,
+        W: Write,
+    {
+        Ok(self.chat_turns(input, output).await?)
+    }
+
+    async fn chat_turns<R, W>(&mut self, input: R, mut output: W) -> Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: Write,
+    {
+        let mut lines = input.lines();
+        let mut session_id = self.create_session();
+
+        loop {
+            write!(output, "> ")?;
+            output.flush()?;
+
+            let Some(line) = lines.next_line().await? else {
+                break;
+            };
+            match line.trim() {
+                "" => continue,
+                "/exit" => break,
+                "/reset" => {
+                    self.drop_session(session_id)?;
+                    session_id = self.create_session();
+                }
+                message => {
+                    let mut written = Ok(());
+                    self.chat_in(session_id, message, |data| {
+                        if written.is_ok() {
+                            written = if data.is_empty() {
+                                writeln!(output)
+                            } else {
+                                write!(output, "{data}")
+                            }
+                            .and_then(|_| output.flush());
+                        }
+                    })
+                    .await?;
+                    written?;
+                }
+            }
+        }
+
+        self.drop_session(session_id)
+    }
+
+    fn session_tokens(&self, session_id: SessionId) -> Result<Vec<u32>> {
+        self.sessions
+            .get(&session_id)
+            .map(|session| session.tokens.clone())
+            .ok_or_else(|| anyhow!("unknown session {session_id}"))
+    }
+
+    /// Generates from the tokens of the session followed by the new ones, and records the
+    /// generated tokens in the session.
+    async fn generate_in_session<S>(
+        &mut self,
+        session_id: SessionId,
+        args: &Args,
+        tokens: Vec<u32>,
+        mut stream: S,
+    ) -> Result<FinishReason>
+    where
+        S: FnMut(&str),
+    {
+        self.activate_session(session_id).await?;
+
+        let mut sequences = self
+            .generate_sequences(
+                args,
+                vec![tokens],
+                false,
+                &CancellationToken::default(),
+                |_, event| {
+                    if let Some(text) = event.streamed_text() {
+                        stream(text)
+                    }
+                },
+            )
+            .await?;
+        let sequence = sequences.remove(0);
+        if let Some(session) = self.sessions.get_mut(&session_id) {
+            session.tokens = sequence.tokens;
+        }
+
+        Ok(sequence.finish_reason.unwrap_or(FinishReason::Length))
+    }
+
+    /// Keeps the caches of the active session for it and switches to the ones of session_id,
+    /// which start empty if it has none: the master swaps its local caches and the workers the
+    /// ones they keep for every session.
+    async fn activate_session(&mut self, session_id: SessionId) -> Result<()> {
+        if self.active_session == Some(session_id) {
+            return self.drop_caches().await;
+        }
+
+        let cache = self.ctx.cache.as_new();
+        let cache = std::mem::replace(&mut self.ctx.cache, cache);
+        let history = std::mem::take(&mut self.history);
+        if let Some(session) = self
+            .active_session
+            .take()
+            .and_then(|active| self.sessions.get_mut(&active))
+        {
+            // other generations may have replaced the caches of the session since
+            if !history.is_empty() && session.tokens.starts_with(&history) {
+                session.saved = Some((cache, history));
+            }
+        }
+
+        let mut saved = self
+            .sessions
+            .get_mut(&session_id)
+            .and_then(|session| session.saved.take());
+        match self.model.select_cache(session_id.cache_id()).await {
+            Ok(()) => {}
+            Err(e) if matches!(e.downcast_ref(), Some(ClientError::CacheLost { .. })) => {
+                log::warn!("{e}, the caches of session {session_id} are computed again");
+                saved = None;
+            }
+            Err(e) => return Err(e),
+        }
+        self.drop_caches().await?;
+
+        match saved {
+            Some((cache, history)) => {
+                log::debug!("restoring the caches of session {session_id}");
+
+                self.ctx.cache = cache;
+                self.history = history;
+            }
+            None => self.reset().await?,
+        }
+        self.active_session = Some(session_id);
+
+        Ok(())
+    }
+
+    /// Discards the caches the blocks keep for the dropped sessions.
+    async fn drop_caches(&mut self) -> Result<()> {
+        for id in std::mem::take(&mut self.dropped_caches) {
+            self.model.drop_cache(id).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::FinishReason;
+    use crate::{
+        cake::{Master, Message},
+        model::HookKind,
+        test_utils::{self, Reply},
+    };
+
+    #[tokio::test]
+    async fn interactive_turns_continue_the_conversation() {
+        let args = test_utils::args_for(test_utils::chat_model_dir(), &["--max-tokens", "3"]);
+        let mut master = test_utils::master(args).await;
+        // positions processed by every forward pass
+        let lens = Arc::new(Mutex::new(vec![]));
+        let recorded = lens.clone();
+        master
+            .add_hook(
+                0,
+                HookKind::Residual,
+                Box::new(move |x| {
+                    recorded.lock().unwrap().push(x.dim(1)?);
+                    Ok(())
+                }),
+            )
+            .unwrap();
+
+        let input = "the cat sat\n\nthe dog ran\n/reset\nthe cat sat\n/exit\nignored\n";
+        let mut output = vec![];
+        master
+            .interactive(input.as_bytes(), &mut output)
+            .await
+            .unwrap();
+
+        // the first turn and the one after the reset process the whole conversation, the second
+        // one its own tokens only: the end of turn of the previous reply and the new turn
+        let lens = lens.lock().unwrap().clone();
+        let prefills: Vec<usize> = lens.iter().copied().filter(|len| *len > 1).collect();
+        let first = master.encode_chat_prompt(
+            master
+                .chat_tokens()
+                .unwrap()
+                .build_chat_prompt(None, "the cat sat"),
+        );
+        let next =
+            master.encode_chat_prompt(master.chat_tokens().unwrap().build_next_turn("the dog ran"));
+        let (first, next) = (first.unwrap().len(), next.unwrap().len());
+        assert_eq!(prefills, [first, 1 + 1 + next, first]);
+        assert_eq!(lens.len(), 3 * 3);
+
+        let output = String::from_utf8(output).unwrap();
+        let replies: Vec<&str> = output.split("> ").filter(|r| !r.is_empty()).collect();
+        assert_eq!(replies.len(), 3, "{output}");
+        assert_eq!(replies[0], replies[2]);
+        assert!(
+            replies[0].ends_with('\n') && replies[0].len() > 1,
+            "{output}"
+        );
+        assert!(master.sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn decoding_session_isnt_held_up_by_a_long_prompt() {
+        let args = test_utils::args(&[
+            "--max-tokens",
+            "6",
+            "--ignore-eos",
+            "--prefill-chunk-size",
+            "8",
+        ]);
+        let mut master = test_utils::master(args).await;
+        let long_prompt = test_utils::WORDS[3..].repeat(4).join(" ");
+        assert_eq!(master.encode(&long_prompt).unwrap().len(), 1 + 29 * 4);
+
+        // what each session generates on its own
+        let mut expected = vec![];
+        for prompt in [long_prompt.as_str(), "the cat sat"] {
+            let session_id = master.create_session();
+            let mut text = String::new();
+            master
+                .generate_in(session_id, prompt, |t| text.push_str(t))
+                .await
+                .unwrap();
+            master.drop_session(session_id).unwrap();
+            expected.push(text);
+        }
+
+        let lens = Arc::new(Mutex::new(vec![]));
+        let recorded = lens.clone();
+        master
+            .add_hook(
+                0,
+                HookKind::Residual,
+                Box::new(move |x| {
+                    recorded.lock().unwrap().push(x.dim(1)?);
+                    Ok(())
+                }),
+            )
+            .unwrap();
+
+        let (prefilling, decoding) = (master.create_session(), master.create_session());
+        let mut events = vec![];
+        let finish_reasons = master
+            .generate_sessions(
+                vec![
+                    (prefilling, long_prompt.clone()),
+                    (decoding, "the cat sat".to_string()),
+                ],
+                |session_id, text| events.push((session_id, text.to_string())),
+            )
+            .await
+            .unwrap();
+        assert_eq!(finish_reasons, [FinishReason::Length; 2]);
+
+        // a chunk of the long prompt, then a single position of the other session, turn by turn
+        let lens = lens.lock().unwrap().clone();
+        assert_eq!(lens[..4], [8, 4, 8, 1]);
+        assert!(lens.iter().all(|len| *len <= 8));
+        assert_eq!(lens.iter().filter(|len| **len == 8).count(), 117 / 8);
+
+        // the other session is done before the long prompt is even processed
+        let first = events.iter().position(|(id, _)| *id == prefilling).unwrap();
+        assert!(events[..first].iter().all(|(id, _)| *id == decoding));
+        let text = |session_id| -> String {
+            events
+                .iter()
+                .filter(|(id, _)| *id == session_id)
+                .map(|(_, text)| text.as_str())
+                .collect()
+        };
+        assert_eq!(text(decoding), expected[1]);
+        let before: String = events[..first]
+            .iter()
+            .map(|(_, text)| text.as_str())
+            .collect();
+        assert_eq!(before, expected[1]);
+        assert_eq!(text(prefilling), expected[0]);
+    }
+    /// Generates from the prompts in sessions of their own, one after the other, then from the
+    /// next prompts in the same sessions.
+    async fn alone(master: &mut Master, prompts: &[&str], next: &str) -> Vec<[String; 2]> {
+        let mut texts = vec![];
+        for prompt in prompts {
+            let session_id = master.create_session();
+            let mut text = [String::new(), String::new()];
+            for (text, prompt) in text.iter_mut().zip([*prompt, next]) {
+                master
+                    .generate_in(session_id, prompt, |t| text.push_str(t))
+                    .await
+                    .unwrap();
+            }
+            master.drop_session(session_id).unwrap();
+            texts.push(text);
+        }
+        texts
+    }
+
+    #[tokio::test]
+    async fn sessions_in_turns_keep_their_caches_on_the_worker() {
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "sessions.yml",
+            &format!("sessions-worker: {{ host: '{address}', layers: [2, 3] }}"),
+        );
+        let _worker = test_utils::worker("sessions-worker", &topology, &[]).await;
+        let mut args = test_utils::args(&[
+            "--max-tokens",
+            "5",
+            "--ignore-eos",
+            "--prefill-chunk-size",
+            "3",
+        ]);
+        args.topology = topology;
+        let mut master = test_utils::master(args).await;
+        let prompts = ["the big red cat sat on the mat", "a dog ran"];
+        let expected = alone(&mut master, &prompts, " and the").await;
+
+        let session_ids = [master.create_session(), master.create_session()];
+        let mut texts = [String::new(), String::new()];
+        let requests = session_ids
+            .iter()
+            .zip(prompts)
+            .map(|(id, prompt)| (*id, prompt.to_string()))
+            .collect();
+        master
+            .generate_sessions(requests, |session_id, text| {
+                let i = session_ids.iter().position(|id| *id == session_id).unwrap();
+                texts[i].push_str(text);
+            })
+            .await
+            .unwrap();
+        assert_eq!(texts, [expected[0][0].clone(), expected[1][0].clone()]);
+
+        // the worker kept the caches of both sessions, which continue their own tokens
+        let lens = Arc::new(Mutex::new(vec![]));
+        let recorded = lens.clone();
+        master
+            .add_hook(
+                0,
+                HookKind::Residual,
+                Box::new(move |x| {
+                    recorded.lock().unwrap().push(x.dim(1)?);
+                    Ok(())
+                }),
+            )
+            .unwrap();
+        for (i, session_id) in session_ids.into_iter().enumerate().rev() {
+            let mut text = String::new();
+            master
+                .generate_in(session_id, " and the", |t| text.push_str(t))
+                .await
+                .unwrap();
+            assert_eq!(text, expected[i][1]);
+        }
+        // the last tokens generated in turns, then the new ones
+        let lens = lens.lock().unwrap().clone();
+        let prefills: Vec<usize> = lens.into_iter().filter(|len| *len > 1).collect();
+        assert_eq!(prefills, [1 + 2, 1 + 2]);
+    }
+
+    #[tokio::test]
+    async fn switching_sessions_sends_no_cache() {
+        // the worker serves the last two layers as the identity, and records the other messages
+        let received = Arc::new(Mutex::new(vec![]));
+        let worker = test_utils::MockWorker::start({
+            let received = received.clone();
+            move |connection, msg| match msg {
+                Message::Batch { x, .. } => Reply::Message(Message::Tensor(x)),
+                msg => {
+                    received.lock().unwrap().push((connection, msg));
+                    Reply::Nothing
+                }
+            }
+        })
+        .await;
+        let topology = test_utils::topology(
+            "sessions-mock.yml",
+            &format!("mock: {{ host: '{}', layers: [2, 3] }}", worker.address),
+        );
+        let mut args = test_utils::args(&["--max-tokens", "4", "--ignore-eos"]);
+        args.topology = topology;
+        let mut master = test_utils::master(args).await;
+
+        let session_ids = [master.create_session(), master.create_session()];
+        master
+            .generate_sessions(
+                session_ids
+                    .iter()
+                    .map(|id| (*id, "the cat sat".to_string()))
+                    .collect(),
+                |_, _| (),
+            )
+            .await
+            .unwrap();
+        master.drop_session(session_ids[0]).unwrap();
+        master
+            .generate_in(session_ids[1], " on", |_| ())
+            .await
+            .unwrap();
+
+        let received = received.lock().unwrap();
+        assert!(!received
+            .iter()
+            .any(|(_, msg)| matches!(msg, Message::GetCache { .. } | Message::SetCache(_))));
+        // every turn of the sessions switches the caches of the connection of each layer, the
+        // ones of the first session being dropped at the next generation
+        let switches: Vec<(&str, u64)> = received
+            .iter()
+            .filter(|(connection, _)| *connection == 0)
+            .filter_map(|(_, msg)| match msg {
+                Message::SelectCache { id } => Some(("select", *id)),
+                Message::DropCache { id } => Some(("drop", *id)),
+                _ => None,
+            })
+            .collect();
+        let (first, second) = (session_ids[0].cache_id(), session_ids[1].cache_id());
+        let mut expected = vec![("select", first), ("select", second)];
+        expected.extend([("select", first), ("select", second)].repeat(4));
+        expected.push(("drop", first));
+        assert_eq!(switches, expected);
+    }
+}
@@ -0,0 +1,286 @@
+use std::{collections::HashSet, path::Path};
+
+use super::{
+    generation::Pace,
+    sampling::{logprob, sampling_probs, sampling_seed},
+    sequence::{Sequence, TimeBudget},
+    CancellationToken, FinishReason, Master,
+};
+use crate::{
+    cake::{ConnectionOptions, Context, Topology},
+    model::{Cache, Llama},
+    utils::sample_with,
+    Args,
+};
+
+use anyhow::Result;
+use candle_core::Tensor;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+/// Small local model drafting tokens for speculative decoding.
+pub(super) struct Draft {
+    model: Llama,
+    cache: Cache,
+}
+
+impl Master {
+    pub(super) async fn load_draft(ctx: &Context, path: &str) -> Result<Draft> {
+        log::info!("loading draft model from {path}");
+
+        let (config, cache, var_builder) = ctx.load_local_model(Path::new(path))?;
+        if config.vocab_size != ctx.config.vocab_size {
+            bail!(
+                "the draft model has {} tokens, the model has {}",
+                config.vocab_size,
+                ctx.config.vocab_size
+            );
+        }
+
+        // every block of the draft model is local
+        let model = Llama::load(
+            &var_builder,
+            &config,
+            &ctx.device,
+            &Topology::default(),
+            &ConnectionOptions::from_args(&ctx.args)?,
+        )
+        .await?;
+
+        Ok(Draft { model, cache })
+    }
+
+    /// Generates the sequence with speculative decoding and returns the number of tokens
+    /// processed by the model. The draft model proposes up to args.draft_tokens tokens that the
+    /// model verifies with a single forward pass. Each draft d is accepted with probability
+    /// min(1, p(d) / q(d)), p and q being the sampling distributions of the model and of the
+    /// draft, and the first rejected one is replaced by a token sampled from max(0, p - q), so
+    /// that the tokens follow the distribution they'd be sampled from without the draft model.
+    /// With greedy decoding the tokens are the same.
+    #[allow(clippy::too_many_arguments)]
+    pub(super) async fn speculate<S>(
+        &mut self,
+        sequence: &mut Sequence,
+        args: &Args,
+        start_pos: usize,
+        max_tokens: usize,
+        eos_token_ids: &HashSet<u32>,
+        cancel: &CancellationToken,
+        pace: Option<&Pace>,
+        budget: &mut TimeBudget,
+        stream: &mut S,
+    ) -> Result<usize>
+    where
+        S: FnMut(usize, &Sequence, bool, &str),
+    {
+        let draft = self.draft.as_mut().unwrap();
+        let device = &self.ctx.device;
+        let context_size = self.ctx.config.context_size();
+        let mut rng = ChaCha20Rng::seed_from_u64(sampling_seed(args));
+
+        // the draft caches are rebuilt for every sequence
+        draft.cache = draft.cache.as_new();
+        draft.model.reset(&[]).await?;
+
+        // tokens processed by the model and by the draft model
+        let mut index_pos = start_pos;
+        let mut draft_pos = 0;
+        let (mut proposed, mut accepted) = (0, 0);
+
+        while !sequence.finished() && sequence.generated < max_tokens && !cancel.is_cancelled() {
+            if let Some(pace) = pace {
+                pace.wait().await;
+            }
+
+            // the drafts and the token sampled after them must fit in the context
+            let num_drafts = args
+                .draft_tokens
+                .min(max_tokens - sequence.generated - 1)
+                .min(context_size.saturating_sub(sequence.tokens.len()));
+
+            let mut drafts = vec![];
+            let mut draft_probs = vec![];
+            for _ in 0..num_drafts {
+                let input: Vec<u32> = sequence
+                    .tokens
+                    .iter()
+                    .chain(&drafts)
+                    .skip(draft_pos)
+                    .copied()
+                    .collect();
+                let len = input.len();
+                let input = Tensor::new(input, device)?.unsqueeze(0)?;
+                let logits = draft
+                    .model
+                    .forward(&input, draft_pos, &mut draft.cache)
+                    .await?
+                    .squeeze(0)?;
+                draft_pos += len;
+
+                let logits = sequence.adjust_logits(&logits, args, &drafts)?;
+                let probs = sampling_probs(&logits, args)?;
+                drafts.push(sample_with(&probs, &mut rng)?);
+                draft_probs.push(probs);
+            }
+            proposed += num_drafts;
+
+            // a single forward pass returns the logits following the sequence and every draft
+            let input: Vec<u32> = sequence.tokens[index_pos..]
+                .iter()
+                .chain(&drafts)
+                .copied()
+                .collect();
+            let offset = sequence.tokens.len() - index_pos - 1;
+            let input = Tensor::new(input, device)?.unsqueeze(0)?;
+            let logits = self
+                .model
+                .forward_all(&input, index_pos, &mut self.ctx.cache)
+                .await?
+                .squeeze(0)?;
+
+            for idx in 0..=num_drafts {
+                let logits = sequence.adjust_logits(&logits.get(offset + idx)?, args, &[])?;
+                let probs = sampling_probs(&logits, args)?;
+
+                let (token, rejected) = match (drafts.get(idx), draft_probs.get(idx)) {
+                    (Some(&token), Some(q)) => {
+                        let (token, rejected) = verify(token, &probs, q, &mut rng)?;
+                        if !rejected {
+                            accepted += 1;
+                        }
+                        (token, rejected)
+                    }
+                    // every draft has been accepted
+                    _ => (sample_with(&probs, &mut rng)?, false),
+                };
+
+                if let Some(logprobs) = &mut sequence.logprobs {
+                    logprobs.push(logprob(&logits, token, args)?);
+                }
+                let text = sequence.push(token, eos_token_ids)?;
+                stream(0, sequence, true, &text.unwrap_or_default());
+                if rejected || sequence.finished() {
+                    break;
+                }
+            }
+
+            // the cache entries of the discarded tokens are overwritten by the next pass
+            index_pos = sequence.tokens.len() - 1;
+            draft_pos = draft_pos.min(index_pos);
+
+            budget.start();
+            if budget.exceeded() && !sequence.finished() {
+                sequence.finish_reason = Some(FinishReason::TimeLimit);
+            }
+        }
+
+        log::info!("{accepted}/{proposed} draft tokens accepted");
+
+        Ok(index_pos)
+    }
+}
+
+/// Accepts the draft with probability min(1, p(draft) / q(draft)), or returns a token sampled
+/// from max(0, p - q) along with true.
+fn verify(draft: u32, p: &[f32], q: &[f32], rng: &mut ChaCha20Rng) -> Result<(u32, bool)> {
+    let t = draft as usize;
+    if rng.gen::<f32>() < p[t] / q[t] {
+        return Ok((draft, false));
+    }
+
+    let mut residual: Vec<f32> = p.iter().zip(q).map(|(p, q)| (p - q).max(0.)).collect();
+    if residual.iter().sum::<f32>() <= 0. {
+        residual = p.to_vec();
+    }
+    Ok((sample_with(&residual, rng)?, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{cake::CancellationToken, test_utils};
+
+    /// Text generated from the prompt by a master of the arguments.
+    async fn generate(args: &Args, prompt: &str) -> String {
+        let mut master = test_utils::master(args.clone()).await;
+        let tokens = master.encode(prompt).unwrap();
+        let mut text = String::new();
+        master
+            .generate_with(args, tokens, &CancellationToken::default(), |t| {
+                text.push_str(t)
+            })
+            .await
+            .unwrap();
+        text
+    }
+
+    #[test]
+    fn verified_drafts_follow_the_model_distribution() {
+        let p = [0.1, 0.4, 0.3, 0.2];
+        let q = [0.4, 0.1, 0.25, 0.25];
+        let mut rng = ChaCha20Rng::seed_from_u64(7);
+
+        let trials = 50_000;
+        let mut counts = [0; 4];
+        for _ in 0..trials {
+            let draft = sample_with(&q, &mut rng).unwrap();
+            let (token, _) = verify(draft, &p, &q, &mut rng).unwrap();
+            counts[token as usize] += 1;
+        }
+
+        for (count, p) in counts.iter().zip(p) {
+            let freq = *count as f32 / trials as f32;
+            assert!((freq - p).abs() < 0.01, "{counts:?}");
+        }
+    }
+
+    #[test]
+    fn drafts_of_the_model_distribution_are_accepted() {
+        let p = [0.1, 0.4, 0.3, 0.2];
+        let mut rng = ChaCha20Rng::seed_from_u64(7);
+        for _ in 0..1000 {
+            let draft = sample_with(&p, &mut rng).unwrap();
+            assert_eq!(verify(draft, &p, &p, &mut rng).unwrap(), (draft, false));
+        }
+    }
+
+    #[tokio::test]
+    async fn greedy_tokens_are_the_same_with_a_draft_model() {
+        // the weights depend on the shapes: a draft model of another size predicts other tokens
+        let other = test_utils::model_dir_with("draft", serde_json::json!({"hidden_size": 32}));
+        let args = test_utils::args(&["--max-tokens", "12", "--ignore-eos"]);
+        let expected = generate(&args, "the cat sat").await;
+
+        for draft in [test_utils::model_dir(), other.as_path()] {
+            let args = Args {
+                draft_model: Some(draft.display().to_string()),
+                draft_tokens: 3,
+                ..args.clone()
+            };
+            assert_eq!(generate(&args, "the cat sat").await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn sampled_tokens_are_reproducible_with_a_seed() {
+        let other =
+            test_utils::model_dir_with("draft-seed", serde_json::json!({"hidden_size": 32}));
+        let args = test_utils::args(&[
+            "--max-tokens",
+            "12",
+            "--ignore-eos",
+            "--temperature",
+            "1.0",
+            "--seed",
+            "42",
+            "--draft-tokens",
+            "3",
+            "--draft-model",
+            other.to_str().unwrap(),
+        ]);
+        let text = generate(&args, "the cat sat").await;
+        assert!(!text.is_empty());
+        assert_eq!(generate(&args, "the cat sat").await, text);
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use axum::{routing::get, Router};
+
+/// Upper bounds of the forward latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5.,
+];
+
+/// Forward requests of a node, either the address of a worker or "local".
+#[derive(Debug)]
+struct NodeMetrics {
+    node: String,
+    in_flight: u64,
+    // observations of every bucket, not cumulative
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// Counters of the master, always collected and exported in the Prometheus text format by the
+/// endpoint started with --metrics-addr.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    generated_tokens: AtomicU64,
+    active_sessions: AtomicU64,
+    nodes: Mutex<Vec<NodeMetrics>>,
+}
+
+impl Metrics {
+    pub fn add_generated_tokens(&self, tokens: u64) {
+        self.generated_tokens.fetch_add(tokens, Ordering::Relaxed);
+    }
+
+    pub fn set_active_sessions(&self, sessions: usize) {
+        self.active_sessions
+            .store(sessions as u64, Ordering::Relaxed);
+    }
+
+    fn with_node<F: FnOnce(&mut NodeMetrics)>(&self, node: &str, f: F) {
+        let mut nodes = self.nodes.lock().unwrap();
+        let idx = match nodes.iter().position(|n| n.node == node) {
+            Some(idx) => idx,
+            None => {
+                nodes.push(NodeMetrics {
+                    node: node.to_string(),
+                    in_flight: 0,
+                    buckets: [0; LATENCY_BUCKETS.len()],
+                    count: 0,
+                    sum: 0.,
+                });
+                nodes.len() - 1
+            }
+        };
+        f(&mut nodes[idx]);
+    }
+
+    /// Counts a forward request to the node as in flight until the returned guard is dropped,
+    /// which records its latency.
+    pub fn forward(&self, node: &str) -> InFlight<'_> {
+        self.with_node(node, |n| n.in_flight += 1);
+        InFlight {
+            metrics: self,
+            node: node.to_string(),
+            start: Instant::now(),
+        }
+    }
+
+    fn observe(&self, node: &str, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        self.with_node(node, |n| {
+            n.in_flight = n.in_flight.saturating_sub(1);
+            if let Some(bucket) = LATENCY_BUCKETS.iter().position(|le| secs <= *le) {
+                n.buckets[bucket] += 1;
+            }
+            n.count += 1;
+            n.sum += secs;
+        });
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "# HELP cake_generated_tokens_total Tokens generated since the master started."
+        );
+        let _ = writeln!(out, "# TYPE cake_generated_tokens_total counter");
+        let _ = writeln!(
+            out,
+            "cake_generated_tokens_total {}",
+            self.generated_tokens.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP cake_active_sessions Sessions whose caches are kept by the master."
+        );
+        let _ = writeln!(out, "# TYPE cake_active_sessions gauge");
+        let _ = writeln!(
+            out,
+            "cake_active_sessions {}",
+            self.active_sessions.load(Ordering::Relaxed)
+        );
+
+        let nodes = self.nodes.lock().unwrap();
+
+        let _ = writeln!(
+            out,
+            "# HELP cake_in_flight_requests Forward requests being processed by a node."
+        );
+        let _ = writeln!(out, "# TYPE cake_in_flight_requests gauge");
+        for n in nodes.iter() {
+            let _ = writeln!(
+                out,
+                "cake_in_flight_requests{{node=\"{}\"}} {}",
+                escape_label(&n.node),
+                n.in_flight
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP cake_forward_duration_seconds Latency of the forward requests of a node, the layers of a worker being forwarded together."
+        );
+        let _ = writeln!(out, "# TYPE cake_forward_duration_seconds histogram");
+        for n in nodes.iter() {
+            let node = escape_label(&n.node);
+            let mut cumulative = 0;
+            for (le, observations) in LATENCY_BUCKETS.iter().zip(n.buckets) {
+                cumulative += observations;
+                let _ = writeln!(
+                    out,
+                    "cake_forward_duration_seconds_bucket{{node=\"{node}\",le=\"{le}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "cake_forward_duration_seconds_bucket{{node=\"{node}\",le=\"+Inf\"}} {}",
+                n.count
+            );
+            let _ = writeln!(
+                out,
+                "cake_forward_duration_seconds_sum{{node=\"{node}\"}} {}",
+                n.sum
+            );
+            let _ = writeln!(
+                out,
+                "cake_forward_duration_seconds_count{{node=\"{node}\"}} {}",
+                n.count
+            );
+        }
+
+        out
+    }
+
+    /// Binds the address and serves the metrics as GET /metrics in the background.
+    pub async fn serve(self: Arc<Self>, address: &str) -> Result<()> {
+        let app = Router::new().route(
+            "/metrics",
+            get(move || async move {
+                (
+                    [("content-type", "text/plain; version=0.0.4")],
+                    self.render(),
+                )
+            }),
+        );
+
+        let listener = tokio::net::TcpListener::bind(address)
+            .await
+            .map_err(|e| anyhow!("can't bind the metrics endpoint to {address}: {e}"))?;
+
+        log::info!("metrics served on http://{address}/metrics");
+
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, app).await {
+                log::error!("metrics endpoint failed: {e}");
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/// A forward request being processed, see Metrics::forward.
+pub struct InFlight<'a> {
+    metrics: &'a Metrics,
+    node: String,
+    start: Instant,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.metrics.observe(&self.node, self.start.elapsed());
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    /// Value of the sample of the rendered metrics, if any.
+    fn sample(rendered: &str, name: &str) -> Option<f64> {
+        rendered
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' ')?.parse().ok())
+    }
+
+    #[test]
+    fn forward_latency_histogram() {
+        let metrics = Metrics::default();
+        let in_flight = metrics.forward("worker");
+        assert_eq!(
+            sample(
+                &metrics.render(),
+                "cake_in_flight_requests{node=\"worker\"}"
+            ),
+            Some(1.)
+        );
+        drop(in_flight);
+        drop(metrics.forward("worker"));
+        // as if a request took 30ms
+        metrics.with_node("worker", |n| n.in_flight += 1);
+        metrics.observe("worker", Duration::from_millis(30));
+
+        let rendered = metrics.render();
+        let bucket = |le: &str| {
+            sample(
+                &rendered,
+                &format!("cake_forward_duration_seconds_bucket{{node=\"worker\",le=\"{le}\"}}"),
+            )
+        };
+        assert_eq!(
+            sample(&rendered, "cake_in_flight_requests{node=\"worker\"}"),
+            Some(0.)
+        );
+        assert_eq!(
+            sample(
+                &rendered,
+                "cake_forward_duration_seconds_count{node=\"worker\"}"
+            ),
+            Some(3.)
+        );
+        // the buckets are cumulative
+        assert_eq!(bucket("0.01"), Some(2.));
+        assert_eq!(bucket("0.025"), Some(2.));
+        assert_eq!(bucket("0.05"), Some(3.));
+        assert_eq!(bucket("+Inf"), Some(3.));
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint() {
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "metrics.yml",
+            &format!("metrics-0: {{ host: '{address}', layers: [2, 3] }}"),
+        );
+        let _worker = test_utils::worker("metrics-0", &topology, &[]).await;
+
+        let metrics_addr = test_utils::free_address();
+        let mut args = test_utils::args(&[
+            "--metrics-addr",
+            &metrics_addr,
+            "--max-tokens",
+            "4",
+            "--ignore-eos",
+        ]);
+        args.topology = topology;
+        let mut master = test_utils::master(args.clone()).await;
+        let tokens = master.encode("the cat sat").unwrap();
+        master
+            .generate_with(&args, tokens, &Default::default(), |_| {})
+            .await
+            .unwrap();
+
+        let url = format!("http://{metrics_addr}/metrics");
+        let rendered = tokio::task::spawn_blocking(move || {
+            let response = ureq::get(&url).call().unwrap();
+            assert_eq!(response.content_type(), "text/plain");
+            response.into_string().unwrap()
+        })
+        .await
+        .unwrap();
+
+        for name in [
+            "# TYPE cake_generated_tokens_total counter",
+            "# TYPE cake_active_sessions gauge",
+            "# TYPE cake_in_flight_requests gauge",
+            "# TYPE cake_forward_duration_seconds histogram",
+        ] {
+            assert!(rendered.contains(name), "{name} not in {rendered}");
+        }
+        assert_eq!(sample(&rendered, "cake_generated_tokens_total"), Some(4.));
+        assert_eq!(sample(&rendered, "cake_active_sessions"), Some(0.));
+        let node =
+            |name: &str, node: &str| sample(&rendered, &format!("{name}{{node=\"{node}\"}}"));
+        assert_eq!(node("cake_in_flight_requests", &address), Some(0.));
+        // the prompt in one forward, then a forward per token but the last one
+        assert_eq!(
+            node("cake_forward_duration_seconds_count", &address),
+            Some(4.)
+        );
+        // the two local layers are forwarded one by one
+        assert_eq!(
+            node("cake_forward_duration_seconds_count", "local"),
+            Some(8.)
+        );
+    }
+}
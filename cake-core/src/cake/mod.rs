@@ -1,6 +1,7 @@
 use std::{
     fmt::{Debug, Display},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use anyhow::Result;
@@ -9,27 +10,86 @@ use candle_core::{DType, Device, Tensor};
 use candle_nn::VarBuilder;
 
 use crate::{
-    model::{Cache, Config, LlamaConfig},
+    model::{self, Cache, Config, Gguf, LlamaConfig, LoadMode, ShardProgress, Shards},
     utils, Args,
 };
 
+pub mod api;
+mod bench;
 mod client;
+mod count;
+mod error;
 mod master;
+mod metrics;
+mod pipeline;
 mod proto;
+mod registry;
+mod router;
+mod status;
+mod tls;
 mod topology;
 mod worker;
 
+pub use bench::*;
 pub use client::*;
+pub use count::*;
+pub use error::*;
 pub use master::*;
+pub use metrics::*;
+pub use pipeline::*;
 pub use proto::*;
+pub use registry::*;
+pub use router::*;
+pub use status::*;
 pub use topology::*;
 pub use worker::*;
 
+/// Returns whether a tensor of the checkpoint is loaded by the node.
+type TensorFilter<'a> = Box<dyn Fn(&str) -> bool + 'a>;
+
 #[derive(clap::ValueEnum, Clone, Debug, Default)]
 pub enum Mode {
     #[default]
     Master,
     Worker,
+    /// Master exposing an OpenAI compatible HTTP API.
+    Api,
+    /// Master printing the embeddings of the prompt instead of generating text.
+    Embeddings,
+    /// Master generating from a synthetic prompt and reporting its throughput and latencies.
+    Bench,
+    /// Queries and prints the status of the workers of the topology.
+    Status,
+    /// Validates the topology against the configuration of the model and prints it as a Graphviz
+    /// diagram.
+    Topology,
+    /// Splits the checkpoint into a model directory for every worker of the topology, holding
+    /// only the tensors of its layers, in --shard-out.
+    Shard,
+    /// Prints the number of tokens of the prompt, templated as the master would, and the room it
+    /// leaves in the context, without loading the model.
+    Count,
+}
+
+/// How the master prints the generated text.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// The prompt followed by the generated text.
+    #[default]
+    Text,
+    /// A JSON object per token, then one with the statistics of the generation.
+    Jsonl,
+}
+
+/// How the logs are formatted.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// A human readable line per record.
+    #[default]
+    Text,
+    /// A JSON object per record, with its level, module, message, the role of the node and the
+    /// structured fields of the record.
+    Json,
 }
 
 pub struct Context {
@@ -43,54 +103,127 @@ pub struct Context {
 }
 
 impl Context {
-    pub fn from_args(args: Args) -> Result<Self> {
+    pub fn from_args(args: Args) -> Result<Self, CakeError> {
+        Ok(Self::load(args)?)
+    }
+
+    fn load(args: Args) -> Result<Self> {
+        log::info!("loading topology from {}", &args.topology);
+
+        let topology = if args.registry_addr.is_some() && !Path::new(&args.topology).exists() {
+            // the workers register the layers they serve
+            Topology::default()
+        } else {
+            Topology::from_path(&args.topology).map_err(|e| CakeError::TopologyInvalid {
+                reason: format!("can't load {}: {e}", &args.topology),
+            })?
+        };
+
+        let data_path = PathBuf::from(&args.model);
+        if let Some(url) = &args.model_url {
+            Self::download_model(url, &data_path, &args, &topology)?;
+        }
+        Self::convert_pth(&data_path)?;
+
         let dtype = match args.dtype.as_deref() {
-            Some("f16") => DType::F16,
-            Some("bf16") => DType::BF16,
-            Some("f32") => DType::F32,
-            Some(dtype) => bail!("unsupported dtype {dtype}"),
-            None => DType::F16,
+            Some(dtype) => utils::parse_dtype(dtype)?,
+            // match the checkpoint so that its tensors aren't converted
+            None => utils::checkpoint_dtype(&data_path)?.unwrap_or(DType::F16),
+        };
+        // a worker runs its layers in the dtype of its topology node, if any
+        let dtype = match (&args.mode, args.name.as_ref()) {
+            (Mode::Worker, Some(name)) => topology
+                .get(name)
+                .map(|node| node.dtype())
+                .transpose()?
+                .flatten()
+                .unwrap_or(dtype),
+            _ => dtype,
         };
 
-        let device = utils::get_inference_device(args.cpu, args.device)
+        // a worker spreading its layers across devices loads the first ones on the main device
+        let ordinal = args.devices.first().copied().unwrap_or(args.device);
+        let device = utils::get_inference_device(&args.device_preference(), ordinal)
             .map_err(|e| anyhow!("can't attach to device: {:?}", e))?;
+        let threads = utils::init_threads(args.threads)
+            .map_err(|e| anyhow!("can't configure the threads: {:?}", e))?;
 
         log::info!(
-            "[{:?}] dtype={:?} device={:?} mem={}",
+            "[{:?}] dtype={:?} device={:?} threads={threads} mem={}",
             args.mode,
             &dtype,
             &device,
             human_bytes::human_bytes(memory_stats::memory_stats().unwrap().physical_mem as f64)
         );
 
-        log::info!("loading topology from {}", &args.topology);
-
-        let topology = Topology::from_path(&args.topology)?;
-
-        let data_path = PathBuf::from(&args.model);
-        let config_filename = data_path.join("config.json");
-        let model_tensors_index = data_path.join("model.safetensors.index.json");
-
-        log::info!("loading configuration from {}", config_filename.display());
+        if let Some(dir) = &args.dump_activations {
+            std::fs::create_dir_all(dir).map_err(|e| anyhow!("can't create {}: {:?}", dir, e))?;
+        }
 
-        let data = std::fs::read(&config_filename)
-            .map_err(|e| anyhow!("can't read {}: {:?}", config_filename.display(), e))?;
-        let config: LlamaConfig = serde_json::from_slice(&data)
-            .map_err(|e| anyhow!("can't parse {}: {:?}", config_filename.display(), e))?;
-        let config = config.into_config();
+        let mut config = Self::load_config(&data_path, Self::open_gguf(&data_path)?)?;
+        config.quantize = args.quantize;
+        config.compute_dtype = args
+            .compute_dtype
+            .as_deref()
+            .map(utils::parse_dtype)
+            .transpose()?;
+        if let Some(max_seq_len) = args.max_seq_len {
+            if max_seq_len == 0 {
+                bail!("--max-seq-len must be at least 1");
+            }
+            if max_seq_len > config.max_position_embeddings {
+                log::warn!(
+                    "--max-seq-len {max_seq_len} exceeds the context length the model has been trained with ({}), quality will degrade past it",
+                    config.max_position_embeddings
+                );
+            }
+            config.max_seq_len = Some(max_seq_len);
+        }
+        if let Some(rope_theta) = args.rope_theta {
+            if rope_theta <= 0. || !rope_theta.is_finite() {
+                bail!("--rope-theta must be a positive number");
+            }
+            config.rope_theta = rope_theta;
+        }
+        log::info!("rope theta: {}", config.rope_theta);
 
-        let cache = Cache::new(true, dtype, &config, &device)?;
+        topology
+            .validate(&config)
+            .map_err(|e| CakeError::TopologyInvalid {
+                reason: e.to_string(),
+            })?;
 
-        log::info!("loading tensors from {} ...", model_tensors_index.display());
+        if config.sliding_window.is_some() && args.prefix_cache_size > 0 {
+            // the cached prefixes are narrowed from the first position, which isn't cached anymore
+            bail!(
+                "--prefix-cache-size can't be used with the sliding window attention of the model"
+            );
+        }
 
-        let filenames: Vec<std::path::PathBuf> =
-            utils::load_safetensors_from_index(model_tensors_index)
-                .map_err(|e| anyhow!("can't load tensors index: {:?}", e))?;
-
-        let var_builder = unsafe {
-            VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device)
-                .map_err(|e| anyhow!("can't create varbuilder from tensors: {:?}", e))?
+        let mut cache = if args.paged_kv {
+            if args.kv_cache_dtype.is_some() {
+                bail!("--paged-kv can't be combined with --kv-cache-dtype");
+            }
+            log::info!(
+                "paged kv cache: {} blocks of {} positions",
+                args.kv_blocks,
+                args.kv_block_size
+            );
+            Cache::new_paged(args.kv_block_size, args.kv_blocks, dtype, &config, &device)?
+        } else {
+            Cache::new(true, dtype, &config, &device)?
         };
+        cache.kv_dtype = args.kv_cache_dtype;
+        cache.flash_attn = args.flash_attn && Self::flash_attn_supported(&device, dtype, &config);
+
+        let var_builder = Self::load_var_builder(
+            &data_path,
+            &config,
+            dtype,
+            &device,
+            &args,
+            &Self::loads_tensor(&args, &topology)?,
+        )?;
 
         Ok(Context {
             args,
@@ -102,6 +235,248 @@ impl Context {
             var_builder,
         })
     }
+
+    /// Returns true if the flash attention kernel can run the model on the device, warning
+    /// otherwise.
+    fn flash_attn_supported(device: &Device, dtype: DType, config: &Config) -> bool {
+        let head_dim = config.hidden_size / config.num_attention_heads;
+        let unsupported = if !cfg!(feature = "flash-attn") {
+            "cake hasn't been compiled with the flash-attn feature".to_string()
+        } else if !device.is_cuda() {
+            format!("it needs a CUDA device, got {device:?}")
+        } else if !matches!(dtype, DType::F16 | DType::BF16) {
+            format!("it needs the model in f16 or bf16, got {dtype:?}")
+        } else if !head_dim.is_multiple_of(8) || head_dim > 256 {
+            format!("it needs a head size multiple of 8 up to 256, got {head_dim}")
+        } else if config.sliding_window.is_some() {
+            "it doesn't support the sliding window attention of the model".to_string()
+        } else {
+            log::info!("using flash attention");
+            return true;
+        };
+
+        log::warn!("--flash-attn ignored, {unsupported}");
+        false
+    }
+
+    /// Loads the configuration, an empty cache and the tensors of the model at data_path, to run it
+    /// locally alongside the main one.
+    pub fn load_local_model(
+        &self,
+        data_path: &Path,
+    ) -> Result<(Config, Cache, VarBuilder<'static>)> {
+        let dtype = self.cache.cos.dtype();
+        Self::convert_pth(data_path)?;
+        let mut config = Self::load_config(data_path, Self::open_gguf(data_path)?)?;
+        config.quantize = self.args.quantize;
+        config.compute_dtype = self.config.compute_dtype;
+        config.max_seq_len = self.config.max_seq_len;
+
+        let mut cache = Cache::new(true, dtype, &config, &self.device)?;
+        cache.kv_dtype = self.args.kv_cache_dtype;
+        cache.flash_attn =
+            self.args.flash_attn && Self::flash_attn_supported(&self.device, dtype, &config);
+        let var_builder = Self::load_var_builder(
+            data_path,
+            &config,
+            dtype,
+            &self.device,
+            &self.args,
+            // every layer of the model is local
+            &|_| true,
+        )?;
+
+        Ok((config, cache, var_builder))
+    }
+
+    /// Converts the original checkpoint of the model, if that's all the directory has, so it can
+    /// be loaded like the others.
+    fn convert_pth(data_path: &Path) -> Result<()> {
+        if let Some(pth) = model::find_pth(data_path)? {
+            model::convert_pth(&pth, data_path)?;
+        }
+        Ok(())
+    }
+
+    /// Loads the configuration of the model at --model, without its weights.
+    pub fn load_model_config(args: &Args) -> Result<Config, CakeError> {
+        let data_path = PathBuf::from(&args.model);
+        Ok(Self::load_config(&data_path, Self::open_gguf(&data_path)?)?)
+    }
+
+    fn open_gguf(data_path: &Path) -> Result<Option<Arc<Gguf>>> {
+        utils::find_gguf(data_path)?
+            .map(|path| {
+                log::info!("loading gguf from {}", path.display());
+                Ok(Arc::new(Gguf::open(path)?))
+            })
+            .transpose()
+    }
+
+    /// Loads the configuration of the model, along with the GGUF file the weights are read from
+    /// if there's one.
+    fn load_config(data_path: &Path, gguf: Option<Arc<Gguf>>) -> Result<Config> {
+        let config_filename = data_path.join("config.json");
+        let mut config = match &gguf {
+            // quantized models can come without a configuration file
+            Some(gguf) if !config_filename.exists() => gguf.config()?,
+            _ => {
+                log::info!("loading configuration from {}", config_filename.display());
+
+                let config_parse = |reason: String| CakeError::ConfigParse {
+                    path: config_filename.clone(),
+                    reason,
+                };
+                let data =
+                    std::fs::read(&config_filename).map_err(|e| config_parse(e.to_string()))?;
+                let config: LlamaConfig =
+                    serde_json::from_slice(&data).map_err(|e| config_parse(e.to_string()))?;
+                config.validate()?;
+                config.into_config()
+            }
+        };
+        config.gguf = gguf;
+        Ok(config)
+    }
+
+    /// Creates another var builder loading the model tensors on the given device.
+    pub fn var_builder_for(&self, device: &Device) -> Result<VarBuilder<'static>> {
+        Self::load_var_builder(
+            &self.data_path,
+            &self.config,
+            self.cache.cos.dtype(),
+            device,
+            &self.args,
+            &Self::loads_tensor(&self.args, &self.topology)?,
+        )
+    }
+
+    /// Returns whether the node loads a tensor of the checkpoint: a worker loads the tensors of the
+    /// layers it serves, the master the embeddings, the final norm, the lm_head and the layers no
+    /// worker serves, or all of them with --fallback-local.
+    fn loads_tensor<'a>(args: &Args, topology: &'a Topology) -> Result<TensorFilter<'a>> {
+        // model.layers.N of the model.layers.N.* tensors
+        fn layer_of(tensor: &str) -> Option<String> {
+            let index = tensor.strip_prefix("model.layers.")?.split('.').next()?;
+            Some(format!("model.layers.{index}"))
+        }
+
+        Ok(match (&args.mode, args.name.as_ref()) {
+            (Mode::Worker, Some(name)) => {
+                let node = topology
+                    .get(name)
+                    .ok_or_else(|| CakeError::TopologyInvalid {
+                        reason: format!("could not find topology for {name}"),
+                    })?;
+                Box::new(|tensor| {
+                    layer_of(tensor).is_some_and(|layer| node.layers.contains(&layer))
+                })
+            }
+            // the layers of any worker may have to be loaded with --fallback-local
+            _ if args.fallback_local => Box::new(|_| true),
+            _ => Box::new(|tensor| {
+                layer_of(tensor).is_none_or(|layer| {
+                    topology
+                        .get_nodes_for_layer(&layer)
+                        .iter()
+                        .all(|(_, node)| node.is_local())
+                })
+            }),
+        })
+    }
+
+    /// Creates the var builder of the tensors the node loads once serving the layers as topology
+    /// assigns them, downloading the missing shards first with --model-url.
+    pub fn var_builder_with(&self, topology: &Topology) -> Result<VarBuilder<'static>> {
+        if let Some(url) = &self.args.model_url {
+            Self::download_model(url, &self.data_path, &self.args, topology)?;
+        }
+        let mut config = self.config.clone();
+        if config.gguf.is_none() {
+            // just downloaded
+            config.gguf = Self::open_gguf(&self.data_path)?;
+        }
+        Self::load_var_builder(
+            &self.data_path,
+            &config,
+            self.cache.cos.dtype(),
+            &self.device,
+            &self.args,
+            &Self::loads_tensor(&self.args, topology)?,
+        )
+    }
+
+    /// Downloads the files of the model the node needs, see loads_tensor.
+    fn download_model(url: &str, data_path: &Path, args: &Args, topology: &Topology) -> Result<()> {
+        log::info!(
+            "downloading the model from {url} to {} ...",
+            data_path.display()
+        );
+
+        let tokenizer = !matches!(args.mode, Mode::Worker) && args.tokenizer.is_none();
+        utils::download_model(
+            url,
+            data_path,
+            tokenizer,
+            Self::loads_tensor(args, topology)?,
+        )
+    }
+
+    fn load_var_builder(
+        data_path: &Path,
+        config: &Config,
+        dtype: DType,
+        device: &Device,
+        args: &Args,
+        loads_tensor: &dyn Fn(&str) -> bool,
+    ) -> Result<VarBuilder<'static>> {
+        if let Some(gguf) = &config.gguf {
+            return Ok(gguf.var_builder(config, dtype, device));
+        }
+
+        let model_tensors_index = data_path.join("model.safetensors.index.json");
+
+        log::info!("loading tensors from {} ...", data_path.display());
+
+        // the other shards aren't mapped, nor downloaded with --model-url
+        let filenames: Vec<std::path::PathBuf> =
+            utils::load_safetensors_for(model_tensors_index, loads_tensor)
+                .map_err(|e| anyhow!("can't find the model tensors: {:?}", e))?;
+
+        // the shards read eagerly take their whole size in memory
+        let mut peak_memory = 0;
+        let shards = Shards::open(&filenames, args.load_mode, |p: &ShardProgress| {
+            if let Some(stats) = memory_stats::memory_stats() {
+                peak_memory = peak_memory.max(stats.physical_mem);
+            }
+            let level = if args.progress {
+                log::Level::Info
+            } else {
+                log::Level::Debug
+            };
+            log::log!(
+                level,
+                "  [{}/{}] {} - {} / {} ({:.0}%)",
+                p.shard,
+                p.shards,
+                p.path.display(),
+                human_bytes::human_bytes(p.bytes as f64),
+                human_bytes::human_bytes(p.total_bytes as f64),
+                p.percent()
+            );
+        })
+        .map_err(|e| anyhow!("can't create varbuilder from tensors: {:?}", e))?;
+
+        if args.load_mode == LoadMode::Eager {
+            log::info!(
+                "read {} shards into memory, peak memory while loading {}",
+                filenames.len(),
+                human_bytes::human_bytes(peak_memory as f64)
+            );
+        }
+
+        Ok(shards.var_builder(dtype, device))
+    }
 }
 
 #[async_trait]
@@ -114,13 +489,112 @@ pub(crate) trait Forwarder: Debug + Send + Display {
         cache: &mut Cache,
     ) -> Result<Tensor>;
 
+    /// Runs the (layer name, index_pos, block_idx) ops of the batch one after another, each on
+    /// the output of the previous one.
     async fn forward_batch(
+        &mut self,
+        x: &Tensor,
+        batch: Vec<(String, usize, usize)>,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let mut x = x.clone();
+        for (_, index_pos, block_idx) in batch {
+            x = self.forward(&x, index_pos, block_idx, cache).await?;
+        }
+        Ok(x)
+    }
+
+    /// Sends the batch of ops to the worker without waiting for its output, which is then read
+    /// by finish_batch, so that the worker computes while the caller does something else. Only
+    /// the remote forwarders support it.
+    async fn start_batch(
         &mut self,
         _x: &Tensor,
         _batch: Vec<(String, usize, usize)>,
-        _cache: &mut Cache,
-    ) -> Result<Tensor> {
-        unimplemented!()
+        _cache: &Cache,
+    ) -> Result<()> {
+        Err(anyhow!(
+            "{} can't run a forward in the background",
+            self.ident()
+        ))
+    }
+
+    /// Returns the output of the batch started by start_batch.
+    async fn finish_batch(&mut self) -> Result<Tensor> {
+        Err(anyhow!(
+            "{} can't run a forward in the background",
+            self.ident()
+        ))
+    }
+
+    /// Same as forward, also returning the (batch, num_attention_heads, seq_len, kv_len)
+    /// attention probabilities of the block.
+    async fn forward_attention(
+        &mut self,
+        x: &Tensor,
+        index_pos: usize,
+        block_idx: usize,
+        cache: &mut Cache,
+    ) -> Result<(Tensor, Tensor)> {
+        cache.capture_attention = true;
+        let y = self.forward(x, index_pos, block_idx, cache).await;
+        cache.capture_attention = false;
+        let y = y?;
+        let attention = cache
+            .take_attention()
+            .ok_or_else(|| anyhow!("{} recorded no attention", self.layer_name()))?;
+        Ok((y, attention))
+    }
+
+    /// Discards any state kept for the current sequences, left_padding holds the number of
+    /// padding tokens of each row of the upcoming batch.
+    async fn reset(&mut self, _left_padding: &[usize]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Processes the next sequences with the state kept under id, empty at first, keeping the
+    /// current one under its own id until it's selected again. The state starts under id 0, the
+    /// local blocks keeping theirs in the cache of the master. Returns CacheLost if the state of
+    /// id has been lost, the block then starting from an empty one.
+    async fn select_cache(&mut self, _id: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// Discards the state kept under id.
+    async fn drop_cache(&mut self, _id: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the key-value cache entries of the given blocks, if any.
+    async fn get_kv_cache(
+        &mut self,
+        block_idxs: &[usize],
+        cache: &Cache,
+    ) -> Result<Vec<(usize, Tensor, Tensor)>> {
+        let mut kvs = vec![];
+        for &idx in block_idxs {
+            if let Some((k, v)) = cache.kv(idx)? {
+                kvs.push((idx, k, v));
+            }
+        }
+        Ok(kvs)
+    }
+
+    /// Replaces the key-value cache entries of the given blocks.
+    async fn set_kv_cache(
+        &mut self,
+        kvs: Vec<(usize, Tensor, Tensor)>,
+        cache: &mut Cache,
+    ) -> Result<()> {
+        for (idx, k, v) in kvs {
+            cache.set_kv(idx, k, v)?;
+        }
+        Ok(())
+    }
+
+    /// Liveness of the remote workers serving this block, none if it's served locally.
+    fn status(&self) -> Vec<WorkerStatus> {
+        vec![]
     }
 
     fn layer_name(&self) -> &str;
@@ -129,3 +603,106 @@ pub(crate) trait Forwarder: Debug + Send + Display {
         "local"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::test_utils;
+
+    /// Shard of the tensors of the model in sharded_model.
+    fn shard_of(tensor: &str) -> &'static str {
+        match tensor.strip_prefix("model.layers.") {
+            Some(layer) if layer.starts_with("0.") || layer.starts_with("1.") => {
+                "model-00002-of-00003.safetensors"
+            }
+            Some(_) => "model-00003-of-00003.safetensors",
+            None => "model-00001-of-00003.safetensors",
+        }
+    }
+
+    /// Model of model_dir split in three shards: the embeddings, the final norm and the lm_head,
+    /// the layers 0 and 1, the layers 2 and 3.
+    fn sharded_model() -> PathBuf {
+        let dir = test_utils::model_dir_with("sharded", serde_json::json!({}));
+        let tensors =
+            candle_core::safetensors::load(dir.join("model.safetensors"), &Device::Cpu).unwrap();
+        std::fs::remove_file(dir.join("model.safetensors")).unwrap();
+
+        let mut shards: HashMap<&str, HashMap<String, Tensor>> = HashMap::new();
+        for (name, tensor) in &tensors {
+            shards
+                .entry(shard_of(name))
+                .or_default()
+                .insert(name.clone(), tensor.clone());
+        }
+        for (shard, tensors) in &shards {
+            candle_core::safetensors::save(tensors, dir.join(shard)).unwrap();
+        }
+        let weight_map: HashMap<_, _> = tensors
+            .keys()
+            .map(|name| (name.clone(), shard_of(name)))
+            .collect();
+        std::fs::write(
+            dir.join("model.safetensors.index.json"),
+            serde_json::json!({ "metadata": {}, "weight_map": weight_map }).to_string(),
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn shards_of_the_tensors_a_node_loads() {
+        let dir = sharded_model();
+        let address = test_utils::free_address();
+        let topology = dir.join("topology.yml");
+        std::fs::write(
+            &topology,
+            format!("sharded-0: {{ host: '{address}', layers: [2, 3] }}"),
+        )
+        .unwrap();
+        let shards = |extra: &[&str]| -> Vec<String> {
+            let args = test_utils::args_for(&dir, extra);
+            let topology = Topology::from_path(&args.topology).unwrap();
+            utils::load_safetensors_for(
+                dir.join("model.safetensors.index.json"),
+                Context::loads_tensor(&args, &topology).unwrap(),
+            )
+            .unwrap()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+            .collect()
+        };
+
+        let worker = [
+            "--mode",
+            "worker",
+            "--name",
+            "sharded-0",
+            "--address",
+            &address,
+        ];
+        assert_eq!(shards(&worker), ["model-00003-of-00003.safetensors"]);
+        assert_eq!(
+            shards(&[]),
+            [
+                "model-00001-of-00003.safetensors",
+                "model-00002-of-00003.safetensors"
+            ]
+        );
+
+        // the worker loads its layers without the other shards
+        for shard in [
+            "model-00001-of-00003.safetensors",
+            "model-00002-of-00003.safetensors",
+        ] {
+            std::fs::remove_file(dir.join(shard)).unwrap();
+        }
+        let ctx = Context::from_args(test_utils::args_for(&dir, &worker)).unwrap();
+        assert!(ctx
+            .var_builder
+            .contains_tensor("model.layers.2.mlp.up_proj.weight"));
+        assert!(Context::from_args(test_utils::args_for(&dir, &[])).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use anyhow::Result;
+use candle_core::{Device, IndexOp, Tensor};
+
+use super::{CakeError, ConnectionOptions, Context};
+use crate::model::{Cache, Hook, HookKind, Llama};
+
+/// Chain of the layers of the model, served as the topology of the context assigns them, without
+/// the tokenization, the sampling and the sessions of the master. This is what tools running the
+/// model over whole sequences, such as perplexity scoring, build on.
+pub struct Pipeline {
+    model: Llama,
+    device: Device,
+}
+
+impl Pipeline {
+    /// Loads the layers the context serves locally and connects to the workers serving the
+    /// others.
+    pub async fn new(ctx: &Context) -> Result<Self, CakeError> {
+        let options = ConnectionOptions::from_args(&ctx.args)?;
+        let model = Llama::load(
+            &ctx.var_builder,
+            &ctx.config,
+            &ctx.device,
+            &ctx.topology,
+            &options,
+        )
+        .await?;
+
+        Ok(Self {
+            model,
+            device: ctx.device.clone(),
+        })
+    }
+
+    /// Runs the tokens through the model as a new sequence and returns the logits of every
+    /// position, in f32 with shape (seq_len, vocab_size). The cache, typically a clone of the one
+    /// of the context, and the caches of the workers are reset first, so the same tokens always
+    /// get the same logits.
+    pub async fn forward_tokens(
+        &mut self,
+        tokens: &[u32],
+        cache: &mut Cache,
+    ) -> Result<Tensor, CakeError> {
+        Ok(self.forward_sequence(tokens, cache).await?)
+    }
+
+    /// Calls hook at every forward_tokens with the attention probabilities of the layer layer_idx,
+    /// with shape (num_attention_heads, seq_len, seq_len), or its output, with shape
+    /// (seq_len, hidden_size). Remote layers are hooked too.
+    pub fn add_hook(
+        &mut self,
+        layer_idx: usize,
+        kind: HookKind,
+        mut hook: Hook,
+    ) -> Result<(), CakeError> {
+        // the sequences of the pipeline aren't batched
+        Ok(self
+            .model
+            .add_hook(layer_idx, kind, Box::new(move |x| hook(&x.i(0)?)))?)
+    }
+
+    /// Removes the hooks added with add_hook.
+    pub fn clear_hooks(&mut self) {
+        self.model.clear_hooks();
+    }
+
+    async fn forward_sequence(&mut self, tokens: &[u32], cache: &mut Cache) -> Result<Tensor> {
+        if tokens.is_empty() {
+            bail!("no tokens to forward");
+        }
+
+        *cache = cache.as_new();
+        self.model.reset(&[]).await?;
+
+        let input = Tensor::new(tokens, &self.device)?.unsqueeze(0)?;
+        Ok(self.model.forward_all(&input, 0, cache).await?.squeeze(0)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    async fn logits(args: crate::Args, tokens: &[u32]) -> (Vec<usize>, Vec<Vec<f32>>) {
+        let ctx = Context::from_args(args).unwrap();
+        let mut pipeline = Pipeline::new(&ctx).await.unwrap();
+        let mut cache = ctx.cache.clone();
+        let logits = pipeline.forward_tokens(tokens, &mut cache).await.unwrap();
+        // the cache is reset, so a second run gets the same logits
+        let again = pipeline.forward_tokens(tokens, &mut cache).await.unwrap();
+        assert_eq!(
+            logits.to_vec2::<f32>().unwrap(),
+            again.to_vec2::<f32>().unwrap()
+        );
+        (logits.dims().to_vec(), logits.to_vec2().unwrap())
+    }
+
+    #[tokio::test]
+    async fn logits_of_every_position() {
+        let tokens = [1, 3, 5, 7, 8, 3, 9];
+        let (dims, local) = logits(test_utils::args(&[]), &tokens).await;
+        assert_eq!(dims, [tokens.len(), test_utils::WORDS.len()]);
+        // the positions don't all get the same logits
+        assert_ne!(local[0], local[tokens.len() - 1]);
+
+        // through a worker serving some of the layers
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "pipeline.yml",
+            &format!("pipeline-worker: {{ host: '{address}', layers: [1, 2] }}"),
+        );
+        let _worker = test_utils::worker("pipeline-worker", &topology, &[]).await;
+        let args = crate::Args {
+            topology,
+            ..test_utils::args(&[])
+        };
+        let (_, remote) = logits(args, &tokens).await;
+        for (local, remote) in local.iter().zip(&remote) {
+            for (a, b) in local.iter().zip(remote) {
+                assert!((a - b).abs() < 1e-5, "{a} != {b}");
+            }
+        }
+
+        let ctx = Context::from_args(test_utils::args(&[])).unwrap();
+        let mut pipeline = Pipeline::new(&ctx).await.unwrap();
+        assert!(pipeline
+            .forward_tokens(&[], &mut ctx.cache.clone())
+            .await
+            .is_err());
+    }
+
+    /// What the hooks on layer 2 observe of the tokens going through the pipeline of args, with
+    /// their shapes, attention probabilities first.
+    async fn hooked(args: crate::Args, tokens: &[u32]) -> Vec<(Vec<usize>, Vec<f32>)> {
+        let ctx = Context::from_args(args).unwrap();
+        let mut pipeline = Pipeline::new(&ctx).await.unwrap();
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        for kind in [HookKind::Attention, HookKind::Residual] {
+            let seen = seen.clone();
+            let hook = move |x: &Tensor| {
+                let values = x.flatten_all()?.to_vec1::<f32>()?;
+                seen.lock().unwrap().push((kind, x.dims().to_vec(), values));
+                Ok(())
+            };
+            pipeline.add_hook(2, kind, Box::new(hook)).unwrap();
+        }
+        pipeline
+            .forward_tokens(tokens, &mut ctx.cache.clone())
+            .await
+            .unwrap();
+
+        let mut seen = std::mem::take(&mut *seen.lock().unwrap());
+        seen.sort_by_key(|(kind, _, _)| *kind != HookKind::Attention);
+        seen.into_iter()
+            .map(|(_, dims, values)| (dims, values))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn hooks_of_local_and_remote_layers() {
+        let tokens = [1, 3, 5, 7, 8];
+        let local = hooked(test_utils::args(&[]), &tokens).await;
+        assert_eq!(local.len(), 2);
+        let [(attention_dims, attention), (residual_dims, _)] = &local[..] else {
+            unreachable!()
+        };
+        assert_eq!(attention_dims, &[4, 5, 5]);
+        assert_eq!(residual_dims, &[5, 64]);
+        // causal probabilities
+        for (row, probs) in attention.chunks(5).enumerate() {
+            let pos = row % 5;
+            assert!((probs.iter().sum::<f32>() - 1.).abs() < 1e-5);
+            assert!(probs[pos + 1..].iter().all(|p| *p == 0.));
+        }
+
+        // the worker ships back what its layer observes
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "hooks.yml",
+            &format!("hooks-worker: {{ host: '{address}', layers: [1, 2] }}"),
+        );
+        let _worker = test_utils::worker("hooks-worker", &topology, &[]).await;
+        let args = crate::Args {
+            topology,
+            ..test_utils::args(&[])
+        };
+        let remote = hooked(args, &tokens).await;
+        assert_eq!(remote.len(), 2);
+        for ((dims, values), (local_dims, local_values)) in remote.iter().zip(&local) {
+            assert_eq!(dims, local_dims);
+            for (a, b) in values.iter().zip(local_values) {
+                assert!((a - b).abs() < 1e-5, "{a} != {b}");
+            }
+        }
+    }
+}
@@ -1,4 +1,8 @@
-use std::str::FromStr;
+use std::{
+    io::{IoSlice, Read},
+    str::FromStr,
+    time::Duration,
+};
 
 use anyhow::Result;
 use candle_core::{DType, Device, Tensor};
@@ -6,11 +10,62 @@ use safetensors::View;
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+use super::RateLimiter;
+
+/// The data of a tensor doesn't match the checksum it has been sent with.
+#[derive(Debug)]
+pub struct ChecksumMismatch {
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "tensor checksum mismatch: expected {:08x}, got {:08x}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// A message read is larger than the --max-message-bytes limit, it's rejected before its payload
+/// is read.
+#[derive(Debug)]
+pub struct MessageTooLarge {
+    /// Size declared in the header, or None if it's the decompressed payload that is too large.
+    pub size: Option<u32>,
+    pub limit: u32,
+}
+
+impl std::fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.size {
+            Some(size) => write!(
+                f,
+                "message of {size} bytes exceeds the {} bytes limit",
+                self.limit
+            ),
+            None => write!(
+                f,
+                "decompressed message exceeds the {} bytes limit",
+                self.limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
+
 #[derive(Serialize, Debug, Deserialize)]
 pub struct RawTensor {
     pub data: Vec<u8>,
     pub dtype: String,
     pub shape: Vec<usize>,
+    /// CRC32 of the data, verified when the message is read if set.
+    pub checksum: Option<u32>,
 }
 
 impl RawTensor {
@@ -18,7 +73,33 @@ impl RawTensor {
         let data: Vec<u8> = x.data().to_vec();
         let dtype = x.dtype().as_str().to_string();
         let shape = x.shape().clone().into_dims();
-        Self { data, dtype, shape }
+        Self {
+            data,
+            dtype,
+            shape,
+            checksum: None,
+        }
+    }
+
+    pub fn with_checksum(self) -> Self {
+        Self {
+            checksum: Some(crc32fast::hash(&self.data)),
+            ..self
+        }
+    }
+
+    pub fn verify(&self) -> std::result::Result<(), ChecksumMismatch> {
+        match self.checksum {
+            Some(expected) => {
+                let actual = crc32fast::hash(&self.data);
+                if actual == expected {
+                    Ok(())
+                } else {
+                    Err(ChecksumMismatch { expected, actual })
+                }
+            }
+            None => Ok(()),
+        }
     }
 
     pub fn to_tensor(&self, device: &Device) -> Result<Tensor> {
@@ -27,14 +108,65 @@ impl RawTensor {
     }
 }
 
-#[derive(Serialize, Debug, Deserialize)]
+#[derive(Serialize, Debug, Deserialize, Clone, Default)]
 pub struct WorkerInfo {
+    /// Name of the worker in the topology.
+    pub name: String,
+    /// Address the worker has been reached at, set by the client.
+    #[serde(skip)]
+    pub host: String,
+    pub device: String,
+    /// Dtype the worker runs its layers in.
+    pub dtype: String,
+    /// Memory available on the worker, in bytes.
+    pub memory: u64,
+}
+
+/// What a worker started with --master-addr announces to the master as it registers.
+#[derive(Serialize, Debug, Deserialize, Clone, Default)]
+pub struct Registration {
+    /// Name of the worker in the topology.
+    pub name: String,
+    /// Layers the worker has loaded.
+    pub layers: Vec<String>,
+    pub device: String,
+    /// Dtype the worker runs its layers in.
+    pub dtype: String,
+    /// Memory available on the worker, in bytes.
+    pub memory: u64,
+    /// Number of layers the memory of the worker devices could hold.
+    pub layer_capacity: usize,
+}
+
+/// State of a worker, sent in response to a StatusRequest.
+#[derive(Serialize, Debug, Deserialize, Clone, Default)]
+pub struct WorkerState {
+    pub name: String,
     pub device: String,
+    pub dtype: String,
+    /// Layers the worker has loaded.
+    pub layers: Vec<String>,
+    /// Number of open connections, the one of the request included.
+    pub connections: usize,
+    /// Number of forward requests being processed.
+    pub in_flight: usize,
+    /// Number of forward requests waiting for one of the --worker-concurrency slots.
+    pub queued: usize,
+    /// Milliseconds since the last forward request has been processed, if any.
+    pub idle_ms: Option<u64>,
 }
 
 #[derive(Serialize, Debug, Deserialize)]
 pub enum Message {
-    Hello,
+    Hello {
+        /// Token of the master, required by the workers started with --auth-token.
+        auth_token: Option<String>,
+        /// --master-id of the master, the connections without one are accounted on their own.
+        master_id: Option<String>,
+    },
+    /// Sent by the worker instead of its info when the token of the hello doesn't match its own,
+    /// the connection is then closed.
+    Unauthorized,
     WorkerInfo(WorkerInfo),
     TransformerOp {
         layer_name: String,
@@ -46,7 +178,69 @@ pub enum Message {
         x: RawTensor,
         batch: Vec<(String, usize, usize)>,
     },
+    /// Same as TransformerOp, the worker replies with an Attention.
+    AttentionOp {
+        layer_name: String,
+        x: RawTensor,
+        index_pos: usize,
+        block_idx: usize,
+    },
     Tensor(RawTensor),
+    /// Output of the block of an AttentionOp, along with its (batch, num_attention_heads,
+    /// seq_len, kv_len) attention probabilities.
+    Attention {
+        x: RawTensor,
+        attention: RawTensor,
+    },
+    /// Sent by the master when new sequences start, the worker clears the connection cache.
+    ResetCache {
+        left_padding: Vec<usize>,
+    },
+    /// Requests the key-value cache entries of the given blocks.
+    GetCache {
+        blocks: Vec<usize>,
+    },
+    /// Key-value cache entries as (block index, keys, values).
+    Cache(Vec<(usize, RawTensor, RawTensor)>),
+    /// Replaces the key-value cache entries of the connection, no response is sent.
+    SetCache(Vec<(usize, RawTensor, RawTensor)>),
+    /// Sent by the worker at regular intervals while it's processing a request.
+    Heartbeat,
+    /// Sent by the worker before it exits, it won't process any other request. Also sent by the
+    /// master before it closes a connection.
+    Shutdown,
+    /// Requests the state of the worker, which replies with a StatusResponse.
+    StatusRequest,
+    StatusResponse(WorkerState),
+    /// Sent by the worker instead of the response when a tensor of the request doesn't match
+    /// its checksum, the request can be sent again.
+    ChecksumMismatch,
+    /// Sent by the worker instead of the response when too many forward requests are already
+    /// waiting to be processed, the request can be sent again later.
+    Overloaded,
+    /// Sent by the worker instead of the response when the forward pass failed or panicked, with
+    /// the reason. The worker keeps serving the connection.
+    WorkerError(String),
+    /// Sent by a worker started with --master-addr on the connection it keeps open to the master,
+    /// which then sends a Dial whenever it needs a new connection to the worker.
+    Register(Registration),
+    /// Asks a registered worker to open a connection to the master, which it then serves like
+    /// the ones it accepts.
+    Dial,
+    /// First message of a connection opened by a registered worker.
+    Attach {
+        name: String,
+    },
+    /// Makes the worker process the next requests of the connection with the caches kept under
+    /// id, empty ones at first, while the current ones are kept under their own id until they're
+    /// selected again. The caches of a connection start under id 0, no response is sent.
+    SelectCache {
+        id: u64,
+    },
+    /// Discards the caches kept under id, no response is sent.
+    DropCache {
+        id: u64,
+    },
 }
 
 impl Message {
@@ -77,6 +271,80 @@ impl Message {
         }
     }
 
+    fn tensors(&self) -> Vec<&RawTensor> {
+        match self {
+            Self::TransformerOp { x, .. }
+            | Self::Batch { x, .. }
+            | Self::AttentionOp { x, .. }
+            | Self::Tensor(x) => vec![x],
+            Self::Attention { x, attention } => vec![x, attention],
+            Self::Cache(kvs) | Self::SetCache(kvs) => {
+                kvs.iter().flat_map(|(_, k, v)| [k, v]).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Sets the checksum of every tensor of the message.
+    pub fn with_checksums(self) -> Self {
+        let kvs = |kvs: Vec<(usize, RawTensor, RawTensor)>| {
+            kvs.into_iter()
+                .map(|(idx, k, v)| (idx, k.with_checksum(), v.with_checksum()))
+                .collect()
+        };
+        match self {
+            Self::TransformerOp {
+                layer_name,
+                x,
+                index_pos,
+                block_idx,
+            } => Self::TransformerOp {
+                layer_name,
+                x: x.with_checksum(),
+                index_pos,
+                block_idx,
+            },
+            Self::Batch { x, batch } => Self::Batch {
+                x: x.with_checksum(),
+                batch,
+            },
+            Self::AttentionOp {
+                layer_name,
+                x,
+                index_pos,
+                block_idx,
+            } => Self::AttentionOp {
+                layer_name,
+                x: x.with_checksum(),
+                index_pos,
+                block_idx,
+            },
+            Self::Tensor(x) => Self::Tensor(x.with_checksum()),
+            Self::Attention { x, attention } => Self::Attention {
+                x: x.with_checksum(),
+                attention: attention.with_checksum(),
+            },
+            Self::Cache(cache) => Self::Cache(kvs(cache)),
+            Self::SetCache(cache) => Self::SetCache(kvs(cache)),
+            msg => msg,
+        }
+    }
+
+    /// Returns true if the tensors of the message have been sent with their checksums.
+    pub fn has_checksums(&self) -> bool {
+        self.tensors()
+            .iter()
+            .any(|tensor| tensor.checksum.is_some())
+    }
+
+    /// Verifies the tensors of the message sent with a checksum.
+    pub fn verify_checksums(&self) -> std::result::Result<(), ChecksumMismatch> {
+        for tensor in self.tensors() {
+            tensor.verify()?;
+        }
+        Ok(())
+    }
+
     // Yes, I could use GRPC, but this is simpler and faster.
     // Check bitcode benchmarks ;)
     fn to_bytes(&self) -> Result<Vec<u8>> {
@@ -87,7 +355,16 @@ impl Message {
         bitcode::deserialize(raw).map_err(|e| anyhow!(e))
     }
 
-    pub async fn from_reader<R>(reader: &mut R) -> Result<Self>
+    /// Reads a message, failing with MessageTooLarge if it's larger than max_size.
+    pub async fn from_reader<R>(reader: &mut R, max_size: u32) -> Result<Self>
+    where
+        R: AsyncReadExt + Unpin,
+    {
+        Ok(Self::from_reader_compressed(reader, max_size).await?.0)
+    }
+
+    /// Same as from_reader, also returning whether the message was compressed.
+    pub async fn from_reader_compressed<R>(reader: &mut R, max_size: u32) -> Result<(Self, bool)>
     where
         R: AsyncReadExt + Unpin,
     {
@@ -97,31 +374,397 @@ impl Message {
         }
 
         let req_size = reader.read_u32().await?;
-        if req_size > super::MESSAGE_MAX_SIZE {
-            return Err(anyhow!("request size {req_size} > MESSAGE_MAX_SIZE"));
+        let compressed = req_size & super::COMPRESSED_FLAG != 0;
+        let req_size = req_size & !super::COMPRESSED_FLAG;
+        // checked before allocating the payload
+        if req_size > max_size {
+            return Err(MessageTooLarge {
+                size: Some(req_size),
+                limit: max_size,
+            }
+            .into());
         }
 
         let mut req = vec![0_u8; req_size as usize];
 
         reader.read_exact(&mut req).await?;
 
-        Self::from_bytes(&req)
+        if compressed {
+            req = decompress(&req, max_size)?;
+        }
+
+        Ok((Self::from_bytes(&req)?, compressed))
     }
 
     pub async fn to_writer<W>(&self, writer: &mut W) -> Result<()>
     where
         W: AsyncWriteExt + Unpin,
     {
-        let req = self.to_bytes()?;
+        self.to_writer_compressed(writer, None).await
+    }
+
+    /// Writes the message, compressing it with zstd if a level is provided.
+    pub async fn to_writer_compressed<W>(&self, writer: &mut W, level: Option<i32>) -> Result<()>
+    where
+        W: AsyncWriteExt + Unpin,
+    {
+        self.to_writer_throttled(writer, level, None).await
+    }
+
+    /// Same as to_writer_compressed, writing no faster than the limiter allows if set.
+    pub async fn to_writer_throttled<W>(
+        &self,
+        writer: &mut W,
+        level: Option<i32>,
+        limiter: Option<&mut RateLimiter>,
+    ) -> Result<()>
+    where
+        W: AsyncWriteExt + Unpin,
+    {
+        let mut req = self.to_bytes()?;
+        let mut flags = 0;
+        if let Some(level) = level {
+            let compressed = compress(&req, level)?;
+
+            log::debug!(
+                "compressed {} bytes to {} ({:.2}x)",
+                req.len(),
+                compressed.len(),
+                req.len() as f64 / compressed.len() as f64
+            );
+
+            req = compressed;
+            flags = super::COMPRESSED_FLAG;
+        }
+
         let req_size = req.len() as u32;
         if req_size > super::MESSAGE_MAX_SIZE {
             return Err(anyhow!("request size {req_size} > MESSAGE_MAX_SIZE"));
         }
 
-        writer.write_u32(super::PROTO_MAGIC).await?;
-        writer.write_u32(req_size).await?;
-        writer.write_all(&req).await?;
+        // the header goes in its own buffer rather than in a copy of the payload, which may be a
+        // whole activation or cache tensor
+        let mut header = [0u8; 8];
+        header[..4].copy_from_slice(&super::PROTO_MAGIC.to_be_bytes());
+        header[4..].copy_from_slice(&(req_size | flags).to_be_bytes());
+        match limiter {
+            Some(limiter) => {
+                let mut throttled = Duration::ZERO;
+                let first = req.len().min(super::THROTTLE_CHUNK_SIZE - header.len());
+                throttled += limiter.acquire(header.len() + first).await;
+                write_frame(writer, &header, &req[..first]).await?;
+                for chunk in req[first..].chunks(super::THROTTLE_CHUNK_SIZE) {
+                    throttled += limiter.acquire(chunk.len()).await;
+                    writer.write_all(chunk).await?;
+                }
+                if !throttled.is_zero() {
+                    log::debug!("throttled a message of {req_size} bytes for {throttled:?}");
+                }
+            }
+            None => write_frame(writer, &header, &req).await?,
+        }
 
         Ok(())
     }
 }
+
+/// Writes the header and the payload with vectored writes, so that the message isn't split into a
+/// small segment waiting to be acknowledged and the rest.
+async fn write_frame<W>(writer: &mut W, header: &[u8], payload: &[u8]) -> Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    let mut written = 0;
+    while written < header.len() {
+        let bufs = [IoSlice::new(&header[written..]), IoSlice::new(payload)];
+        match writer.write_vectored(&bufs).await? {
+            0 => return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into()),
+            n => written += n,
+        }
+    }
+    writer.write_all(&payload[written - header.len()..]).await?;
+    Ok(())
+}
+
+/// Compresses a serialized message with zstd.
+pub fn compress(data: &[u8], level: i32) -> Result<Vec<u8>> {
+    zstd::bulk::compress(data, level).map_err(|e| anyhow!("can't compress message: {:?}", e))
+}
+
+/// Decompresses a serialized message, refusing to expand it past max_size.
+pub fn decompress(data: &[u8], max_size: u32) -> Result<Vec<u8>> {
+    let decoder = zstd::stream::Decoder::new(data)
+        .map_err(|e| anyhow!("can't decompress message: {:?}", e))?;
+    let mut raw = vec![];
+    decoder
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut raw)
+        .map_err(|e| anyhow!("can't decompress message: {:?}", e))?;
+    if raw.len() > max_size as usize {
+        return Err(MessageTooLarge {
+            size: None,
+            limit: max_size,
+        }
+        .into());
+    }
+    Ok(raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cake::MESSAGE_MAX_SIZE, test_utils};
+
+    /// Writes the message and reads it back, with whether it was compressed.
+    async fn round_trip(message: &Message, level: Option<i32>) -> (Message, bool, usize) {
+        let mut frame = vec![];
+        message
+            .to_writer_compressed(&mut frame, level)
+            .await
+            .unwrap();
+        let (message, compressed) =
+            Message::from_reader_compressed(&mut frame.as_slice(), MESSAGE_MAX_SIZE)
+                .await
+                .unwrap();
+        (message, compressed, frame.len())
+    }
+
+    #[tokio::test]
+    async fn compressed_tensor_round_trip() {
+        // activations in f16 with repeated values, as zstd sees them
+        let x = Tensor::randn(0f32, 1., (1, 16, 64), &Device::Cpu)
+            .unwrap()
+            .round()
+            .unwrap()
+            .to_dtype(DType::F16)
+            .unwrap();
+        let message = Message::transformer_op("model.layers.3", &x, 7, 3);
+
+        let (_, compressed, plain_size) = round_trip(&message, None).await;
+        assert!(!compressed);
+        let (read, compressed, size) = round_trip(&message, Some(3)).await;
+        assert!(compressed);
+        assert!(size < plain_size, "{size} >= {plain_size}");
+
+        let Message::TransformerOp {
+            layer_name,
+            x: raw,
+            index_pos,
+            block_idx,
+        } = read
+        else {
+            panic!("not a transformer op");
+        };
+        assert_eq!(
+            (layer_name.as_str(), index_pos, block_idx),
+            ("model.layers.3", 7, 3)
+        );
+        assert_eq!(raw.data, RawTensor::from_tensor(&x).data);
+        let y = raw.to_tensor(&Device::Cpu).unwrap();
+        assert_eq!(y.dtype(), DType::F16);
+        assert_eq!(y.dims(), x.dims());
+    }
+
+    #[tokio::test]
+    async fn decompression_is_bounded() {
+        let zeros = Tensor::zeros((64, 64), DType::F32, &Device::Cpu).unwrap();
+        let mut frame = vec![];
+        Message::from_tensor(&zeros)
+            .to_writer_compressed(&mut frame, Some(3))
+            .await
+            .unwrap();
+        // well below the declared size limit once compressed
+        assert!(frame.len() < 1024);
+        let err = Message::from_reader(&mut frame.as_slice(), 1024)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<MessageTooLarge>().is_some());
+    }
+
+    #[tokio::test]
+    async fn oversized_length_is_rejected_before_reading() {
+        // a header claiming nearly 2GB, followed by a few bytes only
+        let mut frame = [
+            super::super::PROTO_MAGIC.to_be_bytes(),
+            0x7fff_ff00u32.to_be_bytes(),
+        ]
+        .concat();
+        frame.extend_from_slice(b"payload");
+        let mut reader = std::io::Cursor::new(frame);
+        let err = Message::from_reader(&mut reader, MESSAGE_MAX_SIZE)
+            .await
+            .unwrap_err();
+        let too_large = err.downcast_ref::<MessageTooLarge>().unwrap();
+        assert_eq!(
+            (too_large.size, too_large.limit),
+            (Some(0x7fff_ff00), MESSAGE_MAX_SIZE)
+        );
+        // nothing read past the header, where the buffer of the payload would have been filled
+        assert_eq!(reader.position(), 8);
+
+        // the limit is the one given, a message of its size is read
+        let mut frame = vec![];
+        Message::Heartbeat.to_writer(&mut frame).await.unwrap();
+        let size = frame.len() as u32 - 8;
+        let read = Message::from_reader(&mut frame.as_slice(), size).await;
+        assert!(matches!(read, Ok(Message::Heartbeat)));
+        let err = Message::from_reader(&mut frame.as_slice(), size - 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "message of {size} bytes exceeds the {} bytes limit",
+                size - 1
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn corrupted_payload_is_detected() {
+        let x = Tensor::arange(0f32, 64., &Device::Cpu)
+            .unwrap()
+            .reshape((1, 1, 64))
+            .unwrap();
+        let data = RawTensor::from_tensor(&x).data;
+
+        let message = Message::transformer_op("model.layers.0", &x, 0, 0).with_checksums();
+        assert!(message.has_checksums());
+        message.verify_checksums().unwrap();
+        let frame = test_utils::corrupted_frame(&message, &data).await;
+        let read = Message::from_reader(&mut frame.as_slice(), MESSAGE_MAX_SIZE)
+            .await
+            .unwrap();
+        let e = read.verify_checksums().unwrap_err();
+        assert_ne!(e.expected, e.actual);
+
+        // without a checksum the corruption goes unnoticed
+        let message = Message::transformer_op("model.layers.0", &x, 0, 0);
+        let frame = test_utils::corrupted_frame(&message, &data).await;
+        let read = Message::from_reader(&mut frame.as_slice(), MESSAGE_MAX_SIZE)
+            .await
+            .unwrap();
+        assert!(!read.has_checksums());
+        read.verify_checksums().unwrap();
+    }
+
+    #[tokio::test]
+    async fn throttled_writes_follow_the_rate() {
+        // 1MB of activations at 8Mbps, 1MB per second
+        let x = Tensor::zeros((1, 1024, 256), DType::F32, &Device::Cpu).unwrap();
+        let message = Message::transformer_op("model.layers.0", &x, 0, 0);
+
+        let write = |limiter: Option<RateLimiter>| {
+            let message = &message;
+            async move {
+                let mut limiter = limiter;
+                let mut frame = vec![];
+                let start = std::time::Instant::now();
+                message
+                    .to_writer_throttled(&mut frame, None, limiter.as_mut())
+                    .await
+                    .unwrap();
+                (start.elapsed(), frame)
+            }
+        };
+
+        let (unthrottled, expected) = write(None).await;
+        let (elapsed, frame) = write(Some(RateLimiter::from_mbps(8.))).await;
+        assert_eq!(frame, expected);
+        // the time spent serializing aside, the first tenth of a second is a burst
+        let throttled = elapsed.saturating_sub(unthrottled).as_secs_f64();
+        let expected_secs = frame.len() as f64 / 1_000_000. - 0.1;
+        assert!(
+            (throttled - expected_secs).abs() < 0.15,
+            "throttled for {throttled}s, {elapsed:?} in all, for {} bytes",
+            frame.len()
+        );
+    }
+
+    /// Writer taking at most max bytes per write, recording the size of each write.
+    struct Recording {
+        data: Vec<u8>,
+        writes: Vec<usize>,
+        max: usize,
+    }
+
+    impl tokio::io::AsyncWrite for Recording {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+        }
+
+        fn poll_write_vectored(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let mut n = 0;
+            for buf in bufs {
+                let take = buf.len().min(self.max - n);
+                self.data.extend_from_slice(&buf[..take]);
+                n += take;
+            }
+            self.writes.push(n);
+            std::task::Poll::Ready(Ok(n))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn header_and_payload_in_one_write() {
+        let x = Tensor::ones((1, 16, 64), DType::F32, &Device::Cpu).unwrap();
+        let message = Message::transformer_op("model.layers.0", &x, 0, 0);
+        let payload = message.to_bytes().unwrap();
+        let mut expected = super::super::PROTO_MAGIC.to_be_bytes().to_vec();
+        expected.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        expected.extend_from_slice(&payload);
+
+        for max in [usize::MAX, 5, 3000] {
+            let mut writer = Recording {
+                data: vec![],
+                writes: vec![],
+                max,
+            };
+            message.to_writer(&mut writer).await.unwrap();
+            assert_eq!(writer.data, expected, "max {max}");
+            if max == usize::MAX {
+                assert_eq!(writer.writes, [expected.len()]);
+            }
+        }
+
+        // throttled, the header goes out along with the first chunk
+        let mut writer = Recording {
+            data: vec![],
+            writes: vec![],
+            max: usize::MAX,
+        };
+        let mut limiter = RateLimiter::from_mbps(1000.);
+        message
+            .to_writer_throttled(&mut writer, None, Some(&mut limiter))
+            .await
+            .unwrap();
+        assert_eq!(writer.data, expected);
+        assert_eq!(writer.writes, [expected.len()]);
+    }
+}
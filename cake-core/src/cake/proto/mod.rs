@@ -1,8 +1,17 @@
 const PROTO_MAGIC: u32 = 0x104F4C7;
 
-// does more than this even make sense?
-const MESSAGE_MAX_SIZE: u32 = 512 * 1024 * 1024;
+/// Largest message that is sent, and read unless --max-message-bytes is lower. Twice the f32
+/// activations of a 8192 tokens prompt at a hidden size of 8192.
+pub const MESSAGE_MAX_SIZE: u32 = 512 * 1024 * 1024;
+
+// set in the size field of the header when the payload is zstd compressed
+const COMPRESSED_FLAG: u32 = 1 << 31;
+
+// messages are throttled in chunks of this size
+const THROTTLE_CHUNK_SIZE: usize = 64 * 1024;
 
 mod message;
+mod throttle;
 
 pub use message::*;
+pub use throttle::*;
@@ -0,0 +1,73 @@
+use std::time::{Duration, Instant};
+
+/// Token bucket limiting the rate bytes are written at, bursts are limited to a tenth of a second
+/// worth of bytes.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    // bytes per second
+    rate: f64,
+    capacity: f64,
+    // bytes that can be written right away, negative while the writes are ahead of the rate
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: f64) -> Self {
+        let capacity = bytes_per_sec / 10.;
+        Self {
+            rate: bytes_per_sec,
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    /// Creates a limiter from a rate in megabits per second.
+    pub fn from_mbps(mbps: f64) -> Self {
+        Self::new(mbps * 1_000_000. / 8.)
+    }
+
+    /// Waits until the bytes can be written without exceeding the rate, returns the time waited.
+    pub async fn acquire(&mut self, bytes: usize) -> Duration {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate)
+            .min(self.capacity);
+        self.last = now;
+        self.tokens -= bytes as f64;
+
+        if self.tokens < 0. {
+            let delay = Duration::from_secs_f64(-self.tokens / self.rate);
+            tokio::time::sleep(delay).await;
+            delay
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn bursts_are_limited_to_a_tenth_of_a_second() {
+        // 100KB per second, 10KB bursts
+        let mut limiter = RateLimiter::new(100_000.);
+        assert_eq!(limiter.acquire(10_000).await, Duration::ZERO);
+
+        // ahead of the rate by the 20KB written past the burst
+        let start = Instant::now();
+        let waited = limiter.acquire(20_000).await;
+        assert!(
+            (waited.as_secs_f64() - 0.2).abs() < 0.02,
+            "waited {waited:?}"
+        );
+        assert!(start.elapsed() >= waited);
+
+        // idle time refills the bucket up to the burst only
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(limiter.acquire(10_000).await, Duration::ZERO);
+        assert!(limiter.acquire(5_000).await > Duration::from_millis(40));
+    }
+}
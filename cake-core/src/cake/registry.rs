@@ -0,0 +1,335 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot, watch},
+};
+
+use super::{Message, Node, Registration, Topology};
+
+/// Prefix of the topology hosts of the workers that registered to the master, followed by their
+/// name.
+pub const REGISTERED_HOST_PREFIX: &str = "registered:";
+
+/// Time a registered worker has to open a connection once asked to.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time a new connection has to send its first message.
+const FIRST_MESSAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct Registered {
+    registration: Registration,
+    // so that the end of a registration doesn't remove the one that replaced it
+    id: usize,
+    // asks the registration connection to send a Dial
+    dial: mpsc::UnboundedSender<()>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    workers: HashMap<String, Registered>,
+    // connections awaited from each worker, in the order they have been asked for
+    pending: HashMap<String, VecDeque<oneshot::Sender<TcpStream>>>,
+    next_id: usize,
+}
+
+/// Workers started with --master-addr that registered to the master. Since they may not be
+/// reachable, the master doesn't dial them but asks them to open the connections it needs.
+#[derive(Debug)]
+pub struct Registry {
+    state: Mutex<State>,
+    // incremented whenever a worker registers or goes away
+    changes: watch::Sender<usize>,
+    // size above which the messages of the workers are rejected
+    max_message_bytes: u32,
+}
+
+impl Registry {
+    /// Accepts the registrations and the connections of the workers on address, rejecting the
+    /// messages larger than max_message_bytes.
+    pub async fn listen(address: &str, max_message_bytes: u32) -> Result<Arc<Self>> {
+        let listener = TcpListener::bind(address)
+            .await
+            .map_err(|e| anyhow!("can't listen for registrations on {address}: {e}"))?;
+
+        log::info!("accepting worker registrations on {address} ...");
+
+        let registry = Arc::new(Self {
+            state: Mutex::new(State::default()),
+            changes: watch::channel(0).0,
+            max_message_bytes,
+        });
+
+        let accepting = registry.clone();
+        tokio::spawn(async move {
+            loop {
+                let (socket, address) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        log::error!("could not accept registration connection: {}", e);
+                        continue;
+                    }
+                };
+
+                let registry = accepting.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = registry.accept(socket, address).await {
+                        log::warn!("[{address}] {e}");
+                    }
+                });
+            }
+        });
+
+        Ok(registry)
+    }
+
+    async fn accept(&self, mut socket: TcpStream, address: SocketAddr) -> Result<()> {
+        socket.set_nodelay(true)?;
+
+        let msg = tokio::time::timeout(
+            FIRST_MESSAGE_TIMEOUT,
+            Message::from_reader(&mut socket, self.max_message_bytes),
+        )
+        .await
+        .map_err(|_| anyhow!("no message within {:?}", FIRST_MESSAGE_TIMEOUT))??;
+
+        match msg {
+            Message::Register(registration) => self.serve(socket, address, registration).await,
+            Message::Attach { name } => loop {
+                // the clients that stopped waiting are skipped
+                let waiter = self
+                    .state
+                    .lock()
+                    .unwrap()
+                    .pending
+                    .get_mut(&name)
+                    .and_then(|waiters| waiters.pop_front());
+                match waiter {
+                    Some(waiter) => match waiter.send(socket) {
+                        Ok(()) => return Ok(()),
+                        Err(unused) => socket = unused,
+                    },
+                    None => bail!("connection from {name} wasn't asked for"),
+                }
+            },
+            msg => bail!("unexpected message {:?}", msg),
+        }
+    }
+
+    /// Keeps the registration until its connection drops, sending a Dial whenever a connection
+    /// to the worker is needed.
+    async fn serve(
+        &self,
+        socket: TcpStream,
+        address: SocketAddr,
+        registration: Registration,
+    ) -> Result<()> {
+        let name = registration.name.clone();
+
+        log::info!(
+            "{name} registered from {address}: {} layers on {} ({}, room for {} layers)",
+            registration.layers.len(),
+            &registration.device,
+            human_bytes::human_bytes(registration.memory as f64),
+            registration.layer_capacity
+        );
+
+        let (dial, mut dials) = mpsc::unbounded_channel();
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            let id = state.next_id;
+            state.next_id += 1;
+            state.workers.insert(
+                name.clone(),
+                Registered {
+                    registration,
+                    id,
+                    dial,
+                },
+            );
+            id
+        };
+        self.changes.send_modify(|changes| *changes += 1);
+
+        let (mut reader, mut writer) = socket.into_split();
+        let e = loop {
+            tokio::select! {
+                Some(()) = dials.recv() => {
+                    if let Err(e) = Message::Dial.to_writer(&mut writer).await {
+                        break e;
+                    }
+                }
+                // the worker doesn't send anything else, reading only tells when it's gone
+                res = Message::from_reader(&mut reader, self.max_message_bytes) => break match res {
+                    Ok(msg) => anyhow!("unexpected message {:?}", msg),
+                    Err(e) => e,
+                },
+            }
+        };
+
+        {
+            let mut state = self.state.lock().unwrap();
+            if state
+                .workers
+                .get(&name)
+                .is_some_and(|worker| worker.id == id)
+            {
+                state.workers.remove(&name);
+                // nothing will answer them anymore
+                state.pending.remove(&name);
+            }
+        }
+        self.changes.send_modify(|changes| *changes += 1);
+
+        bail!("{name} unregistered: {e}")
+    }
+
+    /// Opens a connection to a registered worker by asking it to dial the master.
+    pub async fn connect(&self, name: &str) -> Result<TcpStream> {
+        let (waiter, connection) = oneshot::channel();
+        {
+            let mut state = self.state.lock().unwrap();
+            let State {
+                workers, pending, ..
+            } = &mut *state;
+
+            let registered = workers
+                .get(name)
+                .is_some_and(|worker| worker.dial.send(()).is_ok());
+            if !registered {
+                bail!("{name} isn't registered");
+            }
+            pending
+                .entry(name.to_string())
+                .or_default()
+                .push_back(waiter);
+        }
+
+        tokio::time::timeout(DIAL_TIMEOUT, connection)
+            .await
+            .map_err(|_| anyhow!("{name} didn't connect within {:?}", DIAL_TIMEOUT))?
+            .map_err(|_| anyhow!("{name} unregistered"))
+    }
+
+    /// Adds a node to the topology for every registered worker, replacing the ones with the same
+    /// name.
+    pub fn extend(&self, topology: &mut Topology) {
+        let state = self.state.lock().unwrap();
+        for (name, worker) in &state.workers {
+            topology.insert(
+                name.clone(),
+                Node {
+                    host: format!("{REGISTERED_HOST_PREFIX}{name}"),
+                    description: Some(worker.registration.device.clone()),
+                    layers: worker.registration.layers.clone(),
+                    // so that the master casts the tensors it exchanges with the worker
+                    dtype: Some(worker.registration.dtype.clone()),
+                },
+            );
+        }
+    }
+
+    /// Waits until every layer of the model is served by a node of the topology or a registered
+    /// worker, then returns the topology with the registered workers added.
+    pub async fn wait_for_layers(&self, topology: &Topology, num_layers: usize) -> Topology {
+        let mut changes = self.changes.subscribe();
+        loop {
+            let mut complete = topology.clone();
+            self.extend(&mut complete);
+
+            let missing: Vec<String> = (0..num_layers)
+                .map(|layer_idx| format!("model.layers.{layer_idx}"))
+                .filter(|layer_name| complete.get_node_for_layer(layer_name).is_none())
+                .collect();
+            if missing.is_empty() {
+                return complete;
+            }
+
+            log::info!(
+                "waiting for workers to register {} of the {num_layers} layers: {}",
+                missing.len(),
+                missing.join(", ")
+            );
+
+            // the registry keeps the sender
+            let _ = changes.changed().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        cake::{CancellationToken, Master},
+        test_utils,
+    };
+
+    /// Text generated by the master.
+    async fn generate(master: &mut Master) -> String {
+        let mut text = String::new();
+        master
+            .generate(&CancellationToken::default(), |piece| text.push_str(piece))
+            .await
+            .unwrap();
+        text
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn registered_workers_complete_the_topology() {
+        let registry_addr = test_utils::free_address();
+        let topology = test_utils::topology(
+            "registered.yml",
+            &format!(
+                "registered-0: {{ host: '{}', layers: [0, 1] }}\n\
+                 registered-1: {{ host: '{}', layers: [2, 3] }}",
+                test_utils::free_address(),
+                test_utils::free_address()
+            ),
+        );
+        let extra = ["--master-addr", registry_addr.as_str()];
+        let _first = test_utils::worker("registered-0", &topology, &extra).await;
+
+        // the master has no topology file of its own
+        let mut args = test_utils::args(&["--registry-addr", &registry_addr, "--max-tokens", "8"]);
+        args.topology = test_utils::temp_dir("registered-master")
+            .join("topology.yml")
+            .display()
+            .to_string();
+        let master = tokio::spawn(test_utils::master(args));
+        // nothing serves layers 2 and 3 yet
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!master.is_finished());
+
+        let _second = test_utils::worker("registered-1", &topology, &extra).await;
+        let mut master = tokio::time::timeout(Duration::from_secs(10), master)
+            .await
+            .unwrap()
+            .unwrap();
+        for (layer_idx, name) in [
+            (0, "registered-0"),
+            (1, "registered-0"),
+            (3, "registered-1"),
+        ] {
+            let (node_name, node) = master
+                .topology()
+                .get_node_for_layer(&format!("model.layers.{layer_idx}"))
+                .unwrap();
+            assert_eq!(node_name, name);
+            assert_eq!(node.host, format!("{REGISTERED_HOST_PREFIX}{name}"));
+        }
+
+        // served over the connections the workers open
+        let mut local = test_utils::master(test_utils::args(&["--max-tokens", "8"])).await;
+        assert_eq!(generate(&mut master).await, generate(&mut local).await);
+    }
+}
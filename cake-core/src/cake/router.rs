@@ -0,0 +1,661 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use candle_core::{Device, Tensor};
+
+use crate::model::Cache;
+
+use super::{Client, ClientError, ConnectionOptions, Forwarder, WorkerStatus};
+
+/// Time a failed replica is only used as a last resort, before being tried again like the others.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Weight of the last sample in the average latency of a replica.
+const LATENCY_WEIGHT: f64 = 0.2;
+
+/// A worker serving the same layers as the other replicas.
+#[derive(Debug)]
+struct Replica {
+    address: String,
+    // connected to on demand, dropped once it fails
+    client: Option<Client>,
+    // average latency of the single token forwards, unknown until it served some
+    latency: Option<Duration>,
+    // time and reason of its last failure, cleared once it serves sequences again
+    failure: Option<(Instant, String)>,
+}
+
+impl Replica {
+    fn failed_recently(&self) -> bool {
+        self.failure
+            .as_ref()
+            .is_some_and(|(at, _)| at.elapsed() < RETRY_INTERVAL)
+    }
+}
+
+/// Serves a layer assigned to several workers. Since the workers keep the cache of the sequences
+/// they process, every sequence is served by a single replica, picked as it starts: the fastest
+/// of the ones that didn't fail recently, the ones whose latency isn't known yet being tried
+/// first. Once the replica fails, the sequences are processed again on another one.
+#[derive(Debug)]
+pub struct Router {
+    device: Device,
+    layer_name: String,
+    options: ConnectionOptions,
+    // addresses of the replicas, as the blocks served by the same workers are identified
+    ident: String,
+    replicas: Vec<Replica>,
+    // replica serving the current sequences
+    active: Option<usize>,
+    left_padding: Vec<usize>,
+    // caches the replicas process the requests with, see Forwarder::select_cache
+    cache_id: u64,
+    // replica keeping the state of each other id, None once it failed
+    stashed: HashMap<u64, Option<usize>>,
+}
+
+impl Router {
+    /// Connects to the replicas, failing only if none of them can be reached.
+    pub async fn new(
+        device: Device,
+        addresses: &[String],
+        layer_name: &str,
+        options: ConnectionOptions,
+    ) -> Result<Self> {
+        let mut router = Self {
+            device,
+            layer_name: layer_name.to_string(),
+            options,
+            ident: addresses.join(","),
+            replicas: addresses
+                .iter()
+                .map(|address| Replica {
+                    address: address.clone(),
+                    client: None,
+                    latency: None,
+                    failure: None,
+                })
+                .collect(),
+            active: None,
+            left_padding: vec![],
+            cache_id: 0,
+            stashed: HashMap::new(),
+        };
+
+        for idx in 0..router.replicas.len() {
+            // failures are logged, the replica is tried again later
+            let _ = router.connect(idx).await;
+        }
+        if router
+            .replicas
+            .iter()
+            .all(|replica| replica.client.is_none())
+        {
+            return Err(router.no_healthy_replica());
+        }
+
+        Ok(router)
+    }
+
+    async fn connect(&mut self, idx: usize) -> Result<()> {
+        if self.replicas[idx].client.is_some() {
+            return Ok(());
+        }
+
+        let res = Client::new(
+            self.device.clone(),
+            &self.replicas[idx].address,
+            &self.layer_name,
+            self.options.clone(),
+        )
+        .await;
+        let res = match res {
+            // the connections start with the caches of id 0
+            Ok(mut client) => match client.select_cache(self.cache_id).await {
+                Ok(()) => Ok(client),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(client) => {
+                self.replicas[idx].client = Some(client);
+                Ok(())
+            }
+            Err(e) => {
+                self.fail(idx, &e);
+                Err(e)
+            }
+        }
+    }
+
+    fn fail(&mut self, idx: usize, e: &anyhow::Error) {
+        let replica = &mut self.replicas[idx];
+        log::warn!(
+            "replica {} of {} failed: {e}",
+            &replica.address,
+            &self.layer_name
+        );
+
+        // the connection can't be trusted anymore
+        replica.client = None;
+        replica.failure = Some((Instant::now(), e.to_string()));
+        if self.active == Some(idx) {
+            self.active = None;
+        }
+        for kept in self.stashed.values_mut() {
+            if *kept == Some(idx) {
+                *kept = None;
+            }
+        }
+    }
+
+    fn no_healthy_replica(&self) -> anyhow::Error {
+        let reason = self
+            .replicas
+            .iter()
+            .map(|replica| match &replica.failure {
+                Some((_, reason)) => format!("{}: {reason}", &replica.address),
+                None => format!("{}: not connected", &replica.address),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        ClientError::NoHealthyReplica {
+            layers: self.layer_name.clone(),
+            reason,
+        }
+        .into()
+    }
+
+    /// Picks the replica serving the next sequences and resets its cache.
+    async fn select(&mut self) -> Result<usize> {
+        let mut candidates: Vec<usize> = (0..self.replicas.len()).collect();
+        candidates.sort_by_key(|&idx| {
+            let replica = &self.replicas[idx];
+            (replica.failed_recently(), replica.latency)
+        });
+
+        for idx in candidates {
+            if self.connect(idx).await.is_err() {
+                continue;
+            }
+
+            let left_padding = self.left_padding.clone();
+            let res = match self.replicas[idx].client.as_mut() {
+                Some(client) => client.reset(&left_padding).await,
+                None => continue,
+            };
+            match res {
+                Ok(()) => {
+                    log::debug!(
+                        "{} routed to {}",
+                        &self.layer_name,
+                        &self.replicas[idx].address
+                    );
+
+                    self.replicas[idx].failure = None;
+                    self.active = Some(idx);
+                    return Ok(idx);
+                }
+                Err(e) => self.fail(idx, &e),
+            }
+        }
+
+        Err(self.no_healthy_replica())
+    }
+
+    /// Returns the replica serving the current sequences, picking one if they're starting.
+    async fn active(&mut self, index_pos: usize) -> Result<usize> {
+        match self.active {
+            Some(idx) => Ok(idx),
+            None if index_pos > 0 => Err(self.cache_lost()),
+            None => self.select().await,
+        }
+    }
+
+    /// No replica holds the cache of the current sequences anymore.
+    fn cache_lost(&self) -> anyhow::Error {
+        ClientError::CacheLost {
+            address: self.ident.clone(),
+            reason: None,
+        }
+        .into()
+    }
+
+    fn client(&mut self, idx: usize) -> Result<&mut Client> {
+        self.replicas[idx]
+            .client
+            .as_mut()
+            .ok_or_else(|| anyhow!("replica {} is not connected", idx))
+    }
+
+    /// Records the outcome of a request to a replica, the latency of the single token forwards
+    /// included. A failed replica is replaced and the cache of the current sequences is lost,
+    /// unless no other replica can be reached.
+    async fn record<T>(
+        &mut self,
+        idx: usize,
+        res: Result<T>,
+        latency: Option<Duration>,
+    ) -> Result<T> {
+        let e = match res {
+            Ok(value) => {
+                if let Some(sample) = latency {
+                    let replica = &mut self.replicas[idx];
+                    replica.latency = Some(match replica.latency {
+                        Some(average) => {
+                            average.mul_f64(1. - LATENCY_WEIGHT) + sample.mul_f64(LATENCY_WEIGHT)
+                        }
+                        None => sample,
+                    });
+                }
+                return Ok(value);
+            }
+            // the replica reconnected by itself
+            Err(e) if matches!(e.downcast_ref(), Some(ClientError::CacheLost { .. })) => {
+                return Err(e)
+            }
+            Err(e) => e,
+        };
+
+        self.fail(idx, &e);
+        self.select().await?;
+
+        Err(ClientError::CacheLost {
+            address: self.replicas[idx].address.clone(),
+            reason: Some(e.to_string()),
+        }
+        .into())
+    }
+}
+
+impl std::fmt::Display for Router {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let replicas: Vec<String> = self
+            .replicas
+            .iter()
+            .map(|replica| match &replica.client {
+                Some(client) => format!("{} [{}]", &replica.address, &client.worker_info().device),
+                None => format!("{} [unreachable]", &replica.address),
+            })
+            .collect();
+        write!(f, "{}@{}", &self.layer_name, replicas.join(", "))
+    }
+}
+
+#[async_trait]
+impl Forwarder for Router {
+    async fn forward(
+        &mut self,
+        x: &Tensor,
+        index_pos: usize,
+        block_idx: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let idx = self.active(index_pos).await?;
+        let start = Instant::now();
+        let res = self
+            .client(idx)?
+            .forward(x, index_pos, block_idx, cache)
+            .await;
+        let latency = (x.dim(1)? == 1).then(|| start.elapsed());
+        self.record(idx, res, latency).await
+    }
+
+    async fn forward_batch(
+        &mut self,
+        x: &Tensor,
+        batch: Vec<(String, usize, usize)>,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let index_pos = batch.first().map(|(_, pos, _)| *pos).unwrap_or_default();
+        let idx = self.active(index_pos).await?;
+        let start = Instant::now();
+        let res = self.client(idx)?.forward_batch(x, batch, cache).await;
+        let latency = (x.dim(1)? == 1).then(|| start.elapsed());
+        self.record(idx, res, latency).await
+    }
+
+    async fn start_batch(
+        &mut self,
+        x: &Tensor,
+        batch: Vec<(String, usize, usize)>,
+        cache: &Cache,
+    ) -> Result<()> {
+        let index_pos = batch.first().map(|(_, pos, _)| *pos).unwrap_or_default();
+        let idx = self.active(index_pos).await?;
+        let res = self.client(idx)?.start_batch(x, batch, cache).await;
+        self.record(idx, res, None).await
+    }
+
+    async fn finish_batch(&mut self) -> Result<Tensor> {
+        let idx = self.active.ok_or_else(|| self.cache_lost())?;
+        let res = self.client(idx)?.finish_batch().await;
+        self.record(idx, res, None).await
+    }
+
+    async fn reset(&mut self, left_padding: &[usize]) -> Result<()> {
+        self.left_padding = left_padding.to_vec();
+        self.select().await.map(|_| ())
+    }
+
+    async fn select_cache(&mut self, id: u64) -> Result<()> {
+        if id == self.cache_id {
+            return Ok(());
+        }
+        if let Some(idx) = self.active.take() {
+            self.stashed.insert(self.cache_id, Some(idx));
+        }
+        self.cache_id = id;
+        let kept = self.stashed.remove(&id);
+        self.active = kept.flatten();
+
+        // every replica switches, the sequences of id may start on any of them
+        for idx in 0..self.replicas.len() {
+            let Some(client) = self.replicas[idx].client.as_mut() else {
+                continue;
+            };
+            match client.select_cache(id).await {
+                Ok(()) => {}
+                // the replica reconnected by itself
+                Err(e) if matches!(e.downcast_ref(), Some(ClientError::CacheLost { .. })) => {
+                    if self.active == Some(idx) {
+                        self.active = None;
+                    }
+                }
+                Err(e) => self.fail(idx, &e),
+            }
+        }
+
+        if kept.is_some() && self.active.is_none() {
+            return Err(self.cache_lost());
+        }
+        Ok(())
+    }
+
+    async fn drop_cache(&mut self, id: u64) -> Result<()> {
+        if id == self.cache_id {
+            self.active = None;
+        }
+        self.stashed.remove(&id);
+        for idx in 0..self.replicas.len() {
+            let Some(client) = self.replicas[idx].client.as_mut() else {
+                continue;
+            };
+            if let Err(e) = client.drop_cache(id).await {
+                self.fail(idx, &e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_kv_cache(
+        &mut self,
+        block_idxs: &[usize],
+        cache: &Cache,
+    ) -> Result<Vec<(usize, Tensor, Tensor)>> {
+        let idx = self.active.ok_or_else(|| self.cache_lost())?;
+        let res = self.client(idx)?.get_kv_cache(block_idxs, cache).await;
+        self.record(idx, res, None).await
+    }
+
+    async fn set_kv_cache(
+        &mut self,
+        kvs: Vec<(usize, Tensor, Tensor)>,
+        cache: &mut Cache,
+    ) -> Result<()> {
+        // the entries replace whatever the replica holds
+        let idx = self.active(0).await?;
+        let res = self.client(idx)?.set_kv_cache(kvs, cache).await;
+        self.record(idx, res, None).await
+    }
+
+    fn status(&self) -> Vec<WorkerStatus> {
+        self.replicas
+            .iter()
+            .map(|replica| match (&replica.client, &replica.failure) {
+                (Some(client), _) => client.status(),
+                (None, failure) => WorkerStatus {
+                    address: replica.address.clone(),
+                    healthy: false,
+                    last_seen: failure
+                        .as_ref()
+                        .map(|(at, _)| *at)
+                        .unwrap_or_else(Instant::now),
+                },
+            })
+            .collect()
+    }
+
+    fn layer_name(&self) -> &str {
+        &self.layer_name
+    }
+
+    fn ident(&self) -> &str {
+        &self.ident
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{
+        cake::{CakeError, Context, Master},
+        test_utils, Args,
+    };
+
+    /// Text generated greedily by the master.
+    async fn generate(master: &mut Master, args: &Args) -> String {
+        let tokens = master.encode("the cat sat").unwrap();
+        let mut text = String::new();
+        master
+            .generate_with(args, tokens, &Default::default(), |t| text.push_str(t))
+            .await
+            .unwrap();
+        text
+    }
+
+    /// Address forwarding the connections to target, delaying what the target sends, along with
+    /// the number of bytes the target answered with.
+    async fn slow_proxy(target: String, delay: Duration) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let answered = Arc::new(AtomicUsize::new(0));
+        let counter = answered.clone();
+        tokio::spawn(async move {
+            loop {
+                let (client, _) = listener.accept().await.unwrap();
+                let server = TcpStream::connect(&target).await.unwrap();
+                let (mut client_read, mut client_write) = client.into_split();
+                let (mut server_read, mut server_write) = server.into_split();
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 64 * 1024];
+                    while let Ok(n @ 1..) = client_read.read(&mut buf).await {
+                        if server_write.write_all(&buf[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 64 * 1024];
+                    while let Ok(n @ 1..) = server_read.read(&mut buf).await {
+                        counter.fetch_add(n, Ordering::SeqCst);
+                        tokio::time::sleep(delay).await;
+                        if client_write.write_all(&buf[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (address, answered)
+    }
+
+    #[tokio::test]
+    async fn sequences_go_to_the_fastest_replica() {
+        let (slow, fast) = (test_utils::free_address(), test_utils::free_address());
+        let topology = test_utils::topology(
+            "replicas.yml",
+            &format!(
+                "replica-0: {{ host: '{slow}', layers: [2, 3] }}\n\
+                 replica-1: {{ host: '{fast}', layers: [2, 3] }}"
+            ),
+        );
+        let _slow_worker = test_utils::worker("replica-0", &topology, &[]).await;
+        let _fast_worker = test_utils::worker("replica-1", &topology, &[]).await;
+        let (proxy, answered) = slow_proxy(slow, Duration::from_millis(20)).await;
+
+        let local_args = test_utils::args(&["--max-tokens", "4", "--ignore-eos"]);
+        let expected = generate(
+            &mut test_utils::master(local_args.clone()).await,
+            &local_args,
+        )
+        .await;
+
+        let mut args = local_args.clone();
+        args.topology = test_utils::topology(
+            "replicas-proxied.yml",
+            &format!(
+                "replica-0: {{ host: '{proxy}', layers: [2, 3] }}\n\
+                 replica-1: {{ host: '{fast}', layers: [2, 3] }}"
+            ),
+        );
+        let mut master = test_utils::master(args.clone()).await;
+
+        // both replicas are tried once to learn their latency
+        assert_eq!(generate(&mut master, &args).await, expected);
+        assert_eq!(generate(&mut master, &args).await, expected);
+        let slow_traffic = answered.load(Ordering::SeqCst);
+        assert!(slow_traffic > 0);
+
+        // then the slow one doesn't serve any forward anymore
+        assert_eq!(generate(&mut master, &args).await, expected);
+        assert_eq!(generate(&mut master, &args).await, expected);
+        assert_eq!(answered.load(Ordering::SeqCst), slow_traffic);
+    }
+
+    #[tokio::test]
+    async fn unreachable_replicas_are_skipped() {
+        let (down, up) = (test_utils::free_address(), test_utils::free_address());
+        let topology = test_utils::topology(
+            "replicas-down.yml",
+            &format!(
+                "replica-0: {{ host: '{down}', layers: [2, 3] }}\n\
+                 replica-1: {{ host: '{up}', layers: [2, 3] }}"
+            ),
+        );
+        let worker = test_utils::worker("replica-1", &topology, &[]).await;
+
+        let local_args = test_utils::args(&["--max-tokens", "4", "--ignore-eos"]);
+        let expected = generate(
+            &mut test_utils::master(local_args.clone()).await,
+            &local_args,
+        )
+        .await;
+
+        let mut args = test_utils::args(&[
+            "--max-tokens",
+            "4",
+            "--ignore-eos",
+            "--reconnect-attempts",
+            "0",
+        ]);
+        args.topology = topology;
+        let mut master = test_utils::master(args.clone()).await;
+        assert_eq!(generate(&mut master, &args).await, expected);
+        assert_eq!(generate(&mut master, &args).await, expected);
+
+        // none of them is healthy once the last one is down as well
+        drop(worker);
+        while TcpStream::connect(&up).await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        match Master::new(Context::from_args(args).unwrap()).await {
+            Err(CakeError::NoHealthyReplica { layers, reason }) => {
+                assert!(layers.contains("model.layers.2"), "{layers}");
+                assert!(reason.contains(&down) && reason.contains(&up), "{reason}");
+            }
+            res => panic!("unexpected result {:?}", res.err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_in_turns_across_the_replicas() {
+        let addresses = [test_utils::free_address(), test_utils::free_address()];
+        let topology = test_utils::topology(
+            "replicas-sessions.yml",
+            &format!(
+                "replica-0: {{ host: '{}', layers: [2, 3] }}\n\
+                 replica-1: {{ host: '{}', layers: [2, 3] }}",
+                addresses[0], addresses[1]
+            ),
+        );
+        let _workers = [
+            test_utils::worker("replica-0", &topology, &[]).await,
+            test_utils::worker("replica-1", &topology, &[]).await,
+        ];
+        let mut args = test_utils::args(&["--max-tokens", "4", "--ignore-eos"]);
+        args.topology = topology;
+        let mut master = test_utils::master(args).await;
+        let prompts = ["the cat sat", "a big dog ran home"];
+
+        // each session on its own, then continued
+        let mut expected = vec![];
+        for prompt in prompts {
+            let session_id = master.create_session();
+            for prompt in [prompt, " and"] {
+                let mut text = String::new();
+                master
+                    .generate_in(session_id, prompt, |t| text.push_str(t))
+                    .await
+                    .unwrap();
+                expected.push(text);
+            }
+            master.drop_session(session_id).unwrap();
+        }
+
+        // the sessions in turns, whose sequences each replica may serve
+        let session_ids = [master.create_session(), master.create_session()];
+        let mut texts = [String::new(), String::new()];
+        master
+            .generate_sessions(
+                session_ids
+                    .iter()
+                    .zip(prompts)
+                    .map(|(id, prompt)| (*id, prompt.to_string()))
+                    .collect(),
+                |session_id, text| {
+                    let i = session_ids.iter().position(|id| *id == session_id).unwrap();
+                    texts[i].push_str(text);
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(texts, [expected[0].clone(), expected[2].clone()]);
+        for (i, session_id) in session_ids.into_iter().enumerate() {
+            let mut text = String::new();
+            master
+                .generate_in(session_id, " and", |t| text.push_str(t))
+                .await
+                .unwrap();
+            assert_eq!(text, expected[2 * i + 1]);
+        }
+    }
+}
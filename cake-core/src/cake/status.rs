@@ -0,0 +1,167 @@
+use std::{fmt, time::Duration};
+
+use candle_core::Device;
+use serde::Serialize;
+
+use super::{Client, ConnectionOptions, Topology, WorkerStatus};
+
+/// Time a worker has to answer a status query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Status of a worker of the topology.
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeStatus {
+    pub name: String,
+    pub host: String,
+    /// Layers assigned to the worker by the topology.
+    pub layers: Vec<String>,
+    /// Whether the worker answered the status query.
+    pub reachable: bool,
+    /// Why the worker couldn't be queried.
+    pub error: Option<String>,
+    pub device: Option<String>,
+    pub dtype: Option<String>,
+    /// Layers the worker has actually loaded.
+    pub loaded_layers: Vec<String>,
+    pub connections: Option<usize>,
+    /// Number of forward requests the worker is processing.
+    pub in_flight: Option<usize>,
+    /// Number of forward requests waiting to be processed by the worker.
+    pub queued: Option<usize>,
+    /// Milliseconds since the worker last processed a forward request.
+    pub idle_ms: Option<u64>,
+    /// Whether the connections of the master to the worker are healthy, if known.
+    pub healthy: Option<bool>,
+    /// Milliseconds since the master last received a message or heartbeat from the worker.
+    pub last_seen_ms: Option<u64>,
+}
+
+/// Status of every worker of the topology.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClusterStatus {
+    pub workers: Vec<NodeStatus>,
+}
+
+impl ClusterStatus {
+    /// Queries every worker of the topology over a new connection.
+    pub async fn query(topology: &Topology, options: &ConnectionOptions) -> Self {
+        // fail fast, the worker is reported as unreachable
+        let options = ConnectionOptions {
+            reconnect_attempts: 0,
+            ..options.clone()
+        };
+
+        // local nodes are served by the master
+        let mut names: Vec<&String> = topology
+            .iter()
+            .filter(|(_, node)| !node.is_local())
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+
+        let mut workers = vec![];
+        for name in names {
+            let node = &topology[name];
+            let state = tokio::time::timeout(QUERY_TIMEOUT, async {
+                let mut client = Client::new(Device::Cpu, &node.host, "", options.clone()).await?;
+                client.state().await
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("no answer within {:?}", QUERY_TIMEOUT)));
+
+            let mut status = NodeStatus {
+                name: name.clone(),
+                host: node.host.clone(),
+                layers: node.layers.clone(),
+                reachable: state.is_ok(),
+                error: None,
+                device: None,
+                dtype: None,
+                loaded_layers: vec![],
+                connections: None,
+                in_flight: None,
+                queued: None,
+                idle_ms: None,
+                healthy: None,
+                last_seen_ms: None,
+            };
+            match state {
+                Ok(state) => {
+                    status.device = Some(state.device);
+                    status.dtype = Some(state.dtype);
+                    status.loaded_layers = state.layers;
+                    status.connections = Some(state.connections);
+                    status.in_flight = Some(state.in_flight);
+                    status.queued = Some(state.queued);
+                    status.idle_ms = state.idle_ms;
+                }
+                Err(e) => status.error = Some(e.to_string()),
+            }
+            workers.push(status);
+        }
+
+        Self { workers }
+    }
+
+    /// Adds the liveness of the connections of a master to the status of the workers.
+    pub fn with_liveness(mut self, liveness: &[WorkerStatus]) -> Self {
+        for worker in self.workers.iter_mut() {
+            if let Some(live) = liveness.iter().find(|live| live.address == worker.host) {
+                worker.healthy = Some(live.healthy);
+                worker.last_seen_ms = Some(live.last_seen.elapsed().as_millis() as u64);
+            }
+        }
+        self
+    }
+
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| anyhow!(e))
+    }
+}
+
+impl fmt::Display for ClusterStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let opt = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+
+        writeln!(
+            f,
+            "{:<16} {:<24} {:<8} {:<6} {:>6} {:>5} {:>9} {:>6} {:>10} {:>10}",
+            "worker",
+            "host",
+            "status",
+            "dtype",
+            "layers",
+            "conn",
+            "in-flight",
+            "queued",
+            "idle (ms)",
+            "seen (ms)"
+        )?;
+        for worker in &self.workers {
+            let status = match (worker.reachable, worker.healthy) {
+                (false, _) => "down",
+                (true, Some(false)) => "unhealthy",
+                (true, _) => "up",
+            };
+            writeln!(
+                f,
+                "{:<16} {:<24} {:<8} {:<6} {:>6} {:>5} {:>9} {:>6} {:>10} {:>10}",
+                worker.name,
+                worker.host,
+                status,
+                opt(worker.dtype.clone()),
+                worker.layers.len(),
+                opt(worker.connections.map(|n| n.to_string())),
+                opt(worker.in_flight.map(|n| n.to_string())),
+                opt(worker.queued.map(|n| n.to_string())),
+                opt(worker.idle_ms.map(|ms| ms.to_string())),
+                opt(worker.last_seen_ms.map(|ms| ms.to_string())),
+            )?;
+            if let Some(error) = &worker.error {
+                writeln!(f, "  {error}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+use std::{fs::File, io::BufReader, sync::Arc};
+
+use anyhow::Result;
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::Args;
+
+/// A connection between the master and a worker, plain or encrypted.
+pub trait Stream: AsyncRead + AsyncWrite + Send + Sync + Unpin + std::fmt::Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin + std::fmt::Debug> Stream for T {}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).map_err(|e| anyhow!("can't open {path}: {:?}", e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("can't parse certificates from {path}: {:?}", e))?;
+    if certs.is_empty() {
+        bail!("no certificates found in {path}");
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).map_err(|e| anyhow!("can't open {path}: {:?}", e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| anyhow!("can't parse private key from {path}: {:?}", e))?
+        .ok_or_else(|| anyhow!("no private key found in {path}"))
+}
+
+fn load_roots(path: &str) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .map_err(|e| anyhow!("invalid ca certificate in {path}: {:?}", e))?;
+    }
+    Ok(roots)
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Returns the configuration used by the master, if TLS is enabled with --tls-ca. The workers
+/// must present a certificate signed by that ca, --tls-cert and --tls-key provide the certificate
+/// of the master when the workers require one.
+pub fn client_config(args: &Args) -> Result<Option<Arc<ClientConfig>>> {
+    let ca = if let Some(ca) = &args.tls_ca {
+        ca
+    } else {
+        return Ok(None);
+    };
+
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(load_roots(ca)?);
+
+    let config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => bail!("--tls-cert and --tls-key must be provided together"),
+    };
+
+    Ok(Some(Arc::new(config)))
+}
+
+/// Returns the acceptor used by a worker, if TLS is enabled with --tls-cert and --tls-key. When
+/// --tls-ca is also provided, only clients with a certificate signed by that ca are accepted.
+pub fn acceptor(args: &Args) -> Result<Option<TlsAcceptor>> {
+    let (cert, key) = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) => return Ok(None),
+        _ => bail!("--tls-cert and --tls-key must be provided together"),
+    };
+
+    let builder =
+        ServerConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+
+    let builder = if let Some(ca) = &args.tls_ca {
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(load_roots(ca)?), provider())
+                .build()?;
+        builder.with_client_cert_verifier(verifier)
+    } else {
+        builder.with_no_client_auth()
+    };
+
+    let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+/// Connects to a worker, over TLS if a configuration is provided.
+pub async fn connect(address: &str, tls: Option<&Arc<ClientConfig>>) -> Result<Box<dyn Stream>> {
+    let stream = TcpStream::connect(address).await?;
+    // every forward waits for the previous one, so the messages are sent without delay rather
+    // than coalesced with the next ones
+    stream.set_nodelay(true)?;
+    connect_stream(stream, address, tls).await
+}
+
+/// Same as connect over an established connection, the certificate of the worker must be valid
+/// for the host of address.
+pub async fn connect_stream(
+    stream: TcpStream,
+    address: &str,
+    tls: Option<&Arc<ClientConfig>>,
+) -> Result<Box<dyn Stream>> {
+    if let Some(config) = tls {
+        // the certificate of the worker must be valid for the host it's reached at
+        let host = address
+            .rsplit_once(':')
+            .map(|(host, _)| host)
+            .unwrap_or(address)
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|e| anyhow!("invalid host name {host}: {:?}", e))?;
+        let connector = TlsConnector::from(config.clone());
+        Ok(Box::new(connector.connect(server_name, stream).await?))
+    } else {
+        Ok(Box::new(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use crate::{
+        cake::{Client, ConnectionOptions, Context, Forwarder},
+        model::{Block, Cache},
+        test_utils,
+    };
+
+    /// Certificates of tests/tls, signed by ca.pem except for rogue.pem.
+    fn pem(name: &str) -> String {
+        format!("{}/tests/tls/{name}", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    /// Starts a worker serving the first layer over TLS, requiring a client certificate if mtls.
+    async fn tls_worker(name: &str, mtls: bool) -> (test_utils::TestWorker, String) {
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            &format!("{name}.yml"),
+            &format!("{name}: {{ host: '{address}', layers: [0] }}"),
+        );
+        let (cert, key, ca) = (pem("worker.pem"), pem("worker.key"), pem("ca.pem"));
+        let mut extra = vec!["--tls-cert", &cert, "--tls-key", &key];
+        if mtls {
+            extra.extend(["--tls-ca", &ca]);
+        }
+        (test_utils::worker(name, &topology, &extra).await, address)
+    }
+
+    async fn connect(address: &str, extra: &[&str]) -> anyhow::Result<Client> {
+        let options = ConnectionOptions::from_args(&test_utils::args(extra))?;
+        Client::new(Device::Cpu, address, "model.layers.0", options).await
+    }
+
+    #[tokio::test]
+    async fn forward_over_mtls() {
+        let (_worker, address) = tls_worker("tls-worker", true).await;
+        let (ca, cert, key) = (pem("ca.pem"), pem("master.pem"), pem("master.key"));
+        let mut client = connect(
+            &address,
+            &["--tls-ca", &ca, "--tls-cert", &cert, "--tls-key", &key],
+        )
+        .await
+        .unwrap();
+
+        let ctx = Context::from_args(test_utils::args(&[])).unwrap();
+        let mut block = Block::load(
+            "model.layers.0",
+            ctx.var_builder.pp("model.layers.0"),
+            &ctx.config,
+        )
+        .unwrap();
+        let x = Tensor::randn(0f32, 1., (1, 3, 64), &Device::Cpu).unwrap();
+        let mut cache = Cache::new(true, DType::F32, &ctx.config, &Device::Cpu).unwrap();
+        let y = client.forward(&x, 0, 0, &mut cache).await.unwrap();
+        let expected = block
+            .forward(&x, 0, 0, &mut ctx.cache.as_new())
+            .await
+            .unwrap();
+        assert_eq!(
+            y.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+            expected.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn mtls_rejects_unknown_clients() {
+        let (_worker, address) = tls_worker("mtls-worker", true).await;
+        let ca = pem("ca.pem");
+        // no certificate
+        assert!(connect(&address, &["--tls-ca", &ca]).await.is_err());
+        // a certificate of another ca
+        let (cert, key) = (pem("rogue.pem"), pem("rogue.key"));
+        assert!(connect(
+            &address,
+            &["--tls-ca", &ca, "--tls-cert", &cert, "--tls-key", &key]
+        )
+        .await
+        .is_err());
+        // plaintext
+        assert!(connect(&address, &[]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tls_without_client_certificates() {
+        let (_worker, address) = tls_worker("tls-only-worker", false).await;
+        let ca = pem("ca.pem");
+        connect(&address, &["--tls-ca", &ca]).await.unwrap();
+        // the certificate of the worker must be signed by the ca of the master
+        let rogue = pem("rogue.pem");
+        assert!(connect(&address, &["--tls-ca", &rogue]).await.is_err());
+    }
+}
@@ -1,19 +1,44 @@
 use std::collections::HashMap;
 
 use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use candle_core::DType;
+use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(Serialize, Deserialize)]
+use super::WorkerInfo;
+use crate::{model::Config, utils};
+
+/// Host of the nodes whose layers are served by the master itself.
+pub const LOCAL_HOST: &str = "local";
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Node {
     pub host: String,
     pub description: Option<String>,
+    /// Names of the layers the node serves, in any order. The topology file can also list their
+    /// indices, 2 standing for model.layers.2.
+    #[serde(deserialize_with = "deserialize_layers")]
     pub layers: Vec<String>,
+    /// Dtype the node loads its layers in, overriding --dtype.
+    pub dtype: Option<String>,
 }
 
 impl Node {
+    pub fn dtype(&self) -> Result<Option<DType>> {
+        self.dtype.as_deref().map(utils::parse_dtype).transpose()
+    }
+
+    /// Returns true if the layers of this node are served by the master.
+    pub fn is_local(&self) -> bool {
+        self.host == LOCAL_HOST
+    }
+
     pub fn is_layer_owner(&self, full_layer_name: &str) -> bool {
         for prefix in &self.layers {
-            if full_layer_name.starts_with(prefix) {
+            // model.layers.1 doesn't own model.layers.10
+            if full_layer_name
+                .strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+            {
                 return true;
             }
         }
@@ -21,7 +46,28 @@ impl Node {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// A layer of a node in the topology file, by name or index.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LayerEntry {
+    Index(usize),
+    Name(String),
+}
+
+fn deserialize_layers<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Vec::<LayerEntry>::deserialize(deserializer)?
+        .into_iter()
+        .map(|entry| match entry {
+            LayerEntry::Index(layer_idx) => format!("model.layers.{layer_idx}"),
+            LayerEntry::Name(layer_name) => layer_name,
+        })
+        .collect())
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
 pub struct Topology(HashMap<String, Node>);
 
 impl Topology {
@@ -29,6 +75,298 @@ impl Topology {
         serde_yaml::from_str(&std::fs::read_to_string(path)?).map_err(|e| anyhow!(e))
     }
 
+    /// Assigns every layer of the model to the workers, each one gets a contiguous range of
+    /// layers proportional to the memory it advertised.
+    pub fn auto_balance(workers: &[WorkerInfo], config: &Config, dtype: DType) -> Result<Self> {
+        let num_layers = config.num_hidden_layers;
+        let layer_size = Self::layer_size(config, dtype) as u64;
+        // number of layers each worker can hold at most
+        let capacities: Vec<usize> = workers
+            .iter()
+            .map(|worker| (worker.memory / layer_size) as usize)
+            .collect();
+
+        if capacities.iter().sum::<usize>() < num_layers {
+            let available: u64 = workers.iter().map(|worker| worker.memory).sum();
+            let required = num_layers as u64 * layer_size;
+            bail!(
+                "the workers can't hold the {num_layers} layers of the model: {} required ({} per layer), {} available ({} short, memory may also be too fragmented across workers) - {}",
+                human_bytes::human_bytes(required as f64),
+                human_bytes::human_bytes(layer_size as f64),
+                human_bytes::human_bytes(available as f64),
+                human_bytes::human_bytes(required.saturating_sub(available) as f64),
+                workers
+                    .iter()
+                    .zip(&capacities)
+                    .map(|(worker, capacity)| format!(
+                        "{}: {} ({capacity} layers)",
+                        &worker.name,
+                        human_bytes::human_bytes(worker.memory as f64)
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        // proportional shares, rounded down and then topped up by largest remainder
+        let total: u64 = workers.iter().map(|worker| worker.memory).sum();
+        let shares: Vec<f64> = workers
+            .iter()
+            .map(|worker| num_layers as f64 * worker.memory as f64 / total as f64)
+            .collect();
+        let mut counts: Vec<usize> = shares
+            .iter()
+            .zip(&capacities)
+            .map(|(share, capacity)| (share.floor() as usize).min(*capacity))
+            .collect();
+
+        while counts.iter().sum::<usize>() < num_layers {
+            let (idx, _) = shares
+                .iter()
+                .enumerate()
+                .filter(|(idx, _)| counts[*idx] < capacities[*idx])
+                .max_by(|(a, share_a), (b, share_b)| {
+                    (*share_a - counts[*a] as f64).total_cmp(&(*share_b - counts[*b] as f64))
+                })
+                .unwrap();
+            counts[idx] += 1;
+        }
+
+        let mut nodes = HashMap::new();
+        let mut first = 0;
+        for (worker, count) in workers.iter().zip(counts) {
+            let layers = (first..first + count)
+                .map(|layer_idx| format!("model.layers.{layer_idx}"))
+                .collect();
+            first += count;
+
+            log::info!(
+                "{} will serve {count} layers ({})",
+                &worker.name,
+                human_bytes::human_bytes(worker.memory as f64)
+            );
+
+            nodes.insert(
+                worker.name.clone(),
+                Node {
+                    host: worker.host.clone(),
+                    description: Some(worker.device.clone()),
+                    layers,
+                    dtype: None,
+                },
+            );
+        }
+
+        Ok(Self(nodes))
+    }
+
+    /// Size in bytes of the weights of a single transformer block.
+    fn layer_size(config: &Config, dtype: DType) -> usize {
+        let head_dim = config.hidden_size / config.num_attention_heads;
+        let kv_size = config.num_key_value_heads * head_dim;
+        let attention =
+            2 * config.hidden_size * config.hidden_size + 2 * config.hidden_size * kv_size;
+        let mlp = 3 * config.hidden_size * config.intermediate_size;
+        let norms = 2 * config.hidden_size;
+
+        (attention + mlp + norms) * dtype.size_in_bytes()
+    }
+
+    /// Checks that every layer of the topology exists in the model and is listed once per node,
+    /// that the node dtypes are supported and that the layers served by the master are not
+    /// assigned to other nodes. The layers of a node don't need to be contiguous.
+    /// Layers that are not assigned to any node, or assigned to a node whose host is local, are
+    /// served by the master. Layers assigned to several workers are replicated on them.
+    pub fn validate(&self, config: &Config) -> Result<()> {
+        let mut owners: Vec<Vec<&str>> = vec![vec![]; config.num_hidden_layers];
+
+        for (node_name, node) in &self.0 {
+            if node.layers.is_empty() {
+                log::warn!("node {node_name} has no layers assigned");
+            }
+
+            node.dtype()
+                .map_err(|e| anyhow!("invalid dtype for node {node_name}: {e}"))?;
+            if node.is_local() && node.dtype.is_some() {
+                bail!("node {node_name} is served by the master, its layers are loaded in --dtype");
+            }
+
+            for layer_name in &node.layers {
+                let layer_idx = layer_name
+                    .strip_prefix("model.layers.")
+                    .and_then(|idx| idx.parse::<usize>().ok())
+                    .filter(|idx| *idx < config.num_hidden_layers)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "node {node_name} serves {layer_name}, which is not a layer of the model (expected model.layers.0 to model.layers.{})",
+                            config.num_hidden_layers - 1
+                        )
+                    })?;
+
+                if owners[layer_idx].contains(&node_name.as_str()) {
+                    bail!("node {node_name} lists {layer_name} more than once");
+                }
+                owners[layer_idx].push(node_name);
+            }
+        }
+
+        let mut local = vec![];
+        let mut replicated = vec![];
+        for (layer_idx, nodes) in owners.iter_mut().enumerate() {
+            match nodes.len() {
+                0 => local.push(layer_idx),
+                1 if self.0[nodes[0]].is_local() => local.push(layer_idx),
+                1 => {}
+                _ if nodes.iter().any(|node| self.0[*node].is_local()) => {
+                    nodes.sort();
+                    bail!(
+                        "model.layers.{layer_idx} is assigned more than once and served by the master: {}",
+                        nodes.join(", ")
+                    );
+                }
+                _ => replicated.push(layer_idx),
+            }
+        }
+
+        if !replicated.is_empty() {
+            log::info!(
+                "{} layers are replicated on several workers: {:?}",
+                replicated.len(),
+                &replicated
+            );
+        }
+
+        if !local.is_empty() {
+            log::info!(
+                "{} layers are not assigned to any worker and will be served by the master: {:?}",
+                local.len(),
+                &local
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Returns the nodes serving the layer sorted by name, several ones being replicas.
+    pub fn get_nodes_for_layer(&self, layer_name: &str) -> Vec<(&str, &Node)> {
+        let mut nodes: Vec<(&str, &Node)> = self
+            .0
+            .iter()
+            .filter(|(_, node)| node.layers.iter().any(|name| name == layer_name))
+            .map(|(name, node)| (name.as_str(), node))
+            .collect();
+        nodes.sort_by_key(|(name, _)| *name);
+        nodes
+    }
+
+    /// Adds a node, replacing the one with the same name if any.
+    pub fn insert(&mut self, name: String, node: Node) -> Option<Node> {
+        self.0.insert(name, node)
+    }
+
+    /// Renders the topology as a Graphviz diagram: a box per node with its host, layers and dtype,
+    /// and the activations going from node to node in the order of the layers, from the
+    /// embeddings of the master back to its lm_head.
+    pub fn to_dot(&self, config: &Config) -> String {
+        const MASTER_ID: &str = "master";
+
+        // the ids of the nodes serving each layer, the master for the ones no worker serves
+        let owners: Vec<Vec<String>> = (0..config.num_hidden_layers)
+            .map(|layer_idx| {
+                let owners: Vec<String> = self
+                    .get_nodes_for_layer(&format!("model.layers.{layer_idx}"))
+                    .into_iter()
+                    .filter(|(_, node)| !node.is_local())
+                    .map(|(name, _)| format!("worker:{name}"))
+                    .collect();
+                if owners.is_empty() {
+                    vec![MASTER_ID.to_string()]
+                } else {
+                    owners
+                }
+            })
+            .collect();
+
+        let mut dot = String::from("digraph topology {\n  rankdir=LR;\n  node [shape=box];\n");
+
+        let local: Vec<usize> = (0..config.num_hidden_layers)
+            .filter(|layer_idx| owners[*layer_idx] == [MASTER_ID])
+            .collect();
+        let mut label = "master\nembeddings, lm_head".to_string();
+        if !local.is_empty() {
+            label += &format!("\nlayers {}", layer_ranges(&local));
+        }
+        dot += &format!("  {} [label={}];\n", quote(MASTER_ID), quote(&label));
+
+        let mut names: Vec<&String> = self.0.keys().collect();
+        names.sort();
+        for name in names {
+            let node = &self.0[name];
+            if node.is_local() {
+                continue;
+            }
+            let mut layers: Vec<usize> = node
+                .layers
+                .iter()
+                .filter_map(|layer_name| layer_name.strip_prefix("model.layers.")?.parse().ok())
+                .collect();
+            layers.sort();
+
+            let mut label = format!("{name}\n{}", &node.host);
+            if let Some(description) = &node.description {
+                label += &format!("\n{description}");
+            }
+            label += &format!("\nlayers {}", layer_ranges(&layers));
+            if let Some(dtype) = &node.dtype {
+                label += &format!("\n{dtype}");
+            }
+            dot += &format!(
+                "  {} [label={}];\n",
+                quote(&format!("worker:{name}")),
+                quote(&label)
+            );
+        }
+
+        // an edge per hop, from every replica of the previous layers to every one of the next
+        let mut from = vec![MASTER_ID.to_string()];
+        let mut first = 0;
+        for layer_idx in 1..=config.num_hidden_layers {
+            if layer_idx < config.num_hidden_layers && owners[layer_idx] == owners[first] {
+                continue;
+            }
+            let to = &owners[first];
+            let layers: Vec<usize> = (first..layer_idx).collect();
+            let label = format!("layers {}", layer_ranges(&layers));
+            for src in &from {
+                for dst in to {
+                    if src != dst {
+                        dot += &format!(
+                            "  {} -> {} [label={}];\n",
+                            quote(src),
+                            quote(dst),
+                            quote(&label)
+                        );
+                    }
+                }
+            }
+            from = to.clone();
+            first = layer_idx;
+        }
+        for src in &from {
+            if src != MASTER_ID {
+                dot += &format!(
+                    "  {} -> {} [label={}];\n",
+                    quote(src),
+                    quote(MASTER_ID),
+                    quote("lm_head")
+                );
+            }
+        }
+
+        dot += "}\n";
+        dot
+    }
+
     pub fn get_node_for_layer(&self, layer_name: &str) -> Option<(&str, &Node)> {
         for (node_name, node) in &self.0 {
             for node_layer_name in &node.layers {
@@ -41,9 +379,340 @@ impl Topology {
     }
 }
 
+/// Sorted layer indices as ranges, such as 0-3, 5.
+fn layer_ranges(layers: &[usize]) -> String {
+    let mut ranges: Vec<String> = vec![];
+    let mut start = 0;
+    for idx in 1..=layers.len() {
+        if idx < layers.len() && layers[idx] == layers[idx - 1] + 1 {
+            continue;
+        }
+        ranges.push(if layers[start] == layers[idx - 1] {
+            layers[start].to_string()
+        } else {
+            format!("{}-{}", layers[start], layers[idx - 1])
+        });
+        start = idx;
+    }
+    ranges.join(", ")
+}
+
+/// A Graphviz string literal, its lines separated with centered line breaks.
+fn quote(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
 impl std::ops::Deref for Topology {
     type Target = HashMap<String, Node>;
     fn deref(&self) -> &HashMap<String, Node> {
         &self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cake::{CakeError, Context},
+        test_utils,
+    };
+
+    fn topology(yaml: &str) -> Topology {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn invalid(yaml: &str) -> String {
+        topology(yaml)
+            .validate(&test_utils::model_config())
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn full_cover() {
+        let config = test_utils::model_config();
+        topology(
+            "
+            w0: { host: '127.0.0.1:1', layers: [0, 1] }
+            w1: { host: '127.0.0.1:2', layers: ['model.layers.2', 'model.layers.3'] }
+            ",
+        )
+        .validate(&config)
+        .unwrap();
+        // the layers of a node don't need to be contiguous
+        topology(
+            "
+            w0: { host: '127.0.0.1:1', layers: [0, 2] }
+            w1: { host: '127.0.0.1:2', layers: [1, 3] }
+            ",
+        )
+        .validate(&config)
+        .unwrap();
+    }
+
+    #[test]
+    fn gap_is_served_by_the_master() {
+        let config = test_utils::model_config();
+        topology("w0: { host: '127.0.0.1:1', layers: [0, 3] }")
+            .validate(&config)
+            .unwrap();
+        topology("{}").validate(&config).unwrap();
+    }
+
+    #[test]
+    fn node_dtypes() {
+        let topology = topology(
+            "
+            w0: { host: '127.0.0.1:1', layers: [0, 1], dtype: bf16 }
+            w1: { host: '127.0.0.1:2', layers: [2, 3] }
+            ",
+        );
+        assert_eq!(
+            topology.get("w0").unwrap().dtype().unwrap(),
+            Some(DType::BF16)
+        );
+        assert_eq!(topology.get("w1").unwrap().dtype().unwrap(), None);
+
+        assert!(
+            invalid("w0: { host: '127.0.0.1:1', layers: [0], dtype: f8 }")
+                .starts_with("invalid dtype for node w0")
+        );
+        assert_eq!(
+            invalid("m: { host: local, layers: [0], dtype: f16 }"),
+            "node m is served by the master, its layers are loaded in --dtype"
+        );
+    }
+
+    #[test]
+    fn duplicate_assignment() {
+        assert_eq!(
+            invalid("w0: { host: '127.0.0.1:1', layers: [0, 1, 0] }"),
+            "node w0 lists model.layers.0 more than once"
+        );
+        assert_eq!(
+            invalid(
+                "
+                w0: { host: '127.0.0.1:1', layers: [0, 1] }
+                m: { host: local, layers: [1, 2] }
+                "
+            ),
+            "model.layers.1 is assigned more than once and served by the master: m, w0"
+        );
+        // replicas of the same layer on different workers
+        topology(
+            "
+            w0: { host: '127.0.0.1:1', layers: [0, 1] }
+            w1: { host: '127.0.0.1:2', layers: [0, 1] }
+            ",
+        )
+        .validate(&test_utils::model_config())
+        .unwrap();
+    }
+
+    #[test]
+    fn layer_outside_of_the_model() {
+        assert_eq!(
+            invalid("w0: { host: '127.0.0.1:1', layers: [0, 4] }"),
+            "node w0 serves model.layers.4, which is not a layer of the model (expected model.layers.0 to model.layers.3)"
+        );
+        assert!(invalid("w0: { host: '127.0.0.1:1', layers: [lm_head] }")
+            .starts_with("node w0 serves lm_head"));
+        assert!(
+            invalid("w0: { host: '127.0.0.1:1', layers: [0], dtype: f8 }")
+                .starts_with("invalid dtype for node w0")
+        );
+    }
+
+    #[test]
+    fn invalid_topology_fails_the_context() {
+        let path = test_utils::topology(
+            "topology-duplicate.yml",
+            "w0: { host: '127.0.0.1:1', layers: [1, 1] }",
+        );
+        let mut args = test_utils::args(&[]);
+        args.topology = path;
+        assert!(matches!(
+            Context::from_args(args),
+            Err(CakeError::TopologyInvalid { reason }) if reason.contains("more than once")
+        ));
+    }
+
+    #[tokio::test]
+    async fn interleaved_layers_like_contiguous_ones() {
+        // text generated with the layers of the two workers assigned as given
+        let generate = |name: &'static str, layers: [[usize; 2]; 2]| async move {
+            let addresses = [test_utils::free_address(), test_utils::free_address()];
+            let path = test_utils::topology(
+                &format!("{name}.yml"),
+                &format!(
+                    "{name}-0: {{ host: '{}', layers: {:?} }}\n{name}-1: {{ host: '{}', layers: {:?} }}",
+                    addresses[0], layers[0], addresses[1], layers[1]
+                ),
+            );
+            let _workers = [
+                test_utils::worker(&format!("{name}-0"), &path, &[]).await,
+                test_utils::worker(&format!("{name}-1"), &path, &[]).await,
+            ];
+            let mut args = test_utils::args(&["--max-tokens", "8"]);
+            args.topology = path;
+            let mut master = test_utils::master(args).await;
+            let mut text = String::new();
+            master
+                .generate(&crate::cake::CancellationToken::default(), |piece| {
+                    text.push_str(piece)
+                })
+                .await
+                .unwrap();
+            text
+        };
+
+        // every layer is a hop to the other worker
+        let interleaved = generate("interleaved", [[0, 2], [1, 3]]).await;
+        let contiguous = generate("contiguous", [[0, 1], [2, 3]]).await;
+        assert!(!interleaved.is_empty());
+        assert_eq!(interleaved, contiguous);
+    }
+
+    #[test]
+    fn dot_of_the_nodes_in_layer_order() {
+        let dot = topology(
+            "
+            w1: { host: '127.0.0.1:2', layers: [3] }
+            w0: { host: '127.0.0.1:1', layers: [0, 1], dtype: f16 }
+            ",
+        )
+        .to_dot(&test_utils::model_config());
+
+        // a node per worker, the master serving layer 2
+        assert!(dot.starts_with("digraph topology {"), "{dot}");
+        for node in [
+            r#""master" [label="master\nembeddings, lm_head\nlayers 2"];"#,
+            r#""worker:w0" [label="w0\n127.0.0.1:1\nlayers 0-1\nf16"];"#,
+            r#""worker:w1" [label="w1\n127.0.0.1:2\nlayers 3"];"#,
+        ] {
+            assert!(dot.contains(node), "{node} not in {dot}");
+        }
+        let edges: Vec<&str> = dot.lines().filter(|line| line.contains("->")).collect();
+        assert_eq!(
+            edges,
+            [
+                r#"  "master" -> "worker:w0" [label="layers 0-1"];"#,
+                r#"  "worker:w0" -> "master" [label="layers 2"];"#,
+                r#"  "master" -> "worker:w1" [label="layers 3"];"#,
+                r#"  "worker:w1" -> "master" [label="lm_head"];"#,
+            ]
+        );
+    }
+
+    fn worker(name: &str, memory: u64) -> WorkerInfo {
+        WorkerInfo {
+            name: name.to_string(),
+            host: format!("{name}:10128"),
+            memory,
+            ..Default::default()
+        }
+    }
+
+    /// Indices of the layers of a node, in order.
+    fn layers(topology: &Topology, name: &str) -> Vec<usize> {
+        topology.0[name]
+            .layers
+            .iter()
+            .map(|layer| {
+                layer
+                    .strip_prefix("model.layers.")
+                    .unwrap()
+                    .parse()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn auto_balance_by_memory() {
+        let config = Config {
+            num_hidden_layers: 32,
+            ..test_utils::model_config()
+        };
+        let layer_size = Topology::layer_size(&config, DType::F16) as u64;
+        let workers = [
+            worker("small", 10 * layer_size),
+            worker("medium", 20 * layer_size),
+            worker("large", 50 * layer_size),
+        ];
+        let topology = Topology::auto_balance(&workers, &config, DType::F16).unwrap();
+        topology.validate(&config).unwrap();
+
+        // 32 layers shared 1:2:5, contiguous and in the order of the workers
+        assert_eq!(layers(&topology, "small"), (0..4).collect::<Vec<_>>());
+        assert_eq!(layers(&topology, "medium"), (4..12).collect::<Vec<_>>());
+        assert_eq!(layers(&topology, "large"), (12..32).collect::<Vec<_>>());
+        assert_eq!(topology.0["large"].host, "large:10128");
+    }
+
+    #[test]
+    fn auto_balance_rounds_to_a_full_cover() {
+        let config = test_utils::model_config();
+        let layer_size = Topology::layer_size(&config, DType::F32) as u64;
+        let workers = [
+            worker("a", 3 * layer_size),
+            worker("b", 3 * layer_size),
+            worker("c", 3 * layer_size),
+        ];
+        let topology = Topology::auto_balance(&workers, &config, DType::F32).unwrap();
+        let mut all: Vec<usize> = ["a", "b", "c"]
+            .iter()
+            .flat_map(|name| layers(&topology, name))
+            .collect();
+        all.sort();
+        assert_eq!(all, (0..4).collect::<Vec<_>>());
+        for name in ["a", "b", "c"] {
+            let layers = layers(&topology, name);
+            assert!((1..=2).contains(&layers.len()), "{name}: {layers:?}");
+            assert!(layers.windows(2).all(|w| w[1] == w[0] + 1));
+        }
+    }
+
+    #[test]
+    fn auto_balance_caps_a_worker_at_its_capacity() {
+        let config = test_utils::model_config();
+        let layer_size = Topology::layer_size(&config, DType::F32) as u64;
+        // the proportional share of small would be 2 layers, it only holds 1
+        let workers = [
+            worker("small", layer_size + 1),
+            worker("large", 3 * layer_size),
+        ];
+        let topology = Topology::auto_balance(&workers, &config, DType::F32).unwrap();
+        assert_eq!(layers(&topology, "small"), [0]);
+        assert_eq!(layers(&topology, "large"), [1, 2, 3]);
+    }
+
+    #[test]
+    fn auto_balance_reports_the_shortfall() {
+        let config = test_utils::model_config();
+        let layer_size = Topology::layer_size(&config, DType::F32) as u64;
+        let workers = [worker("a", layer_size), worker("b", 2 * layer_size)];
+        let e = Topology::auto_balance(&workers, &config, DType::F32)
+            .map(|_| ())
+            .unwrap_err()
+            .to_string();
+        assert!(
+            e.starts_with("the workers can't hold the 4 layers of the model"),
+            "{e}"
+        );
+        assert!(e.contains("a: ") && e.contains("(1 layers)"), "{e}");
+        assert!(e.contains("b: ") && e.contains("(2 layers)"), "{e}");
+        assert!(
+            e.contains(&format!(
+                "({} short",
+                human_bytes::human_bytes(layer_size as f64)
+            )),
+            "{e}"
+        );
+    }
+}
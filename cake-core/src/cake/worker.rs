@@ -1,110 +1,793 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    any::Any,
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    panic::AssertUnwindSafe,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
+};
 
-use super::{Context, Message, WorkerInfo};
-use crate::model::{Block, Cache};
+use super::{
+    tls::{self, Stream},
+    CakeError, Context, Message, MessageTooLarge, RawTensor, Registration, WorkerInfo, WorkerState,
+};
+use crate::{
+    model::{Block, Cache},
+    utils,
+};
 
 use anyhow::Result;
-use candle_core::Device;
-use tokio::net::{TcpListener, TcpStream};
+use candle_core::{Device, Tensor};
+use futures::FutureExt;
+use tokio::{
+    io::WriteHalf,
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch, Mutex, Semaphore},
+    task::JoinSet,
+};
+use tokio_rustls::TlsAcceptor;
+
+/// Settings shared by the connections of a worker.
+#[derive(Debug, Clone)]
+struct ConnectionSettings {
+    /// Name of the worker in the topology.
+    name: String,
+    heartbeat_interval: Duration,
+    /// Level used to compress the responses to the clients compressing their messages.
+    compression_level: i32,
+    /// Directory the output of every layer is written to, if any.
+    dump_activations: Option<PathBuf>,
+    /// Add checksums to the responses even if the requests have none.
+    verify_checksums: bool,
+    /// Token the clients must send in their hello, if any.
+    auth_token: Option<String>,
+    /// Shared by all the connections.
+    stats: Arc<WorkerStats>,
+    /// Slots of the forward requests processed at the same time, shared by all the connections.
+    forward_slots: Arc<Semaphore>,
+    /// Number of forward requests that can wait for a slot, the next ones are rejected.
+    max_queued: usize,
+    /// Size above which the messages of the clients are rejected.
+    max_message_bytes: u32,
+    /// Bytes of cache each master can hold across its connections, unlimited if not set.
+    max_master_cache_bytes: Option<u64>,
+}
+
+/// Activity of the worker reported to the status requests.
+#[derive(Debug, Default)]
+struct WorkerStats {
+    connections: AtomicUsize,
+    in_flight: AtomicUsize,
+    queued: AtomicUsize,
+    last_request: std::sync::Mutex<Option<Instant>>,
+    /// Cache memory held by the connections of each master, by master id.
+    masters: std::sync::Mutex<HashMap<String, MasterUsage>>,
+}
+
+#[derive(Debug, Default)]
+struct MasterUsage {
+    connections: usize,
+    cache_bytes: u64,
+}
+
+/// Accounts the cache of a connection to its master until dropped.
+struct MasterGuard<'a> {
+    masters: &'a std::sync::Mutex<HashMap<String, MasterUsage>>,
+    master_id: String,
+    // cache bytes of the connection included in the usage of the master
+    held: u64,
+}
+
+impl<'a> MasterGuard<'a> {
+    fn new(masters: &'a std::sync::Mutex<HashMap<String, MasterUsage>>, master_id: String) -> Self {
+        masters
+            .lock()
+            .unwrap()
+            .entry(master_id.clone())
+            .or_default()
+            .connections += 1;
+        Self {
+            masters,
+            master_id,
+            held: 0,
+        }
+    }
+
+    /// Sets the cache bytes of the connection, returns the ones of the master.
+    fn update(&mut self, cache_bytes: u64) -> u64 {
+        let mut masters = self.masters.lock().unwrap();
+        let usage = masters.entry(self.master_id.clone()).or_default();
+        usage.cache_bytes = usage.cache_bytes - self.held + cache_bytes;
+        self.held = cache_bytes;
+        usage.cache_bytes
+    }
+}
+
+impl Drop for MasterGuard<'_> {
+    fn drop(&mut self) {
+        let mut masters = self.masters.lock().unwrap();
+        if let Some(usage) = masters.get_mut(&self.master_id) {
+            usage.connections -= 1;
+            usage.cache_bytes -= self.held;
+            if usage.connections == 0 {
+                masters.remove(&self.master_id);
+            }
+        }
+    }
+}
+
+/// Increments a counter until dropped.
+struct CounterGuard<'a>(&'a AtomicUsize);
+
+impl<'a> CounterGuard<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::AcqRel);
+        Self(counter)
+    }
+
+    fn count(&self) -> usize {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for CounterGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
 
 pub struct Worker {
     listener: TcpListener,
+    tls: Option<TlsAcceptor>,
 
-    cache: Cache,
-    blocks: Arc<HashMap<String, Block>>,
-    device: Device,
+    /// One cache per device, the blocks use the cache of the device they're loaded on.
+    caches: Vec<Cache>,
+    /// Blocks by layer name, with the index of their device.
+    blocks: Arc<HashMap<String, (usize, Block)>>,
+    devices: Vec<Device>,
+    settings: ConnectionSettings,
+    /// Master to register to with --master-addr, along with what to announce.
+    master: Option<(String, Registration)>,
+    reconnect_backoff_base: Duration,
+    reconnect_backoff_max: Duration,
 }
 
 impl Worker {
-    pub async fn new(ctx: Context) -> Result<Self> {
+    pub async fn new(ctx: Context) -> Result<Self, CakeError> {
+        Ok(Self::load(ctx).await?)
+    }
+
+    async fn load(ctx: Context) -> Result<Self> {
         let worker_name = if let Some(name) = &ctx.args.name {
             name.to_string()
         } else {
-            return Err(anyhow!("no --name provided for worker"));
+            return Err(CakeError::InvalidArgument {
+                reason: "no --name provided for worker".to_string(),
+            }
+            .into());
         };
 
         let worker_topology = if let Some(node) = ctx.topology.get(&worker_name) {
             node
         } else {
-            return Err(anyhow!("could not find topology for {worker_name}"));
+            return Err(CakeError::TopologyInvalid {
+                reason: format!("could not find topology for {worker_name}"),
+            }
+            .into());
         };
+        if worker_topology.is_local() {
+            return Err(CakeError::TopologyInvalid {
+                reason: format!(
+                    "{worker_name} has a local host, its layers are served by the master"
+                ),
+            }
+            .into());
+        }
+        if ctx.args.worker_concurrency == 0 {
+            return Err(CakeError::InvalidArgument {
+                reason: "--worker-concurrency must be at least 1".to_string(),
+            }
+            .into());
+        }
+
+        // the first device is the one of the context
+        let mut devices = vec![ctx.device.clone()];
+        let mut var_builders = vec![ctx.var_builder.clone()];
+        let mut caches = vec![ctx.cache.as_new()];
+        if ctx.args.devices.len() > 1 {
+            for device in
+                utils::get_inference_devices(&ctx.args.device_preference(), &ctx.args.devices[1..])?
+            {
+                var_builders.push(ctx.var_builder_for(&device)?);
+                caches.push(ctx.cache.on_device(&ctx.config, &device)?);
+                devices.push(device);
+            }
+        }
+
+        let num_layers = worker_topology.layers.len();
+        let offloaded = ctx.args.cpu_offload_layers;
+        if offloaded > num_layers {
+            return Err(CakeError::InvalidArgument {
+                reason: format!(
+                    "--cpu-offload-layers {offloaded} exceeds the {num_layers} layers of {worker_name}"
+                ),
+            }
+            .into());
+        }
+        if offloaded > 0 {
+            log::info!("offloading the last {offloaded} layers to the CPU");
+            var_builders.push(ctx.var_builder_for(&Device::Cpu)?);
+            caches.push(ctx.cache.on_device(&ctx.config, &Device::Cpu)?);
+            devices.push(Device::Cpu);
+        }
+        let layer_devices = Self::layer_devices(devices.len(), num_layers, offloaded);
+
+        if !ctx.args.skip_memory_check {
+            Self::check_memory(&ctx, &devices, &layer_devices)?;
+        }
 
         let mut blocks = HashMap::new();
 
-        for block_layer_name in &worker_topology.layers {
-            log::info!("loading {} ...", &block_layer_name);
+        for (block_layer_name, &device_idx) in worker_topology.layers.iter().zip(&layer_devices) {
+            log::info!(
+                "loading {} on {:?} ...",
+                &block_layer_name,
+                &devices[device_idx]
+            );
 
             let block = Block::load(
                 block_layer_name,
-                ctx.var_builder.pp(block_layer_name),
+                var_builders[device_idx].pp(block_layer_name),
                 &ctx.config,
             )?;
 
-            blocks.insert(block_layer_name.to_string(), block);
+            blocks.insert(block_layer_name.to_string(), (device_idx, block));
         }
 
         let blocks = Arc::new(blocks);
 
+        let tls = tls::acceptor(&ctx.args)?;
         let listener = TcpListener::bind(&ctx.args.address).await?;
 
         log::info!(
-            "listening on {} (mem:{}) ...",
+            "listening on {}{} (mem:{}) ...",
             &ctx.args.address,
+            if tls.is_some() { " with tls" } else { "" },
             human_bytes::human_bytes(memory_stats::memory_stats().unwrap().physical_mem as f64)
         );
 
-        let cache = ctx.cache;
-        let device = ctx.device;
+        let master = match &ctx.args.master_addr {
+            Some(master_addr) => {
+                let registration =
+                    Self::registration(&ctx, &worker_name, &devices, &worker_topology.layers)?;
+                Some((master_addr.clone(), registration))
+            }
+            None => None,
+        };
 
-        Ok(Self {
+        let settings = ConnectionSettings {
+            name: worker_name,
+            heartbeat_interval: Duration::from_millis(ctx.args.heartbeat_interval),
+            // zero selects the default zstd level
+            compression_level: ctx.args.compress.unwrap_or(0),
+            dump_activations: ctx.args.dump_activations.as_ref().map(PathBuf::from),
+            verify_checksums: ctx.args.verify_checksums,
+            auth_token: ctx.args.auth_token.clone(),
+            stats: Arc::new(WorkerStats::default()),
+            forward_slots: Arc::new(Semaphore::new(ctx.args.worker_concurrency)),
+            max_queued: ctx.args.worker_queue,
+            max_message_bytes: ctx.args.max_message_bytes(),
+            max_master_cache_bytes: ctx.args.max_master_cache_bytes,
+        };
+
+        let worker = Self {
             listener,
-            cache,
+            tls,
+            caches,
             blocks,
-            device,
+            devices,
+            settings,
+            master,
+            reconnect_backoff_base: Duration::from_millis(ctx.args.reconnect_backoff_base),
+            reconnect_backoff_max: Duration::from_millis(ctx.args.reconnect_backoff_max),
+        };
+
+        if ctx.args.warmup {
+            worker.warmup(ctx.config.hidden_size).await?;
+        }
+
+        Ok(worker)
+    }
+
+    /// Returns the index of the device of each of the num_layers layers of the worker: the last
+    /// offloaded ones go to the last device, the CPU, and the others are spread across the
+    /// other devices.
+    fn layer_devices(num_devices: usize, num_layers: usize, offloaded: usize) -> Vec<usize> {
+        let spread = num_layers - offloaded;
+        let spread_devices = if offloaded > 0 {
+            num_devices - 1
+        } else {
+            num_devices
+        };
+        (0..num_layers)
+            .map(|layer_pos| {
+                if layer_pos < spread {
+                    // contiguous ranges of layers per device, so tensors only move between
+                    // devices at the end of each range
+                    layer_pos * spread_devices / spread
+                } else {
+                    num_devices - 1
+                }
+            })
+            .collect()
+    }
+
+    /// Fails before loading anything if the layers, spread across the devices as layer_devices
+    /// assigns them, are estimated not to fit in their memory.
+    fn check_memory(ctx: &Context, devices: &[Device], layer_devices: &[usize]) -> Result<()> {
+        let dtype = ctx.cache.cos.dtype();
+        let layer_memory = ctx.config.layer_memory(dtype);
+
+        for (device_idx, device) in devices.iter().enumerate() {
+            let layers = layer_devices
+                .iter()
+                .filter(|&&layer_device| layer_device == device_idx)
+                .count();
+            let required = layers as u64 * layer_memory;
+            let available = utils::device_memory(device)?;
+
+            log::debug!(
+                "{layers} layers need about {} on {:?}, {} available",
+                human_bytes::human_bytes(required as f64),
+                device.location(),
+                human_bytes::human_bytes(available as f64)
+            );
+
+            if required > available {
+                return Err(CakeError::InsufficientMemory {
+                    device: format!("{:?}", device.location()),
+                    layers,
+                    required,
+                    available,
+                }
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// What the worker announces to the master it registers to.
+    fn registration(
+        ctx: &Context,
+        name: &str,
+        devices: &[Device],
+        layers: &[String],
+    ) -> Result<Registration> {
+        let dtype = ctx.cache.cos.dtype();
+        let layer_memory = ctx.config.layer_memory(dtype);
+        let mut layer_capacity = 0;
+        for device in devices {
+            layer_capacity += (utils::device_memory(device)? / layer_memory) as usize;
+        }
+
+        Ok(Registration {
+            name: name.to_string(),
+            layers: layers.to_vec(),
+            device: devices
+                .iter()
+                .map(|device| format!("{:?}", device))
+                .collect::<Vec<_>>()
+                .join(", "),
+            dtype: dtype.as_str().to_string(),
+            memory: utils::available_memory(),
+            layer_capacity,
         })
     }
 
+    /// Keeps the worker registered to the master, registering again with an exponential backoff
+    /// whenever the registration connection drops. The connections the master asks for are sent
+    /// to dialed, until it's closed.
+    async fn register(
+        master_addr: String,
+        registration: Registration,
+        dialed: mpsc::Sender<(TcpStream, SocketAddr)>,
+        backoff_base: Duration,
+        backoff_max: Duration,
+        max_message_bytes: u32,
+    ) {
+        let mut delay = backoff_base;
+        loop {
+            let res = Self::serve_registration(
+                &master_addr,
+                &registration,
+                &dialed,
+                max_message_bytes,
+                || delay = backoff_base,
+            )
+            .await;
+            match res {
+                Ok(()) => return,
+                Err(e) => log::warn!(
+                    "registration to {master_addr} lost: {e}, registering again in {:?} ...",
+                    delay
+                ),
+            }
+
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(backoff_max);
+        }
+    }
+
+    /// Registers to the master and opens a connection every time it sends a Dial, returns once
+    /// the worker stops accepting connections.
+    async fn serve_registration(
+        master_addr: &str,
+        registration: &Registration,
+        dialed: &mpsc::Sender<(TcpStream, SocketAddr)>,
+        max_message_bytes: u32,
+        registered: impl FnOnce(),
+    ) -> Result<()> {
+        let mut socket = TcpStream::connect(master_addr).await?;
+        socket.set_nodelay(true)?;
+        Message::Register(registration.clone())
+            .to_writer(&mut socket)
+            .await?;
+
+        log::info!("registered to {master_addr}");
+        registered();
+
+        loop {
+            match Message::from_reader(&mut socket, max_message_bytes).await? {
+                Message::Dial => {
+                    let mut connection = TcpStream::connect(master_addr).await?;
+                    Message::Attach {
+                        name: registration.name.clone(),
+                    }
+                    .to_writer(&mut connection)
+                    .await?;
+
+                    let master = connection.peer_addr()?;
+                    if dialed.send((connection, master)).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                msg => bail!("unexpected message {:?}", msg),
+            }
+        }
+    }
+
+    /// Runs a single token through every layer, in order, with throwaway caches, and returns the
+    /// output of the last one.
+    pub async fn warmup(&self, hidden_size: usize) -> Result<Tensor> {
+        let start = Instant::now();
+
+        let mut layers: Vec<(usize, &String)> = self
+            .blocks
+            .keys()
+            .map(|name| {
+                let block_idx = name
+                    .rsplit('.')
+                    .next()
+                    .and_then(|idx| idx.parse().ok())
+                    .unwrap_or_default();
+                (block_idx, name)
+            })
+            .collect();
+        layers.sort();
+
+        let mut caches: Vec<Cache> = self.caches.iter().map(|cache| cache.as_new()).collect();
+        let dtype = self.caches[0].cos.dtype();
+        // zeros would go through the blocks unchanged
+        let mut x = Tensor::ones((1, 1, hidden_size), dtype, &self.devices[0])?;
+        for (block_idx, name) in layers {
+            let (device_idx, block) = &self.blocks[name];
+            x = x.to_device(&self.devices[*device_idx])?;
+            x = block
+                .forward_imm(&x, 0, block_idx, &mut caches[*device_idx])
+                .await?;
+        }
+
+        log::info!(
+            "warmed up {} layers in {:?} (mem:{})",
+            self.blocks.len(),
+            start.elapsed(),
+            human_bytes::human_bytes(memory_stats::memory_stats().unwrap().physical_mem as f64)
+        );
+
+        Ok(x)
+    }
+
+    /// Sends a heartbeat at every interval while the busy flag is set, until the writer is dropped.
+    fn spawn_heartbeat(
+        writer: Weak<Mutex<WriteHalf<Box<dyn Stream>>>>,
+        busy: Arc<AtomicBool>,
+        interval: Duration,
+    ) {
+        if interval.is_zero() {
+            return;
+        }
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+
+                let writer = if let Some(writer) = writer.upgrade() {
+                    writer
+                } else {
+                    break;
+                };
+
+                if busy.load(Ordering::Acquire)
+                    && Message::Heartbeat
+                        .to_writer(&mut *writer.lock().await)
+                        .await
+                        .is_err()
+                {
+                    break;
+                }
+            }
+        });
+    }
+
     async fn handle_client(
-        mut socket: TcpStream,
+        socket: Box<dyn Stream>,
         client: SocketAddr,
-        blocks: Arc<HashMap<String, Block>>,
-        device: Device,
-        mut cache: Cache,
+        blocks: Arc<HashMap<String, (usize, Block)>>,
+        devices: Vec<Device>,
+        mut caches: Vec<Cache>,
+        settings: ConnectionSettings,
+        mut shutdown: watch::Receiver<bool>,
     ) -> Result<()> {
+        let (mut reader, writer) = tokio::io::split(socket);
+        // shared with the heartbeat task
+        let writer = Arc::new(Mutex::new(writer));
+        let busy = Arc::new(AtomicBool::new(false));
+
+        Self::spawn_heartbeat(
+            Arc::downgrade(&writer),
+            busy.clone(),
+            settings.heartbeat_interval,
+        );
+
         // read and validate Hello
-        let hello = Message::from_reader(&mut socket).await;
-        let hello = if let Ok(hello) = hello {
+        let hello = Message::from_reader_compressed(&mut reader, settings.max_message_bytes).await;
+        let (hello, compressed) = if let Ok(hello) = hello {
             hello
         } else {
             return Err(anyhow!("[{}] could not read Hello: {:?}", &client, hello));
         };
-        if !matches!(hello, Message::Hello) {
+        let (auth_token, master_id) = if let Message::Hello {
+            auth_token,
+            master_id,
+        } = hello
+        {
+            (auth_token, master_id)
+        } else {
             return Err(anyhow!(
                 "[{}] unpexpected message instead of hello: {:?}",
                 &client,
                 hello
             ));
+        };
+
+        // reply compressed to the clients compressing their messages
+        let compression = compressed.then_some(settings.compression_level);
+        if compressed {
+            log::debug!("[{}] compressing responses", &client);
+        }
+
+        if let Some(expected) = &settings.auth_token {
+            let authorized = auth_token.is_some_and(|token| {
+                utils::constant_time_eq(token.as_bytes(), expected.as_bytes())
+            });
+            if !authorized {
+                Message::Unauthorized
+                    .to_writer_compressed(&mut *writer.lock().await, compression)
+                    .await?;
+                return Err(anyhow!("[{}] unauthorized", &client));
+            }
         }
 
+        let device = devices
+            .iter()
+            .map(|device| format!("{:?}", device))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let dtype = caches[0].cos.dtype().as_str().to_string();
+
         // send info
         let info = Message::WorkerInfo(WorkerInfo {
-            device: format!("{:?}", &device),
+            name: settings.name.clone(),
+            device: device.clone(),
+            dtype: dtype.clone(),
+            memory: crate::utils::available_memory(),
+            ..Default::default()
         });
-        if let Err(e) = info.to_writer(&mut socket).await {
+        if let Err(e) = info
+            .to_writer_compressed(&mut *writer.lock().await, compression)
+            .await
+        {
             return Err(anyhow!("[{}] could not send worker info: {:?}", &client, e));
         }
 
-        // read next message
-        while let Ok(msg) = Message::from_reader(&mut socket).await {
-            let (x, ops) = match msg {
+        let _connection = CounterGuard::new(&settings.stats.connections);
+        // the connections without a master id are accounted on their own
+        let master_id = master_id.unwrap_or_else(|| client.to_string());
+        log::info!("[{}] connected for master {master_id}", &client);
+        let mut master = MasterGuard::new(&settings.stats.masters, master_id);
+
+        // set once the client sends its tensors with checksums
+        let mut checksums = settings.verify_checksums;
+        // caches of the other ids the client selected, see Message::SelectCache
+        let mut stashed: HashMap<u64, Vec<Cache>> = HashMap::new();
+        let mut cache_id = 0;
+        let with_checksums = |msg: Message, checksums: bool| {
+            if checksums {
+                msg.with_checksums()
+            } else {
+                msg
+            }
+        };
+
+        loop {
+            // read next message, unless the worker is shutting down
+            let msg = tokio::select! {
+                msg = Message::from_reader(&mut reader, settings.max_message_bytes) => match msg {
+                    Ok(msg) => msg,
+                    Err(e) if e.is::<MessageTooLarge>() => {
+                        log::warn!("[{}] {e}, closing the connection", &client);
+                        break;
+                    }
+                    Err(_) => break,
+                },
+                _ = shutdown.changed() => {
+                    log::info!("[{}] notifying shutdown", &client);
+                    Message::Shutdown
+                        .to_writer_compressed(&mut *writer.lock().await, compression)
+                        .await?;
+                    break;
+                }
+            };
+
+            checksums |= msg.has_checksums();
+            if let Err(e) = msg.verify_checksums() {
+                if matches!(msg, Message::SetCache(_)) {
+                    // no response is expected, the client notices the cache loss once reconnected
+                    log::warn!("[{}] dropping connection: corrupted cache: {}", &client, e);
+                    break;
+                }
+                log::warn!("[{}] corrupted request: {}", &client, e);
+                Message::ChecksumMismatch
+                    .to_writer_compressed(&mut *writer.lock().await, compression)
+                    .await?;
+                continue;
+            }
+
+            let (x, ops, attention) = match msg {
                 // single block operation
                 Message::TransformerOp {
                     layer_name,
                     x,
                     index_pos,
                     block_idx,
-                } => (x, vec![(layer_name, index_pos, block_idx)]),
-                Message::Batch { x, batch } => (x, batch),
+                } => (x, vec![(layer_name, index_pos, block_idx)], false),
+                Message::AttentionOp {
+                    layer_name,
+                    x,
+                    index_pos,
+                    block_idx,
+                } => (x, vec![(layer_name, index_pos, block_idx)], true),
+                Message::Batch { x, batch } => (x, batch, false),
+                Message::ResetCache { left_padding } => {
+                    log::debug!("[{}] resetting cache", &client);
+                    for cache in caches.iter_mut() {
+                        *cache = cache.as_new();
+                        cache.left_padding = left_padding.clone();
+                    }
+                    master.update(Self::cache_memory(&caches, &stashed));
+                    continue;
+                }
+                Message::SelectCache { id } => {
+                    if id != cache_id {
+                        log::debug!("[{}] selecting cache {id}", &client);
+                        let selected = stashed
+                            .remove(&id)
+                            .unwrap_or_else(|| caches.iter().map(|cache| cache.as_new()).collect());
+                        stashed.insert(cache_id, std::mem::replace(&mut caches, selected));
+                        cache_id = id;
+                    }
+                    continue;
+                }
+                Message::DropCache { id } => {
+                    log::debug!("[{}] dropping cache {id}", &client);
+                    if id == cache_id {
+                        for cache in caches.iter_mut() {
+                            *cache = cache.as_new();
+                        }
+                    } else {
+                        stashed.remove(&id);
+                    }
+                    master.update(Self::cache_memory(&caches, &stashed));
+                    continue;
+                }
+                Message::GetCache { blocks } => {
+                    let mut kvs = vec![];
+                    for idx in blocks {
+                        // only the cache of the block device holds its entry
+                        let mut found = None;
+                        for cache in &caches {
+                            if let Some(kv) = cache.kv(idx)? {
+                                found = Some(kv);
+                                break;
+                            }
+                        }
+                        if let Some((k, v)) = found {
+                            kvs.push((idx, RawTensor::from_tensor(&k), RawTensor::from_tensor(&v)));
+                        }
+                    }
+                    if let Err(e) = with_checksums(Message::Cache(kvs), checksums)
+                        .to_writer_compressed(&mut *writer.lock().await, compression)
+                        .await
+                    {
+                        return Err(anyhow!("[{}] could not send cache: {:?}", &client, e));
+                    }
+                    continue;
+                }
+                Message::SetCache(kvs) => {
+                    log::debug!("[{}] restoring cache for {} blocks", &client, kvs.len());
+                    for (idx, k, v) in kvs {
+                        let device_idx = blocks
+                            .get(&format!("model.layers.{idx}"))
+                            .map(|(device_idx, _)| *device_idx)
+                            .unwrap_or(0);
+                        let (cache, device) = (&mut caches[device_idx], &devices[device_idx]);
+                        if idx >= cache.num_blocks() {
+                            return Err(anyhow!("[{}] invalid cache block {idx}", &client));
+                        }
+                        cache.set_kv(idx, k.to_tensor(device)?, v.to_tensor(device)?)?;
+                    }
+                    continue;
+                }
+                Message::Shutdown => {
+                    log::info!("[{}] master closed the connection", &client);
+                    break;
+                }
+                Message::StatusRequest => {
+                    let mut layers: Vec<String> = blocks.keys().cloned().collect();
+                    layers.sort_by_key(|name| {
+                        name.rsplit('.')
+                            .next()
+                            .and_then(|idx| idx.parse::<usize>().ok())
+                    });
+                    let stats = &settings.stats;
+                    let state = WorkerState {
+                        name: settings.name.clone(),
+                        device: device.clone(),
+                        dtype: dtype.clone(),
+                        layers,
+                        connections: stats.connections.load(Ordering::Acquire),
+                        in_flight: stats.in_flight.load(Ordering::Acquire),
+                        queued: stats.queued.load(Ordering::Acquire),
+                        idle_ms: stats
+                            .last_request
+                            .lock()
+                            .unwrap()
+                            .map(|at| at.elapsed().as_millis() as u64),
+                    };
+                    if let Err(e) = Message::StatusResponse(state)
+                        .to_writer_compressed(&mut *writer.lock().await, compression)
+                        .await
+                    {
+                        return Err(anyhow!("[{}] could not send status: {:?}", &client, e));
+                    }
+                    continue;
+                }
                 _ => {
                     return Err(anyhow!(
                         "[{}] unhandled message in loop: {:?}",
@@ -114,28 +797,92 @@ impl Worker {
                 }
             };
 
-            // load raw tensor to device
-            let mut x = x.to_tensor(&device).unwrap();
-
-            // log::info!("{}", if ops.len() == 1 { "RUN" } else { "BATCH" });
+            // heartbeats are sent while the request waits for a slot too
+            busy.store(true, Ordering::Release);
+            let slot = match settings.forward_slots.try_acquire() {
+                Ok(slot) => slot,
+                Err(_) => {
+                    let queued = CounterGuard::new(&settings.stats.queued);
+                    if queued.count() > settings.max_queued {
+                        // the tensor of the request is dropped right away
+                        drop(queued);
+                        busy.store(false, Ordering::Release);
+                        log::warn!("[{}] too many queued requests, rejecting", &client);
+                        Message::Overloaded
+                            .to_writer_compressed(&mut *writer.lock().await, compression)
+                            .await?;
+                        continue;
+                    }
+                    settings.forward_slots.acquire().await?
+                }
+            };
+            let in_flight = CounterGuard::new(&settings.stats.in_flight);
 
-            // for each element in the ops batch
-            for (layer_name, index_pos, block_idx) in ops {
-                // get layer block by name
-                if let Some(block) = blocks.get(&layer_name) {
-                    // log::info!("  x = {}.forward(x, {index_pos}, {block_idx})", &layer_name);
-                    // run forward pass
-                    x = block
-                        .forward_imm(&x, index_pos, block_idx, &mut cache)
-                        .await
-                        .unwrap()
-                } else {
-                    return Err(anyhow!("could not find layer {}", &layer_name));
+            // a panicking forward only fails its own request
+            let forward = Self::forward(
+                x,
+                ops,
+                attention,
+                &blocks,
+                &devices,
+                &mut caches,
+                &settings,
+                client,
+            );
+            let resp = match AssertUnwindSafe(forward).catch_unwind().await {
+                Ok(Ok((x, attention))) => {
+                    let master_bytes = master.update(Self::cache_memory(&caches, &stashed));
+                    match settings.max_master_cache_bytes {
+                        Some(limit) if master_bytes > limit => {
+                            // the master can't go on with a partial cache, which is reset below
+                            let reason = format!(
+                                "master {} holds {master_bytes} bytes of cache, more than the --max-master-cache-bytes {limit}",
+                                &master.master_id
+                            );
+                            log::warn!("[{}] {reason}", &client);
+                            Message::WorkerError(reason)
+                        }
+                        _ => with_checksums(
+                            match attention {
+                                Some(attention) => Message::Attention {
+                                    x: RawTensor::from_tensor(&x),
+                                    attention: RawTensor::from_tensor(&attention),
+                                },
+                                None => Message::from_tensor(&x),
+                            },
+                            checksums,
+                        ),
+                    }
+                }
+                Ok(Err(e)) => {
+                    log::error!("[{}] forward failed: {}", &client, e);
+                    Message::WorkerError(format!("forward failed: {e}"))
                 }
+                Err(panic) => {
+                    let reason = panic_message(panic.as_ref());
+                    log::error!("[{}] forward panicked: {}", &client, reason);
+                    Message::WorkerError(format!("forward panicked: {reason}"))
+                }
+            };
+            if matches!(resp, Message::WorkerError(_)) {
+                // the blocks before the failing one may have written their entries already
+                for cache in caches.iter_mut() {
+                    *cache = cache.as_new();
+                }
+                master.update(Self::cache_memory(&caches, &stashed));
             }
 
             // send response tensor
-            if let Err(e) = Message::from_tensor(&x).to_writer(&mut socket).await {
+            let res = resp
+                .to_writer_compressed(&mut *writer.lock().await, compression)
+                .await;
+
+            busy.store(false, Ordering::Release);
+            drop(in_flight);
+            drop(slot);
+            *settings.stats.last_request.lock().unwrap() = Some(Instant::now());
+
+            if let Err(e) = res {
                 return Err(anyhow!(
                     "[{}] could not send response tensor: {:?}",
                     &client,
@@ -147,22 +894,885 @@ impl Worker {
         Ok(())
     }
 
-    pub async fn run(&mut self) -> Result<()> {
-        while let Ok((socket, client)) = self.listener.accept().await {
+    /// Memory held by the caches of a connection, the ones kept for the ids it doesn't process
+    /// included.
+    fn cache_memory(caches: &[Cache], stashed: &HashMap<u64, Vec<Cache>>) -> u64 {
+        caches
+            .iter()
+            .chain(stashed.values().flatten())
+            .map(|cache| cache.memory() as u64)
+            .sum()
+    }
+
+    /// Runs x through the blocks of a forward request, in order, also returning the attention
+    /// probabilities of the last block if asked for.
+    #[allow(clippy::too_many_arguments)]
+    async fn forward(
+        x: RawTensor,
+        ops: Vec<(String, usize, usize)>,
+        attention: bool,
+        blocks: &HashMap<String, (usize, Block)>,
+        devices: &[Device],
+        caches: &mut [Cache],
+        settings: &ConnectionSettings,
+        client: SocketAddr,
+    ) -> Result<(Tensor, Option<Tensor>)> {
+        // load raw tensor to device
+        let mut x = x.to_tensor(&devices[0])?;
+        // the master may send the activations in another dtype, they're replied in it
+        let dtype = x.dtype();
+        x = x.to_dtype(caches[0].cos.dtype())?;
+        let mut probs = None;
+
+        for (layer_name, index_pos, block_idx) in ops {
+            let (device_idx, block) = blocks
+                .get(&layer_name)
+                .ok_or_else(|| anyhow!("could not find layer {}", &layer_name))?;
+
+            // copied when crossing to the next device
+            x = x.to_device(&devices[*device_idx])?;
+            // run forward pass
+            let start = Instant::now();
+            let cache = &mut caches[*device_idx];
+            cache.capture_attention = attention;
+            let y = block.forward_imm(&x, index_pos, block_idx, cache).await;
+            cache.capture_attention = false;
+            x = y?;
+            if attention {
+                probs = Some(
+                    cache
+                        .take_attention()
+                        .ok_or_else(|| anyhow!("{layer_name} recorded no attention"))?,
+                );
+            }
+            let duration_ms = start.elapsed().as_secs_f64() * 1000.;
+            log::debug!(
+                layer_name = layer_name.as_str(), duration_ms;
+                "[{}] {layer_name} forward took {duration_ms:.2}ms", &client
+            );
+
+            if let Some(dir) = &settings.dump_activations {
+                utils::dump_activations(dir, &format!("layer_{block_idx}"), &x)?;
+            }
+        }
+
+        Ok((x.to_dtype(dtype)?, probs))
+    }
+
+    /// Performs the TLS handshake if enabled, the client certificate is verified at this point.
+    async fn accept(socket: TcpStream, tls: Option<TlsAcceptor>) -> Result<Box<dyn Stream>> {
+        // the master waits for each response before sending the next request
+        socket.set_nodelay(true)?;
+        Ok(if let Some(tls) = tls {
+            Box::new(tls.accept(socket).await?)
+        } else {
+            Box::new(socket)
+        })
+    }
+
+    /// Serves the clients until SIGINT or SIGTERM is received.
+    pub async fn run(&mut self) -> Result<(), CakeError> {
+        self.run_until(shutdown_signal()).await
+    }
+
+    /// Serves the clients until the shutdown future completes. Connected clients are then notified
+    /// once the request they're waiting for, if any, has been processed.
+    pub async fn run_until<F>(&mut self, shutdown: F) -> Result<(), CakeError>
+    where
+        F: Future<Output = ()>,
+    {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut connections = JoinSet::new();
+
+        // the connections opened to the master it's registered to, if any
+        let (dialed_tx, mut dialed_rx) = mpsc::channel(1);
+        let registration = self.master.clone().map(|(master_addr, registration)| {
+            tokio::spawn(Self::register(
+                master_addr,
+                registration,
+                dialed_tx,
+                self.reconnect_backoff_base,
+                self.reconnect_backoff_max,
+                self.settings.max_message_bytes,
+            ))
+        });
+
+        tokio::pin!(shutdown);
+
+        loop {
+            let (socket, client) = tokio::select! {
+                res = self.listener.accept() => match res {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        log::error!("could not accept connection: {}", e);
+                        break;
+                    }
+                },
+                Some(connection) = dialed_rx.recv() => connection,
+                _ = &mut shutdown => {
+                    log::info!("shutting down ...");
+                    break;
+                }
+            };
+
             log::info!("{} connected", &client);
 
+            // forget the connections that are over
+            while connections.try_join_next().is_some() {}
+
             // each client loop gets a new cache
-            let cache = self.cache.as_new();
+            let caches = self.caches.iter().map(|cache| cache.as_new()).collect();
             let blocks = self.blocks.clone();
-            let device = self.device.clone();
+            let devices = self.devices.clone();
+            let settings = self.settings.clone();
+            let tls = self.tls.clone();
+            let shutdown = shutdown_rx.clone();
 
-            tokio::spawn(async move {
-                if let Err(e) = Self::handle_client(socket, client, blocks, device, cache).await {
+            connections.spawn(async move {
+                let socket = match Self::accept(socket, tls).await {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        log::error!("[{}] could not establish tls session: {}", &client, e);
+                        return;
+                    }
+                };
+
+                if let Err(e) =
+                    Self::handle_client(socket, client, blocks, devices, caches, settings, shutdown)
+                        .await
+                {
                     log::error!("{}", e);
                 }
             });
         }
 
+        // the master stops sending requests to a worker that unregistered
+        if let Some(registration) = registration {
+            registration.abort();
+        }
+
+        // wait for the connections to finish their requests and notify their clients
+        let _ = shutdown_tx.send(true);
+        while connections.join_next().await.is_some() {}
+
+        log::info!("worker stopped");
+
         Ok(())
     }
 }
+
+/// The message a panic has been raised with, if any.
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Completes when SIGINT, or SIGTERM on unix, is received.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            log::error!("could not listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                log::error!("could not listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::DType;
+
+    use super::*;
+    use crate::{
+        cake::{Client, ClientError, ConnectionOptions, Forwarder, MESSAGE_MAX_SIZE},
+        model::Block,
+        test_utils,
+    };
+
+    /// Next message of the stream, if one comes in time.
+    async fn next<R>(reader: &mut R) -> Result<Result<Message>, tokio::time::error::Elapsed>
+    where
+        R: tokio::io::AsyncReadExt + Unpin,
+    {
+        tokio::time::timeout(
+            Duration::from_millis(200),
+            Message::from_reader(reader, MESSAGE_MAX_SIZE),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn heartbeats_while_busy() {
+        let (master, worker) = tokio::io::duplex(1024);
+        let (_, writer) = tokio::io::split(Box::new(worker) as Box<dyn Stream>);
+        let writer = Arc::new(Mutex::new(writer));
+        let busy = Arc::new(AtomicBool::new(false));
+        Worker::spawn_heartbeat(
+            Arc::downgrade(&writer),
+            busy.clone(),
+            Duration::from_millis(20),
+        );
+        let (mut master, _) = tokio::io::split(master);
+
+        // idle
+        assert!(next(&mut master).await.is_err());
+
+        busy.store(true, Ordering::Release);
+        for _ in 0..3 {
+            assert!(matches!(
+                next(&mut master).await,
+                Ok(Ok(Message::Heartbeat))
+            ));
+        }
+
+        // the heartbeats stop with the connection
+        busy.store(false, Ordering::Release);
+        drop(writer);
+        assert!(matches!(next(&mut master).await, Ok(Err(_))));
+    }
+
+    #[tokio::test]
+    async fn compresses_for_the_compressing_clients() {
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "compressing-worker.yml",
+            &format!("compressing-worker: {{ host: '{address}', layers: [0] }}"),
+        );
+        let _worker =
+            test_utils::worker("compressing-worker", &topology, &["--compress", "3"]).await;
+
+        for level in [None, Some(1)] {
+            let mut stream = TcpStream::connect(&address).await.unwrap();
+            let hello = Message::Hello {
+                auth_token: None,
+                master_id: None,
+            };
+            hello
+                .to_writer_compressed(&mut stream, level)
+                .await
+                .unwrap();
+            let (info, compressed) = Message::from_reader_compressed(&mut stream, MESSAGE_MAX_SIZE)
+                .await
+                .unwrap();
+            assert!(matches!(info, Message::WorkerInfo(_)));
+            assert_eq!(compressed, level.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_message_closes_the_connection() {
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "small-messages-worker.yml",
+            &format!("small-messages-worker: {{ host: '{address}', layers: [0] }}"),
+        );
+        let extra = ["--max-message-bytes", "4096"];
+        let _worker = test_utils::worker("small-messages-worker", &topology, &extra).await;
+
+        let hello = || Message::Hello {
+            auth_token: None,
+            master_id: None,
+        };
+        let mut stream = TcpStream::connect(&address).await.unwrap();
+        hello().to_writer(&mut stream).await.unwrap();
+        assert!(matches!(
+            next(&mut stream).await,
+            Ok(Ok(Message::WorkerInfo(_)))
+        ));
+
+        // a header claiming a gigabyte, with no payload
+        let mut header = vec![];
+        Message::Heartbeat.to_writer(&mut header).await.unwrap();
+        header.truncate(4);
+        header.extend_from_slice(&(1u32 << 30).to_be_bytes());
+        tokio::io::AsyncWriteExt::write_all(&mut stream, &header)
+            .await
+            .unwrap();
+        assert!(matches!(next(&mut stream).await, Ok(Err(_))));
+
+        // the other connections are still served
+        let mut stream = TcpStream::connect(&address).await.unwrap();
+        hello().to_writer(&mut stream).await.unwrap();
+        assert!(matches!(
+            next(&mut stream).await,
+            Ok(Ok(Message::WorkerInfo(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn corrupted_request_is_answered_with_a_checksum_mismatch() {
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "checksum-worker.yml",
+            &format!("checksum-worker: {{ host: '{address}', layers: [0] }}"),
+        );
+        let _worker = test_utils::worker("checksum-worker", &topology, &[]).await;
+
+        let mut stream = TcpStream::connect(&address).await.unwrap();
+        let hello = Message::Hello {
+            auth_token: None,
+            master_id: None,
+        };
+        hello.to_writer(&mut stream).await.unwrap();
+        Message::from_reader(&mut stream, MESSAGE_MAX_SIZE)
+            .await
+            .unwrap();
+
+        let x = Tensor::arange(0f32, 64., &Device::Cpu)
+            .unwrap()
+            .reshape((1, 1, 64))
+            .unwrap();
+        let request = Message::transformer_op("model.layers.0", &x, 0, 0).with_checksums();
+        let data = RawTensor::from_tensor(&x).data;
+        let frame = test_utils::corrupted_frame(&request, &data).await;
+        tokio::io::AsyncWriteExt::write_all(&mut stream, &frame)
+            .await
+            .unwrap();
+        let resp = Message::from_reader(&mut stream, MESSAGE_MAX_SIZE).await;
+        assert!(matches!(resp, Ok(Message::ChecksumMismatch)), "{resp:?}");
+
+        // the request can be sent again on the same connection
+        request.to_writer(&mut stream).await.unwrap();
+        match Message::from_reader(&mut stream, MESSAGE_MAX_SIZE).await {
+            Ok(Message::Tensor(y)) => y.verify().unwrap(),
+            resp => panic!("unexpected response {resp:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn warmup_runs_every_layer() {
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "warmup.yml",
+            &format!("warmup: {{ host: '{address}', layers: [3, 1] }}"),
+        );
+        let worker_args = ["--mode", "worker", "--name", "warmup", "--warmup"];
+        let mut args = test_utils::args(&[&worker_args[..], &["--address", &address]].concat());
+        args.topology = topology;
+        let ctx = Context::from_args(args).unwrap();
+        let (var_builder, config) = (ctx.var_builder.clone(), ctx.config.clone());
+        let worker = Worker::new(ctx).await.unwrap();
+
+        // one token through the layers of the worker, in order
+        let mut cache = worker.caches[0].as_new();
+        let mut expected = Tensor::ones((1, 1, 64), DType::F32, &Device::Cpu).unwrap();
+        for block_idx in [1, 3] {
+            let name = format!("model.layers.{block_idx}");
+            let block = Block::load(&name, var_builder.pp(&name), &config).unwrap();
+            expected = block
+                .forward_imm(&expected, 0, block_idx, &mut cache)
+                .await
+                .unwrap();
+        }
+        let y = worker.warmup(config.hidden_size).await.unwrap();
+        assert_eq!(
+            y.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+            expected.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+        );
+    }
+
+    #[test]
+    fn layers_spread_across_devices() {
+        assert_eq!(Worker::layer_devices(1, 3, 0), [0, 0, 0]);
+        assert_eq!(Worker::layer_devices(2, 4, 0), [0, 0, 1, 1]);
+        assert_eq!(Worker::layer_devices(2, 5, 0), [0, 0, 0, 1, 1]);
+        // the cpu comes last
+        assert_eq!(Worker::layer_devices(3, 5, 1), [0, 0, 1, 1, 2]);
+        assert_eq!(Worker::layer_devices(2, 2, 2), [1, 1]);
+    }
+
+    /// Worker named multi-device serving the 4 layers of the model, on the CPU unless gpu.
+    async fn multi_device_worker(extra: &[&str], gpu: bool) -> Worker {
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "multi-device.yml",
+            &format!("multi-device: {{ host: '{address}', layers: [0, 1, 2, 3] }}"),
+        );
+        let mut worker_args = vec!["--mode", "worker", "--name", "multi-device"];
+        worker_args.extend(["--address", &address]);
+        worker_args.extend(extra);
+        let mut args = test_utils::args(&worker_args);
+        args.topology = topology;
+        args.cpu = !gpu;
+        Worker::new(Context::from_args(args).unwrap())
+            .await
+            .unwrap()
+    }
+
+    fn block_devices(worker: &Worker) -> Vec<usize> {
+        (0..4)
+            .map(|i| worker.blocks[&format!("model.layers.{i}")].0)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn layers_are_loaded_on_their_device() {
+        let worker = multi_device_worker(&["--devices", "0,1"], false).await;
+        assert_eq!(worker.devices.len(), 2);
+        assert_eq!(worker.caches.len(), 2);
+        assert_eq!(block_devices(&worker), [0, 0, 1, 1]);
+
+        let extra = ["--devices", "0,1", "--cpu-offload-layers", "1"];
+        let worker = multi_device_worker(&extra, false).await;
+        assert_eq!(worker.devices.len(), 3);
+        assert!(worker.devices[2].is_cpu());
+        assert_eq!(block_devices(&worker), [0, 0, 1, 2]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs two GPUs"]
+    async fn layers_are_loaded_on_their_gpu() {
+        let worker = multi_device_worker(&["--devices", "0,1"], true).await;
+        assert_eq!(block_devices(&worker), [0, 0, 1, 1]);
+        let ordinals: Vec<_> = worker
+            .devices
+            .iter()
+            .map(|device| match device.location() {
+                candle_core::DeviceLocation::Cuda { gpu_id }
+                | candle_core::DeviceLocation::Metal { gpu_id } => gpu_id,
+                location => panic!("{location:?} isn't a gpu"),
+            })
+            .collect();
+        assert_eq!(ordinals, [0, 1]);
+    }
+
+    #[tokio::test]
+    async fn offloaded_layers_give_the_same_text() {
+        let text = |args: crate::Args| async move {
+            let mut master = test_utils::master(args).await;
+            let mut text = String::new();
+            master
+                .generate(&crate::cake::CancellationToken::default(), |t| {
+                    text.push_str(t)
+                })
+                .await
+                .unwrap();
+            text
+        };
+        let args = test_utils::args(&["--max-tokens", "8", "--ignore-eos", "--prompt", "the cat"]);
+        let expected = text(args.clone()).await;
+
+        // the last of the layers of the worker on the cpu, after the move of the activations
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "cpu-offload.yml",
+            &format!("offload: {{ host: '{address}', layers: [1, 2, 3] }}"),
+        );
+        let extra = ["--devices", "0,1", "--cpu-offload-layers", "1"];
+        let _worker = test_utils::worker("offload", &topology, &extra).await;
+        let offloaded = crate::Args {
+            topology: topology.clone(),
+            ..args
+        };
+        assert_eq!(text(offloaded).await, expected);
+
+        let mut worker_args = vec!["--mode", "worker", "--name", "offload"];
+        worker_args.extend(["--address", &address, "--cpu-offload-layers", "4"]);
+        let mut args = test_utils::args(&worker_args);
+        args.topology = topology;
+        let Err(e) = Worker::new(Context::from_args(args).unwrap()).await else {
+            panic!("more layers offloaded than served");
+        };
+        assert!(e.to_string().contains("exceeds the 3 layers"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn default_args_load_a_worker() {
+        let defaults = crate::Args::default();
+        assert_eq!(defaults.worker_concurrency, 1);
+        assert_eq!(defaults.worker_queue, 16);
+        assert_eq!(defaults.heartbeat_interval, 1000);
+        assert!(defaults.heartbeat_misses > 0 && defaults.reconnect_attempts > 0);
+
+        // the fields of the ios worker, the others left to their defaults
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "default-args.yml",
+            &format!("ios: {{ host: '{address}', layers: [0, 1, 2, 3] }}"),
+        );
+        let args = crate::Args {
+            address: address.clone(),
+            mode: crate::cake::Mode::Worker,
+            name: Some("ios".to_string()),
+            model: test_utils::model_dir().display().to_string(),
+            topology: topology.clone(),
+            cpu: true,
+            dtype: Some("f32".to_string()),
+            ..Default::default()
+        };
+        let _worker = test_utils::TestWorker::start(args).await.unwrap();
+
+        let mut args = test_utils::args(&["--max-tokens", "4", "--prompt", "the cat"]);
+        args.topology = topology;
+        let mut master = test_utils::master(args).await;
+        let mut text = String::new();
+        master
+            .generate(&crate::cake::CancellationToken::default(), |t| {
+                text.push_str(t)
+            })
+            .await
+            .unwrap();
+        assert!(!text.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a GPU"]
+    async fn offloaded_layers_run_on_the_cpu() {
+        let worker = multi_device_worker(&["--cpu-offload-layers", "2"], true).await;
+        assert_eq!(worker.devices.len(), 2);
+        assert!(!worker.devices[0].is_cpu());
+        assert!(worker.devices[1].is_cpu());
+        assert_eq!(block_devices(&worker), [0, 0, 1, 1]);
+        assert!(worker.caches[1].cos.device().is_cpu());
+
+        // the gpu layers followed by the cpu ones, like all of them on the cpu
+        let all_cpu = multi_device_worker(&[], false).await;
+        let y = worker.warmup(64).await.unwrap();
+        assert!(y.device().is_cpu());
+        let expected = all_cpu.warmup(64).await.unwrap();
+        let diff = (y.to_dtype(DType::F32).unwrap() - expected.to_dtype(DType::F32).unwrap())
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!(diff < 1e-3, "{diff}");
+    }
+
+    #[tokio::test]
+    async fn shutdown_is_notified_to_the_clients() {
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "shutting-down.yml",
+            &format!("shutting-down: {{ host: '{address}', layers: [0] }}"),
+        );
+        let mut args = test_utils::args(&[
+            "--mode",
+            "worker",
+            "--name",
+            "shutting-down",
+            "--address",
+            &address,
+        ]);
+        args.topology = topology;
+        let mut worker = Worker::new(Context::from_args(args).unwrap())
+            .await
+            .unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let task = tokio::spawn(async move {
+            worker
+                .run_until(async {
+                    let _ = stopped.await;
+                })
+                .await
+        });
+        test_utils::wait_listening(&address).await;
+
+        let mut stream = TcpStream::connect(&address).await.unwrap();
+        Message::Hello {
+            auth_token: None,
+            master_id: None,
+        }
+        .to_writer(&mut stream)
+        .await
+        .unwrap();
+        assert!(matches!(
+            Message::from_reader(&mut stream, MESSAGE_MAX_SIZE).await,
+            Ok(Message::WorkerInfo(_))
+        ));
+        let mut client = Client::new(
+            Device::Cpu,
+            &address,
+            "model.layers.0",
+            ConnectionOptions {
+                reconnect_attempts: 0,
+                ..test_utils::connection_options()
+            },
+        )
+        .await
+        .unwrap();
+
+        stop.send(()).unwrap();
+        assert!(matches!(next(&mut stream).await, Ok(Ok(Message::Shutdown))));
+        // the accept loop returns once the connections are over
+        tokio::time::timeout(Duration::from_secs(2), task)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(TcpStream::connect(&address).await.is_err());
+
+        // the client gives up on the worker rather than failing on a broken connection
+        let x = Tensor::ones((1, 1, 64), DType::F32, &Device::Cpu).unwrap();
+        let mut cache =
+            crate::model::Cache::new(true, DType::F32, &test_utils::model_config(), &Device::Cpu)
+                .unwrap();
+        assert!(client.forward(&x, 0, 0, &mut cache).await.is_err());
+        assert!(!client.status().healthy);
+    }
+
+    /// Connection to the worker at address, once the handshake is done.
+    async fn connect(address: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        Message::Hello {
+            auth_token: None,
+            master_id: None,
+        }
+        .to_writer(&mut stream)
+        .await
+        .unwrap();
+        assert!(matches!(
+            Message::from_reader(&mut stream, MESSAGE_MAX_SIZE).await,
+            Ok(Message::WorkerInfo(_))
+        ));
+        stream
+    }
+
+    /// Next message of the stream other than a heartbeat.
+    async fn response(stream: &mut TcpStream) -> Message {
+        loop {
+            match Message::from_reader(stream, MESSAGE_MAX_SIZE)
+                .await
+                .unwrap()
+            {
+                Message::Heartbeat => {}
+                msg => return msg,
+            }
+        }
+    }
+
+    /// Number of forward requests waiting for a slot, as reported by the worker at address.
+    async fn queued(address: &str) -> usize {
+        let mut stream = connect(address).await;
+        Message::StatusRequest.to_writer(&mut stream).await.unwrap();
+        match response(&mut stream).await {
+            Message::StatusResponse(state) => state.queued,
+            msg => panic!("unexpected response {msg:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_the_queue_are_rejected() {
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "queueing.yml",
+            &format!("queueing: {{ host: '{address}', layers: [0] }}"),
+        );
+        let mut args = test_utils::args(&[
+            "--mode",
+            "worker",
+            "--name",
+            "queueing",
+            "--address",
+            &address,
+            "--worker-queue",
+            "2",
+        ]);
+        args.topology = topology;
+        let mut worker = Worker::new(Context::from_args(args).unwrap())
+            .await
+            .unwrap();
+        // the only slot is taken until the queue is full
+        let slot = worker
+            .settings
+            .forward_slots
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap();
+        let task = tokio::spawn(async move { worker.run_until(std::future::pending()).await });
+        test_utils::wait_listening(&address).await;
+
+        let x = Tensor::ones((1, 1, 64), DType::F32, &Device::Cpu).unwrap();
+        let request = Message::transformer_op("model.layers.0", &x, 0, 0);
+        let mut waiting = vec![];
+        for expected in 1..=2 {
+            let mut stream = connect(&address).await;
+            request.to_writer(&mut stream).await.unwrap();
+            while queued(&address).await < expected {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            waiting.push(stream);
+        }
+
+        // the next requests are rejected rather than held in memory, as long as the queue is full
+        for _ in 0..3 {
+            let mut stream = connect(&address).await;
+            request.to_writer(&mut stream).await.unwrap();
+            assert!(matches!(response(&mut stream).await, Message::Overloaded));
+        }
+        assert_eq!(queued(&address).await, 2);
+
+        drop(slot);
+        for stream in &mut waiting {
+            assert!(matches!(response(stream).await, Message::Tensor(_)));
+        }
+        assert_eq!(queued(&address).await, 0);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn masters_have_caches_of_their_own() {
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "shared-worker.yml",
+            &format!("shared-worker: {{ host: '{address}', layers: [2] }}"),
+        );
+        // the keys and values of a position of the layer take 2 * 2 heads * 16 * 4 bytes
+        let extra = ["--max-master-cache-bytes", "1536"];
+        let _worker = test_utils::worker("shared-worker", &topology, &extra).await;
+        let client = |master_id: &str| {
+            let options = ConnectionOptions {
+                master_id: Some(master_id.to_string()),
+                ..test_utils::connection_options()
+            };
+            Client::new(Device::Cpu, &address, "model.layers.2", options)
+        };
+        let (mut a, mut b) = (client("a").await.unwrap(), client("b").await.unwrap());
+
+        // every master gets the outputs of a cache of its own
+        let ctx = Context::from_args(test_utils::args(&[])).unwrap();
+        let block = Block::load(
+            "model.layers.2",
+            ctx.var_builder.pp("model.layers.2"),
+            &ctx.config,
+        )
+        .unwrap();
+        let mut caches = [ctx.cache.as_new(), ctx.cache.as_new()];
+        let mut unused = ctx.cache.as_new();
+        for pos in 0..4 {
+            for (master, client) in [&mut a, &mut b].into_iter().enumerate() {
+                let x = Tensor::randn(0f32, 1., (1, 1, 64), &Device::Cpu).unwrap();
+                let y = client.forward(&x, pos, 2, &mut unused).await.unwrap();
+                let expected = block
+                    .forward_imm(&x, pos, 2, &mut caches[master])
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    y.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+                    expected.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+                );
+            }
+        }
+
+        // another connection of a shares its limit, not b
+        let mut other_a = client("a").await.unwrap();
+        let x = Tensor::ones((1, 1, 64), DType::F32, &Device::Cpu).unwrap();
+        for pos in 0..2 {
+            other_a.forward(&x, pos, 2, &mut unused).await.unwrap();
+        }
+        let e = other_a.forward(&x, 2, 2, &mut unused).await.unwrap_err();
+        assert!(e.to_string().contains("master a holds 1792 bytes"), "{e}");
+        b.forward(&x, 4, 2, &mut unused).await.unwrap();
+
+        // the limit goes with the connections of the master
+        drop(a);
+        drop(other_a);
+        // once the worker has read their shutdown
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut new_a = client("a").await.unwrap();
+        for pos in 0..4 {
+            new_a.forward(&x, pos, 2, &mut unused).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn panicking_forward_is_reported() {
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "panic.yml",
+            &format!("panic-worker: {{ host: '{address}', layers: [2, 3] }}"),
+        );
+        let _worker = test_utils::worker("panic-worker", &topology, &[]).await;
+        let mut client = Client::new(
+            Device::Cpu,
+            &address,
+            "model.layers.2",
+            test_utils::connection_options(),
+        )
+        .await
+        .unwrap();
+        let x = Tensor::ones((1, 1, 64), DType::F32, &Device::Cpu).unwrap();
+        let mut cache =
+            crate::model::Cache::new(true, DType::F32, &test_utils::model_config(), &Device::Cpu)
+                .unwrap();
+
+        // the cache of the worker has no entry for block 100
+        let e = client.forward(&x, 0, 100, &mut cache).await.unwrap_err();
+        match CakeError::from(e) {
+            CakeError::WorkerInternal { addr, message } => {
+                assert_eq!(addr, address);
+                assert!(message.contains("forward panicked"), "{message}");
+                assert!(message.contains("out of bounds"), "{message}");
+            }
+            e => panic!("unexpected error {e:?}"),
+        }
+
+        // the worker goes on serving the connection
+        let y = client.forward(&x, 0, 2, &mut cache).await.unwrap();
+        assert_eq!(y.dims(), [1, 1, 64]);
+
+        // the cache written so far is dropped along with the failing forward, for the master to
+        // process the sequences again
+        let e = client.forward(&x, 1, 100, &mut cache).await.unwrap_err();
+        match e.downcast_ref() {
+            Some(ClientError::CacheLost {
+                address: addr,
+                reason: Some(reason),
+            }) => {
+                assert_eq!(addr, &address);
+                assert!(reason.contains("forward panicked"), "{reason}");
+            }
+            _ => panic!("unexpected error {e:?}"),
+        }
+
+        // the next forward attends over a fresh cache, not over the position written before
+        let ctx = Context::from_args(test_utils::args(&[])).unwrap();
+        let block = Block::load(
+            "model.layers.2",
+            ctx.var_builder.pp("model.layers.2"),
+            &ctx.config,
+        )
+        .unwrap();
+        let next = Tensor::randn(0f32, 1., (1, 1, 64), &Device::Cpu).unwrap();
+        let y = client.forward(&next, 1, 2, &mut cache).await.unwrap();
+        let fresh = block
+            .forward_imm(&next, 1, 2, &mut ctx.cache.as_new())
+            .await
+            .unwrap();
+        let mut stale = ctx.cache.as_new();
+        block.forward_imm(&x, 0, 2, &mut stale).await.unwrap();
+        let stale = block.forward_imm(&next, 1, 2, &mut stale).await.unwrap();
+        let values = |t: &Tensor| t.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+        assert_eq!(values(&y), values(&fresh));
+        assert_ne!(values(&y), values(&stale));
+    }
+}
@@ -1,63 +1,389 @@
 #[macro_use]
 extern crate anyhow;
 
+use std::collections::HashMap;
+
 use cake::Mode;
 
 use clap::Parser;
 
 pub mod cake;
 pub mod model;
+pub mod prompt;
 pub mod utils;
 
-#[derive(Parser, Default, Debug)]
+#[cfg(test)]
+mod test_utils;
+
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     /// GPU device index.
     #[arg(long, default_value_t = 0)]
     pub device: usize,
+    /// GPU device indexes a worker spreads its layers across, overriding --device.
+    #[arg(long, value_delimiter = ',')]
+    pub devices: Vec<usize>,
+    /// Number of the last layers of a worker that run on the CPU rather than on its devices, for
+    /// the workers whose devices can't hold all of their layers.
+    #[arg(long, default_value_t = 0)]
+    pub cpu_offload_layers: usize,
     /// Mode.
     #[arg(long, default_value_t, value_enum)]
     pub mode: Mode,
+    /// How the generated text is printed in master mode.
+    #[arg(long, default_value_t, value_enum)]
+    pub output: cake::OutputFormat,
+    /// How the logs are formatted.
+    #[arg(long, default_value_t, value_enum)]
+    pub log_format: cake::LogFormat,
+    /// Append the logs to this file rather than writing them to stderr.
+    #[arg(long)]
+    pub log_file: Option<String>,
     /// Worker name.
     #[arg(long)]
     pub name: Option<String>,
-    /// Binding address and port if in worker mode.
+    /// Binding address and port if in worker or api mode.
     #[arg(long, default_value = "127.0.0.1:10128")]
     pub address: String,
+    /// Address of the master to register to in worker mode, the master then serves the layers of
+    /// the worker over connections the worker opens.
+    #[arg(long)]
+    pub master_addr: Option<String>,
+    /// Address the master accepts the registrations of the workers started with --master-addr on.
+    /// It waits for the registered workers to serve every layer the topology file doesn't assign.
+    #[arg(long)]
+    pub registry_addr: Option<String>,
     /// Llama3 model data path.
     #[arg(long, default_value = "./cake-data/Meta-Llama-3-8B/")]
     pub model: String,
+    /// Path of the tokenizer.json to use instead of the one in the model directory.
+    #[arg(long)]
+    pub tokenizer: Option<String>,
+    /// Download the files of the model the node needs from this URL to --model first, such as
+    /// https://huggingface.co/meta-llama/Meta-Llama-3-8B/resolve/main.
+    #[arg(long)]
+    pub model_url: Option<String>,
+    /// Address to serve the Prometheus metrics of the master on, as GET /metrics.
+    #[arg(long)]
+    pub metrics_addr: Option<String>,
     /// Topology file.
     #[arg(long, default_value = "./cake-data/topology.yml")]
     pub topology: String,
     /// The initial prompt.
     #[arg(long, default_value = "Hi! I am ")]
     pub prompt: String,
-    /// The seed to use when generating random samples.
-    #[arg(long, default_value_t = 299792458)]
-    pub seed: u64,
-    /// The length of the sample to generate (in tokens).
-    #[arg(short = 'n', long, default_value_t = 100)]
-    pub sample_len: usize,
-    /// The temperature used to generate samples.
-    #[arg(long, default_value_t = 1.0)]
-    pub temperature: f64,
+    /// Wrap the prompt with the Llama 3 chat template.
+    #[arg(long)]
+    pub chat: bool,
+    /// Chat with the model in master mode, reading the user turns from stdin and keeping the
+    /// caches of the conversation between turns. /reset starts a new conversation and /exit
+    /// quits.
+    #[arg(long)]
+    pub interactive: bool,
+    /// System prompt to use in chat mode.
+    #[arg(long)]
+    pub system: Option<String>,
+    /// Fill in the middle between --fim-prefix and --fim-suffix instead of continuing the prompt,
+    /// with the fill-in-the-middle tokens of the tokenizer config. The generation ends at the end
+    /// of the middle.
+    #[arg(long, conflicts_with = "chat")]
+    pub fim: bool,
+    /// Text before the middle to fill in fim mode.
+    #[arg(long, default_value_t)]
+    pub fim_prefix: String,
+    /// Text after the middle to fill in fim mode.
+    #[arg(long, default_value_t)]
+    pub fim_suffix: String,
+    /// Beginning of the reply the model continues from, after the assistant header in chat
+    /// mode. It's streamed before the generated text.
+    #[arg(long)]
+    pub prefill: Option<String>,
+    /// The seed to use when generating random samples, random if not set.
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Stop generating when this sequence is produced, can be repeated.
+    #[arg(long)]
+    pub stop: Vec<String>,
+    /// Ids of the tokens ending the generation, overriding the ones of the model config and of
+    /// its generation_config.json. The end of turn token also ends it in chat mode.
+    #[arg(long, value_delimiter = ',')]
+    pub eos_ids: Vec<u32>,
+    /// Keep generating once the end of text token is produced, until --max-tokens or a stop
+    /// sequence.
+    #[arg(long)]
+    pub ignore_eos: bool,
+    /// File with a GBNF grammar the generated text must match, generation stops once it's
+    /// complete.
+    #[arg(long)]
+    pub grammar: Option<String>,
+    /// Context length to run the model at, overriding the one of its config. Workers must be
+    /// started with the same value.
+    #[arg(long)]
+    pub max_seq_len: Option<usize>,
+    /// Base frequency of the rotary embeddings, overriding the one of the model config. Workers
+    /// must be started with the same value.
+    #[arg(long)]
+    pub rope_theta: Option<f32>,
+    /// Maximum number of new tokens to generate, fills the model context if not set.
+    #[arg(short = 'n', long, alias = "sample-len")]
+    pub max_tokens: Option<usize>,
+    /// What's done with a prompt that doesn't leave room for --max-tokens new tokens in the
+    /// context.
+    #[arg(long, default_value_t, value_enum)]
+    pub truncate: cake::Truncation,
+    /// Whether the prompts are tokenized with the bos token first, auto follows the tokenizer.
+    #[arg(long, default_value_t, value_enum)]
+    pub add_bos: cake::AddToken,
+    /// Whether the prompts are tokenized with the eos token last, auto follows the tokenizer.
+    #[arg(long, default_value_t, value_enum)]
+    pub add_eos: cake::AddToken,
+    /// Stop generating at the next token once this many milliseconds have passed since the first
+    /// generated token, whatever the number of tokens generated.
+    #[arg(long)]
+    pub max_time_ms: Option<u64>,
+    /// Count the time allowed by --max-time-ms from the start of the prompt processing instead.
+    #[arg(long)]
+    pub max_time_includes_prompt: bool,
+    /// The temperature used to generate samples, greedy sampling if not set.
+    #[arg(long)]
+    pub temperature: Option<f64>,
     /// Nucleus sampling probability cutoff.
     #[arg(long)]
     pub top_p: Option<f64>,
     /// Only sample among the top K samples.
     #[arg(long)]
     pub top_k: Option<usize>,
+    /// Only sample among the tokens at least min_p times as likely as the most likely one. It's
+    /// applied after the temperature and before top-k and top-p.
+    #[arg(long)]
+    pub min_p: Option<f64>,
     /// Penalty to be applied for repeating tokens, 1. means no penalty.
-    #[arg(long, default_value_t = 1.1)]
+    #[arg(long, default_value_t = 1.0)]
     pub repeat_penalty: f32,
     /// The context size to consider for the repeat penalty.
-    #[arg(long, default_value_t = 128)]
+    #[arg(long, default_value_t = 64)]
     pub repeat_last_n: usize,
-    /// Use different dtype than f16
+    /// Apply the repeat penalty to the prompt tokens too.
+    #[arg(long)]
+    pub repeat_penalty_prompt: bool,
+    /// Replace the NaN logits with -inf and the infinite ones with the largest finite values
+    /// before sampling, rather than failing the generation.
+    #[arg(long)]
+    pub sanitize_logits: bool,
+    /// JSON object mapping token ids to a bias added to their logits before sampling, "-inf"
+    /// bans a token.
+    #[arg(long, value_parser = utils::parse_logit_bias, default_value = "{}")]
+    pub logit_bias: HashMap<u32, f32>,
+    /// How the hidden states of the prompt tokens are pooled in embeddings mode.
+    #[arg(long, default_value_t, value_enum)]
+    pub pooling: cake::Pooling,
+    /// Number of most likely alternatives returned with the log-probability of each token.
+    #[arg(long, default_value_t = 0)]
+    pub top_logprobs: usize,
+    /// Generate this many completions and keep the one whose tokens have the highest mean
+    /// log-probability, sampling with --temperature, --top-k, --top-p or --min-p.
+    #[arg(long, default_value_t = 1)]
+    pub best_of: usize,
+    /// Small model run locally to draft the tokens verified by the full model, it must share
+    /// the tokenizer of the full model.
+    #[arg(long)]
+    pub draft_model: Option<String>,
+    /// Number of tokens drafted before each verification by the full model.
+    #[arg(long, default_value_t = 4)]
+    pub draft_tokens: usize,
+    /// Number of tokens of the synthetic prompt in bench mode.
+    #[arg(long, default_value_t = 128)]
+    pub bench_prompt_len: usize,
+    /// Also write the bench mode report to this file as JSON.
+    #[arg(long)]
+    pub bench_json: Option<String>,
+    /// Write the diagram of the topology mode to this file instead of stdout.
+    #[arg(long)]
+    pub topology_out: Option<String>,
+    /// Directory the shard mode writes the model directory of each worker to, named after it.
+    #[arg(long)]
+    pub shard_out: Option<String>,
+    /// Write the output of every layer to layer_{index}.safetensors in this directory, the master
+    /// also writes the hidden state fed to the lm_head and the logits. Every forward pass replaces
+    /// the files of the previous one.
+    #[arg(long)]
+    pub dump_activations: Option<String>,
+    /// Restore the key-value cache from this file before generating.
+    #[arg(long)]
+    pub cache_in: Option<String>,
+    /// Save the key-value cache to this file once the generation is over.
+    #[arg(long)]
+    pub cache_out: Option<String>,
+    /// Number of prompt caches kept for prompts sharing the same prefix, disabled if 0.
+    #[arg(long, default_value_t = 0)]
+    pub prefix_cache_size: usize,
+    /// Number of prompt tokens a session processes per turn when several sessions generate at
+    /// once, so that the longer prompts don't hold up the other sessions.
+    #[arg(long, default_value_t = 64)]
+    pub prefill_chunk_size: usize,
+    /// Interval in milliseconds between the heartbeats a worker sends while busy.
+    #[arg(long, default_value_t = 1000)]
+    pub heartbeat_interval: u64,
+    /// Run a forward pass through the layers of a worker once loaded, so that their weights are
+    /// read from disk before the first request rather than during it.
+    #[arg(long)]
+    pub warmup: bool,
+    /// Load the layers of a worker even if they're estimated not to fit in the memory of its
+    /// devices.
+    #[arg(long)]
+    pub skip_memory_check: bool,
+    /// Number of forward requests a worker processes at the same time, across all its
+    /// connections.
+    #[arg(long, default_value_t = 1)]
+    pub worker_concurrency: usize,
+    /// Number of forward requests a worker keeps waiting while busy, the next ones are rejected
+    /// and sent again by the masters later.
+    #[arg(long, default_value_t = 16)]
+    pub worker_queue: usize,
+    /// Number of consecutive heartbeats a worker can miss before being considered unhealthy.
+    #[arg(long, default_value_t = 5)]
+    pub heartbeat_misses: u32,
+    /// Number of attempts to reconnect to a worker once its connection drops.
+    #[arg(long, default_value_t = 5)]
+    pub reconnect_attempts: usize,
+    /// Once a worker can't be reached anymore, load its layers on the CPU of the master and
+    /// process the current sequences again rather than failing. The master maps the whole
+    /// checkpoint to do so.
+    #[arg(long)]
+    pub fallback_local: bool,
+    /// Delay in milliseconds before the first reconnection attempt, doubled at every attempt.
+    #[arg(long, default_value_t = 250)]
+    pub reconnect_backoff_base: u64,
+    /// Maximum delay in milliseconds between two reconnection attempts.
+    #[arg(long, default_value_t = 8000)]
+    pub reconnect_backoff_max: u64,
+    /// Time in milliseconds a worker has to answer each forward request, no limit if 0.
+    #[arg(long, default_value_t = 0)]
+    pub rpc_timeout_ms: u64,
+    /// Certificate in PEM format, for the worker to serve TLS connections or for the master to
+    /// authenticate to the workers.
+    #[arg(long)]
+    pub tls_cert: Option<String>,
+    /// Private key in PEM format of the --tls-cert certificate.
+    #[arg(long)]
+    pub tls_key: Option<String>,
+    /// CA certificate in PEM format, enables TLS on the master to verify the workers and, once
+    /// set on a worker, rejects the clients without a certificate signed by it.
+    #[arg(long)]
+    pub tls_ca: Option<String>,
+    /// Token the master sends to the workers when connecting, the workers reject the connections
+    /// without the same token.
+    #[arg(long)]
+    pub auth_token: Option<String>,
+    /// Id the master identifies itself with to the workers, which account the cache of the
+    /// connections of each master separately. Random if not set.
+    #[arg(long)]
+    pub master_id: Option<String>,
+    /// Bytes of key-value cache a worker holds at most for each master, across its connections.
+    /// The forward requests growing it past the limit fail and clear the cache of their
+    /// connection. Unlimited if not set.
+    #[arg(long)]
+    pub max_master_cache_bytes: Option<u64>,
+    /// Compress the messages sent to the workers with zstd, at the given level or the default one.
+    /// Workers reply compressed to the masters that compress their messages.
+    #[arg(long, num_args = 0..=1, default_missing_value = "3")]
+    pub compress: Option<i32>,
+    /// Send a CRC32 checksum with every tensor, the receiver verifies it and a corrupted request
+    /// is sent again. Workers add checksums to their responses once a request carries them.
+    #[arg(long)]
+    pub verify_checksums: bool,
+    /// Rate the master sends its messages to each worker connection at, in megabits per second.
+    /// Unlimited if not set.
+    #[arg(long)]
+    pub max_bandwidth_mbps: Option<f64>,
+    /// Split the prompts in this many chunks going through the workers one after another, so that
+    /// while a worker computes a chunk the worker serving the next layers computes the previous
+    /// one. Cuts the time to the first token when the activations take long to transfer. Prompts
+    /// are processed at once if not set.
+    #[arg(long)]
+    pub pipeline_chunks: Option<usize>,
+    /// Size in bytes above which a message read from another node is rejected before its payload
+    /// is allocated. 512MiB if not set, which is also the largest message that is sent.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=cake::MESSAGE_MAX_SIZE as i64))]
+    pub max_message_bytes: Option<u32>,
+    /// Dtype to load the model in, the one of its safetensors checkpoint if not set, f16 for
+    /// GGUF models.
     #[arg(long)]
     pub dtype: Option<String>,
+    /// Dtype the attention scores, their softmax and the RMS norms are computed in, their inputs
+    /// being cast to it and their outputs back to --dtype. The attention is computed in f32 and
+    /// the norms in --dtype if not set.
+    #[arg(long)]
+    pub compute_dtype: Option<String>,
+    /// Dtype the master casts the activations to between the layers, including the ones it
+    /// exchanges with the workers, which cast them to their own dtype. The weights stay in their
+    /// dtype, only the precision of the activations changes. The API takes it per request as
+    /// `dtype`.
+    #[arg(long)]
+    pub activation_dtype: Option<String>,
+    /// Quantize the weights of the linear layers as they are loaded.
+    #[arg(long, value_enum)]
+    pub quantize: Option<model::Quantization>,
+    /// Store the key-value cache in this dtype rather than in the dtype of the model.
+    #[arg(long, value_enum)]
+    pub kv_cache_dtype: Option<model::KvCacheDtype>,
+    /// Compute the attention with the fused flash attention kernel, on CUDA devices loading the
+    /// model in f16 or bf16 with cake compiled with the flash-attn feature. Falls back to the
+    /// standard attention otherwise.
+    #[arg(long)]
+    pub flash_attn: bool,
+    /// Store the key-value cache in a pool of fixed-size blocks allocated to the sequences as they
+    /// grow, rather than in contiguous tensors.
+    #[arg(long)]
+    pub paged_kv: bool,
+    /// Number of positions of each block of the paged key-value cache.
+    #[arg(long, default_value_t = 16)]
+    pub kv_block_size: usize,
+    /// Number of blocks of the paged key-value cache, shared by all the sequences.
+    #[arg(long, default_value_t = 256)]
+    pub kv_blocks: usize,
     /// Run on CPU rather than on GPU.
     #[arg(long)]
     pub cpu: bool,
+    /// Devices to try in order, the first one that can be initialized is used. Defaults to
+    /// cuda,metal,cpu.
+    #[arg(long, value_delimiter = ',', value_enum, conflicts_with = "cpu")]
+    pub device_preference: Vec<utils::DeviceKind>,
+    /// Number of threads used by the CPU operations, all the cores if not set.
+    #[arg(long)]
+    pub threads: Option<usize>,
+    /// Log the progress of the safetensors shards while they're loaded.
+    #[arg(long)]
+    pub progress: bool,
+    /// How the safetensors shards are loaded, eager reads them whole into memory rather than
+    /// mapping them, for network filesystems.
+    #[arg(long, default_value_t, value_enum)]
+    pub load_mode: model::LoadMode,
+}
+
+impl Default for Args {
+    /// The defaults of the command line, the fields not named by a struct literal taking the
+    /// values they'd have had without their flag.
+    fn default() -> Self {
+        Self::parse_from(["cake"])
+    }
+}
+
+impl Args {
+    /// The kinds of devices to try in order, only the cpu with --cpu.
+    pub fn device_preference(&self) -> Vec<utils::DeviceKind> {
+        if self.cpu {
+            vec![utils::DeviceKind::Cpu]
+        } else if self.device_preference.is_empty() {
+            utils::DEFAULT_DEVICE_PREFERENCE.to_vec()
+        } else {
+            self.device_preference.clone()
+        }
+    }
+
+    /// The --max-message-bytes limit of the messages read, MESSAGE_MAX_SIZE if not set.
+    pub fn max_message_bytes(&self) -> u32 {
+        self.max_message_bytes.unwrap_or(cake::MESSAGE_MAX_SIZE)
+    }
 }
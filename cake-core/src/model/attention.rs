@@ -1,8 +1,7 @@
 use candle_core::{DType, Result, Tensor, D};
 use candle_nn::{Module, VarBuilder};
-use candle_transformers::models::with_tracing::{linear_no_bias as linear, Linear};
 
-use super::MAX_SEQ_LEN;
+use super::{linear, Linear};
 
 #[derive(Debug, Clone)]
 pub struct CausalSelfAttention {
@@ -13,11 +12,32 @@ pub struct CausalSelfAttention {
     num_attention_heads: usize,
     num_key_value_heads: usize,
     head_dim: usize,
+    /// Dtype the attention scores and their softmax are computed in.
+    compute_dtype: DType,
+}
+
+#[cfg(feature = "flash-attn")]
+fn flash_attn(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    softmax_scale: f32,
+    causal: bool,
+) -> Result<Tensor> {
+    candle_flash_attn::flash_attn(q, k, v, softmax_scale, causal)
+}
+
+#[cfg(not(feature = "flash-attn"))]
+fn flash_attn(_: &Tensor, _: &Tensor, _: &Tensor, _: f32, _: bool) -> Result<Tensor> {
+    // Cache::flash_attn is never set without the feature
+    unreachable!("compiled without the flash-attn feature")
 }
 
 fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
     let shape = mask.shape();
-    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
+    let on_true = Tensor::new(on_true, on_false.device())?
+        .to_dtype(on_false.dtype())?
+        .broadcast_as(shape.dims())?;
     let m = mask.where_cond(&on_true, on_false)?;
     Ok(m)
 }
@@ -55,31 +75,34 @@ impl CausalSelfAttention {
             .reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?
             .transpose(1, 2)?
             .contiguous()?;
-        let mut v = v
+        let v = v
             .reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?
             .transpose(1, 2)?;
 
         let q = self.apply_rotary_emb(&q, index_pos, cache)?;
-        let mut k = self.apply_rotary_emb(&k, index_pos, cache)?;
-
-        if cache.use_kv_cache {
-            if let Some((cache_k, cache_v)) = &cache.kvs[block_idx] {
-                k = Tensor::cat(&[cache_k, &k], 2)?.contiguous()?;
-                v = Tensor::cat(&[cache_v, &v], 2)?.contiguous()?;
-                let k_seq_len = k.dims()[1];
-                if k_seq_len > MAX_SEQ_LEN {
-                    k = k
-                        .narrow(D::Minus1, k_seq_len - MAX_SEQ_LEN, MAX_SEQ_LEN)?
-                        .contiguous()?
-                }
-                let v_seq_len = v.dims()[1];
-                if v_seq_len > 2 * MAX_SEQ_LEN {
-                    v = v
-                        .narrow(D::Minus1, v_seq_len - MAX_SEQ_LEN, MAX_SEQ_LEN)?
-                        .contiguous()?
-                }
-            }
-            cache.kvs[block_idx] = Some((k.clone(), v.clone()))
+        let k = self.apply_rotary_emb(&k, index_pos, cache)?;
+
+        // the cache only holds the key-value heads, they're repeated for their query heads below
+        let (k, v) = if cache.use_kv_cache {
+            cache.append_kv(block_idx, index_pos, k, v.contiguous()?)?
+        } else {
+            (k, v)
+        };
+
+        // the kernel doesn't return the attention probabilities
+        if cache.flash_attn
+            && !cache.capture_attention
+            && cache.padding_mask(index_pos, seq_len, k.dim(2)?)?.is_none()
+        {
+            // the kernel takes (batch, seq_len, heads, head_dim) tensors, handles the grouped
+            // key-value heads and aligns the causal mask on the last key like Cache::mask does
+            let q = q.transpose(1, 2)?;
+            let k = k.transpose(1, 2)?;
+            let v = v.transpose(1, 2)?;
+            let softmax_scale = 1. / (self.head_dim as f32).sqrt();
+            let y = flash_attn(&q, &k, &v, softmax_scale, seq_len > 1)?;
+            let y = y.reshape(&[b_sz, seq_len, hidden_size])?;
+            return self.o_proj.forward(&y);
         }
 
         let k = self.repeat_kv(k)?;
@@ -87,17 +110,23 @@ impl CausalSelfAttention {
 
         let y = {
             let in_dtype = q.dtype();
-            let q = q.to_dtype(DType::F32)?;
-            let k = k.to_dtype(DType::F32)?;
-            let v = v.to_dtype(DType::F32)?;
+            let q = q.to_dtype(self.compute_dtype)?;
+            let k = k.to_dtype(self.compute_dtype)?;
+            let v = v.to_dtype(self.compute_dtype)?;
             let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
-            let att = if seq_len == 1 {
+            let kv_len = att.dim(D::Minus1)?;
+            let att = if let Some(mask) = cache.padding_mask(index_pos, seq_len, kv_len)? {
+                masked_fill(&att, &mask.broadcast_as(att.shape())?, f32::NEG_INFINITY)?
+            } else if seq_len == 1 && !cache.exceeds_window(kv_len) {
                 att
             } else {
-                let mask = cache.mask(seq_len)?.broadcast_as(att.shape())?;
+                let mask = cache.mask(seq_len, kv_len)?.broadcast_as(att.shape())?;
                 masked_fill(&att, &mask, f32::NEG_INFINITY)?
             };
             let att = candle_nn::ops::softmax(&att, D::Minus1)?;
+            if cache.capture_attention {
+                cache.record_attention(att.clone());
+            }
             // Convert to contiguous as matmul doesn't support strided vs for now.
             att.matmul(&v.contiguous()?)?.to_dtype(in_dtype)?
         };
@@ -106,6 +135,9 @@ impl CausalSelfAttention {
         Ok(y)
     }
 
+    /// Repeats the (batch, num_key_value_heads, seq_len, head_dim) keys or values for the query
+    /// heads, query head i attending to the key-value head i / (num_attention_heads /
+    /// num_key_value_heads).
     fn repeat_kv(&self, x: Tensor) -> Result<Tensor> {
         candle_transformers::utils::repeat_kv(
             x,
@@ -117,10 +149,10 @@ impl CausalSelfAttention {
         let size_in = cfg.hidden_size;
         let size_q = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_attention_heads;
         let size_kv = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_key_value_heads;
-        let q_proj = linear(size_in, size_q, vb.pp("q_proj"))?;
-        let k_proj = linear(size_in, size_kv, vb.pp("k_proj"))?;
-        let v_proj = linear(size_in, size_kv, vb.pp("v_proj"))?;
-        let o_proj = linear(size_q, size_in, vb.pp("o_proj"))?;
+        let q_proj = linear(size_in, size_q, vb.pp("q_proj"), cfg)?;
+        let k_proj = linear(size_in, size_kv, vb.pp("k_proj"), cfg)?;
+        let v_proj = linear(size_in, size_kv, vb.pp("v_proj"), cfg)?;
+        let o_proj = linear(size_q, size_in, vb.pp("o_proj"), cfg)?;
         Ok(Self {
             q_proj,
             k_proj,
@@ -129,6 +161,241 @@ impl CausalSelfAttention {
             num_attention_heads: cfg.num_attention_heads,
             num_key_value_heads: cfg.num_key_value_heads,
             head_dim: cfg.hidden_size / cfg.num_attention_heads,
+            compute_dtype: cfg.compute_dtype.unwrap_or(DType::F32),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device};
+
+    use super::*;
+    use crate::{
+        cake::Context,
+        model::{Cache, Config},
+        test_utils,
+    };
+
+    const WINDOW: usize = 4;
+
+    fn diff(a: &Tensor, b: &Tensor) -> f32 {
+        (a - b)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar()
+            .unwrap()
+    }
+
+    #[test]
+    fn sliding_window_hides_the_older_keys() {
+        let dir = test_utils::model_dir_with(
+            "sliding-window",
+            serde_json::json!({ "sliding_window": WINDOW }),
+        );
+        let ctx = Context::from_args(test_utils::args_for(&dir, &[])).unwrap();
+        assert_eq!(ctx.config.sliding_window, Some(WINDOW));
+        let attention =
+            CausalSelfAttention::load(ctx.var_builder.pp("model.layers.0.self_attn"), &ctx.config)
+                .unwrap();
+        let full_config = Config {
+            sliding_window: None,
+            ..ctx.config.clone()
+        };
+
+        let len = 10;
+        let x = Tensor::randn(0f32, 1., (1, len, 64), &Device::Cpu).unwrap();
+        let mut prefill = ctx.cache.as_new();
+        let prefilled = attention.forward(&x, 0, 0, &mut prefill).unwrap();
+
+        // decoding one token at a time keeps the last keys only
+        let mut decoding = ctx.cache.as_new();
+        for pos in 0..len {
+            let y = attention
+                .forward(&x.narrow(1, pos, 1).unwrap(), pos, 0, &mut decoding)
+                .unwrap();
+            assert!(diff(&y, &prefilled.narrow(1, pos, 1).unwrap()) < 1e-5);
+        }
+        let (k, _) = decoding.kv(0).unwrap().unwrap();
+        assert_eq!(k.dim(2).unwrap(), 2 * WINDOW - 1);
+
+        // rope only depends on the distance between the positions: a query attending to the
+        // window is a query attending to the same keys at the start of a sequence
+        for pos in 0..len {
+            let start = (pos + 1).saturating_sub(WINDOW);
+            let mut cache = Cache::new(true, DType::F32, &full_config, &Device::Cpu).unwrap();
+            let window = x.narrow(1, start, pos + 1 - start).unwrap();
+            let expected = attention.forward(&window, 0, 0, &mut cache).unwrap();
+            let expected = expected.narrow(1, pos - start, 1).unwrap();
+            let y = prefilled.narrow(1, pos, 1).unwrap();
+            assert!(diff(&y, &expected) < 1e-4, "{pos}: {}", diff(&y, &expected));
+        }
+
+        // without a window every key is attended to
+        let mut cache = Cache::new(true, DType::F32, &full_config, &Device::Cpu).unwrap();
+        let full = attention.forward(&x, 0, 0, &mut cache).unwrap();
+        assert_eq!(
+            diff(
+                &full.narrow(1, 0, WINDOW).unwrap(),
+                &prefilled.narrow(1, 0, WINDOW).unwrap()
+            ),
+            0.
+        );
+        assert!(
+            diff(
+                &full.narrow(1, WINDOW, 1).unwrap(),
+                &prefilled.narrow(1, WINDOW, 1).unwrap()
+            ) > 1e-3
+        );
+    }
+
+    #[test]
+    fn grouped_key_value_heads() {
+        let ctx = Context::from_args(test_utils::args(&[])).unwrap();
+        let config = &ctx.config;
+        assert_eq!(
+            (config.num_attention_heads, config.num_key_value_heads),
+            (4, 2)
+        );
+        let vb = ctx.var_builder.pp("model.layers.0.self_attn");
+        let attention = CausalSelfAttention::load(vb.clone(), config).unwrap();
+
+        // the same attention with every key-value head duplicated for the two query heads of its
+        // group
+        let weight = |name: &str, rows: usize| vb.get((rows, 64), name).unwrap();
+        let expand = |w: Tensor| {
+            w.reshape((2, 1, 16, 64))
+                .unwrap()
+                .broadcast_as((2, 2, 16, 64))
+                .unwrap()
+                .reshape((64, 64))
+                .unwrap()
+        };
+        let tensors = std::collections::HashMap::from([
+            ("q_proj.weight".to_string(), weight("q_proj.weight", 64)),
+            (
+                "k_proj.weight".to_string(),
+                expand(weight("k_proj.weight", 32)),
+            ),
+            (
+                "v_proj.weight".to_string(),
+                expand(weight("v_proj.weight", 32)),
+            ),
+            ("o_proj.weight".to_string(), weight("o_proj.weight", 64)),
+        ]);
+        let expanded_config = Config {
+            num_key_value_heads: 4,
+            ..config.clone()
+        };
+        let expanded = CausalSelfAttention::load(
+            VarBuilder::from_tensors(tensors, DType::F32, &Device::Cpu),
+            &expanded_config,
+        )
+        .unwrap();
+
+        let x = Tensor::randn(0f32, 1., (1, 5, 64), &Device::Cpu).unwrap();
+        let next = Tensor::randn(0f32, 1., (1, 1, 64), &Device::Cpu).unwrap();
+        let mut cache = Cache::new(true, DType::F32, config, &Device::Cpu).unwrap();
+        let mut expanded_cache =
+            Cache::new(true, DType::F32, &expanded_config, &Device::Cpu).unwrap();
+        for (x, pos) in [(&x, 0), (&next, 5)] {
+            let y = attention.forward(x, pos, 0, &mut cache).unwrap();
+            let expected = expanded.forward(x, pos, 0, &mut expanded_cache).unwrap();
+            assert!(diff(&y, &expected) < 1e-5, "{}", diff(&y, &expected));
+        }
+
+        // the cache only holds the key-value heads
+        let (k, v) = cache.kv(0).unwrap().unwrap();
+        assert_eq!(k.dims(), [1, 2, 6, 16]);
+        assert_eq!(v.dims(), [1, 2, 6, 16]);
+        let (expanded_k, _) = expanded_cache.kv(0).unwrap().unwrap();
+        assert_eq!(expanded_k.dims(), [1, 4, 6, 16]);
+        // query heads 0 and 1 attend to the keys of head 0, 2 and 3 to the ones of head 1
+        for head in 0..4 {
+            let key = k.narrow(1, head / 2, 1).unwrap();
+            assert!(diff(&key, &expanded_k.narrow(1, head, 1).unwrap()) < 1e-6);
+        }
+        assert_eq!(expanded_cache.memory(), 2 * cache.memory());
+    }
+
+    #[test]
+    fn flash_attention_is_ignored_where_unsupported() {
+        // the kernel needs a CUDA device, whatever the dtype
+        for dtype in ["f32", "f16"] {
+            let args = crate::Args {
+                dtype: Some(dtype.to_string()),
+                ..test_utils::args(&["--flash-attn"])
+            };
+            let ctx = Context::from_args(args).unwrap();
+            assert!(!ctx.cache.flash_attn);
+        }
+    }
+
+    /// Runs on the first CUDA device, if any.
+    #[cfg(feature = "flash-attn")]
+    #[test]
+    fn flash_attention_matches_the_standard_one() {
+        let Ok(device) = Device::new_cuda(0) else {
+            return;
+        };
+        let args = crate::Args {
+            cpu: false,
+            dtype: Some("f16".to_string()),
+            flash_attn: true,
+            ..test_utils::args(&[])
+        };
+        let ctx = Context::from_args(args).unwrap();
+        assert!(ctx.cache.flash_attn);
+        let attention =
+            CausalSelfAttention::load(ctx.var_builder.pp("model.layers.0.self_attn"), &ctx.config)
+                .unwrap();
+
+        let len = 12;
+        let x = Tensor::randn(0f32, 1., (1, len, 64), &device)
+            .unwrap()
+            .to_dtype(DType::F16)
+            .unwrap();
+        let run = |flash_attn: bool| {
+            let mut cache = ctx.cache.as_new();
+            cache.flash_attn = flash_attn;
+            // the prompt, then a token at a time
+            let prefill = attention
+                .forward(&x.narrow(1, 0, len - 2).unwrap(), 0, 0, &mut cache)
+                .unwrap();
+            let mut ys = vec![prefill];
+            for pos in len - 2..len {
+                let token = x.narrow(1, pos, 1).unwrap();
+                ys.push(attention.forward(&token, pos, 0, &mut cache).unwrap());
+            }
+            Tensor::cat(&ys, 1).unwrap().to_dtype(DType::F32).unwrap()
+        };
+
+        let (flash, standard) = (run(true), run(false));
+        assert!(
+            diff(&flash, &standard) < 1e-2,
+            "{}",
+            diff(&flash, &standard)
+        );
+    }
+
+    #[test]
+    fn sliding_window_mask() {
+        let config = Config {
+            sliding_window: Some(2),
+            ..test_utils::model_config()
+        };
+        let mut cache = Cache::new(true, DType::F32, &config, &Device::Cpu).unwrap();
+        let mask = cache.mask(3, 4).unwrap().to_vec2::<u8>().unwrap();
+        assert_eq!(mask, [[0, 0, 1, 1], [1, 0, 0, 1], [1, 1, 0, 0]]);
+
+        let mut cache =
+            Cache::new(true, DType::F32, &test_utils::model_config(), &Device::Cpu).unwrap();
+        let mask = cache.mask(3, 4).unwrap().to_vec2::<u8>().unwrap();
+        assert_eq!(mask, [[0, 0, 1, 1], [0, 0, 0, 1], [0, 0, 0, 0]]);
+    }
+}
@@ -1,16 +1,94 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
 
-use candle_core::{DType, Device, Result, Tensor};
+use candle_core::{DType, Device, Result, Tensor, D};
 
-use super::Config;
+use super::{quantize_int8, BlockPool, BlockTable, Config, RopeScaling};
+
+/// Storage of the cached keys and values, regardless of the dtype of the model.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KvCacheDtype {
+    /// Int8 with a symmetric scale per head and position, dequantized when attention reads it.
+    Int8,
+}
+
+/// Keys and values cached for a block.
+#[derive(Debug, Clone)]
+enum KvEntry {
+    Full(Tensor, Tensor),
+    /// Quantized keys and values, along with their (batch, heads, seq_len) scales.
+    Int8 {
+        k: Tensor,
+        k_scales: Tensor,
+        v: Tensor,
+        v_scales: Tensor,
+    },
+    /// Number of positions written to the blocks of the cache block table.
+    Paged(usize),
+}
+
+impl KvEntry {
+    /// Number of positions held by the entry.
+    fn len(&self) -> Result<usize> {
+        match self {
+            Self::Full(k, _) | Self::Int8 { k, .. } => k.dim(2),
+            Self::Paged(len) => Ok(*len),
+        }
+    }
+
+    /// Drops the first positions of the entry.
+    fn skip(self, n: usize) -> Result<Self> {
+        let skip = |t: Tensor| -> Result<Tensor> {
+            let len = t.dim(2)?;
+            t.narrow(2, n, len - n)?.contiguous()
+        };
+        Ok(match self {
+            Self::Full(k, v) => Self::Full(skip(k)?, skip(v)?),
+            Self::Int8 {
+                k,
+                k_scales,
+                v,
+                v_scales,
+            } => Self::Int8 {
+                k: skip(k)?,
+                k_scales: skip(k_scales)?,
+                v: skip(v)?,
+                v_scales: skip(v_scales)?,
+            },
+            Self::Paged(len) => Self::Paged(len),
+        })
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Cache {
-    masks: HashMap<usize, Tensor>,
+    masks: HashMap<(usize, usize), Tensor>,
     pub use_kv_cache: bool,
-    pub kvs: Vec<Option<(Tensor, Tensor)>>,
+    /// Storage of the key-value entries, in the dtype of the model if not set.
+    pub kv_dtype: Option<KvCacheDtype>,
+    /// Compute the attention with the flash attention kernel, for the batches without padding.
+    pub flash_attn: bool,
+    /// Entries of every block, (batch, num_key_value_heads, seq_len, head_dim) keys and values.
+    kvs: Vec<Option<KvEntry>>,
+    /// Blocks of the shared pool holding the key-value entries, if the cache is paged.
+    paged: Option<Arc<BlockTable>>,
+    /// Number of positions each token attends to, from the model config.
+    pub sliding_window: Option<usize>,
+    /// Position of the first entry cached for each block, past zero once the entries that fell
+    /// out of the sliding window have been dropped.
+    offsets: Vec<usize>,
     pub cos: Tensor,
     pub sin: Tensor,
+    /// Number of padding tokens at the beginning of each batch row.
+    pub left_padding: Vec<usize>,
+    /// Record the attention probabilities of the blocks, see take_attention.
+    pub capture_attention: bool,
+    /// Attention probabilities recorded by the last forward pass of a block.
+    attention: Option<Tensor>,
     device: Device,
 }
 
@@ -22,10 +100,17 @@ impl Cache {
             .step_by(2)
             .map(|i| 1f32 / config.rope_theta.powf(i as f32 / n_elem as f32))
             .collect();
+        let theta = match (&config.rope_scaling, &config.rope_freqs) {
+            (Some(scaling), _) => Self::scale_frequencies(theta, scaling),
+            // the same scaling, precomputed by llama.cpp
+            (None, Some(factors)) => theta.iter().zip(factors).map(|(t, f)| t / f).collect(),
+            (None, None) => theta,
+        };
         let theta = Tensor::new(theta.as_slice(), device)?;
-        let idx_theta = Tensor::arange(0, super::MAX_SEQ_LEN as u32, device)?
+        let context_size = config.context_size();
+        let idx_theta = Tensor::arange(0, context_size as u32, device)?
             .to_dtype(DType::F32)?
-            .reshape((super::MAX_SEQ_LEN, 1))?
+            .reshape((context_size, 1))?
             .matmul(&theta.reshape((1, theta.elem_count()))?)?;
         // This is different from the paper, see:
         // https://github.com/huggingface/transformers/blob/6112b1c6442aaf7affd2b0676a1cd4eee30c45cf/src/transformers/models/llama/modeling_llama.py#L112
@@ -34,32 +119,829 @@ impl Cache {
         Ok(Self {
             masks: HashMap::new(),
             use_kv_cache,
+            kv_dtype: None,
+            flash_attn: false,
             kvs: vec![None; config.num_hidden_layers],
+            paged: None,
+            sliding_window: config.sliding_window,
+            offsets: vec![0; config.num_hidden_layers],
+            left_padding: vec![],
+            capture_attention: false,
+            attention: None,
             device: device.clone(),
             cos,
             sin,
         })
     }
 
-    pub fn mask(&mut self, t: usize) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
+    /// Creates a cache storing the key-value entries in a pool of num_blocks blocks of block_size
+    /// positions, allocated to the sequences as they grow. The caches returned by as_new share
+    /// the pool and the blocks of a cache are freed once it's dropped or reset.
+    pub fn new_paged(
+        block_size: usize,
+        num_blocks: usize,
+        dtype: DType,
+        config: &Config,
+        device: &Device,
+    ) -> Result<Self> {
+        let mut cache = Self::new(true, dtype, config, device)?;
+        let pool = BlockPool::new(
+            block_size,
+            num_blocks,
+            config.num_hidden_layers,
+            config.num_key_value_heads,
+            config.hidden_size / config.num_attention_heads,
+            dtype,
+            device,
+        )?;
+        cache.paged = Some(Arc::new(BlockTable::new(Arc::new(Mutex::new(pool)))));
+        Ok(cache)
+    }
+
+    /// Creates an empty cache with the same settings on another device, paged caches get a pool
+    /// of their own.
+    pub fn on_device(&self, config: &Config, device: &Device) -> Result<Self> {
+        let dtype = self.cos.dtype();
+        let mut cache = match &self.paged {
+            Some(table) => {
+                let pool = table.pool().lock().unwrap();
+                Self::new_paged(pool.block_size(), pool.num_blocks(), dtype, config, device)?
+            }
+            None => Self::new(self.use_kv_cache, dtype, config, device)?,
+        };
+        cache.kv_dtype = self.kv_dtype;
+        cache.flash_attn = self.flash_attn;
+        Ok(cache)
+    }
+
+    /// Returns the (free, total) number of blocks of the pool of a paged cache.
+    pub fn paged_blocks(&self) -> Option<(usize, usize)> {
+        self.paged.as_ref().map(|table| {
+            let pool = table.pool().lock().unwrap();
+            (pool.num_free(), pool.num_blocks())
+        })
+    }
+
+    /// Applies the Llama 3.1 scaling: low frequencies are divided by the factor, high ones are kept
+    /// and the ones in between are interpolated.
+    fn scale_frequencies(theta: Vec<f32>, scaling: &RopeScaling) -> Vec<f32> {
+        let original_len = scaling.original_max_position_embeddings as f32;
+        let low_freq_wavelen = original_len / scaling.low_freq_factor;
+        let high_freq_wavelen = original_len / scaling.high_freq_factor;
+
+        theta
+            .into_iter()
+            .map(|freq| {
+                let wavelen = 2. * std::f32::consts::PI / freq;
+                if wavelen < high_freq_wavelen {
+                    freq
+                } else if wavelen > low_freq_wavelen {
+                    freq / scaling.factor
+                } else {
+                    let smooth = (original_len / wavelen - scaling.low_freq_factor)
+                        / (scaling.high_freq_factor - scaling.low_freq_factor);
+                    (1. - smooth) * freq / scaling.factor + smooth * freq
+                }
+            })
+            .collect()
+    }
+
+    /// Returns true if a key at distance positions from the query is out of its sliding window.
+    fn out_of_window(&self, distance: usize) -> bool {
+        self.sliding_window.is_some_and(|window| distance >= window)
+    }
+
+    /// Returns true if some of kv_len keys are out of the sliding window of the last query.
+    pub fn exceeds_window(&self, kv_len: usize) -> bool {
+        self.out_of_window(kv_len.saturating_sub(1))
+    }
+
+    /// Returns the causal mask for the last t positions of a sequence of kv_len tokens, which
+    /// also hides the keys out of the sliding window.
+    pub fn mask(&mut self, t: usize, kv_len: usize) -> Result<Tensor> {
+        if let Some(mask) = self.masks.get(&(t, kv_len)) {
             Ok(mask.clone())
         } else {
+            let offset = kv_len - t;
+            let cache = &*self;
             let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
+                .flat_map(|i| {
+                    (0..kv_len).map(move |j| {
+                        u8::from(j > i + offset || cache.out_of_window(i + offset - j))
+                    })
+                })
                 .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), &self.device)?;
-            self.masks.insert(t, mask.clone());
+            let mask = Tensor::from_slice(&mask, (t, kv_len), &self.device)?;
+            self.masks.insert((t, kv_len), mask.clone());
             Ok(mask)
         }
     }
 
+    /// Returns the (batch, 1, seq_len, kv_len) mask for the seq_len positions starting at
+    /// index_pos of a padded batch, or None if the batch has no padding. Besides the causal mask,
+    /// the padding keys are hidden from the actual tokens of each row.
+    pub fn padding_mask(
+        &self,
+        index_pos: usize,
+        seq_len: usize,
+        kv_len: usize,
+    ) -> Result<Option<Tensor>> {
+        if self.left_padding.iter().all(|&pad| pad == 0) {
+            return Ok(None);
+        }
+
+        // the keys that fell out of the sliding window are no longer cached
+        let first = index_pos + seq_len - kv_len;
+        let batch_size = self.left_padding.len();
+        let mut mask = Vec::with_capacity(batch_size * seq_len * kv_len);
+        for &pad in &self.left_padding {
+            for i in 0..seq_len {
+                let pos = index_pos + i;
+                // padding positions still attend to each other so that no row is fully masked
+                mask.extend((first..first + kv_len).map(|j| {
+                    u8::from(j > pos || (j < pad && pos >= pad) || self.out_of_window(pos - j))
+                }));
+            }
+        }
+
+        Ok(Some(Tensor::from_slice(
+            &mask,
+            (batch_size, 1, seq_len, kv_len),
+            &self.device,
+        )?))
+    }
+
+    /// Number of blocks the cache holds entries for.
+    pub fn num_blocks(&self) -> usize {
+        self.kvs.len()
+    }
+
+    /// Keeps the (batch, num_attention_heads, seq_len, kv_len) attention probabilities of a block
+    /// computed while capture_attention is set.
+    pub fn record_attention(&mut self, attention: Tensor) {
+        self.attention = Some(attention);
+    }
+
+    /// Returns the attention probabilities recorded by the last block, if any.
+    pub fn take_attention(&mut self) -> Option<Tensor> {
+        self.attention.take()
+    }
+
+    /// Bytes held by the key-value entries, the blocks of the pool the cache references included.
+    pub fn memory(&self) -> usize {
+        let bytes = |t: &Tensor| t.elem_count() * t.dtype().size_in_bytes();
+        let entries: usize = self
+            .kvs
+            .iter()
+            .flatten()
+            .map(|kv| match kv {
+                KvEntry::Full(k, v) => bytes(k) + bytes(v),
+                KvEntry::Int8 {
+                    k,
+                    k_scales,
+                    v,
+                    v_scales,
+                } => bytes(k) + bytes(k_scales) + bytes(v) + bytes(v_scales),
+                KvEntry::Paged(_) => 0,
+            })
+            .sum();
+        let paged = self.paged.as_ref().map_or(0, |table| {
+            table.num_blocks() * table.pool().lock().unwrap().block_bytes()
+        });
+        entries + paged
+    }
+
+    /// Returns the keys and values cached for a block, in the dtype of the model.
+    pub fn kv(&self, block_idx: usize) -> Result<Option<(Tensor, Tensor)>> {
+        let dtype = self.cos.dtype();
+        match self.kvs.get(block_idx).and_then(|kv| kv.as_ref()) {
+            None => Ok(None),
+            Some(KvEntry::Full(k, v)) => Ok(Some((k.clone(), v.clone()))),
+            Some(KvEntry::Paged(len)) => match &self.paged {
+                Some(table) => table.read(block_idx, *len).map(Some),
+                None => candle_core::bail!("cache of block {block_idx} is not paged"),
+            },
+            Some(KvEntry::Int8 {
+                k,
+                k_scales,
+                v,
+                v_scales,
+            }) => Ok(Some((
+                Self::dequantize(k, k_scales, dtype)?,
+                Self::dequantize(v, v_scales, dtype)?,
+            ))),
+        }
+    }
+
+    /// Replaces the keys and values cached for a block.
+    pub fn set_kv(&mut self, block_idx: usize, k: Tensor, v: Tensor) -> Result<()> {
+        if block_idx >= self.kvs.len() {
+            candle_core::bail!("invalid cache block {block_idx}");
+        }
+
+        self.offsets[block_idx] = 0;
+        if let Some(table) = &self.paged {
+            table.write(block_idx, 0, &k, &v)?;
+            self.kvs[block_idx] = Some(KvEntry::Paged(k.dim(2)?));
+            return Ok(());
+        }
+
+        self.kvs[block_idx] = Some(match self.kv_dtype {
+            None => KvEntry::Full(k, v),
+            Some(KvCacheDtype::Int8) => {
+                let (k, k_scales) = quantize_int8(&k)?;
+                let (v, v_scales) = quantize_int8(&v)?;
+                KvEntry::Int8 {
+                    k,
+                    k_scales,
+                    v,
+                    v_scales,
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Number of entries cached for a block before index_pos, and the position of the first one.
+    fn cached_before(&self, block_idx: usize, index_pos: usize) -> Result<(usize, usize)> {
+        let len = match &self.kvs[block_idx] {
+            Some(entry) => entry.len()?,
+            None => return Ok((0, index_pos)),
+        };
+        let start = self.offsets[block_idx];
+        // restored entries end where the sequence continues, whatever they have been trimmed to
+        let start = if start + len < index_pos {
+            index_pos - len
+        } else {
+            start
+        };
+        let cached = len.min(index_pos.saturating_sub(start));
+        let needed = match self.sliding_window {
+            Some(window) => index_pos.min(window.saturating_sub(1)),
+            None => index_pos,
+        };
+        if index_pos < start || cached < needed {
+            candle_core::bail!(
+                "the cache of block {block_idx} starts at position {start}, can't rewind it to {index_pos}"
+            );
+        }
+        Ok((cached, start))
+    }
+
+    /// Appends the keys and values of the positions starting at index_pos to the cache of a
+    /// block and returns the ones of the whole sequence, the ones out of the sliding window
+    /// excepted. Entries past index_pos belong to discarded tokens, like rejected drafts, and
+    /// are dropped.
+    pub fn append_kv(
+        &mut self,
+        block_idx: usize,
+        index_pos: usize,
+        k: Tensor,
+        v: Tensor,
+    ) -> Result<(Tensor, Tensor)> {
+        if let Some(table) = &self.paged {
+            let cached = match &self.kvs[block_idx] {
+                Some(KvEntry::Paged(len)) => (*len).min(index_pos),
+                _ => 0,
+            };
+            let len = cached + k.dim(2)?;
+            table.write(block_idx, cached, &k, &v)?;
+            self.kvs[block_idx] = Some(KvEntry::Paged(len));
+            return table.read(block_idx, len);
+        }
+
+        let dtype = self.cos.dtype();
+        let (cached, start) = self.cached_before(block_idx, index_pos)?;
+        let (entry, k, v) = match (self.kvs[block_idx].take(), self.kv_dtype) {
+            (None, None) => (KvEntry::Full(k.clone(), v.clone()), k, v),
+            (Some(KvEntry::Full(cache_k, cache_v)), None) => {
+                let k = Tensor::cat(&[&cache_k.narrow(2, 0, cached)?, &k], 2)?.contiguous()?;
+                let v = Tensor::cat(&[&cache_v.narrow(2, 0, cached)?, &v], 2)?.contiguous()?;
+                (KvEntry::Full(k.clone(), v.clone()), k, v)
+            }
+            (entry, _) => {
+                // only the new positions are quantized, the cached ones keep their scales
+                let (new_k, new_k_scales) = quantize_int8(&k)?;
+                let (new_v, new_v_scales) = quantize_int8(&v)?;
+                let (k, k_scales, v, v_scales) = match entry {
+                    None => (new_k, new_k_scales, new_v, new_v_scales),
+                    Some(KvEntry::Int8 {
+                        k,
+                        k_scales,
+                        v,
+                        v_scales,
+                    }) => {
+                        let cat = |cached_t: &Tensor, new_t: &Tensor| {
+                            Tensor::cat(&[&cached_t.narrow(2, 0, cached)?, new_t], 2)
+                        };
+                        (
+                            cat(&k, &new_k)?,
+                            cat(&k_scales, &new_k_scales)?,
+                            cat(&v, &new_v)?,
+                            cat(&v_scales, &new_v_scales)?,
+                        )
+                    }
+                    Some(KvEntry::Full(..)) | Some(KvEntry::Paged(..)) => {
+                        candle_core::bail!("cache of block {block_idx} is not quantized")
+                    }
+                };
+                let full_k = Self::dequantize(&k, &k_scales, dtype)?;
+                let full_v = Self::dequantize(&v, &v_scales, dtype)?;
+                (
+                    KvEntry::Int8 {
+                        k,
+                        k_scales,
+                        v,
+                        v_scales,
+                    },
+                    full_k,
+                    full_v,
+                )
+            }
+        };
+
+        // the next token only attends to the last window - 1 positions besides itself, another
+        // window is kept so that the sequence can be rewound, like when drafts are rejected
+        let len = entry.len()?;
+        let keep = self
+            .sliding_window
+            .map_or(len, |window| len.min(2 * window - 1));
+        self.kvs[block_idx] = Some(entry.skip(len - keep)?);
+        self.offsets[block_idx] = start + len - keep;
+        Ok((k, v))
+    }
+
+    fn dequantize(quantized: &Tensor, scales: &Tensor, dtype: DType) -> Result<Tensor> {
+        quantized
+            .to_dtype(dtype)?
+            .affine(1., -128.)?
+            .broadcast_mul(&scales.unsqueeze(D::Minus1)?)
+    }
+
     pub fn as_new(&self) -> Self {
         let mut copy = self.clone();
 
         copy.masks.clear();
         copy.kvs = vec![None; self.kvs.len()];
+        copy.offsets = vec![0; self.kvs.len()];
+        copy.left_padding.clear();
+        copy.attention = None;
+        // the blocks of the previous sequences are freed once no cache references them
+        copy.paged = self
+            .paged
+            .as_ref()
+            .map(|table| Arc::new(BlockTable::new(table.pool().clone())));
+
+        copy
+    }
 
+    /// Returns an empty cache holding its entries in contiguous tensors of the model dtype, for the
+    /// copies that shouldn't take blocks from the pool nor be quantized.
+    pub fn as_contiguous(&self) -> Self {
+        let mut copy = self.as_new();
+        copy.kv_dtype = None;
+        copy.paged = None;
         copy
     }
+
+    /// Saves the key-value tensors, along with the ids of the tokens they have been computed
+    /// from, to a safetensors file.
+    pub fn save<P: AsRef<Path>>(&self, path: P, tokens: &[u32]) -> anyhow::Result<()> {
+        let mut tensors = HashMap::new();
+        for block_idx in 0..self.kvs.len() {
+            let (k, v) = self
+                .kv(block_idx)?
+                .ok_or_else(|| anyhow!("no cache for block {block_idx}"))?;
+            tensors.insert(format!("layers.{block_idx}.k"), k);
+            tensors.insert(format!("layers.{block_idx}.v"), v);
+        }
+        tensors.insert("tokens".to_string(), Tensor::new(tokens, &Device::Cpu)?);
+
+        let metadata = HashMap::from([
+            ("position".to_string(), tokens.len().to_string()),
+            ("num_hidden_layers".to_string(), self.kvs.len().to_string()),
+            ("dtype".to_string(), self.cos.dtype().as_str().to_string()),
+        ]);
+
+        safetensors::serialize_to_file(tensors, &Some(metadata), path.as_ref())
+            .map_err(|e| anyhow!("can't save cache to {}: {:?}", path.as_ref().display(), e))
+    }
+
+    /// Loads a cache saved with Cache::save, returning it along with the ids of the tokens it has
+    /// been computed from. The cache is validated against the model configuration.
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        config: &Config,
+        device: &Device,
+    ) -> anyhow::Result<(Self, Vec<u32>)> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)
+            .map_err(|e| anyhow!("can't read cache {}: {:?}", path.display(), e))?;
+        let (_, metadata) = safetensors::SafeTensors::read_metadata(&data)
+            .map_err(|e| anyhow!("can't parse cache {}: {:?}", path.display(), e))?;
+        let metadata = metadata.metadata().clone().unwrap_or_default();
+        let get = |key: &str| {
+            metadata
+                .get(key)
+                .ok_or_else(|| anyhow!("cache {} has no {key} metadata", path.display()))
+        };
+
+        let num_hidden_layers: usize = get("num_hidden_layers")?.parse()?;
+        if num_hidden_layers != config.num_hidden_layers {
+            bail!(
+                "cache {} has {num_hidden_layers} layers, the model has {}",
+                path.display(),
+                config.num_hidden_layers
+            );
+        }
+        let position: usize = get("position")?.parse()?;
+        let dtype = DType::from_str(get("dtype")?)?;
+
+        let mut tensors = candle_core::safetensors::load_buffer(&data, device)?;
+        let tokens = tensors
+            .remove("tokens")
+            .ok_or_else(|| anyhow!("cache {} has no tokens", path.display()))?
+            .to_vec1::<u32>()?;
+        if tokens.len() != position {
+            bail!("cache {} has inconsistent position", path.display());
+        }
+
+        let head_dim = config.hidden_size / config.num_attention_heads;
+        let mut cache = Self::new(true, dtype, config, device)?;
+        for block_idx in 0..num_hidden_layers {
+            let mut kv = vec![];
+            for name in ["k", "v"] {
+                let tensor = tensors
+                    .remove(&format!("layers.{block_idx}.{name}"))
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "cache {} has no {name} for block {block_idx}",
+                            path.display()
+                        )
+                    })?;
+                let (_, num_kv_heads, seq_len, dim) = tensor.dims4()?;
+                if num_kv_heads != config.num_key_value_heads || dim != head_dim {
+                    bail!(
+                        "cache {} block {block_idx} has shape {:?}, expected {} kv heads of size {head_dim}",
+                        path.display(),
+                        tensor.shape(),
+                        config.num_key_value_heads
+                    );
+                }
+                if seq_len != position {
+                    bail!(
+                        "cache {} block {block_idx} has {seq_len} positions instead of {position}",
+                        path.display()
+                    );
+                }
+                kv.push(tensor);
+            }
+            let v = kv.pop().unwrap();
+            let k = kv.pop().unwrap();
+            cache.set_kv(block_idx, k, v)?;
+        }
+
+        Ok((cache, tokens))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    /// Cache of 5 positions of random keys and values for every block.
+    fn filled(config: &Config) -> Cache {
+        let mut cache = Cache::new(true, DType::F32, config, &Device::Cpu).unwrap();
+        let head_dim = config.hidden_size / config.num_attention_heads;
+        let shape = (1, config.num_key_value_heads, 5, head_dim);
+        for block_idx in 0..config.num_hidden_layers {
+            let k = Tensor::randn(0f32, 1., shape, &Device::Cpu).unwrap();
+            let v = Tensor::randn(0f32, 1., shape, &Device::Cpu).unwrap();
+            cache.set_kv(block_idx, k, v).unwrap();
+        }
+        cache
+    }
+
+    #[test]
+    fn int8_entries_are_close_to_the_appended_ones() {
+        let config = test_utils::model_config();
+        let mut cache = Cache::new(true, DType::F32, &config, &Device::Cpu).unwrap();
+        cache.kv_dtype = Some(KvCacheDtype::Int8);
+        let shape = (1, config.num_key_value_heads, 3, 16);
+        let (k0, v0) = (
+            Tensor::randn(0f32, 1., shape, &Device::Cpu).unwrap(),
+            Tensor::randn(0f32, 1., shape, &Device::Cpu).unwrap(),
+        );
+        let (k1, v1) = (
+            Tensor::randn(0f32, 10., shape, &Device::Cpu).unwrap(),
+            Tensor::randn(0f32, 10., shape, &Device::Cpu).unwrap(),
+        );
+        cache.append_kv(0, 0, k0.clone(), v0.clone()).unwrap();
+        let (k, v) = cache.append_kv(0, 3, k1.clone(), v1.clone()).unwrap();
+
+        let Some(KvEntry::Int8 { k: stored, .. }) = &cache.kvs[0] else {
+            panic!("the entry isn't quantized");
+        };
+        assert_eq!(stored.dtype(), DType::U8);
+        assert_eq!(stored.dims(), [1, config.num_key_value_heads, 6, 16]);
+
+        // within half a step of the scale of each position
+        for (quantized, expected) in [
+            (k, Tensor::cat(&[&k0, &k1], 2)),
+            (v, Tensor::cat(&[&v0, &v1], 2)),
+        ] {
+            let expected = expected.unwrap();
+            let step = expected
+                .abs()
+                .unwrap()
+                .max_keepdim(D::Minus1)
+                .unwrap()
+                .affine(1. / 127., 0.)
+                .unwrap();
+            let error = (quantized - &expected)
+                .unwrap()
+                .abs()
+                .unwrap()
+                .broadcast_div(&step)
+                .unwrap();
+            let error = error
+                .flatten_all()
+                .unwrap()
+                .max(0)
+                .unwrap()
+                .to_scalar::<f32>()
+                .unwrap();
+            assert!(error <= 0.51, "{error}");
+        }
+    }
+
+    #[tokio::test]
+    async fn int8_cache_generates_the_tokens_of_the_f16_one() {
+        let args = crate::Args {
+            dtype: Some("f16".to_string()),
+            ..test_utils::args(&["--max-tokens", "16", "--ignore-eos", "--seed", "7"])
+        };
+        let generate = |args: crate::Args| async move {
+            let mut master = test_utils::master(args.clone()).await;
+            let tokens = master.encode("the cat sat on the mat").unwrap();
+            let mut text = String::new();
+            master
+                .generate_with(&args, tokens, &Default::default(), |t| text.push_str(t))
+                .await
+                .unwrap();
+            text
+        };
+
+        let expected = generate(args.clone()).await;
+        let int8 = crate::Args {
+            kv_cache_dtype: Some(KvCacheDtype::Int8),
+            ..args
+        };
+        assert_eq!(generate(int8).await, expected);
+    }
+
+    #[tokio::test]
+    async fn max_seq_len_overrides_the_context_of_the_model() {
+        let dir = test_utils::model_dir_with(
+            "short_context",
+            serde_json::json!({ "max_position_embeddings": 8 }),
+        );
+        let args = test_utils::args_for(&dir, &["--max-seq-len", "24", "--ignore-eos"]);
+        let ctx = crate::cake::Context::from_args(args.clone()).unwrap();
+        assert_eq!(ctx.config.context_size(), 24);
+        assert_eq!(ctx.cache.cos.dims(), [24, 8]);
+        assert_eq!(ctx.cache.sin.dims(), [24, 8]);
+
+        // the generation fills the overridden context, past the one of the model
+        let mut master = crate::cake::Master::new(ctx).await.unwrap();
+        let tokens = master.encode("the cat sat on the mat").unwrap();
+        let prompt_len = tokens.len();
+        let mut generated = 0;
+        master
+            .generate_with_events(&args, tokens, &Default::default(), |event| {
+                generated = event.generated_tokens
+            })
+            .await
+            .unwrap();
+        assert_eq!(generated, 24 - prompt_len);
+    }
+
+    #[test]
+    fn rope_theta_overrides_the_one_of_the_config() {
+        let ctx = crate::cake::Context::from_args(test_utils::args(&[])).unwrap();
+        let overridden =
+            crate::cake::Context::from_args(test_utils::args(&["--rope-theta", "500000"])).unwrap();
+        assert_eq!(ctx.config.rope_theta, 10000.);
+        assert_eq!(overridden.config.rope_theta, 500000.);
+
+        let table = |t: &Tensor| t.to_vec2::<f32>().unwrap();
+        let (cos, sin) = (table(&ctx.cache.cos), table(&ctx.cache.sin));
+        let (overridden_cos, overridden_sin) =
+            (table(&overridden.cache.cos), table(&overridden.cache.sin));
+        // the same at the first position and for the first frequency, which is 1 whatever theta
+        assert_eq!(overridden_cos[0], cos[0]);
+        assert_eq!(overridden_cos[5][0], cos[5][0]);
+        assert_ne!(overridden_cos[5], cos[5]);
+        assert_ne!(overridden_sin[5], sin[5]);
+
+        // and the ones of a config with that theta
+        let config = Config {
+            rope_theta: 500000.,
+            ..ctx.config.clone()
+        };
+        let expected = Cache::new(true, DType::F32, &config, &Device::Cpu).unwrap();
+        assert_eq!(overridden_cos, table(&expected.cos));
+        assert_eq!(overridden_sin, table(&expected.sin));
+
+        for theta in ["--rope-theta=0", "--rope-theta=-1", "--rope-theta=inf"] {
+            let e = crate::cake::Context::from_args(test_utils::args(&[theta]))
+                .err()
+                .unwrap();
+            assert!(
+                e.to_string()
+                    .contains("--rope-theta must be a positive number"),
+                "{e}"
+            );
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let config = test_utils::model_config();
+        let cache = filled(&config);
+        let path = test_utils::temp_dir("cache-round-trip").join("cache.safetensors");
+        cache.save(&path, &[1, 3, 5, 7, 8]).unwrap();
+
+        let (loaded, tokens) = Cache::load(&path, &config, &Device::Cpu).unwrap();
+        assert_eq!(tokens, [1, 3, 5, 7, 8]);
+        for block_idx in 0..config.num_hidden_layers {
+            let (k, v) = cache.kv(block_idx).unwrap().unwrap();
+            let (lk, lv) = loaded.kv(block_idx).unwrap().unwrap();
+            assert_eq!(
+                k.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+                lk.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+            );
+            assert_eq!(
+                v.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+                lv.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn load_rejects_another_model() {
+        let config = test_utils::model_config();
+        let path = test_utils::temp_dir("cache-mismatch").join("cache.safetensors");
+        filled(&config).save(&path, &[1, 3, 5, 7, 8]).unwrap();
+
+        let layers = Config {
+            num_hidden_layers: 2,
+            ..config.clone()
+        };
+        let e = Cache::load(&path, &layers, &Device::Cpu).unwrap_err();
+        assert!(
+            e.to_string().contains("has 4 layers, the model has 2"),
+            "{e}"
+        );
+
+        let heads = Config {
+            num_key_value_heads: 4,
+            ..config.clone()
+        };
+        let e = Cache::load(&path, &heads, &Device::Cpu).unwrap_err();
+        assert!(e.to_string().contains("expected 4 kv heads"), "{e}");
+
+        // the positions don't match the tokens the cache has been computed from
+        let path = test_utils::temp_dir("cache-position").join("cache.safetensors");
+        filled(&config).save(&path, &[1, 3]).unwrap();
+        let e = Cache::load(&path, &config, &Device::Cpu).unwrap_err();
+        assert!(e.to_string().contains("positions instead of 2"), "{e}");
+    }
+
+    #[test]
+    fn rope_freqs_scale_like_the_rope_scaling() {
+        let scaling: RopeScaling = serde_json::from_value(serde_json::json!({
+            "factor": 8.0,
+            "low_freq_factor": 1.0,
+            "high_freq_factor": 4.0,
+            "original_max_position_embeddings": 64,
+        }))
+        .unwrap();
+        let config = test_utils::model_config();
+        let scaled = Config {
+            rope_scaling: Some(scaling.clone()),
+            ..config.clone()
+        };
+        let theta: Vec<f32> = (0..8)
+            .map(|i| 1f32 / config.rope_theta.powf(i as f32 / 8.))
+            .collect();
+        // as llama.cpp writes them
+        let freqs = Config {
+            rope_freqs: Some(
+                theta
+                    .iter()
+                    .zip(Cache::scale_frequencies(theta.clone(), &scaling))
+                    .map(|(theta, scaled)| theta / scaled)
+                    .collect(),
+            ),
+            ..config.clone()
+        };
+
+        let plain = Cache::new(true, DType::F32, &config, &Device::Cpu).unwrap();
+        let scaled = Cache::new(true, DType::F32, &scaled, &Device::Cpu).unwrap();
+        let freqs = Cache::new(true, DType::F32, &freqs, &Device::Cpu).unwrap();
+        let diff = |a: &Tensor, b: &Tensor| {
+            (a - b)
+                .unwrap()
+                .abs()
+                .unwrap()
+                .flatten_all()
+                .unwrap()
+                .max(0)
+                .unwrap()
+                .to_scalar::<f32>()
+                .unwrap()
+        };
+        assert!(diff(&freqs.cos, &scaled.cos) < 1e-4);
+        assert!(diff(&freqs.sin, &scaled.sin) < 1e-4);
+        assert!(diff(&plain.cos, &scaled.cos) > 0.1);
+    }
+
+    #[test]
+    fn llama_3_1_rope_scaling() {
+        let dir = test_utils::model_dir_with(
+            "llama-3.1-rope",
+            serde_json::json!({
+                "rope_theta": 500000.0,
+                "rope_scaling": {
+                    "factor": 8.0,
+                    "low_freq_factor": 1.0,
+                    "high_freq_factor": 4.0,
+                    "original_max_position_embeddings": 8192,
+                    "rope_type": "llama3",
+                },
+            }),
+        );
+        let cache = crate::cake::Context::from_args(test_utils::args_for(&dir, &[]))
+            .unwrap()
+            .cache;
+
+        // from the reference implementation, with heads of 16: the first 4 frequencies are kept,
+        // the fifth is interpolated and the last ones divided by the factor
+        let expected = [
+            (
+                1,
+                [0.540302, 0.981256, 0.999293, 0.999973, 1.0, 1.0, 1.0, 1.0],
+                [
+                    0.841471, 0.19271, 0.037597, 0.007293, 0.000525, 3.4e-5, 7e-6, 1e-6,
+                ],
+            ),
+            (
+                100,
+                [
+                    0.862319, 0.856308, -0.814453, 0.745663, 0.998623, 0.999994, 1.0, 1.0,
+                ],
+                [
+                    -0.506366, 0.516466, -0.580229, 0.666323, 0.052461, 0.003428, 0.000665,
+                    0.000129,
+                ],
+            ),
+            (
+                255,
+                [
+                    -0.862304, 0.685736, -0.986458, -0.284834, 0.991057, 0.999962, 0.999999, 1.0,
+                ],
+                [
+                    -0.506392, -0.727851, -0.164016, 0.958577, 0.133437, 0.008742, 0.001695,
+                    0.000329,
+                ],
+            ),
+        ];
+        for (pos, cos, sin) in expected {
+            let row = |t: &Tensor| t.get(pos).unwrap().to_vec1::<f32>().unwrap();
+            for (got, expected) in row(&cache.cos).iter().zip(cos) {
+                assert!(
+                    (got - expected).abs() < 1e-4,
+                    "cos at {pos}: {got} != {expected}"
+                );
+            }
+            for (got, expected) in row(&cache.sin).iter().zip(sin) {
+                assert!(
+                    (got - expected).abs() < 1e-4,
+                    "sin at {pos}: {got} != {expected}"
+                );
+            }
+        }
+
+        // without scaling the frequencies are the plain ones
+        let config = test_utils::model_config();
+        assert!(config.rope_scaling.is_none());
+        let plain = Cache::new(true, DType::F32, &config, &Device::Cpu).unwrap();
+        let inv_freq: Vec<f32> = (0..8).map(|i| 1. / 10000f32.powf(i as f32 / 8.)).collect();
+        let got = plain.sin.get(3).unwrap().to_vec1::<f32>().unwrap();
+        for (got, freq) in got.iter().zip(inv_freq) {
+            assert_eq!(*got, (3. * freq).sin());
+        }
+    }
 }
@@ -1,9 +1,46 @@
+use std::sync::Arc;
+
+use candle_core::DType;
+
+use super::{Gguf, Quantization};
+use crate::cake::CakeError;
+
 pub const MAX_SEQ_LEN: usize = 4096;
 
 fn default_rope() -> f32 {
     10_000.0
 }
 
+fn default_max_position_embeddings() -> usize {
+    MAX_SEQ_LEN
+}
+
+/// Rope frequency scaling of the Llama 3.1 models.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct RopeScaling {
+    pub factor: f32,
+    pub low_freq_factor: f32,
+    pub high_freq_factor: f32,
+    pub original_max_position_embeddings: usize,
+}
+
+/// End of sequence token ids of a config, a list for the Llama 3.1 models.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(untagged)]
+pub enum EosTokenIds {
+    Single(u32),
+    Multiple(Vec<u32>),
+}
+
+impl EosTokenIds {
+    pub fn into_vec(self) -> Vec<u32> {
+        match self {
+            Self::Single(id) => vec![id],
+            Self::Multiple(ids) => ids,
+        }
+    }
+}
+
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct LlamaConfig {
     pub hidden_size: usize,
@@ -15,15 +52,103 @@ pub struct LlamaConfig {
     pub rms_norm_eps: f64,
     #[serde(default = "default_rope")]
     pub rope_theta: f32,
+    #[serde(default = "default_max_position_embeddings")]
+    pub max_position_embeddings: usize,
+    pub rope_scaling: Option<RopeScaling>,
     pub bos_token_id: Option<u32>,
-    pub eos_token_id: Option<u32>,
+    pub eos_token_id: Option<EosTokenIds>,
+    pub sliding_window: Option<usize>,
+    // only read to reject the architectures the model doesn't implement
+    pub model_type: Option<String>,
+    pub num_local_experts: Option<usize>,
+    pub head_dim: Option<usize>,
+    pub hidden_act: Option<String>,
+    pub attention_bias: Option<bool>,
+    pub mlp_bias: Option<bool>,
 }
 
+/// Values of model_type sharing the Llama architecture.
+const SUPPORTED_MODEL_TYPES: [&str; 2] = ["llama", "mistral"];
+
 impl LlamaConfig {
     pub fn num_key_value_heads(&self) -> usize {
         self.num_key_value_heads.unwrap_or(self.num_attention_heads)
     }
 
+    /// Fails with the first field describing an architecture the model doesn't implement.
+    pub fn validate(&self) -> Result<(), CakeError> {
+        let unsupported = |field: &str, value: &dyn std::fmt::Display, reason: &str| {
+            Err(CakeError::ArchitectureUnsupported {
+                field: field.to_string(),
+                value: value.to_string(),
+                reason: reason.to_string(),
+            })
+        };
+
+        if let Some(experts) = self.num_local_experts.filter(|&experts| experts > 0) {
+            return unsupported(
+                "num_local_experts",
+                &experts,
+                "mixture of experts models aren't supported",
+            );
+        }
+        if let Some(model_type) = &self.model_type {
+            if !SUPPORTED_MODEL_TYPES.contains(&model_type.as_str()) {
+                return unsupported(
+                    "model_type",
+                    model_type,
+                    "only the llama and mistral architectures are supported",
+                );
+            }
+        }
+        if let Some(hidden_act) = &self.hidden_act {
+            if hidden_act != "silu" {
+                return unsupported("hidden_act", hidden_act, "only silu is supported");
+            }
+        }
+        if self.attention_bias == Some(true) {
+            return unsupported(
+                "attention_bias",
+                &true,
+                "the attention projections have no bias",
+            );
+        }
+        if self.mlp_bias == Some(true) {
+            return unsupported("mlp_bias", &true, "the mlp projections have no bias");
+        }
+        if self.num_attention_heads == 0
+            || !self.hidden_size.is_multiple_of(self.num_attention_heads)
+        {
+            return unsupported(
+                "num_attention_heads",
+                &self.num_attention_heads,
+                &format!("it must divide hidden_size ({})", self.hidden_size),
+            );
+        }
+        let num_key_value_heads = self.num_key_value_heads();
+        if num_key_value_heads == 0 || !self.num_attention_heads.is_multiple_of(num_key_value_heads)
+        {
+            return unsupported(
+                "num_key_value_heads",
+                &num_key_value_heads,
+                &format!(
+                    "it must divide num_attention_heads ({})",
+                    self.num_attention_heads
+                ),
+            );
+        }
+        let head_dim = self.hidden_size / self.num_attention_heads;
+        if let Some(got) = self.head_dim.filter(|&got| got != head_dim) {
+            return unsupported(
+                "head_dim",
+                &got,
+                &format!("it must be hidden_size / num_attention_heads ({head_dim})"),
+            );
+        }
+
+        Ok(())
+    }
+
     pub fn into_config(self) -> Config {
         Config {
             hidden_size: self.hidden_size,
@@ -34,8 +159,19 @@ impl LlamaConfig {
             num_key_value_heads: self.num_key_value_heads(),
             rms_norm_eps: self.rms_norm_eps,
             rope_theta: self.rope_theta,
+            max_position_embeddings: self.max_position_embeddings,
+            rope_scaling: self.rope_scaling,
             bos_token_id: self.bos_token_id,
-            eos_token_id: self.eos_token_id,
+            eos_token_ids: self
+                .eos_token_id
+                .map(EosTokenIds::into_vec)
+                .unwrap_or_default(),
+            sliding_window: self.sliding_window,
+            max_seq_len: None,
+            quantize: None,
+            compute_dtype: None,
+            rope_freqs: None,
+            gguf: None,
         }
     }
 }
@@ -50,6 +186,185 @@ pub struct Config {
     pub num_key_value_heads: usize,
     pub rms_norm_eps: f64,
     pub rope_theta: f32,
+    pub max_position_embeddings: usize,
+    pub rope_scaling: Option<RopeScaling>,
     pub bos_token_id: Option<u32>,
-    pub eos_token_id: Option<u32>,
+    pub eos_token_ids: Vec<u32>,
+    /// Number of positions each token attends to, itself included, all the previous ones if not
+    /// set.
+    pub sliding_window: Option<usize>,
+    /// Context length overriding the one of the model, set from the arguments.
+    pub max_seq_len: Option<usize>,
+    /// Quantization of the linear layers weights, set from the arguments.
+    pub quantize: Option<Quantization>,
+    /// Dtype of the attention softmax and of the norms, set from the arguments. The attention is
+    /// computed in f32 and the norms in the dtype of the weights if not set.
+    pub compute_dtype: Option<DType>,
+    /// Divisors of the rope frequencies, read from the rope_freqs.weight tensor of GGUF files in
+    /// place of rope_scaling.
+    pub rope_freqs: Option<Vec<f32>>,
+    /// GGUF file the weights are read from, set when loading the model. Its quantized linear
+    /// layers are kept quantized.
+    pub gguf: Option<Arc<Gguf>>,
+}
+
+impl Config {
+    /// Number of positions the model processes, the ones of the prompt included.
+    pub fn context_size(&self) -> usize {
+        self.max_seq_len
+            .unwrap_or_else(|| self.max_position_embeddings.min(MAX_SEQ_LEN))
+    }
+
+    /// Estimated memory taken by the weights of a decoder layer loaded in dtype and by the
+    /// key-value cache of a single sequence spanning the whole context, in bytes.
+    pub fn layer_memory(&self, dtype: DType) -> u64 {
+        let hidden = self.hidden_size as u64;
+        let kv = (self.hidden_size / self.num_attention_heads * self.num_key_value_heads) as u64;
+        let intermediate = self.intermediate_size as u64;
+        let size = dtype.size_in_bytes() as u64;
+        let kv_cache = 2 * self.context_size() as u64 * kv * size;
+
+        if let Some(gguf) = &self.gguf {
+            return gguf.layer_bytes(dtype) + kv_cache;
+        }
+
+        // (in, out) of the linear layers
+        let linears = [
+            (hidden, hidden),
+            (hidden, kv),
+            (hidden, kv),
+            (hidden, hidden),
+            (hidden, intermediate),
+            (hidden, intermediate),
+            (intermediate, hidden),
+        ];
+        let weights: u64 = linears
+            .iter()
+            .map(|(size_in, size_out)| match self.quantize {
+                None => size_in * size_out * size,
+                // a byte per weight and a scale per output channel
+                Some(Quantization::Int8) => size_in * size_out + size_out * size,
+            })
+            .sum();
+        // the two norms
+        let norms = 2 * hidden * size;
+
+        weights + norms + kv_cache
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn layer_memory_of_the_weights_and_of_the_cache() {
+        let config = test_utils::model_config();
+        let tensors = candle_core::safetensors::load(
+            test_utils::model_dir().join("model.safetensors"),
+            &candle_core::Device::Cpu,
+        )
+        .unwrap();
+        let weights: usize = tensors
+            .iter()
+            .filter(|(name, _)| name.starts_with("model.layers.0."))
+            .map(|(_, tensor)| tensor.elem_count())
+            .sum();
+        // keys and values of 2 heads of 16 over the whole context
+        let cache = 2 * config.context_size() * 2 * 16;
+
+        assert_eq!(
+            config.layer_memory(DType::F32),
+            (weights + cache) as u64 * 4
+        );
+        assert_eq!(
+            config.layer_memory(DType::F16),
+            (weights + cache) as u64 * 2
+        );
+    }
+
+    /// The configuration of the test models with the entries of overrides.
+    fn llama_config(overrides: serde_json::Value) -> LlamaConfig {
+        let mut config = test_utils::config();
+        for (key, value) in overrides.as_object().unwrap() {
+            config[key] = value.clone();
+        }
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[test]
+    fn mixtral_is_unsupported() {
+        let dir = test_utils::model_dir_with(
+            "mixtral",
+            serde_json::json!({
+                "model_type": "mixtral", "num_local_experts": 8, "num_experts_per_tok": 2,
+            }),
+        );
+        match crate::cake::Context::from_args(test_utils::args_for(&dir, &[])) {
+            Err(e @ CakeError::ArchitectureUnsupported { .. }) => assert_eq!(
+                e.to_string(),
+                "unsupported model architecture, num_local_experts is 8: mixture of experts models aren't supported"
+            ),
+            res => panic!("unexpected result {:?}", res.err()),
+        }
+    }
+
+    #[test]
+    fn unsupported_fields() {
+        for (overrides, field, value) in [
+            (
+                serde_json::json!({"model_type": "gpt2"}),
+                "model_type",
+                "gpt2",
+            ),
+            (
+                serde_json::json!({"hidden_act": "gelu"}),
+                "hidden_act",
+                "gelu",
+            ),
+            (
+                serde_json::json!({"attention_bias": true}),
+                "attention_bias",
+                "true",
+            ),
+            (serde_json::json!({"mlp_bias": true}), "mlp_bias", "true"),
+            (
+                serde_json::json!({"num_attention_heads": 5}),
+                "num_attention_heads",
+                "5",
+            ),
+            (
+                serde_json::json!({"num_key_value_heads": 3}),
+                "num_key_value_heads",
+                "3",
+            ),
+            (serde_json::json!({"head_dim": 32}), "head_dim", "32"),
+        ] {
+            match llama_config(overrides).validate() {
+                Err(CakeError::ArchitectureUnsupported {
+                    field: got_field,
+                    value: got_value,
+                    ..
+                }) => assert_eq!((got_field.as_str(), got_value.as_str()), (field, value)),
+                res => panic!("{field} accepted: {res:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn llama_variants_are_unchanged() {
+        let reference = format!("{:?}", llama_config(serde_json::json!({})).into_config());
+        for overrides in [
+            serde_json::json!({"model_type": "llama"}),
+            serde_json::json!({"model_type": "mistral", "num_local_experts": 0}),
+            serde_json::json!({
+                "hidden_act": "silu", "attention_bias": false, "mlp_bias": false, "head_dim": 16,
+            }),
+        ] {
+            let config = llama_config(overrides);
+            config.validate().unwrap();
+            assert_eq!(format!("{:?}", config.into_config()), reference);
+        }
+    }
 }
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use candle_core::{Device, Tensor};
+
+use super::{Block, Cache};
+use crate::cake::Forwarder;
+
+/// Block of a worker that can't be reached anymore, served by the master on the CPU with a cache
+/// of its own. The activations are copied from and back to the device of the master.
+#[derive(Debug)]
+pub(crate) struct CpuBlock {
+    block: Block,
+    cache: Cache,
+    // see Forwarder::select_cache
+    cache_id: u64,
+    stashed: HashMap<u64, Cache>,
+}
+
+impl CpuBlock {
+    pub fn new(block: Block, cache: Cache) -> Self {
+        Self {
+            block,
+            cache,
+            cache_id: 0,
+            stashed: HashMap::new(),
+        }
+    }
+}
+
+impl std::fmt::Display for CpuBlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@<local cpu>", self.block.layer_name())
+    }
+}
+
+#[async_trait]
+impl Forwarder for CpuBlock {
+    async fn forward(
+        &mut self,
+        x: &Tensor,
+        index_pos: usize,
+        block_idx: usize,
+        _cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let y = self
+            .block
+            .forward(
+                &x.to_device(&Device::Cpu)?,
+                index_pos,
+                block_idx,
+                &mut self.cache,
+            )
+            .await?;
+        Ok(y.to_device(x.device())?)
+    }
+
+    async fn forward_attention(
+        &mut self,
+        x: &Tensor,
+        index_pos: usize,
+        block_idx: usize,
+        _cache: &mut Cache,
+    ) -> Result<(Tensor, Tensor)> {
+        let (y, attention) = self
+            .block
+            .forward_attention(
+                &x.to_device(&Device::Cpu)?,
+                index_pos,
+                block_idx,
+                &mut self.cache,
+            )
+            .await?;
+        Ok((y.to_device(x.device())?, attention.to_device(x.device())?))
+    }
+
+    async fn reset(&mut self, left_padding: &[usize]) -> Result<()> {
+        self.cache = self.cache.as_new();
+        self.cache.left_padding = left_padding.to_vec();
+        Ok(())
+    }
+
+    async fn select_cache(&mut self, id: u64) -> Result<()> {
+        if id != self.cache_id {
+            let selected = self
+                .stashed
+                .remove(&id)
+                .unwrap_or_else(|| self.cache.as_new());
+            let previous = std::mem::replace(&mut self.cache, selected);
+            self.stashed.insert(self.cache_id, previous);
+            self.cache_id = id;
+        }
+        Ok(())
+    }
+
+    async fn drop_cache(&mut self, id: u64) -> Result<()> {
+        if id == self.cache_id {
+            self.cache = self.cache.as_new();
+        } else {
+            self.stashed.remove(&id);
+        }
+        Ok(())
+    }
+
+    async fn get_kv_cache(
+        &mut self,
+        block_idxs: &[usize],
+        cache: &Cache,
+    ) -> Result<Vec<(usize, Tensor, Tensor)>> {
+        let device = cache.cos.device();
+        let mut kvs = vec![];
+        for &idx in block_idxs {
+            if let Some((k, v)) = self.cache.kv(idx)? {
+                kvs.push((idx, k.to_device(device)?, v.to_device(device)?));
+            }
+        }
+        Ok(kvs)
+    }
+
+    async fn set_kv_cache(
+        &mut self,
+        kvs: Vec<(usize, Tensor, Tensor)>,
+        _cache: &mut Cache,
+    ) -> Result<()> {
+        for (idx, k, v) in kvs {
+            self.cache
+                .set_kv(idx, k.to_device(&Device::Cpu)?, v.to_device(&Device::Cpu)?)?;
+        }
+        Ok(())
+    }
+
+    fn layer_name(&self) -> &str {
+        self.block.layer_name()
+    }
+}
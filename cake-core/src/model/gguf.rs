@@ -0,0 +1,522 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use candle_core::{
+    quantized::{
+        ggml_file::qtensor_from_ggml,
+        gguf_file::{Content, Value},
+        GgmlDType, QMatMul, QTensor,
+    },
+    DType, Device, Shape, Tensor,
+};
+use candle_nn::{var_builder::SimpleBackend, Init, VarBuilder};
+
+use super::Config;
+
+/// Llama weights in a GGUF file. Tensors are mapped to the names used by the safetensors
+/// checkpoints. The quantized weights of the linear layers are kept quantized, see
+/// Gguf::qmatmul, the other tensors are dequantized to the dtype of the model when a block is
+/// loaded.
+pub struct Gguf {
+    path: PathBuf,
+    content: Content,
+}
+
+impl std::fmt::Debug for Gguf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // the metadata holds the whole vocabulary
+        f.debug_struct("Gguf").field("path", &self.path).finish()
+    }
+}
+
+impl Gguf {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file =
+            File::open(&path).map_err(|e| anyhow!("can't open {}: {:?}", path.display(), e))?;
+        let content = Content::read(&mut file)
+            .map_err(|e| anyhow!("can't parse {}: {:?}", path.display(), e))?;
+
+        Ok(Self { path, content })
+    }
+
+    fn metadata(&self, key: &str) -> Result<&Value> {
+        self.content
+            .metadata
+            .get(key)
+            .ok_or_else(|| anyhow!("{} has no {key} metadata", self.path.display()))
+    }
+
+    fn metadata_usize(&self, key: &str) -> Result<usize> {
+        let value = self.metadata(key)?;
+        Ok(value
+            .to_u32()
+            .map(|v| v as usize)
+            .or_else(|_| value.to_u64().map(|v| v as usize))?)
+    }
+
+    /// Builds the model configuration from the GGUF metadata.
+    pub fn config(&self) -> Result<Config> {
+        let architecture = self.metadata("general.architecture")?.to_string()?;
+        if architecture != "llama" {
+            bail!(
+                "{} contains a {architecture} model, only llama is supported",
+                self.path.display()
+            );
+        }
+
+        // llama.cpp stores mixtral models as llama ones with experts
+        if let Some(experts) = self
+            .metadata_usize("llama.expert_count")
+            .ok()
+            .filter(|&experts| experts > 0)
+        {
+            bail!(
+                "{} contains a mixture of experts model with {experts} experts, which isn't supported",
+                self.path.display()
+            );
+        }
+
+        let num_attention_heads = self.metadata_usize("llama.attention.head_count")?;
+        let vocab_size = match self.metadata_usize("llama.vocab_size") {
+            Ok(vocab_size) => vocab_size,
+            Err(_) => self
+                .content
+                .tensor_infos
+                .get("token_embd.weight")
+                .ok_or_else(|| anyhow!("{} has no token embeddings", self.path.display()))?
+                .shape
+                .dims()[0],
+        };
+        let optional_u32 = |key: &str| self.metadata(key).and_then(|v| Ok(v.to_u32()?)).ok();
+
+        Ok(Config {
+            hidden_size: self.metadata_usize("llama.embedding_length")?,
+            intermediate_size: self.metadata_usize("llama.feed_forward_length")?,
+            vocab_size,
+            num_hidden_layers: self.metadata_usize("llama.block_count")?,
+            num_attention_heads,
+            num_key_value_heads: self
+                .metadata_usize("llama.attention.head_count_kv")
+                .unwrap_or(num_attention_heads),
+            rms_norm_eps: self
+                .metadata("llama.attention.layer_norm_rms_epsilon")?
+                .to_f32()? as f64,
+            rope_theta: self
+                .metadata("llama.rope.freq_base")
+                .and_then(|v| Ok(v.to_f32()?))
+                .unwrap_or(10_000.0),
+            max_position_embeddings: self
+                .metadata_usize("llama.context_length")
+                .unwrap_or(super::MAX_SEQ_LEN),
+            // llama.cpp stores the scaled frequencies as a tensor instead
+            rope_scaling: None,
+            rope_freqs: self.rope_freqs(num_attention_heads)?,
+            bos_token_id: optional_u32("tokenizer.ggml.bos_token_id"),
+            eos_token_ids: optional_u32("tokenizer.ggml.eos_token_id")
+                .into_iter()
+                .collect(),
+            sliding_window: self.metadata_usize("llama.attention.sliding_window").ok(),
+            max_seq_len: None,
+            quantize: None,
+            compute_dtype: None,
+            gguf: None,
+        })
+    }
+
+    /// Divisors of the rope frequencies from the rope_freqs.weight tensor, which llama.cpp writes
+    /// instead of the rope scaling parameters of the Llama 3.1 models.
+    fn rope_freqs(&self, num_attention_heads: usize) -> Result<Option<Vec<f32>>> {
+        if !self.content.tensor_infos.contains_key("rope_freqs.weight") {
+            return Ok(None);
+        }
+        let freqs = self
+            .read("rope_freqs.weight", &Device::Cpu)?
+            .dequantize(&Device::Cpu)?
+            .to_dtype(DType::F32)?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        let head_dim = self.metadata_usize("llama.embedding_length")? / num_attention_heads;
+        if freqs.len() != head_dim / 2 {
+            bail!(
+                "{} has {} rope frequencies, expected {} for heads of size {head_dim}",
+                self.path.display(),
+                freqs.len(),
+                head_dim / 2
+            );
+        }
+        Ok(Some(freqs))
+    }
+
+    fn read(&self, gguf_name: &str, device: &Device) -> candle_core::Result<QTensor> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        self.content.tensor(&mut reader, gguf_name, device)
+    }
+
+    /// Returns the GGUF name of a tensor and, for the query and key projections, the number of
+    /// heads their rows have been permuted with.
+    fn map_name(&self, name: &str, config: &Config) -> Option<(String, Option<usize>)> {
+        let mapped = match name {
+            "model.embed_tokens.weight" => return Some(("token_embd.weight".into(), None)),
+            "model.norm.weight" => return Some(("output_norm.weight".into(), None)),
+            "lm_head.weight" => {
+                // tied embeddings
+                let name = if self.content.tensor_infos.contains_key("output.weight") {
+                    "output.weight"
+                } else {
+                    "token_embd.weight"
+                };
+                return Some((name.into(), None));
+            }
+            _ => name.strip_prefix("model.layers.")?,
+        };
+
+        let (layer_idx, name) = mapped.split_once('.')?;
+        let (name, heads) = match name {
+            "input_layernorm.weight" => ("attn_norm", None),
+            "self_attn.q_proj.weight" => ("attn_q", Some(config.num_attention_heads)),
+            "self_attn.k_proj.weight" => ("attn_k", Some(config.num_key_value_heads)),
+            "self_attn.v_proj.weight" => ("attn_v", None),
+            "self_attn.o_proj.weight" => ("attn_output", None),
+            "post_attention_layernorm.weight" => ("ffn_norm", None),
+            "mlp.gate_proj.weight" => ("ffn_gate", None),
+            "mlp.up_proj.weight" => ("ffn_up", None),
+            "mlp.down_proj.weight" => ("ffn_down", None),
+            _ => return None,
+        };
+
+        Some((format!("blk.{layer_idx}.{name}.weight"), heads))
+    }
+
+    /// Loads the weight of a linear layer as it's quantized in the file, none if the file has it
+    /// in f32 or f16. The matmul then runs on the quantized blocks, in f32.
+    pub fn qmatmul(
+        &self,
+        name: &str,
+        config: &Config,
+        device: &Device,
+    ) -> candle_core::Result<Option<QMatMul>> {
+        let (gguf_name, heads) = match self.map_name(name, config) {
+            Some(mapped) => mapped,
+            None => return Ok(None),
+        };
+        let dtype = match self.content.tensor_infos.get(&gguf_name) {
+            Some(info) if !matches!(info.ggml_dtype, GgmlDType::F32 | GgmlDType::F16) => {
+                info.ggml_dtype
+            }
+            _ => return Ok(None),
+        };
+
+        let tensor = match heads {
+            Some(heads) => {
+                // whole rows are quantized, they are moved back in place as they are
+                let tensor = self.read(&gguf_name, &Device::Cpu)?;
+                let (rows, cols) = tensor.shape().dims2()?;
+                let data = tensor.data()?;
+                let row_bytes = data.len() / rows;
+                let half = rows / heads / 2;
+                let mut unpermuted = Vec::with_capacity(data.len());
+                for head in 0..heads {
+                    for j in 0..2 {
+                        for i in 0..half {
+                            let row = head * 2 * half + i * 2 + j;
+                            unpermuted
+                                .extend_from_slice(&data[row * row_bytes..(row + 1) * row_bytes]);
+                        }
+                    }
+                }
+                qtensor_from_ggml(dtype, &unpermuted, vec![rows, cols], device)?
+            }
+            None => self.read(&gguf_name, device)?,
+        };
+
+        Ok(Some(QMatMul::from_qtensor(tensor)?))
+    }
+
+    /// Bytes taken by the weights of a decoder layer, the quantized ones staying quantized and
+    /// the others loaded in dtype.
+    pub fn layer_bytes(&self, dtype: DType) -> u64 {
+        self.content
+            .tensor_infos
+            .iter()
+            .filter(|(name, _)| name.starts_with("blk.0."))
+            .map(|(_, info)| {
+                let elems = info.shape.elem_count();
+                match info.ggml_dtype {
+                    GgmlDType::F32 | GgmlDType::F16 => elems * dtype.size_in_bytes(),
+                    ggml_dtype => elems / ggml_dtype.block_size() * ggml_dtype.type_size(),
+                }
+            })
+            .sum::<usize>() as u64
+    }
+
+    /// Creates a var builder reading from this file, tensors are dequantized to dtype.
+    pub fn var_builder(
+        self: &Arc<Self>,
+        config: &Config,
+        dtype: DType,
+        device: &Device,
+    ) -> VarBuilder<'static> {
+        let backend = GgufBackend {
+            gguf: self.clone(),
+            config: config.clone(),
+        };
+        VarBuilder::from_backend(Box::new(backend), dtype, device.clone())
+    }
+}
+
+struct GgufBackend {
+    gguf: Arc<Gguf>,
+    config: Config,
+}
+
+impl SimpleBackend for GgufBackend {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        _: Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> candle_core::Result<Tensor> {
+        let (gguf_name, heads) = self
+            .gguf
+            .map_name(name, &self.config)
+            .ok_or_else(|| candle_core::Error::CannotFindTensor { path: name.into() })?;
+
+        let tensor = self.gguf.read(&gguf_name, dev)?.dequantize(dev)?;
+
+        let tensor = if let Some(heads) = heads {
+            // llama.cpp interleaves the halves of each head for its rotary embedding, undo it
+            let (rows, cols) = tensor.dims2()?;
+            tensor
+                .reshape((heads, rows / heads / 2, 2, cols))?
+                .transpose(1, 2)?
+                .reshape((rows, cols))?
+        } else {
+            tensor
+        };
+
+        if tensor.shape() != &s {
+            Err(candle_core::Error::UnexpectedShape {
+                msg: format!("shape mismatch for {name} ({gguf_name})"),
+                expected: s,
+                got: tensor.shape().clone(),
+            }
+            .bt())?
+        }
+
+        tensor.to_dtype(dtype)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.gguf
+            .map_name(name, &self.config)
+            .is_some_and(|(name, _)| self.gguf.content.tensor_infos.contains_key(&name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::Module;
+
+    use super::*;
+    use crate::{
+        cake::{CancellationToken, Context, Master},
+        model::{linear, Block, Linear},
+        test_utils,
+    };
+
+    /// Writes the model of test_utils::model_dir to a GGUF file of its own directory, its linear
+    /// layers quantized to dtype, along with the rope_freqs.weight tensor if given.
+    fn write_gguf(name: &str, dtype: GgmlDType, rope_freqs: Option<&[f32]>) -> PathBuf {
+        let dir = test_utils::temp_dir(name);
+        let tensors = candle_core::safetensors::load(
+            test_utils::model_dir().join("model.safetensors"),
+            &Device::Cpu,
+        )
+        .unwrap();
+        let config = test_utils::model_config();
+
+        let mut quantized = vec![];
+        for (name, tensor) in &tensors {
+            let (gguf_name, heads) = gguf_name(name, &config);
+            let tensor = match heads {
+                Some(heads) => {
+                    let (rows, cols) = tensor.dims2().unwrap();
+                    tensor
+                        .reshape((heads, 2, rows / heads / 2, cols))
+                        .unwrap()
+                        .transpose(1, 2)
+                        .unwrap()
+                        .reshape((rows, cols))
+                        .unwrap()
+                }
+                None => tensor.clone(),
+            };
+            let dtype = if tensor.rank() == 1 {
+                GgmlDType::F32
+            } else {
+                dtype
+            };
+            quantized.push((gguf_name, QTensor::quantize(&tensor, dtype).unwrap()));
+        }
+        if let Some(freqs) = rope_freqs {
+            let freqs = Tensor::new(freqs, &Device::Cpu).unwrap();
+            quantized.push((
+                "rope_freqs.weight".to_string(),
+                QTensor::quantize(&freqs, GgmlDType::F32).unwrap(),
+            ));
+        }
+
+        let u32 = |v: usize| Value::U32(v as u32);
+        let metadata = [
+            ("general.architecture", Value::String("llama".into())),
+            (
+                "llama.attention.head_count",
+                u32(config.num_attention_heads),
+            ),
+            (
+                "llama.attention.head_count_kv",
+                u32(config.num_key_value_heads),
+            ),
+            ("llama.block_count", u32(config.num_hidden_layers)),
+            ("llama.embedding_length", u32(config.hidden_size)),
+            ("llama.feed_forward_length", u32(config.intermediate_size)),
+            ("llama.context_length", u32(config.max_position_embeddings)),
+            ("llama.attention.layer_norm_rms_epsilon", Value::F32(1e-5)),
+            ("tokenizer.ggml.bos_token_id", u32(1)),
+            ("tokenizer.ggml.eos_token_id", u32(2)),
+        ];
+        let mut file = File::create(dir.join("model.gguf")).unwrap();
+        candle_core::quantized::gguf_file::write(
+            &mut file,
+            &metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>(),
+            &quantized
+                .iter()
+                .map(|(k, v)| (k.as_str(), v))
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("tokenizer.json"),
+            test_utils::tokenizer_json().to_string(),
+        )
+        .unwrap();
+        std::fs::write(dir.join("topology.yml"), "{}\n").unwrap();
+        dir
+    }
+
+    /// Name of a tensor in the GGUF file, see Gguf::map_name.
+    fn gguf_name(name: &str, config: &Config) -> (String, Option<usize>) {
+        if name == "lm_head.weight" {
+            return ("output.weight".into(), None);
+        }
+        let gguf = Gguf {
+            path: PathBuf::new(),
+            content: Content {
+                magic: candle_core::quantized::gguf_file::VersionedMagic::GgufV2,
+                metadata: HashMap::new(),
+                tensor_infos: HashMap::new(),
+                tensor_data_offset: 0,
+            },
+        };
+        gguf.map_name(name, config).unwrap()
+    }
+
+    /// Asserts that the quantization error is at most 5% of the largest value.
+    fn assert_close(a: &Tensor, b: &Tensor) {
+        let max_abs = |t: Tensor| -> f32 {
+            t.abs()
+                .unwrap()
+                .flatten_all()
+                .unwrap()
+                .max(0)
+                .unwrap()
+                .to_scalar()
+                .unwrap()
+        };
+        let diff = max_abs((a - b).unwrap());
+        assert!(diff < 0.05 * max_abs(b.clone()), "{diff} apart");
+    }
+
+    #[tokio::test]
+    async fn loads_a_q8_0_model() {
+        let dir = write_gguf("gguf-q8_0", GgmlDType::Q8_0, None);
+        let ctx = Context::from_args(test_utils::args_for(&dir, &[])).unwrap();
+        let reference = Context::from_args(test_utils::args(&[])).unwrap();
+        assert_eq!(ctx.config.hidden_size, reference.config.hidden_size);
+        assert_eq!(ctx.config.num_key_value_heads, 2);
+        assert_eq!(ctx.config.eos_token_ids, [2]);
+        assert!(ctx.config.layer_memory(DType::F32) < reference.config.layer_memory(DType::F32));
+
+        // the query rows are moved back in place without being dequantized
+        let name = "model.layers.0.self_attn.q_proj";
+        let q_proj = linear(64, 64, ctx.var_builder.pp(name), &ctx.config).unwrap();
+        assert!(matches!(q_proj, Linear::Quantized(_)));
+        let expected = linear(64, 64, reference.var_builder.pp(name), &reference.config).unwrap();
+        let x = Tensor::randn(0f32, 1., (1, 3, 64), &Device::Cpu).unwrap();
+        let expected = expected.forward(&x).unwrap();
+        assert_close(&q_proj.forward(&x).unwrap(), &expected);
+
+        let forward = |ctx: &Context| {
+            let block = Block::load(
+                "model.layers.1",
+                ctx.var_builder.pp("model.layers.1"),
+                &ctx.config,
+            )
+            .unwrap();
+            let mut cache = ctx.cache.as_new();
+            let x = x.clone();
+            async move { block.forward_imm(&x, 0, 1, &mut cache).await.unwrap() }
+        };
+        assert_close(&forward(&ctx).await, &forward(&reference).await);
+
+        // the first greedy tokens of the unquantized model, the error grows with random weights
+        let generate = |args| async move {
+            let mut master = Master::new(Context::from_args(args).unwrap())
+                .await
+                .unwrap();
+            let mut text = String::new();
+            master
+                .generate(&CancellationToken::default(), |t| text.push_str(t))
+                .await
+                .unwrap();
+            text
+        };
+        let flags = [
+            "--prompt",
+            "the cat sat",
+            "--max-tokens",
+            "2",
+            "--ignore-eos",
+        ];
+        assert_eq!(
+            generate(test_utils::args_for(&dir, &flags)).await,
+            generate(test_utils::args(&flags)).await
+        );
+    }
+
+    #[test]
+    fn reads_the_rope_frequencies() {
+        let freqs: Vec<f32> = (1..=8).map(|i| i as f32).collect();
+        let dir = write_gguf("gguf-rope-freqs", GgmlDType::Q8_0, Some(&freqs));
+        let ctx = Context::from_args(test_utils::args_for(&dir, &[])).unwrap();
+        assert_eq!(ctx.config.rope_freqs, Some(freqs.clone()));
+
+        let dir = write_gguf("gguf-bad-rope-freqs", GgmlDType::Q8_0, Some(&freqs[..4]));
+        let err = Context::from_args(test_utils::args_for(&dir, &[]))
+            .map(|_| ())
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("has 4 rope frequencies, expected 8 for heads of size 16"));
+    }
+}
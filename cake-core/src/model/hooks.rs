@@ -0,0 +1,57 @@
+use anyhow::Result;
+use candle_core::Tensor;
+
+/// What a hook added with Llama::add_hook observes of its layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookKind {
+    /// Attention probabilities of the layer, with shape (batch, num_attention_heads, seq_len,
+    /// kv_len), in the dtype the attention is computed in.
+    Attention,
+    /// Output of the layer, the residual stream fed to the next one, with shape
+    /// (batch, seq_len, hidden_size).
+    Residual,
+}
+
+/// Called with what a layer produced at every forward pass, an error fails the pass.
+pub type Hook = Box<dyn FnMut(&Tensor) -> Result<()> + Send>;
+
+/// Hooks of the layers of a model, in the order they were added.
+#[derive(Default)]
+pub(crate) struct Hooks(Vec<(usize, HookKind, Hook)>);
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|(layer_idx, kind, _)| (layer_idx, kind)))
+            .finish()
+    }
+}
+
+impl Hooks {
+    pub fn push(&mut self, layer_idx: usize, kind: HookKind, hook: Hook) {
+        self.0.push((layer_idx, kind, hook));
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// Whether any hook of the given kind, if any, observes a layer in layers.
+    pub fn any(&self, layers: std::ops::Range<usize>, kind: Option<HookKind>) -> bool {
+        self.0.iter().any(|(layer_idx, hook_kind, _)| {
+            layers.contains(layer_idx) && kind.is_none_or(|kind| kind == *hook_kind)
+        })
+    }
+
+    /// Calls the hooks of the given kind observing layer_idx with x.
+    pub fn call(&mut self, layer_idx: usize, kind: HookKind, x: &Tensor) -> Result<()> {
+        for (_, _, hook) in self
+            .0
+            .iter_mut()
+            .filter(|(idx, hook_kind, _)| *idx == layer_idx && *hook_kind == kind)
+        {
+            hook(x)?;
+        }
+        Ok(())
+    }
+}
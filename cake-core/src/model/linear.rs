@@ -0,0 +1,185 @@
+use candle_core::{quantized::QMatMul, DType, Module, Result, Tensor, D};
+use candle_nn::VarBuilder;
+use candle_transformers::models::with_tracing;
+
+use super::Config;
+
+/// Quantization applied to the linear layers weights once loaded.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quantization {
+    /// Per output channel int8, with one scale per row of the weight.
+    Int8,
+}
+
+/// Linear layer without bias, optionally holding a quantized weight.
+#[derive(Debug, Clone)]
+pub enum Linear {
+    Full(with_tracing::Linear),
+    Int8 {
+        /// Quantized weight, stored as u8 with an offset of 128.
+        weight: Tensor,
+        /// Scale of each output channel, in the dtype of the original weight.
+        scales: Tensor,
+    },
+    /// Weight kept as quantized in a GGUF file, the matmul runs in f32.
+    Quantized(QMatMul),
+}
+
+/// Loads a linear layer without bias, quantizing its weight if requested in cfg.quantize. The
+/// weights quantized in the GGUF file of the model are loaded as they are instead.
+pub fn linear(size_in: usize, size_out: usize, vb: VarBuilder, cfg: &Config) -> Result<Linear> {
+    if let Some(gguf) = &cfg.gguf {
+        let name = format!("{}.weight", vb.prefix());
+        if let Some(weight) = gguf.qmatmul(&name, cfg, vb.device())? {
+            let (out, size) = weight_dims(&weight)?;
+            if (out, size) != (size_out, size_in) {
+                candle_core::bail!(
+                    "shape mismatch for {name}, expected ({size_out}, {size_in}), got ({out}, {size})"
+                );
+            }
+            return Ok(Linear::Quantized(weight));
+        }
+    }
+
+    match cfg.quantize {
+        None => Ok(Linear::Full(with_tracing::linear_no_bias(
+            size_in, size_out, vb,
+        )?)),
+        Some(Quantization::Int8) => {
+            let weight = vb.get((size_out, size_in), "weight")?;
+            let (weight, scales) = quantize_int8(&weight)?;
+            Ok(Linear::Int8 { weight, scales })
+        }
+    }
+}
+
+fn weight_dims(weight: &QMatMul) -> Result<(usize, usize)> {
+    match weight {
+        QMatMul::QTensor(weight) => weight.shape().dims2(),
+        QMatMul::Tensor(weight) | QMatMul::TensorF16(weight) => weight.dims2(),
+    }
+}
+
+/// Quantizes a (out, in) weight to int8 with a symmetric scale per output channel.
+pub fn quantize_int8(weight: &Tensor) -> Result<(Tensor, Tensor)> {
+    let dtype = weight.dtype();
+    let weight = weight.to_dtype(DType::F32)?;
+    let scales = (weight.abs()?.max_keepdim(D::Minus1)? / 127.)?;
+    // all zero rows
+    let scales = scales.clamp(f32::MIN_POSITIVE, f32::MAX)?;
+    let quantized = weight
+        .broadcast_div(&scales)?
+        .round()?
+        .clamp(-127f32, 127f32)?
+        .affine(1., 128.)?
+        .to_dtype(DType::U8)?;
+
+    Ok((quantized, scales.squeeze(D::Minus1)?.to_dtype(dtype)?))
+}
+
+impl Module for Linear {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Full(linear) => linear.forward(x),
+            Self::Int8 { weight, scales } => {
+                // scaled before the matmul, integer weights could overflow a f16 accumulation
+                let weight = weight
+                    .to_dtype(x.dtype())?
+                    .affine(1., -128.)?
+                    .broadcast_mul(&scales.unsqueeze(D::Minus1)?)?;
+                match x.dims() {
+                    [b, _, _] => x.matmul(&weight.t()?.broadcast_left(*b)?),
+                    _ => x.matmul(&weight.t()?),
+                }
+            }
+            Self::Quantized(weight) => {
+                let dtype = x.dtype();
+                weight
+                    .forward(&x.to_dtype(DType::F32)?.contiguous()?)?
+                    .to_dtype(dtype)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
+
+    use super::*;
+    use crate::{cake::Context, test_utils};
+
+    #[test]
+    fn int8_matmul_is_close_to_the_f16_one() {
+        let weight = Tensor::randn(0f32, 0.2, (96, 128), &Device::Cpu)
+            .unwrap()
+            .to_dtype(DType::F16)
+            .unwrap();
+        let x = Tensor::randn(0f32, 1., (2, 5, 128), &Device::Cpu)
+            .unwrap()
+            .to_dtype(DType::F16)
+            .unwrap();
+        let expected = x.broadcast_matmul(&weight.t().unwrap()).unwrap();
+
+        let (quantized, scales) = quantize_int8(&weight).unwrap();
+        assert_eq!(quantized.dtype(), DType::U8);
+        assert_eq!(scales.dims(), [96]);
+        let y = Linear::Int8 {
+            weight: quantized,
+            scales,
+        }
+        .forward(&x)
+        .unwrap();
+        assert_eq!(y.dtype(), DType::F16);
+
+        // the error of half a quantization step per weight stays within 2% of the outputs
+        let max_abs = |t: &Tensor| -> f32 {
+            t.to_dtype(DType::F32)
+                .unwrap()
+                .abs()
+                .unwrap()
+                .flatten_all()
+                .unwrap()
+                .max(0)
+                .unwrap()
+                .to_scalar()
+                .unwrap()
+        };
+        let diff = max_abs(&(&y - &expected).unwrap());
+        assert!(diff < 0.02 * max_abs(&expected), "{diff} apart");
+    }
+
+    #[test]
+    fn quantize_int8_at_load() {
+        let ctx = Context::from_args(test_utils::args(&["--quantize", "int8"])).unwrap();
+        let vb = ctx.var_builder.pp("model.layers.0.mlp.up_proj");
+        let up_proj = linear(64, 128, vb.clone(), &ctx.config).unwrap();
+        let Linear::Int8 { weight, .. } = &up_proj else {
+            panic!("up_proj isn't quantized");
+        };
+        assert_eq!(weight.dims(), [128, 64]);
+
+        let full = linear(
+            64,
+            128,
+            vb,
+            &crate::model::Config {
+                quantize: None,
+                ..ctx.config.clone()
+            },
+        )
+        .unwrap();
+        let x = Tensor::randn(0f32, 1., (1, 3, 64), &Device::Cpu).unwrap();
+        let diff: f32 = (up_proj.forward(&x).unwrap() - full.forward(&x).unwrap())
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar()
+            .unwrap();
+        assert!(diff < 0.05, "{diff} apart");
+    }
+}
@@ -1,6 +1,7 @@
 use candle_core::{Result, Tensor};
 use candle_nn::{Module, VarBuilder};
-use candle_transformers::models::with_tracing::{linear_no_bias as linear, Linear};
+
+use super::{linear, Linear};
 
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone)]
@@ -19,9 +20,9 @@ impl MLP {
     pub fn load(vb: VarBuilder, cfg: &super::Config) -> Result<Self> {
         let h_size = cfg.hidden_size;
         let i_size = cfg.intermediate_size;
-        let gate_proj = linear(h_size, i_size, vb.pp("gate_proj"))?;
-        let up_proj = linear(h_size, i_size, vb.pp("up_proj"))?;
-        let down_proj = linear(i_size, h_size, vb.pp("down_proj"))?;
+        let gate_proj = linear(h_size, i_size, vb.pp("gate_proj"), cfg)?;
+        let up_proj = linear(h_size, i_size, vb.pp("up_proj"), cfg)?;
+        let down_proj = linear(i_size, h_size, vb.pp("down_proj"), cfg)?;
         Ok(Self {
             gate_proj,
             up_proj,
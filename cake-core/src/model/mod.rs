@@ -1,15 +1,33 @@
 mod attention;
 mod cache;
 mod config;
+mod fallback;
+mod gguf;
+mod hooks;
+mod linear;
 mod mlp;
+mod norm;
+mod paged;
+mod prefix_cache;
+mod pth;
+mod shards;
 mod transformer;
 
-use std::fmt::Debug;
+use std::{collections::HashMap, fmt::Debug, path::PathBuf, sync::Arc};
 
 pub use attention::*;
 pub use cache::*;
 pub use config::*;
+pub(crate) use fallback::*;
+pub use gguf::*;
+pub use hooks::*;
+pub use linear::*;
 pub use mlp::*;
+pub use norm::*;
+pub use paged::*;
+pub use prefix_cache::*;
+pub use pth::*;
+pub use shards::*;
 
 pub use transformer::*;
 
@@ -17,9 +35,13 @@ use anyhow::Result;
 use candle_core::{DType, Device, IndexOp, Tensor};
 use candle_nn::Embedding;
 use candle_nn::{Module, VarBuilder};
-use candle_transformers::models::with_tracing::{linear_no_bias as linear, Linear, RmsNorm};
 
-use crate::cake::{Forwarder, Topology};
+use crate::{
+    cake::{
+        ClientError, ConnectionOptions, Forwarder, LayerTimings, Metrics, Topology, WorkerStatus,
+    },
+    utils,
+};
 
 pub const EOS_TOKEN: &str = "</s>";
 
@@ -29,9 +51,42 @@ pub struct Llama {
     blocks: Vec<Box<dyn Forwarder>>,
     ln_f: RmsNorm,
     lm_head: Linear,
+    // forward latencies of the blocks, if enabled
+    timings: Option<LayerTimings>,
+    // always collected, exported by the master if enabled
+    metrics: Arc<Metrics>,
+    // directory the activations are dumped to, if any
+    dump_dir: Option<PathBuf>,
+    // dtype of the activations between the blocks, the one of the weights if not set
+    activation_dtype: Option<DType>,
+    // observers of the attention and the outputs of the layers
+    hooks: Hooks,
+    // loads the layers of the workers that can't be reached anymore on the CPU, if enabled
+    fallback: Option<LocalFallback>,
+    // number of chunks the prompts are pipelined through the workers in
+    pipeline_chunks: usize,
+}
+
+/// What the layers of a worker that can't be reached anymore are loaded from.
+struct LocalFallback {
+    var_builder: VarBuilder<'static>,
+    config: Config,
+    // empty cache on the CPU the ones of the blocks are created from
+    cache: Cache,
+}
+
+impl Debug for LocalFallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LocalFallback").finish_non_exhaustive()
+    }
 }
 
 impl Llama {
+    /// Runs the tokens x, with shape (batch, seq_len), at positions index_pos.. through the
+    /// model and returns the logits of their last position. The whole sequence goes through each
+    /// layer, or each group of layers of a worker, in a single forward that fills the key-value
+    /// caches for all of its positions, so a prompt is processed at once rather than token by
+    /// token.
     pub async fn forward(
         &mut self,
         x: &Tensor,
@@ -39,41 +94,619 @@ impl Llama {
         cache: &mut Cache,
     ) -> Result<Tensor> {
         let (_batch_size, seq_len) = x.dims2()?;
+        let x = self.hidden_states(x, index_pos, cache).await?;
+        let x = x.i((.., seq_len - 1, ..))?.contiguous()?;
+        let logits = self.lm_head.forward(&x)?;
+        if let Some(dir) = &self.dump_dir {
+            utils::dump_activations(dir, "hidden", &x)?;
+            utils::dump_activations(dir, "logits", &logits)?;
+        }
+        logits.to_dtype(DType::F32).map_err(|e| anyhow!(e))
+    }
+
+    /// Same as forward, returning the logits of every position with shape
+    /// (batch, seq_len, vocab_size).
+    pub async fn forward_all(
+        &mut self,
+        x: &Tensor,
+        index_pos: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let x = self.hidden_states(x, index_pos, cache).await?;
+        let logits = self.lm_head.forward(&x)?;
+        logits.to_dtype(DType::F32).map_err(|e| anyhow!(e))
+    }
+
+    /// Runs every block and returns the normalized hidden states of the last one, with shape
+    /// (batch, seq_len, hidden_size).
+    pub async fn hidden_states(
+        &mut self,
+        x: &Tensor,
+        index_pos: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
         let mut x = self.embedding.forward(x)?;
+        let dtype = x.dtype();
+        if let Some(activation_dtype) = self.activation_dtype {
+            x = x.to_dtype(activation_dtype)?;
+        }
+        let metrics = self.metrics.clone();
 
-        let num_blocks = self.blocks.len();
-        let mut block_idx = 0;
+        let groups = self.groups();
+        let chunks = self.pipeline_chunks.min(x.dim(1)?);
+        if chunks > 1
+            && groups.len() > 1
+            && self.dump_dir.is_none()
+            && !self.hooks.any(0..self.blocks.len(), None)
+        {
+            let x = self
+                .forward_pipelined(x, index_pos, cache, &groups, chunks)
+                .await?;
+            return self
+                .ln_f
+                .forward(&x.to_dtype(dtype)?)
+                .map_err(|e| anyhow!(e));
+        }
 
-        while block_idx < num_blocks {
-            let curr_block_id = self.blocks[block_idx].ident();
-            if curr_block_id == "local" {
+        for (first, last) in groups {
+            // the output of every layer is needed to dump it or to hook it
+            if self.blocks[first].ident() == "local"
+                || self.dump_dir.is_some()
+                || self.hooks.any(first..last, None)
+            {
                 // do not batch local inferences
-                x = self.blocks[block_idx]
-                    .forward(&x, index_pos, block_idx, cache)
-                    .await?;
+                for block_idx in first..last {
+                    let start = std::time::Instant::now();
+                    let forward = metrics.forward(self.blocks[block_idx].ident());
+                    let block = &mut self.blocks[block_idx];
+                    if self
+                        .hooks
+                        .any(block_idx..block_idx + 1, Some(HookKind::Attention))
+                    {
+                        let attention;
+                        (x, attention) = match block
+                            .forward_attention(&x, index_pos, block_idx, cache)
+                            .await
+                        {
+                            Ok(res) => res,
+                            Err(e) => return Err(self.recover(block_idx, e)),
+                        };
+                        self.hooks
+                            .call(block_idx, HookKind::Attention, &attention)?;
+                    } else {
+                        x = match block.forward(&x, index_pos, block_idx, cache).await {
+                            Ok(x) => x,
+                            Err(e) => return Err(self.recover(block_idx, e)),
+                        };
+                    }
+                    drop(forward);
+                    self.hooks.call(block_idx, HookKind::Residual, &x)?;
+                    let elapsed = start.elapsed();
+                    Self::log_forward(self.blocks[block_idx].layer_name(), 1, elapsed);
+                    if let Some(timings) = &mut self.timings {
+                        timings.record(block_idx, elapsed);
+                    }
+                    if let Some(dir) = &self.dump_dir {
+                        utils::dump_activations(dir, &format!("layer_{block_idx}"), &x)?;
+                    }
+                }
+            } else {
+                // batch all contiguous layers running on the same worker
+                let batch = (first..last)
+                    .map(|block_idx| {
+                        (
+                            self.blocks[block_idx].layer_name().to_string(),
+                            index_pos,
+                            block_idx,
+                        )
+                    })
+                    .collect();
+
+                let start = std::time::Instant::now();
+                let forward = metrics.forward(self.blocks[first].ident());
+                x = match self.blocks[first].forward_batch(&x, batch, cache).await {
+                    Ok(x) => x,
+                    Err(e) => return Err(self.recover(first, e)),
+                };
+                drop(forward);
+                let elapsed = start.elapsed();
+                Self::log_forward(self.blocks[first].layer_name(), last - first, elapsed);
+                if let Some(timings) = &mut self.timings {
+                    // layers of a batch are timed together, split the time evenly
+                    let elapsed = elapsed / (last - first) as u32;
+                    for block_idx in first..last {
+                        timings.record(block_idx, elapsed);
+                    }
+                }
+            }
+        }
+
+        self.ln_f
+            .forward(&x.to_dtype(dtype)?)
+            .map_err(|e| anyhow!(e))
+    }
+
+    /// Runs the chunks of x along the sequence through the groups of blocks as a wavefront: while
+    /// a worker computes a chunk, the worker serving the next layers computes the previous chunk
+    /// and the local blocks another one, rather than every node waiting for the previous one to
+    /// process the whole sequence. Each block still gets the chunks in order, filling its cache as
+    /// if x went through it at once.
+    async fn forward_pipelined(
+        &mut self,
+        x: Tensor,
+        index_pos: usize,
+        cache: &mut Cache,
+        groups: &[(usize, usize)],
+        chunks: usize,
+    ) -> Result<Tensor> {
+        let seq_len = x.dim(1)?;
+        let size = seq_len.div_ceil(chunks);
+        let starts: Vec<usize> = (0..seq_len).step_by(size).collect();
+        let mut xs = starts
+            .iter()
+            .map(|&start| x.narrow(1, start, size.min(seq_len - start))?.contiguous())
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let metrics = self.metrics.clone();
+
+        for tick in 0..xs.len() + groups.len() - 1 {
+            // (group, chunk) pairs of the wavefront, each group on the chunk after the one of the
+            // next group
+            let wave: Vec<(usize, usize)> = (0..groups.len())
+                .filter_map(|g| Some((g, tick.checked_sub(g).filter(|&c| c < xs.len())?)))
+                .collect();
+            let (remote, local): (Vec<_>, Vec<_>) = wave
+                .into_iter()
+                .partition(|&(g, _)| self.blocks[groups[g].0].ident() != "local");
+
+            // the workers get their chunks first so that they compute while the local blocks do
+            let mut requests: HashMap<_, _> = remote
+                .iter()
+                .map(|&(g, c)| {
+                    let (first, last) = groups[g];
+                    let batch = (first..last)
+                        .map(|block_idx| {
+                            (
+                                self.blocks[block_idx].layer_name().to_string(),
+                                index_pos + starts[c],
+                                block_idx,
+                            )
+                        })
+                        .collect();
+                    (first, (c, batch))
+                })
+                .collect();
+            let start = std::time::Instant::now();
+            let in_flight: Vec<_> = remote
+                .iter()
+                .map(|&(g, _)| metrics.forward(self.blocks[groups[g].0].ident()))
+                .collect();
+            let shared: &Cache = cache;
+            let sends = self
+                .blocks
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(block_idx, block)| {
+                    let (c, batch) = requests.remove(&block_idx)?;
+                    let x = &xs[c];
+                    Some(async move { (block_idx, block.start_batch(x, batch, shared).await) })
+                });
+            let mut failed = None;
+            let mut started = vec![];
+            for (block_idx, res) in futures::future::join_all(sends).await {
+                match res {
+                    Ok(()) => started.push(block_idx),
+                    Err(e) => failed = failed.or(Some((block_idx, e))),
+                }
+            }
+
+            if failed.is_none() {
+                'local: for &(g, c) in &local {
+                    for block_idx in groups[g].0..groups[g].1 {
+                        let start = std::time::Instant::now();
+                        let forward = metrics.forward(self.blocks[block_idx].ident());
+                        let pos = index_pos + starts[c];
+                        match self.blocks[block_idx]
+                            .forward(&xs[c], pos, block_idx, cache)
+                            .await
+                        {
+                            Ok(x) => xs[c] = x,
+                            Err(e) => {
+                                failed = Some((block_idx, e));
+                                break 'local;
+                            }
+                        }
+                        drop(forward);
+                        let elapsed = start.elapsed();
+                        Self::log_forward(self.blocks[block_idx].layer_name(), 1, elapsed);
+                        if let Some(timings) = &mut self.timings {
+                            timings.record(block_idx, elapsed);
+                        }
+                    }
+                }
+            }
+
+            // the responses of every started forward are read, even after a failure, so that
+            // they aren't taken for the ones of the next requests
+            let receives = self
+                .blocks
+                .iter_mut()
+                .enumerate()
+                .filter(|(block_idx, _)| started.contains(block_idx))
+                .map(|(block_idx, block)| async move { (block_idx, block.finish_batch().await) });
+            let outputs = futures::future::join_all(receives).await;
+            drop(in_flight);
+            let elapsed = start.elapsed();
+            for (block_idx, res) in outputs {
+                match res {
+                    Ok(x) => {
+                        let &(g, c) = remote
+                            .iter()
+                            .find(|&&(g, _)| groups[g].0 == block_idx)
+                            .expect("started forwards are part of the wavefront");
+                        xs[c] = x;
+                        let layers = groups[g].1 - block_idx;
+                        Self::log_forward(self.blocks[block_idx].layer_name(), layers, elapsed);
+                        if let Some(timings) = &mut self.timings {
+                            // the remote chunks of a wavefront are timed together
+                            let elapsed = elapsed / layers as u32;
+                            for block_idx in block_idx..groups[g].1 {
+                                timings.record(block_idx, elapsed);
+                            }
+                        }
+                    }
+                    Err(e) => failed = failed.or(Some((block_idx, e))),
+                }
+            }
+
+            if let Some((block_idx, e)) = failed {
+                return Err(self.recover(block_idx, e));
+            }
+        }
+
+        Tensor::cat(&xs, 1).map_err(|e| anyhow!(e))
+    }
+
+    /// Serves the layers of the worker serving block_idx on the CPU from now on if the local
+    /// fallback is enabled and e tells that the worker can't be reached anymore. A CacheLost error
+    /// is returned then so that the sequences are processed again, e otherwise.
+    fn recover(&mut self, block_idx: usize, e: anyhow::Error) -> anyhow::Error {
+        let ident = self.blocks[block_idx].ident().to_string();
+        match self.fall_back(block_idx, &e) {
+            Ok(true) => ClientError::CacheLost {
+                address: ident,
+                reason: Some(e.to_string()),
+            }
+            .into(),
+            Ok(false) => e,
+            Err(fallback) => anyhow!("{e}, and its layers can't be loaded locally: {fallback}"),
+        }
+    }
+
+    /// Replaces every block served by the same worker as block_idx with one running on the CPU,
+    /// returning false if the local fallback is disabled or the worker may still be reached.
+    fn fall_back(&mut self, block_idx: usize, e: &anyhow::Error) -> Result<bool> {
+        let Some(fallback) = &self.fallback else {
+            return Ok(false);
+        };
+        if !matches!(
+            e.downcast_ref(),
+            Some(ClientError::Unreachable { .. } | ClientError::NoHealthyReplica { .. })
+        ) {
+            return Ok(false);
+        }
+
+        let ident = self.blocks[block_idx].ident().to_string();
+        let moved: Vec<usize> = (0..self.blocks.len())
+            .filter(|&idx| self.blocks[idx].ident() == ident)
+            .collect();
+        log::error!(
+            "{e}, falling back to serving its {} layers on the CPU of the master",
+            moved.len()
+        );
+
+        for idx in moved {
+            let layer_name = self.blocks[idx].layer_name().to_string();
+            log::info!("loading {layer_name} on the CPU ...");
+            let block = Block::load(
+                &layer_name,
+                fallback.var_builder.pp(&layer_name),
+                &fallback.config,
+            )?;
+            let cache = fallback.cache.as_new();
+            self.blocks[idx] = Box::new(CpuBlock::new(block, cache));
+        }
+
+        Ok(true)
+    }
+
+    /// Logs the latency of a forward pass through the layers starting at layer_name, with
+    /// structured fields for the JSON logs.
+    fn log_forward(layer_name: &str, layers: usize, elapsed: std::time::Duration) {
+        let duration_ms = elapsed.as_secs_f64() * 1000.;
+        log::debug!(
+            layer_name, layers, duration_ms;
+            "forward from {layer_name} took {duration_ms:.2}ms"
+        );
+    }
+
+    /// Writes the output of every layer, the hidden state fed to the lm_head and the logits of
+    /// every forward pass to dir. Remote layers are no longer batched so that the output of each
+    /// of them is received.
+    pub fn dump_activations_to(&mut self, dir: PathBuf) {
+        self.dump_dir = Some(dir);
+    }
+
+    /// Splits the prompts in chunks going through the workers one after another, so that the
+    /// workers serving consecutive layers compute different chunks at the same time. Prompts are
+    /// processed at once with a single chunk, or while activations are dumped or hooked.
+    pub fn set_pipeline_chunks(&mut self, chunks: usize) {
+        self.pipeline_chunks = chunks.max(1);
+    }
+
+    /// Calls hook with the attention probabilities or the output of the layer layer_idx at every
+    /// forward pass, the layer being local or served by a worker. Only the positions the pass
+    /// runs are observed, one per generated token once the prompt is processed. The layers of a
+    /// worker are no longer batched while any of them is hooked, and the flash attention kernel
+    /// is bypassed for the hooked attentions.
+    pub fn add_hook(&mut self, layer_idx: usize, kind: HookKind, hook: Hook) -> Result<()> {
+        if layer_idx >= self.blocks.len() {
+            bail!(
+                "can't hook layer {layer_idx}, the model has {} layers",
+                self.blocks.len()
+            );
+        }
+        self.hooks.push(layer_idx, kind, hook);
+        Ok(())
+    }
+
+    /// Removes the hooks added with add_hook.
+    pub fn clear_hooks(&mut self) {
+        self.hooks.clear();
+    }
+
+    /// Makes the forwards and the resets that fail because a worker can't be reached anymore
+    /// load the layers of the worker on the CPU, from var_builder, once and for all. The layers
+    /// get caches of their own with the settings of cache.
+    pub fn enable_local_fallback(
+        &mut self,
+        var_builder: VarBuilder<'static>,
+        config: &Config,
+        cache: &Cache,
+    ) -> Result<()> {
+        self.fallback = Some(LocalFallback {
+            var_builder,
+            config: config.clone(),
+            cache: cache.on_device(config, &Device::Cpu)?,
+        });
+        Ok(())
+    }
+
+    /// Sets the dtype the activations are cast to between the blocks, None keeping the one of the
+    /// weights. Local blocks and workers cast them to the dtype of their weights, the weights
+    /// aren't converted.
+    pub fn set_activation_dtype(&mut self, dtype: Option<DType>) {
+        self.activation_dtype = dtype;
+    }
+
+    /// Starts recording the forward latency of every block, dropping the previous samples.
+    pub fn enable_timings(&mut self) {
+        self.timings = Some(LayerTimings::new(
+            self.blocks
+                .iter()
+                .map(|block| (block.layer_name().to_string(), block.ident().to_string()))
+                .collect(),
+        ));
+    }
+
+    /// Stops recording the latencies and returns the ones recorded since enable_timings.
+    pub fn take_timings(&mut self) -> Option<LayerTimings> {
+        self.timings.take()
+    }
 
-                block_idx += 1;
+    /// Counters of the forward requests, shared with the master.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Returns the (first, last) ranges of contiguous blocks served by the same node.
+    fn groups(&self) -> Vec<(usize, usize)> {
+        let mut groups = vec![];
+        let mut first = 0;
+        for block_idx in 1..=self.blocks.len() {
+            if block_idx == self.blocks.len()
+                || self.blocks[block_idx].ident() != self.blocks[first].ident()
+            {
+                groups.push((first, block_idx));
+                first = block_idx;
+            }
+        }
+        groups
+    }
+
+    /// Returns a copy of the cache that also holds the key-value tensors kept by the workers.
+    pub async fn kv_cache(&mut self, cache: &Cache) -> Result<Cache> {
+        let mut full = cache.as_contiguous();
+        for (first, last) in self.groups() {
+            // workers keep the cache of a batch in the connection of its first block
+            let block_idxs: Vec<usize> = (first..last).collect();
+            for (block_idx, k, v) in self.blocks[first].get_kv_cache(&block_idxs, cache).await? {
+                full.set_kv(block_idx, k, v)?;
+            }
+        }
+        Ok(full)
+    }
+
+    /// Distributes the key-value tensors of full to the blocks that own them, local entries are
+    /// stored in cache.
+    pub async fn set_kv_cache(&mut self, full: &Cache, cache: &mut Cache) -> Result<()> {
+        for (first, last) in self.groups() {
+            let mut kvs = vec![];
+            for block_idx in first..last {
+                let (k, v) = full
+                    .kv(block_idx)?
+                    .ok_or_else(|| anyhow!("no cache for block {block_idx}"))?;
+                kvs.push((block_idx, k, v));
+            }
+            self.blocks[first].set_kv_cache(kvs, cache).await?;
+        }
+        Ok(())
+    }
+
+    /// Liveness of every worker, a worker is healthy only if all its connections are.
+    pub fn worker_status(&self) -> Vec<WorkerStatus> {
+        let mut workers: Vec<WorkerStatus> = vec![];
+        for status in self.blocks.iter().flat_map(|block| block.status()) {
+            if let Some(worker) = workers.iter_mut().find(|w| w.address == status.address) {
+                worker.healthy &= status.healthy;
+                worker.last_seen = worker.last_seen.max(status.last_seen);
             } else {
-                // collect all contiguous layers running on the same worker
-                let mut batch = vec![];
-                let first = block_idx;
-                while block_idx < num_blocks && self.blocks[block_idx].ident() == curr_block_id {
-                    batch.push((
-                        self.blocks[block_idx].layer_name().to_string(),
-                        index_pos,
-                        block_idx,
-                    ));
-                    block_idx += 1;
+                workers.push(status);
+            }
+        }
+        workers
+    }
+
+    /// Resets the state of every block, local or remote.
+    pub async fn reset(&mut self, left_padding: &[usize]) -> Result<()> {
+        for block_idx in 0..self.blocks.len() {
+            if let Err(e) = self.blocks[block_idx].reset(left_padding).await {
+                if !self.fall_back(block_idx, &e)? {
+                    return Err(e);
                 }
+                // the fallback block starts empty
+                self.blocks[block_idx].reset(left_padding).await?;
+            }
+        }
+        Ok(())
+    }
 
-                x = self.blocks[first].forward_batch(&x, batch, cache).await?;
+    /// Switches every block to the state kept under id, see Forwarder::select_cache, so that the
+    /// workers keep the caches of several sequences rather than sending them to the master. If
+    /// some blocks lost the state of id, every block is switched anyway and CacheLost is returned.
+    pub async fn select_cache(&mut self, id: u64) -> Result<()> {
+        let mut lost = None;
+        for block_idx in 0..self.blocks.len() {
+            if let Err(e) = self.blocks[block_idx].select_cache(id).await {
+                let e = match e.downcast_ref() {
+                    Some(ClientError::CacheLost { .. }) => e,
+                    _ => self.recover(block_idx, e),
+                };
+                if !matches!(e.downcast_ref(), Some(ClientError::CacheLost { .. })) {
+                    return Err(e);
+                }
+                // the fallback block starts with the state of id 0
+                self.blocks[block_idx].select_cache(id).await?;
+                lost.get_or_insert(e);
             }
         }
+        lost.map_or(Ok(()), Err)
+    }
 
-        let x = self.ln_f.forward(&x)?;
-        let x = x.i((.., seq_len - 1, ..))?.contiguous()?;
-        let logits = self.lm_head.forward(&x)?;
-        logits.to_dtype(DType::F32).map_err(|e| anyhow!(e))
+    /// Discards the state kept under id by every block.
+    pub async fn drop_cache(&mut self, id: u64) -> Result<()> {
+        for block in self.blocks.iter_mut() {
+            block.drop_cache(id).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the hosts of the workers serving a block in the topology, several ones being
+    /// replicas.
+    fn block_hosts(topology: &Topology, block_idx: usize) -> Vec<String> {
+        topology
+            .get_nodes_for_layer(&format!("model.layers.{block_idx}"))
+            .into_iter()
+            .filter(|(_, node)| !node.is_local())
+            .map(|(_, node)| node.host.clone())
+            .collect()
+    }
+
+    /// Returns how the block served as the topology assigns it is identified, see
+    /// Forwarder::ident.
+    fn block_ident(topology: &Topology, block_idx: usize) -> String {
+        match Self::block_hosts(topology, block_idx) {
+            hosts if hosts.is_empty() => "local".to_string(),
+            hosts => hosts.join(","),
+        }
+    }
+
+    async fn load_block(
+        vb: &VarBuilder<'static>,
+        cfg: &Config,
+        device: &Device,
+        topology: &Topology,
+        options: &ConnectionOptions,
+        block_idx: usize,
+    ) -> Result<Box<dyn Forwarder>> {
+        let block_layer_name = format!("model.layers.{block_idx}");
+
+        let hosts = Self::block_hosts(topology, block_idx);
+        match hosts.as_slice() {
+            [] => {
+                log::debug!("{} will be served locally", &block_layer_name);
+
+                let block = Block::load(&block_layer_name, vb.pp(&block_layer_name), cfg)?;
+
+                Ok(Box::new(block))
+            }
+            [host] => {
+                log::debug!("{host} will serve {}", &block_layer_name);
+
+                let client = crate::cake::Client::new(
+                    device.clone(),
+                    host,
+                    &block_layer_name,
+                    options.clone(),
+                )
+                .await?;
+
+                Ok(Box::new(client))
+            }
+            hosts => {
+                log::debug!("{} are replicas of {}", hosts.join(", "), &block_layer_name);
+
+                let router = crate::cake::Router::new(
+                    device.clone(),
+                    hosts,
+                    &block_layer_name,
+                    options.clone(),
+                )
+                .await?;
+
+                Ok(Box::new(router))
+            }
+        }
+    }
+
+    /// Serves the blocks as the topology assigns them, connecting to the new workers and closing
+    /// the connections to the workers no longer serving blocks. The blocks served by the same
+    /// node are kept, nothing changes if a block can't be loaded. The caches of the sequences are
+    /// lost and must be reset.
+    pub async fn set_topology(
+        &mut self,
+        vb: &VarBuilder<'static>,
+        cfg: &Config,
+        device: &Device,
+        topology: &Topology,
+        options: &ConnectionOptions,
+    ) -> Result<()> {
+        let mut moved = vec![];
+        for block_idx in 0..self.blocks.len() {
+            if self.blocks[block_idx].ident() != Self::block_ident(topology, block_idx) {
+                moved.push((
+                    block_idx,
+                    Self::load_block(vb, cfg, device, topology, options, block_idx).await?,
+                ));
+            }
+        }
+
+        for (block_idx, block) in moved {
+            log::info!("  {block}");
+            // dropping a client closes its connection
+            self.blocks[block_idx] = block;
+        }
+
+        Ok(())
     }
 
     pub async fn load(
@@ -81,38 +714,24 @@ impl Llama {
         cfg: &Config,
         device: &Device,
         topology: &Topology,
+        options: &ConnectionOptions,
     ) -> Result<Self> {
         log::info!("loading embeddings ...");
         let embedding =
             candle_nn::embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("model.embed_tokens"))?;
 
         log::info!("loading lm_head ...");
-        let lm_head = linear(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?;
+        let lm_head = linear(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"), cfg)?;
 
         log::info!("loading model.norm ...");
-        let ln_f = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("model.norm"))?;
+        let ln_f = RmsNorm::load(vb.pp("model.norm"), cfg)?;
 
         log::info!("loading {} blocks ...", cfg.num_hidden_layers);
 
         let mut blocks: Vec<Box<dyn Forwarder>> = vec![];
 
         for i in 0..cfg.num_hidden_layers {
-            let block_layer_name = format!("model.layers.{i}");
-
-            if let Some((node_name, node)) = topology.get_node_for_layer(&block_layer_name) {
-                log::debug!("node {node_name} will serve {}", &block_layer_name);
-
-                let client =
-                    crate::cake::Client::new(device.clone(), &node.host, &block_layer_name).await?;
-
-                blocks.push(Box::new(client));
-            } else {
-                log::debug!("{} will be served locally", &block_layer_name);
-
-                let block = Block::load(&block_layer_name, vb.pp(&block_layer_name), cfg)?;
-
-                blocks.push(Box::new(block));
-            }
+            blocks.push(Self::load_block(vb, cfg, device, topology, options, i).await?);
         }
 
         for block in &blocks {
@@ -124,6 +743,502 @@ impl Llama {
             blocks,
             ln_f,
             lm_head,
+            timings: None,
+            metrics: Arc::new(Metrics::default()),
+            dump_dir: None,
+            activation_dtype: None,
+            pipeline_chunks: 1,
+            hooks: Hooks::default(),
+            fallback: None,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use candle_core::Device;
+
+    use super::*;
+    use crate::{
+        cake::{CancellationToken, ConnectionOptions, Context, Topology},
+        test_utils,
+    };
+
+    /// Names of the files in dir, sorted.
+    fn dumped(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    async fn generate(args: crate::Args) {
+        test_utils::master(args)
+            .await
+            .generate(&CancellationToken::default(), |_| ())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn dump_activations_of_every_layer() {
+        let flags = [
+            "--prompt",
+            "the cat sat",
+            "--max-tokens",
+            "2",
+            "--ignore-eos",
+        ];
+        let dir = test_utils::temp_dir("dump-local");
+        let dir_arg = dir.display().to_string();
+        generate(test_utils::args(
+            &[&flags[..], &["--dump-activations", &dir_arg]].concat(),
+        ))
+        .await;
+        assert_eq!(
+            dumped(&dir),
+            [
+                "hidden.safetensors",
+                "layer_0.safetensors",
+                "layer_1.safetensors",
+                "layer_2.safetensors",
+                "layer_3.safetensors",
+                "logits.safetensors",
+            ]
+        );
+        let load = |path: &Path| {
+            candle_core::safetensors::load(path, &Device::Cpu).unwrap()["activations"]
+                .flatten_all()
+                .unwrap()
+                .to_vec1::<f32>()
+                .unwrap()
+        };
+        assert_eq!(load(&dir.join("logits.safetensors")).len(), 32);
+
+        // the layers of a worker are dumped by the master as it receives them and by the worker
+        // as it computes them
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "dump.yml",
+            &format!("dump-worker: {{ host: '{address}', layers: [2, 3] }}"),
+        );
+        let worker_dir = test_utils::temp_dir("dump-worker");
+        let worker_dir_arg = worker_dir.display().to_string();
+        let _worker = test_utils::worker(
+            "dump-worker",
+            &topology,
+            &["--dump-activations", &worker_dir_arg],
+        )
+        .await;
+        let master_dir = test_utils::temp_dir("dump-master");
+        let master_dir_arg = master_dir.display().to_string();
+        let mut args =
+            test_utils::args(&[&flags[..], &["--dump-activations", &master_dir_arg]].concat());
+        args.topology = topology;
+        generate(args).await;
+        assert_eq!(dumped(&master_dir), dumped(&dir));
+        assert_eq!(
+            dumped(&worker_dir),
+            ["layer_2.safetensors", "layer_3.safetensors"]
+        );
+        // the same activations as when run locally
+        let layer_3 = load(&dir.join("layer_3.safetensors"));
+        assert_eq!(load(&worker_dir.join("layer_3.safetensors")), layer_3);
+        assert_eq!(load(&master_dir.join("layer_3.safetensors")), layer_3);
+    }
+
+    #[tokio::test]
+    async fn local_nodes_are_served_by_the_master() {
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "partial.yml",
+            &format!(
+                "master: {{ host: local, layers: [0, 1] }}\n\
+                 partial-worker: {{ host: '{address}', layers: [2, 3] }}"
+            ),
+        );
+        let _worker = test_utils::worker("partial-worker", &topology, &[]).await;
+
+        // logits of the prompt, blocks of the model
+        let forward = |topology: Topology| async move {
+            let ctx = Context::from_args(test_utils::args(&[])).unwrap();
+            let options = ConnectionOptions::from_args(&ctx.args).unwrap();
+            let mut model = Llama::load(
+                &ctx.var_builder,
+                &ctx.config,
+                &ctx.device,
+                &topology,
+                &options,
+            )
+            .await
+            .unwrap();
+            let input = Tensor::new(&[[1u32, 3, 5, 7]], &Device::Cpu).unwrap();
+            let mut cache = ctx.cache.as_new();
+            let logits = model.forward(&input, 0, &mut cache).await.unwrap();
+            let idents: Vec<String> = model.blocks.iter().map(|b| b.ident().to_string()).collect();
+            (
+                logits.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+                idents,
+            )
+        };
+
+        let (logits, idents) = forward(Topology::from_path(&topology).unwrap()).await;
+        assert_eq!(
+            idents,
+            ["local", "local", address.as_str(), address.as_str()]
+        );
+        let (local_logits, local_idents) = forward(Topology::default()).await;
+        assert_eq!(local_idents, ["local"; 4]);
+        assert_eq!(logits, local_logits);
+    }
+
+    #[tokio::test]
+    async fn pipelined_prompt_like_serialized() {
+        let addresses = [test_utils::free_address(), test_utils::free_address()];
+        let topology = test_utils::topology(
+            "pipeline.yml",
+            &format!(
+                "master: {{ host: local, layers: [0] }}\n\
+                 pipeline-0: {{ host: '{}', layers: [1, 2] }}\n\
+                 pipeline-1: {{ host: '{}', layers: [3] }}",
+                addresses[0], addresses[1]
+            ),
+        );
+        let _workers = [
+            test_utils::worker("pipeline-0", &topology, &[]).await,
+            test_utils::worker("pipeline-1", &topology, &[]).await,
+        ];
+
+        // logits of every position of a long prompt, then of the next token
+        let forward = |chunks: usize| {
+            let topology = Topology::from_path(&topology).unwrap();
+            async move {
+                let ctx = Context::from_args(test_utils::args(&[])).unwrap();
+                let options = ConnectionOptions::from_args(&ctx.args).unwrap();
+                let mut model = Llama::load(
+                    &ctx.var_builder,
+                    &ctx.config,
+                    &ctx.device,
+                    &topology,
+                    &options,
+                )
+                .await
+                .unwrap();
+                model.set_pipeline_chunks(chunks);
+                let input = Tensor::new(prompt(240).as_slice(), &Device::Cpu)
+                    .unwrap()
+                    .unsqueeze(0)
+                    .unwrap();
+                let mut cache = ctx.cache.as_new();
+                let logits = model.forward_all(&input, 0, &mut cache).await.unwrap();
+
+                // the caches of the workers hold every position either way
+                let next = Tensor::new(&[[3u32]], &Device::Cpu).unwrap();
+                let next = model.forward(&next, 240, &mut cache).await.unwrap();
+                (
+                    logits.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+                    next.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+                )
+            }
+        };
+
+        let (logits, next) = forward(1).await;
+        let (pipelined_logits, pipelined_next) = forward(4).await;
+        for (a, b) in [(&logits, &pipelined_logits), (&next, &pipelined_next)] {
+            assert_eq!(a.len(), b.len());
+            for (a, b) in a.iter().zip(b) {
+                assert!((a - b).abs() < 1e-4, "{a} != {b}");
+            }
+        }
+    }
+
+    /// Tokens of a prompt of the given length.
+    fn prompt(len: u32) -> Vec<u32> {
+        (0..len)
+            .map(|i| i % test_utils::WORDS.len() as u32)
+            .collect()
+    }
+
+    /// Requests in flight to the simulated workers, and the time they would have taken over a
+    /// link of a fixed latency plus a time per position.
+    #[derive(Debug, Default)]
+    struct SimulatedLink {
+        max_in_flight: usize,
+        // latencies of the requests sent since all the previous ones were answered, and the
+        // number of them answered
+        wave: Vec<std::time::Duration>,
+        answered: usize,
+        elapsed: std::time::Duration,
+    }
+
+    impl SimulatedLink {
+        fn start(&mut self, x: &Tensor) {
+            let positions = x.dim(1).unwrap() as u32;
+            let latency = std::time::Duration::from_millis(10)
+                + std::time::Duration::from_micros(500) * positions;
+            self.wave.push(latency);
+            self.max_in_flight = self.max_in_flight.max(self.wave.len() - self.answered);
+        }
+
+        fn finish(&mut self) {
+            self.answered += 1;
+            // the requests in flight together take the time of the slowest one
+            if self.answered == self.wave.len() {
+                self.elapsed += self.wave.drain(..).max().unwrap_or_default();
+                self.answered = 0;
+            }
+        }
+    }
+
+    /// Remote block passing its input through, accounting its requests to the link.
+    #[derive(Debug)]
+    struct SimulatedWorker {
+        layer_name: String,
+        ident: String,
+        link: Arc<std::sync::Mutex<SimulatedLink>>,
+        pending: Option<Tensor>,
+    }
+
+    impl std::fmt::Display for SimulatedWorker {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}@{}", &self.layer_name, &self.ident)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Forwarder for SimulatedWorker {
+        async fn forward(
+            &mut self,
+            x: &Tensor,
+            _index_pos: usize,
+            _block_idx: usize,
+            _cache: &mut Cache,
+        ) -> Result<Tensor> {
+            let mut link = self.link.lock().unwrap();
+            link.start(x);
+            link.finish();
+            Ok(x.clone())
+        }
+
+        async fn forward_batch(
+            &mut self,
+            x: &Tensor,
+            _batch: Vec<(String, usize, usize)>,
+            cache: &mut Cache,
+        ) -> Result<Tensor> {
+            self.forward(x, 0, 0, cache).await
+        }
+
+        async fn start_batch(
+            &mut self,
+            x: &Tensor,
+            _batch: Vec<(String, usize, usize)>,
+            _cache: &Cache,
+        ) -> Result<()> {
+            self.link.lock().unwrap().start(x);
+            self.pending = Some(x.clone());
+            Ok(())
+        }
+
+        async fn finish_batch(&mut self) -> Result<Tensor> {
+            self.link.lock().unwrap().finish();
+            self.pending
+                .take()
+                .ok_or_else(|| anyhow!("no forward is running"))
+        }
+
+        fn layer_name(&self) -> &str {
+            &self.layer_name
+        }
+
+        fn ident(&self) -> &str {
+            &self.ident
+        }
+    }
+
+    #[tokio::test]
+    async fn pipelined_prompt_overlaps_the_workers() {
+        // the requests of a prompt to a worker serving layers 1 and 2 and to one serving layer 3,
+        // how many of them were in flight at once and the time they'd have taken
+        let forward = |chunks: usize| async move {
+            let ctx = Context::from_args(test_utils::args(&[])).unwrap();
+            let options = ConnectionOptions::from_args(&ctx.args).unwrap();
+            let mut model = Llama::load(
+                &ctx.var_builder,
+                &ctx.config,
+                &ctx.device,
+                &Topology::default(),
+                &options,
+            )
+            .await
+            .unwrap();
+            let link = Arc::new(std::sync::Mutex::new(SimulatedLink::default()));
+            for (block_idx, ident) in [(1, "worker-0"), (2, "worker-0"), (3, "worker-1")] {
+                model.blocks[block_idx] = Box::new(SimulatedWorker {
+                    layer_name: format!("model.layers.{block_idx}"),
+                    ident: ident.to_string(),
+                    link: link.clone(),
+                    pending: None,
+                });
+            }
+            model.set_pipeline_chunks(chunks);
+
+            let input = Tensor::new(prompt(240).as_slice(), &Device::Cpu)
+                .unwrap()
+                .unsqueeze(0)
+                .unwrap();
+            let logits = model
+                .forward_all(&input, 0, &mut ctx.cache.as_new())
+                .await
+                .unwrap();
+            let link = link.lock().unwrap();
+            (
+                logits.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+                link.max_in_flight,
+                link.elapsed,
+            )
+        };
+
+        // each worker gets the whole prompt once the previous one is done with it
+        let (logits, in_flight, serialized) = forward(1).await;
+        assert_eq!(in_flight, 1);
+        assert_eq!(serialized.as_millis(), 2 * (10 + 120));
+
+        // the second worker computes a chunk while the first one computes the next chunk: 5
+        // wavefronts of at most one request of 60 positions to each worker
+        let (pipelined_logits, in_flight, pipelined) = forward(4).await;
+        assert_eq!(in_flight, 2);
+        assert_eq!(pipelined.as_millis(), 5 * (10 + 30));
+        assert!(pipelined < serialized);
+        for (a, b) in logits.iter().zip(&pipelined_logits) {
+            assert!((a - b).abs() < 1e-4, "{a} != {b}");
+        }
+    }
+
+    #[tokio::test]
+    async fn batched_prompt_like_token_by_token() {
+        let address = test_utils::free_address();
+        let topology = test_utils::topology(
+            "prefill.yml",
+            &format!(
+                "master: {{ host: local, layers: [0, 1] }}\n\
+                 prefill-worker: {{ host: '{address}', layers: [2, 3] }}"
+            ),
+        );
+        let _worker = test_utils::worker("prefill-worker", &topology, &[]).await;
+        let prompt = [1u32, 3, 5, 7, 8, 3, 5, 7];
+
+        // logits of the last position and key-value cache of every layer, the prompt being
+        // forwarded in chunks of the given size
+        let prefill = |chunk: usize| {
+            let topology = Topology::from_path(&topology).unwrap();
+            async move {
+                let ctx = Context::from_args(test_utils::args(&[])).unwrap();
+                let options = ConnectionOptions::from_args(&ctx.args).unwrap();
+                let mut model = Llama::load(
+                    &ctx.var_builder,
+                    &ctx.config,
+                    &ctx.device,
+                    &topology,
+                    &options,
+                )
+                .await
+                .unwrap();
+                let mut cache = ctx.cache.as_new();
+                let mut logits = None;
+                for (i, tokens) in prompt.chunks(chunk).enumerate() {
+                    let input = Tensor::new(tokens, &Device::Cpu)
+                        .unwrap()
+                        .unsqueeze(0)
+                        .unwrap();
+                    logits = Some(model.forward(&input, i * chunk, &mut cache).await.unwrap());
+                }
+                let full = model.kv_cache(&cache).await.unwrap();
+                let kv: Vec<Vec<f32>> = (0..4)
+                    .flat_map(|block_idx| {
+                        let (k, v) = full.kv(block_idx).unwrap().unwrap();
+                        assert_eq!(k.dim(2).unwrap(), prompt.len());
+                        [k, v].map(|t| t.flatten_all().unwrap().to_vec1::<f32>().unwrap())
+                    })
+                    .collect();
+                let logits = logits.unwrap().flatten_all().unwrap();
+                (logits.to_vec1::<f32>().unwrap(), kv)
+            }
+        };
+
+        let (batched, batched_kv) = prefill(prompt.len()).await;
+        let (stepped, stepped_kv) = prefill(1).await;
+        let close = |a: &[f32], b: &[f32]| {
+            assert_eq!(a.len(), b.len());
+            for (a, b) in a.iter().zip(b) {
+                assert!((a - b).abs() < 1e-4, "{a} != {b}");
+            }
+        };
+        for (a, b) in batched_kv.iter().zip(&stepped_kv) {
+            close(a, b);
+        }
+        close(&batched, &stepped);
+        // the same first token is sampled
+        let argmax = |logits: &[f32]| {
+            (0..logits.len())
+                .max_by(|&a, &b| logits[a].total_cmp(&logits[b]))
+                .unwrap()
+        };
+        assert_eq!(argmax(&batched), argmax(&stepped));
+    }
+
+    #[tokio::test]
+    async fn compute_dtype_bounds_the_error_of_f16() {
+        // logits of the prompt with the model loaded and computed in the given dtypes
+        let logits = |dtype: &str, compute_dtype: Option<&str>| {
+            let args = crate::Args {
+                dtype: Some(dtype.to_string()),
+                compute_dtype: compute_dtype.map(String::from),
+                ..test_utils::args(&[])
+            };
+            async move {
+                let ctx = Context::from_args(args).unwrap();
+                let options = ConnectionOptions::from_args(&ctx.args).unwrap();
+                let mut model = Llama::load(
+                    &ctx.var_builder,
+                    &ctx.config,
+                    &ctx.device,
+                    &Topology::default(),
+                    &options,
+                )
+                .await
+                .unwrap();
+                let input = Tensor::new(&[[1u32, 3, 5, 7, 8, 3, 5, 7]], &Device::Cpu).unwrap();
+                let mut cache = ctx.cache.as_new();
+                let logits = model.forward(&input, 0, &mut cache).await.unwrap();
+                logits.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+            }
+        };
+        let error = |a: &[f32], b: &[f32]| {
+            a.iter()
+                .zip(b)
+                .map(|(a, b)| (a - b).abs())
+                .fold(0f32, f32::max)
+        };
+
+        // the CPU has no bf16 matmul, f16 also loses precision in the reductions
+        let reference = logits("f32", None).await;
+        let f32_compute = error(&logits("f16", Some("f32")).await, &reference);
+        let f16_compute = error(&logits("f16", Some("f16")).await, &reference);
+        let argmax = |logits: &[f32]| {
+            (0..logits.len())
+                .max_by(|&a, &b| logits[a].total_cmp(&logits[b]))
+                .unwrap()
+        };
+        assert_eq!(
+            argmax(&logits("f16", Some("f32")).await),
+            argmax(&reference)
+        );
+        assert!(f32_compute <= f16_compute, "{f32_compute} > {f16_compute}");
+        assert!(f32_compute < 0.1, "{f32_compute}");
+    }
+}
@@ -0,0 +1,35 @@
+use candle_core::{DType, Module, Result, Tensor};
+use candle_nn::VarBuilder;
+
+/// RMS norm computed in the compute dtype of the model if set, its input being cast to it and its
+/// output cast back to the dtype of the input.
+#[derive(Debug, Clone)]
+pub struct RmsNorm {
+    inner: candle_nn::RmsNorm,
+    compute_dtype: Option<DType>,
+}
+
+impl RmsNorm {
+    pub fn load(vb: VarBuilder, cfg: &super::Config) -> Result<Self> {
+        let weight = vb.get_with_hints(cfg.hidden_size, "weight", candle_nn::Init::Const(1.))?;
+        let weight = match cfg.compute_dtype {
+            Some(dtype) => weight.to_dtype(dtype)?,
+            None => weight,
+        };
+        Ok(Self {
+            inner: candle_nn::RmsNorm::new(weight, cfg.rms_norm_eps),
+            compute_dtype: cfg.compute_dtype,
+        })
+    }
+}
+
+impl Module for RmsNorm {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        match self.compute_dtype {
+            Some(dtype) if dtype != x.dtype() => {
+                self.inner.forward(&x.to_dtype(dtype)?)?.to_dtype(x.dtype())
+            }
+            _ => self.inner.forward(x),
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use std::sync::{Arc, Mutex};
+
+use candle_core::{DType, Device, Result, Tensor};
+
+/// Fixed-size blocks of key-value positions, shared by the caches of several sequences.
+#[derive(Debug)]
+pub struct BlockPool {
+    block_size: usize,
+    num_blocks: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    dtype: DType,
+    device: Device,
+    /// Indexes of the blocks not referenced by any block table.
+    free: Vec<usize>,
+    /// (num_blocks, num_kv_heads, block_size, head_dim) keys and values of every layer, allocated
+    /// once the layer is first written so that nodes only hold the layers they serve.
+    layers: Vec<Option<(Tensor, Tensor)>>,
+}
+
+impl BlockPool {
+    pub fn new(
+        block_size: usize,
+        num_blocks: usize,
+        num_layers: usize,
+        num_kv_heads: usize,
+        head_dim: usize,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        if block_size == 0 || num_blocks == 0 {
+            candle_core::bail!("the paged kv cache needs at least one block of one position");
+        }
+
+        Ok(Self {
+            block_size,
+            num_blocks,
+            num_kv_heads,
+            head_dim,
+            dtype,
+            device: device.clone(),
+            // lowest indexes are allocated first
+            free: (0..num_blocks).rev().collect(),
+            layers: vec![None; num_layers],
+        })
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.num_blocks
+    }
+
+    /// Number of blocks that can still be allocated.
+    pub fn num_free(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Bytes of the keys and values a block holds across the layers allocated so far.
+    pub fn block_bytes(&self) -> usize {
+        let layers = self.layers.iter().flatten().count();
+        2 * layers
+            * self.num_kv_heads
+            * self.block_size
+            * self.head_dim
+            * self.dtype.size_in_bytes()
+    }
+
+    fn allocate(&mut self) -> Result<usize> {
+        self.free.pop().ok_or_else(|| {
+            candle_core::Error::Msg(format!(
+                "the paged kv cache is full: all its {} blocks of {} positions are in use, increase --kv-blocks or serve fewer sequences",
+                self.num_blocks, self.block_size
+            ))
+        })
+    }
+
+    fn release(&mut self, blocks: &[usize]) {
+        self.free.extend(blocks.iter().rev());
+    }
+
+    fn layer(&mut self, layer_idx: usize) -> Result<(Tensor, Tensor)> {
+        if let Some(kv) = &self.layers[layer_idx] {
+            return Ok(kv.clone());
+        }
+
+        let shape = (
+            self.num_blocks,
+            self.num_kv_heads,
+            self.block_size,
+            self.head_dim,
+        );
+        let kv = (
+            Tensor::zeros(shape, self.dtype, &self.device)?,
+            Tensor::zeros(shape, self.dtype, &self.device)?,
+        );
+        self.layers[layer_idx] = Some(kv.clone());
+        Ok(kv)
+    }
+}
+
+/// Blocks of the pool holding the positions of each row of a batch, in order. The blocks are
+/// returned to the pool once the table is dropped.
+#[derive(Debug)]
+pub struct BlockTable {
+    pool: Arc<Mutex<BlockPool>>,
+    rows: Mutex<Vec<Vec<usize>>>,
+}
+
+impl BlockTable {
+    pub fn new(pool: Arc<Mutex<BlockPool>>) -> Self {
+        Self {
+            pool,
+            rows: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn pool(&self) -> &Arc<Mutex<BlockPool>> {
+        &self.pool
+    }
+
+    /// Number of blocks referenced by the table.
+    pub fn num_blocks(&self) -> usize {
+        self.rows.lock().unwrap().iter().map(|row| row.len()).sum()
+    }
+
+    /// Writes the (batch, num_kv_heads, seq_len, head_dim) keys and values of a layer at
+    /// position pos, allocating the blocks they need.
+    pub fn write(&self, layer_idx: usize, pos: usize, k: &Tensor, v: &Tensor) -> Result<()> {
+        let mut pool = self.pool.lock().unwrap();
+        let mut rows = self.rows.lock().unwrap();
+        let (batch_size, _, seq_len, _) = k.dims4()?;
+        let block_size = pool.block_size;
+
+        if rows.is_empty() {
+            rows.resize(batch_size, vec![]);
+        } else if rows.len() != batch_size {
+            candle_core::bail!(
+                "paged kv cache holds {} sequences, got a batch of {batch_size}",
+                rows.len()
+            );
+        }
+
+        let needed = (pos + seq_len).div_ceil(block_size);
+        for row in rows.iter_mut() {
+            while row.len() < needed {
+                let block = pool.allocate()?;
+                row.push(block);
+            }
+        }
+
+        let (pool_k, pool_v) = pool.layer(layer_idx)?;
+        for (row_idx, row) in rows.iter().enumerate() {
+            let (k, v) = (k.narrow(0, row_idx, 1)?, v.narrow(0, row_idx, 1)?);
+            let mut written = 0;
+            while written < seq_len {
+                let offset = (pos + written) % block_size;
+                let len = (block_size - offset).min(seq_len - written);
+                let block = row[(pos + written) / block_size];
+                pool_k.narrow(0, block, 1)?.slice_set(
+                    &k.narrow(2, written, len)?.contiguous()?,
+                    2,
+                    offset,
+                )?;
+                pool_v.narrow(0, block, 1)?.slice_set(
+                    &v.narrow(2, written, len)?.contiguous()?,
+                    2,
+                    offset,
+                )?;
+                written += len;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the first len positions of a layer as (batch, num_kv_heads, len, head_dim) keys and
+    /// values.
+    pub fn read(&self, layer_idx: usize, len: usize) -> Result<(Tensor, Tensor)> {
+        let mut pool = self.pool.lock().unwrap();
+        let rows = self.rows.lock().unwrap();
+        let (pool_k, pool_v) = pool.layer(layer_idx)?;
+        let (num_kv_heads, block_size, head_dim) =
+            (pool.num_kv_heads, pool.block_size, pool.head_dim);
+
+        let gather = |pool_t: &Tensor| -> Result<Tensor> {
+            let mut gathered = vec![];
+            for row in rows.iter() {
+                let blocks = &row[..len.div_ceil(block_size)];
+                let idxs: Vec<u32> = blocks.iter().map(|&block| block as u32).collect();
+                let idxs = Tensor::new(idxs.as_slice(), pool_t.device())?;
+                gathered.push(
+                    pool_t
+                        .index_select(&idxs, 0)?
+                        .transpose(0, 1)?
+                        .reshape((num_kv_heads, blocks.len() * block_size, head_dim))?
+                        .narrow(1, 0, len)?
+                        .unsqueeze(0)?,
+                );
+            }
+            Tensor::cat(&gathered, 0)?.contiguous()
+        };
+
+        Ok((gather(&pool_k)?, gather(&pool_v)?))
+    }
+}
+
+impl Drop for BlockTable {
+    fn drop(&mut self) {
+        if let (Ok(mut pool), Ok(rows)) = (self.pool.lock(), self.rows.lock()) {
+            for row in rows.iter() {
+                pool.release(row);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    fn pool(block_size: usize, num_blocks: usize) -> Arc<Mutex<BlockPool>> {
+        let pool = BlockPool::new(block_size, num_blocks, 2, 2, 8, DType::F32, &Device::Cpu);
+        Arc::new(Mutex::new(pool.unwrap()))
+    }
+
+    /// (batch_size, 2, len, 8) keys and values.
+    fn kv(batch_size: usize, len: usize) -> (Tensor, Tensor) {
+        let shape = (batch_size, 2, len, 8);
+        (
+            Tensor::randn(0f32, 1., shape, &Device::Cpu).unwrap(),
+            Tensor::randn(0f32, 1., shape, &Device::Cpu).unwrap(),
+        )
+    }
+
+    fn values(t: &Tensor) -> Vec<f32> {
+        t.flatten_all().unwrap().to_vec1().unwrap()
+    }
+
+    fn num_free(pool: &Arc<Mutex<BlockPool>>) -> usize {
+        pool.lock().unwrap().num_free()
+    }
+
+    #[test]
+    fn blocks_are_allocated_on_demand() {
+        let pool = pool(4, 8);
+        let table = BlockTable::new(pool.clone());
+
+        let (k0, v0) = kv(2, 6);
+        table.write(1, 0, &k0, &v0).unwrap();
+        assert_eq!(table.num_blocks(), 4);
+        assert_eq!(num_free(&pool), 4);
+
+        // within the last block of each row, then across blocks
+        let (k1, v1) = kv(2, 1);
+        table.write(1, 6, &k1, &v1).unwrap();
+        assert_eq!(table.num_blocks(), 4);
+        let (k2, v2) = kv(2, 3);
+        table.write(1, 7, &k2, &v2).unwrap();
+        assert_eq!(table.num_blocks(), 6);
+
+        let (k, v) = table.read(1, 10).unwrap();
+        assert_eq!(k.dims(), [2, 2, 10, 8]);
+        let expected_k = Tensor::cat(&[&k0, &k1, &k2], 2).unwrap();
+        let expected_v = Tensor::cat(&[&v0, &v1, &v2], 2).unwrap();
+        assert_eq!(values(&k), values(&expected_k));
+        assert_eq!(values(&v), values(&expected_v));
+
+        drop(table);
+        assert_eq!(num_free(&pool), 8);
+    }
+
+    #[test]
+    fn freed_blocks_are_reused() {
+        let pool = pool(4, 6);
+        let first = BlockTable::new(pool.clone());
+        let second = BlockTable::new(pool.clone());
+        let (k, v) = kv(1, 12);
+        first.write(0, 0, &k, &v).unwrap();
+        let (second_k, second_v) = kv(1, 8);
+        second.write(0, 0, &second_k, &second_v).unwrap();
+        assert_eq!(num_free(&pool), 1);
+
+        // the blocks in between are freed and can hold a sequence longer than the last block
+        drop(first);
+        assert_eq!(num_free(&pool), 4);
+        let third = BlockTable::new(pool.clone());
+        let (k, v) = kv(1, 16);
+        third.write(0, 0, &k, &v).unwrap();
+        assert_eq!(num_free(&pool), 0);
+        assert_eq!(values(&third.read(0, 16).unwrap().0), values(&k));
+
+        // the sequence of the second table isn't overwritten
+        assert_eq!(values(&second.read(0, 8).unwrap().0), values(&second_k));
+        drop((second, third));
+        assert_eq!(num_free(&pool), 6);
+    }
+
+    #[test]
+    fn full_pool_is_an_error() {
+        let pool = pool(4, 2);
+        let table = BlockTable::new(pool.clone());
+        let (k, v) = kv(1, 9);
+        let e = table.write(0, 0, &k, &v).unwrap_err();
+        assert!(e.to_string().contains("the paged kv cache is full"), "{e}");
+        assert_eq!(num_free(&pool), 0);
+
+        // the blocks allocated before running out are freed with the table
+        drop(table);
+        assert_eq!(num_free(&pool), 2);
+        assert!(BlockPool::new(4, 0, 2, 2, 8, DType::F32, &Device::Cpu).is_err());
+    }
+
+    #[tokio::test]
+    async fn paged_cache_generates_the_tokens_of_the_contiguous_one() {
+        let generate = |args: crate::Args| async move {
+            let mut master = test_utils::master(args.clone()).await;
+            let tokens = master.encode("the cat sat on the mat").unwrap();
+            let mut text = String::new();
+            master
+                .generate_with(&args, tokens, &Default::default(), |t| text.push_str(t))
+                .await
+                .unwrap();
+            text
+        };
+
+        let args = test_utils::args(&["--max-tokens", "12", "--ignore-eos"]);
+        let paged = crate::Args {
+            paged_kv: true,
+            kv_block_size: 4,
+            kv_blocks: 64,
+            ..args.clone()
+        };
+        assert_eq!(generate(paged).await, generate(args).await);
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::Hasher,
+};
+
+use anyhow::Result;
+use candle_core::Tensor;
+
+use super::Cache;
+
+/// Prefixes are indexed every PREFIX_BLOCK_SIZE tokens.
+const PREFIX_BLOCK_SIZE: usize = 16;
+
+/// Key-value tensors of every block, as computed for a sequence of tokens.
+#[derive(Debug, Clone)]
+pub struct CacheState {
+    kvs: Vec<(Tensor, Tensor)>,
+}
+
+impl CacheState {
+    /// Copies the key-value tensors of a cache, every block must have been computed.
+    pub fn from_cache(cache: &Cache) -> Result<Self> {
+        let kvs = (0..cache.num_blocks())
+            .map(|block_idx| {
+                cache
+                    .kv(block_idx)?
+                    .ok_or_else(|| anyhow!("no cache for block {block_idx}"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { kvs })
+    }
+
+    /// Number of positions held by this state.
+    pub fn len(&self) -> usize {
+        self.kvs
+            .first()
+            .and_then(|(k, _)| k.dim(2).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the state of the first len positions only.
+    pub fn narrow(&self, len: usize) -> Result<Self> {
+        let kvs = self
+            .kvs
+            .iter()
+            .map(|(k, v)| {
+                Ok((
+                    k.narrow(2, 0, len)?.contiguous()?,
+                    v.narrow(2, 0, len)?.contiguous()?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { kvs })
+    }
+
+    /// Stores the key-value tensors in the cache.
+    pub fn restore(&self, cache: &mut Cache) -> Result<()> {
+        for (block_idx, (k, v)) in self.kvs.iter().enumerate() {
+            cache.set_kv(block_idx, k.clone(), v.clone())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    tokens: Vec<u32>,
+    state: CacheState,
+    last_used: u64,
+}
+
+/// LRU of cache states, looked up by the hash of the leading tokens of a prompt so that prompts
+/// sharing a prefix (i.e. the same system prompt) only process the tokens that differ.
+#[derive(Debug)]
+pub struct PrefixCache {
+    capacity: usize,
+    entries: HashMap<u64, Entry>,
+    // hash of the first n * PREFIX_BLOCK_SIZE tokens -> key of the entry they come from
+    prefixes: HashMap<u64, u64>,
+    clock: u64,
+}
+
+impl PrefixCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            prefixes: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Hashes of every block aligned prefix of the tokens, followed by the hash of all of them.
+    fn hashes(tokens: &[u32]) -> (Vec<u64>, u64) {
+        let mut hasher = DefaultHasher::new();
+        let mut prefixes = vec![];
+        for (i, token) in tokens.iter().enumerate() {
+            hasher.write_u32(*token);
+            if (i + 1) % PREFIX_BLOCK_SIZE == 0 {
+                prefixes.push(hasher.clone().finish());
+            }
+        }
+        (prefixes, hasher.finish())
+    }
+
+    /// Stores the state computed for the tokens, evicting the least recently used entry if the
+    /// cache is full.
+    pub fn insert(&mut self, tokens: Vec<u32>, state: CacheState) {
+        if !self.is_enabled() || tokens.is_empty() {
+            return;
+        }
+
+        self.clock += 1;
+
+        let (prefixes, key) = Self::hashes(&tokens);
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            if let Some(lru) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key)
+            {
+                self.entries.remove(&lru);
+                self.prefixes.retain(|_, entry_key| *entry_key != lru);
+            }
+        }
+
+        for prefix in prefixes {
+            self.prefixes.insert(prefix, key);
+        }
+        self.entries.insert(
+            key,
+            Entry {
+                tokens,
+                state,
+                last_used: self.clock,
+            },
+        );
+    }
+
+    /// Returns the longest prefix of the tokens a state is cached for, along with that state
+    /// narrowed to the length of the prefix.
+    pub fn longest_match(&mut self, tokens: &[u32]) -> Option<(usize, CacheState)> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let (prefixes, full) = Self::hashes(tokens);
+        let key = std::iter::once(full)
+            .chain(prefixes.into_iter().rev())
+            .find_map(|hash| {
+                self.entries
+                    .get(&hash)
+                    .map(|_| hash)
+                    .or_else(|| self.prefixes.get(&hash).copied())
+            })?;
+
+        self.clock += 1;
+
+        let entry = self.entries.get_mut(&key)?;
+        entry.last_used = self.clock;
+
+        // the match can extend past the block the entry has been found with
+        let len = entry
+            .tokens
+            .iter()
+            .zip(tokens)
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(entry.state.len());
+        if len == 0 {
+            return None;
+        }
+
+        let state = if len == entry.state.len() {
+            entry.state.clone()
+        } else {
+            entry.state.narrow(len).ok()?
+        };
+
+        Some((len, state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device};
+
+    use super::*;
+
+    /// State of len positions of a single block, the value of each being its position.
+    fn state(len: usize) -> CacheState {
+        let positions = Tensor::arange(0u32, len as u32, &Device::Cpu)
+            .unwrap()
+            .to_dtype(DType::F32)
+            .unwrap()
+            .reshape((1, 1, len, 1))
+            .unwrap();
+        CacheState {
+            kvs: vec![(positions.clone(), positions)],
+        }
+    }
+
+    fn tokens(first: u32, len: usize) -> Vec<u32> {
+        (first..first + len as u32).collect()
+    }
+
+    #[test]
+    fn longest_match_of_a_shared_prefix() {
+        let mut cache = PrefixCache::new(4);
+        let cached = tokens(0, 40);
+        cache.insert(cached.clone(), state(40));
+
+        // the whole cached sequence
+        let (len, state) = cache.longest_match(&cached).unwrap();
+        assert_eq!((len, state.len()), (40, 40));
+
+        // a prompt diverging after 20 tokens is found by its first block
+        let mut prompt = cached[..20].to_vec();
+        prompt.extend(tokens(100, 10));
+        let (len, state) = cache.longest_match(&prompt).unwrap();
+        assert_eq!((len, state.len()), (20, 20));
+        let (k, _) = &state.kvs[0];
+        assert_eq!(
+            k.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+            (0..20).map(|i| i as f32).collect::<Vec<_>>()
+        );
+
+        // less than a block in common
+        let mut prompt = cached[..10].to_vec();
+        prompt.extend(tokens(100, 10));
+        assert!(cache.longest_match(&prompt).is_none());
+        assert!(PrefixCache::new(0).longest_match(&cached).is_none());
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let mut cache = PrefixCache::new(2);
+        let (a, b, c) = (tokens(0, 16), tokens(100, 16), tokens(200, 16));
+        cache.insert(a.clone(), state(16));
+        cache.insert(b.clone(), state(16));
+        // a is used last
+        assert!(cache.longest_match(&a).is_some());
+
+        cache.insert(c.clone(), state(16));
+        assert!(cache.longest_match(&a).is_some());
+        assert!(cache.longest_match(&b).is_none());
+        assert!(cache.longest_match(&c).is_some());
+    }
+}
@@ -0,0 +1,334 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use candle_core::Tensor;
+
+/// Names of the tensors of the original checkpoints and the names the model loads them with.
+const TENSOR_NAMES: &[(&str, &str)] = &[
+    ("tok_embeddings.weight", "model.embed_tokens.weight"),
+    ("norm.weight", "model.norm.weight"),
+    ("output.weight", "lm_head.weight"),
+];
+
+/// Same as TENSOR_NAMES for the tensors of every layer, without the layers.{index} prefix.
+const LAYER_TENSOR_NAMES: &[(&str, &str)] = &[
+    ("attention.wq.weight", "self_attn.q_proj.weight"),
+    ("attention.wk.weight", "self_attn.k_proj.weight"),
+    ("attention.wv.weight", "self_attn.v_proj.weight"),
+    ("attention.wo.weight", "self_attn.o_proj.weight"),
+    ("feed_forward.w1.weight", "mlp.gate_proj.weight"),
+    ("feed_forward.w2.weight", "mlp.down_proj.weight"),
+    ("feed_forward.w3.weight", "mlp.up_proj.weight"),
+    ("attention_norm.weight", "input_layernorm.weight"),
+    ("ffn_norm.weight", "post_attention_layernorm.weight"),
+];
+
+/// Model parameters of the original checkpoints.
+#[derive(Debug, serde::Deserialize)]
+struct Params {
+    n_heads: usize,
+    n_kv_heads: Option<usize>,
+    norm_eps: f64,
+    rope_theta: Option<f32>,
+    #[serde(default)]
+    use_scaled_rope: bool,
+}
+
+/// Returns the name the model loads a tensor of an original checkpoint with, or None if the
+/// tensor isn't used.
+pub fn pth_tensor_name(name: &str) -> Option<String> {
+    if let Some((_, mapped)) = TENSOR_NAMES.iter().find(|(pth, _)| *pth == name) {
+        return Some(mapped.to_string());
+    }
+
+    let (index, name) = name.strip_prefix("layers.")?.split_once('.')?;
+    let index: usize = index.parse().ok()?;
+    LAYER_TENSOR_NAMES
+        .iter()
+        .find(|(pth, _)| *pth == name)
+        .map(|(_, mapped)| format!("model.layers.{index}.{mapped}"))
+}
+
+/// Returns the consolidated.00.pth checkpoint of the model directory if it has no safetensors
+/// nor gguf file to load instead.
+pub fn find_pth(data_path: &Path) -> Result<Option<PathBuf>> {
+    let mut found = vec![];
+    for entry in std::fs::read_dir(data_path)
+        .map_err(|e| anyhow!("can't read {}: {:?}", data_path.display(), e))?
+    {
+        let path = entry?.path();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("safetensors") | Some("gguf") => return Ok(None),
+            Some("pth") => found.push(path),
+            _ => {}
+        }
+    }
+
+    match found.len() {
+        0 => Ok(None),
+        1 => Ok(found.pop()),
+        // tensors are split across the files of model parallel checkpoints
+        _ => bail!(
+            "found multiple pth files in {}, only single file checkpoints can be converted",
+            data_path.display()
+        ),
+    }
+}
+
+/// Converts an original checkpoint to the model.safetensors file of its directory, along with
+/// a config.json built from its params.json if there's none.
+pub fn convert_pth(pth: &Path, data_path: &Path) -> Result<()> {
+    log::info!("converting {} to safetensors ...", pth.display());
+
+    let params_filename = data_path.join("params.json");
+    let data = std::fs::read(&params_filename)
+        .map_err(|e| anyhow!("can't read {}: {:?}", params_filename.display(), e))?;
+    let params: Params = serde_json::from_slice(&data)
+        .map_err(|e| anyhow!("can't parse {}: {:?}", params_filename.display(), e))?;
+    let n_kv_heads = params.n_kv_heads.unwrap_or(params.n_heads);
+
+    let mut tensors = HashMap::new();
+    for (name, tensor) in candle_core::pickle::read_all(pth)
+        .map_err(|e| anyhow!("can't read {}: {:?}", pth.display(), e))?
+    {
+        let Some(mapped) = pth_tensor_name(&name) else {
+            log::debug!("skipping {name}");
+            continue;
+        };
+
+        let tensor = if name.ends_with("attention.wq.weight") {
+            unpermute(&tensor, params.n_heads)?
+        } else if name.ends_with("attention.wk.weight") {
+            unpermute(&tensor, n_kv_heads)?
+        } else {
+            tensor
+        };
+
+        tensors.insert(mapped, tensor);
+    }
+
+    let config_filename = data_path.join("config.json");
+    if !config_filename.exists() {
+        let config = config_from_params(&params, &tensors)?;
+        std::fs::write(&config_filename, config.to_string())
+            .map_err(|e| anyhow!("can't write {}: {:?}", config_filename.display(), e))?;
+    }
+
+    let output = data_path.join("model.safetensors");
+    candle_core::safetensors::save(&tensors, &output)
+        .map_err(|e| anyhow!("can't write {}: {:?}", output.display(), e))?;
+
+    log::info!("saved {} tensors to {}", tensors.len(), output.display());
+
+    Ok(())
+}
+
+/// The original checkpoints interleave the two halves of every head rotated by rope, the model
+/// expects them one after the other.
+fn unpermute(weight: &Tensor, heads: usize) -> Result<Tensor> {
+    let (rows, cols) = weight.dims2()?;
+    weight
+        .reshape((heads, rows / heads / 2, 2, cols))?
+        .transpose(1, 2)?
+        .reshape((rows, cols))
+        .map_err(|e| anyhow!(e))
+}
+
+/// Builds a config.json from the parameters of the checkpoint, the sizes the parameters don't
+/// hold are taken from the shapes of the tensors.
+fn config_from_params(
+    params: &Params,
+    tensors: &HashMap<String, Tensor>,
+) -> Result<serde_json::Value> {
+    let dims = |name: &str| {
+        tensors
+            .get(name)
+            .map(|tensor| tensor.dims().to_vec())
+            .ok_or_else(|| anyhow!("checkpoint has no {name} tensor"))
+    };
+    let embeddings = dims("model.embed_tokens.weight")?;
+    let gate = dims("model.layers.0.mlp.gate_proj.weight")?;
+    let num_hidden_layers = (0..)
+        .take_while(|i| tensors.contains_key(&format!("model.layers.{i}.input_layernorm.weight")))
+        .count();
+
+    let mut config = serde_json::json!({
+        "hidden_size": embeddings[1],
+        "intermediate_size": gate[0],
+        "vocab_size": embeddings[0],
+        "num_hidden_layers": num_hidden_layers,
+        "num_attention_heads": params.n_heads,
+        "num_key_value_heads": params.n_kv_heads.unwrap_or(params.n_heads),
+        "rms_norm_eps": params.norm_eps,
+        "rope_theta": params.rope_theta.unwrap_or(10_000.0),
+    });
+    if params.use_scaled_rope {
+        // the scaling Llama 3.1 has been trained with
+        config["rope_scaling"] = serde_json::json!({
+            "factor": 8.0,
+            "low_freq_factor": 1.0,
+            "high_freq_factor": 4.0,
+            "original_max_position_embeddings": 8192,
+        });
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use candle_core::{DType, Device};
+
+    use super::*;
+    use crate::test_utils;
+
+    /// Writes the f32 tensors as a state dict in the zip format of torch.save.
+    fn write_pth(path: &Path, tensors: &[(&str, Tensor)]) {
+        let binunicode = |pkl: &mut Vec<u8>, s: &str| {
+            pkl.push(b'X');
+            pkl.extend((s.len() as u32).to_le_bytes());
+            pkl.extend(s.as_bytes());
+        };
+        let binint = |pkl: &mut Vec<u8>, i: usize| {
+            pkl.push(b'J');
+            pkl.extend((i as i32).to_le_bytes());
+        };
+
+        let mut pkl = vec![0x80, 2, b'}', b'('];
+        for (key, (name, tensor)) in tensors.iter().enumerate() {
+            binunicode(&mut pkl, name);
+            pkl.extend(b"ctorch._utils\n_rebuild_tensor_v2\n(");
+            // persistent id of the storage
+            pkl.push(b'(');
+            binunicode(&mut pkl, "storage");
+            pkl.extend(b"ctorch\nFloatStorage\n");
+            binunicode(&mut pkl, &key.to_string());
+            binunicode(&mut pkl, "cpu");
+            binint(&mut pkl, tensor.elem_count());
+            pkl.extend(b"tQ");
+            binint(&mut pkl, 0);
+            for dims in [tensor.dims().to_vec(), tensor.stride().to_vec()] {
+                pkl.push(b'(');
+                for dim in dims {
+                    binint(&mut pkl, dim);
+                }
+                pkl.push(b't');
+            }
+            pkl.extend([0x89, b'}', b't', b'R']);
+        }
+        pkl.extend(b"u.");
+
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(path).unwrap());
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        zip.start_file("archive/data.pkl", options).unwrap();
+        zip.write_all(&pkl).unwrap();
+        for (key, (_, tensor)) in tensors.iter().enumerate() {
+            zip.start_file(format!("archive/data/{key}"), options)
+                .unwrap();
+            let values = tensor.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+            for value in values {
+                zip.write_all(&value.to_le_bytes()).unwrap();
+            }
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn tensor_names() {
+        assert_eq!(
+            pth_tensor_name("tok_embeddings.weight").as_deref(),
+            Some("model.embed_tokens.weight")
+        );
+        assert_eq!(
+            pth_tensor_name("layers.12.feed_forward.w3.weight").as_deref(),
+            Some("model.layers.12.mlp.up_proj.weight")
+        );
+        assert_eq!(pth_tensor_name("rope.freqs"), None);
+        assert_eq!(pth_tensor_name("layers.x.attention.wq.weight"), None);
+    }
+
+    #[test]
+    fn converts_a_state_dict() {
+        let (dim, kv, ffn, vocab) = (8, 4, 16, 10);
+        let rows = |rows: usize, cols: usize| {
+            // every value is the index of its row
+            Tensor::arange(0f32, rows as f32, &Device::Cpu)
+                .unwrap()
+                .reshape((rows, 1))
+                .unwrap()
+                .repeat((1, cols))
+                .unwrap()
+        };
+        let ones = |size: usize| Tensor::ones(size, DType::F32, &Device::Cpu).unwrap();
+        let tensors = [
+            ("tok_embeddings.weight", rows(vocab, dim)),
+            ("norm.weight", ones(dim)),
+            ("output.weight", rows(vocab, dim)),
+            ("rope.freqs", ones(2)),
+            ("layers.0.attention.wq.weight", rows(dim, dim)),
+            ("layers.0.attention.wk.weight", rows(kv, dim)),
+            ("layers.0.attention.wv.weight", rows(kv, dim)),
+            ("layers.0.attention.wo.weight", rows(dim, dim)),
+            ("layers.0.feed_forward.w1.weight", rows(ffn, dim)),
+            ("layers.0.feed_forward.w2.weight", rows(dim, ffn)),
+            ("layers.0.feed_forward.w3.weight", rows(ffn, dim)),
+            ("layers.0.attention_norm.weight", ones(dim)),
+            ("layers.0.ffn_norm.weight", ones(dim)),
+        ];
+
+        let dir = test_utils::temp_dir("pth");
+        let pth = dir.join("consolidated.00.pth");
+        write_pth(&pth, &tensors);
+        let params =
+            r#"{"dim": 8, "n_layers": 1, "n_heads": 2, "n_kv_heads": 1, "norm_eps": 1e-5}"#;
+        std::fs::write(dir.join("params.json"), params).unwrap();
+        assert_eq!(find_pth(&dir).unwrap(), Some(pth.clone()));
+
+        convert_pth(&pth, &dir).unwrap();
+        assert_eq!(find_pth(&dir).unwrap(), None);
+
+        let converted =
+            candle_core::safetensors::load(dir.join("model.safetensors"), &Device::Cpu).unwrap();
+        let mut names: Vec<&str> = converted.keys().map(String::as_str).collect();
+        names.sort();
+        let mut expected: Vec<String> = tensors
+            .iter()
+            .filter_map(|(name, _)| pth_tensor_name(name))
+            .collect();
+        expected.sort();
+        assert_eq!(names, expected);
+        for (name, tensor) in &tensors {
+            if let Some(mapped) = pth_tensor_name(name) {
+                assert_eq!(converted[&mapped].dims(), tensor.dims(), "{name}");
+            }
+        }
+
+        // the row (head, half, i) of the model is the row (head, i, half) of the checkpoint
+        let q_rows = converted["model.layers.0.self_attn.q_proj.weight"]
+            .get_on_dim(1, 0)
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(q_rows, [0., 2., 1., 3., 4., 6., 5., 7.]);
+        let k_rows = converted["model.layers.0.self_attn.k_proj.weight"]
+            .get_on_dim(1, 0)
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(k_rows, [0., 2., 1., 3.]);
+
+        let config: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(dir.join("config.json")).unwrap()).unwrap();
+        assert_eq!(config["hidden_size"], 8);
+        assert_eq!(config["intermediate_size"], 16);
+        assert_eq!(config["vocab_size"], 10);
+        assert_eq!(config["num_hidden_layers"], 1);
+        assert_eq!(config["num_key_value_heads"], 1);
+    }
+}
@@ -0,0 +1,247 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::Result;
+use candle_core::{
+    safetensors::{BufferedSafetensors, MmapedSafetensors},
+    DType, Device, Shape, Tensor,
+};
+use candle_nn::{var_builder::SimpleBackend, Init, VarBuilder};
+
+/// How the safetensors shards are loaded.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LoadMode {
+    /// Map the shards in memory, their pages being read as the tensors are loaded.
+    #[default]
+    Mmap,
+    /// Read the whole shards into memory up front, which avoids the page faults of the maps on
+    /// network filesystems.
+    Eager,
+}
+
+/// Progress of the safetensors shards being opened.
+#[derive(Debug, Clone)]
+pub struct ShardProgress {
+    pub path: PathBuf,
+    /// Number of shards opened so far, this one included.
+    pub shard: usize,
+    pub shards: usize,
+    /// Size of the shards opened so far, in bytes.
+    pub bytes: u64,
+    pub total_bytes: u64,
+}
+
+impl ShardProgress {
+    pub fn percent(&self) -> f64 {
+        if self.total_bytes == 0 {
+            100.
+        } else {
+            self.bytes as f64 * 100. / self.total_bytes as f64
+        }
+    }
+}
+
+enum Shard {
+    Mapped(MmapedSafetensors),
+    Loaded(BufferedSafetensors),
+}
+
+impl Shard {
+    fn open(path: &PathBuf, mode: LoadMode) -> Result<Self> {
+        Ok(match mode {
+            LoadMode::Mmap => Self::Mapped(
+                unsafe { MmapedSafetensors::new(path) }
+                    .map_err(|e| anyhow!("can't map {}: {:?}", path.display(), e))?,
+            ),
+            LoadMode::Eager => {
+                let buffer = std::fs::read(path)
+                    .map_err(|e| anyhow!("can't read {}: {:?}", path.display(), e))?;
+                Self::Loaded(
+                    BufferedSafetensors::new(buffer)
+                        .map_err(|e| anyhow!("can't parse {}: {:?}", path.display(), e))?,
+                )
+            }
+        })
+    }
+
+    fn tensor_names(&self) -> Vec<String> {
+        let tensors = match self {
+            Self::Mapped(shard) => shard.tensors(),
+            Self::Loaded(shard) => shard.tensors(),
+        };
+        tensors.into_iter().map(|(name, _)| name).collect()
+    }
+}
+
+/// Safetensors shards of a model, opened one at a time so progress can be reported.
+pub struct Shards {
+    shards: Vec<Shard>,
+    // index of the shard holding each tensor
+    routing: HashMap<String, usize>,
+}
+
+impl Shards {
+    pub fn open<F>(filenames: &[PathBuf], mode: LoadMode, mut progress: F) -> Result<Self>
+    where
+        F: FnMut(&ShardProgress),
+    {
+        let sizes = filenames
+            .iter()
+            .map(|path| {
+                std::fs::metadata(path)
+                    .map(|meta| meta.len())
+                    .map_err(|e| anyhow!("can't read {}: {:?}", path.display(), e))
+            })
+            .collect::<Result<Vec<u64>>>()?;
+        let total_bytes = sizes.iter().sum();
+
+        let mut shards = vec![];
+        let mut routing = HashMap::new();
+        let mut bytes = 0;
+
+        for (idx, (path, size)) in filenames.iter().zip(sizes).enumerate() {
+            let shard = Shard::open(path, mode)?;
+            for name in shard.tensor_names() {
+                routing.insert(name, idx);
+            }
+            shards.push(shard);
+            bytes += size;
+
+            progress(&ShardProgress {
+                path: path.clone(),
+                shard: idx + 1,
+                shards: filenames.len(),
+                bytes,
+                total_bytes,
+            });
+        }
+
+        Ok(Self { shards, routing })
+    }
+
+    pub fn var_builder(self, dtype: DType, device: &Device) -> VarBuilder<'static> {
+        VarBuilder::from_backend(Box::new(self), dtype, device.clone())
+    }
+}
+
+impl SimpleBackend for Shards {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        h: Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> candle_core::Result<Tensor> {
+        let idx = self
+            .routing
+            .get(name)
+            .ok_or_else(|| candle_core::Error::CannotFindTensor { path: name.into() })?;
+        match &self.shards[*idx] {
+            Shard::Mapped(shard) => SimpleBackend::get(shard, s, name, h, dtype, dev),
+            Shard::Loaded(shard) => SimpleBackend::get(shard, s, name, h, dtype, dev),
+        }
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.routing.contains_key(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    /// Writes shards of tensors of the given sizes, one tensor per shard.
+    fn write_shards(name: &str, sizes: &[usize]) -> Vec<PathBuf> {
+        let dir = test_utils::temp_dir(name);
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(idx, size)| {
+                let path = dir.join(format!("model-{idx}.safetensors"));
+                let tensor = Tensor::full(idx as f32, *size, &Device::Cpu).unwrap();
+                candle_core::safetensors::save(
+                    &HashMap::from([(format!("tensor.{idx}"), tensor)]),
+                    &path,
+                )
+                .unwrap();
+                path
+            })
+            .collect()
+    }
+
+    #[test]
+    fn progress_of_each_shard() {
+        let filenames = write_shards("shard-progress", &[1000, 10, 5000]);
+        let total: u64 = filenames
+            .iter()
+            .map(|path| std::fs::metadata(path).unwrap().len())
+            .sum();
+
+        for mode in [LoadMode::Mmap, LoadMode::Eager] {
+            let mut reports = vec![];
+            let shards = Shards::open(&filenames, mode, |p| reports.push(p.clone())).unwrap();
+
+            assert_eq!(reports.len(), 3);
+            for (idx, report) in reports.iter().enumerate() {
+                assert_eq!(report.path, filenames[idx]);
+                assert_eq!((report.shard, report.shards), (idx + 1, 3));
+                assert_eq!(report.total_bytes, total);
+            }
+            // by bytes rather than by file
+            assert!(reports.windows(2).all(|w| w[0].bytes < w[1].bytes));
+            assert!(reports[0].percent() > 15. && reports[1].percent() < 25.);
+            assert_eq!(reports[2].bytes, total);
+            assert_eq!(reports[2].percent(), 100.);
+
+            let vb = shards.var_builder(DType::F32, &Device::Cpu);
+            let last = vb.get(5000, "tensor.2").unwrap();
+            assert_eq!(last.sum_all().unwrap().to_scalar::<f32>().unwrap(), 10000.);
+            assert!(vb.get(10, "tensor.1").is_ok());
+            assert!(vb.get(10, "tensor.3").is_err());
+        }
+    }
+
+    #[test]
+    fn eager_tensors_like_mapped_ones() {
+        let filenames = [test_utils::model_dir().join("model.safetensors")];
+        let mapped = Shards::open(&filenames, LoadMode::Mmap, |_| ()).unwrap();
+        let loaded = Shards::open(&filenames, LoadMode::Eager, |_| ()).unwrap();
+        assert!(matches!(loaded.shards[0], Shard::Loaded(_)));
+
+        let mut names: Vec<&String> = mapped.routing.keys().collect();
+        names.sort();
+        assert_eq!(names.len(), loaded.routing.len());
+        assert!(names.len() > 30);
+
+        for dtype in [DType::F32, DType::F16] {
+            for name in &names {
+                let Shard::Mapped(shard) = &mapped.shards[0] else {
+                    unreachable!()
+                };
+                let shape = shard.load(name, &Device::Cpu).unwrap().shape().clone();
+                let get = |shards: &Shards| {
+                    SimpleBackend::get(
+                        shards,
+                        shape.clone(),
+                        name,
+                        Init::Const(0.),
+                        dtype,
+                        &Device::Cpu,
+                    )
+                    .unwrap()
+                    .to_dtype(DType::F32)
+                    .unwrap()
+                    .flatten_all()
+                    .unwrap()
+                    .to_vec1::<f32>()
+                    .unwrap()
+                };
+                let expected = get(&mapped);
+                assert!(expected.iter().any(|v| *v != 0.), "{name}");
+                assert_eq!(get(&loaded), expected, "{name}");
+            }
+        }
+    }
+}
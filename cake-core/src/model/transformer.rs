@@ -1,11 +1,10 @@
 use anyhow::Result;
 use candle_core::Tensor;
 use candle_nn::{Module, VarBuilder};
-use candle_transformers::models::with_tracing::RmsNorm;
 
 use async_trait::async_trait;
 
-use super::{Cache, Forwarder};
+use super::{Cache, Forwarder, RmsNorm};
 
 #[derive(Debug, Clone)]
 pub struct Block {
@@ -21,12 +20,8 @@ impl Block {
         let name = name.to_string();
         let attn = super::CausalSelfAttention::load(vb.pp("self_attn"), cfg)?;
         let mlp = super::MLP::load(vb.pp("mlp"), cfg)?;
-        let rms_1 = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
-        let rms_2 = RmsNorm::new(
-            cfg.hidden_size,
-            cfg.rms_norm_eps,
-            vb.pp("post_attention_layernorm"),
-        )?;
+        let rms_1 = RmsNorm::load(vb.pp("input_layernorm"), cfg)?;
+        let rms_2 = RmsNorm::load(vb.pp("post_attention_layernorm"), cfg)?;
         Ok(Self {
             name,
             rms_1,
@@ -68,10 +63,77 @@ impl Forwarder for Block {
         block_idx: usize,
         cache: &mut Cache,
     ) -> Result<Tensor> {
-        self.forward_imm(x, index_pos, block_idx, cache).await
+        // the activations may have been cast to another dtype than the one of the weights
+        let dtype = cache.cos.dtype();
+        if x.dtype() == dtype {
+            return self.forward_imm(x, index_pos, block_idx, cache).await;
+        }
+        self.forward_imm(&x.to_dtype(dtype)?, index_pos, block_idx, cache)
+            .await?
+            .to_dtype(x.dtype())
+            .map_err(|e| anyhow!(e))
     }
 
     fn layer_name(&self) -> &str {
         &self.name
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device};
+
+    use super::*;
+    use crate::{cake::Context, test_utils};
+
+    #[tokio::test]
+    async fn batched_forward_matches_sequential_forwards() {
+        let ctx = Context::from_args(test_utils::args(&[])).unwrap();
+        let mut block = Block::load(
+            "model.layers.0",
+            ctx.var_builder.pp("model.layers.0"),
+            &ctx.config,
+        )
+        .unwrap();
+        let x = Tensor::randn(0f32, 1., (2, 3, 64), &Device::Cpu).unwrap();
+        let ops = [(0, 0), (0, 1), (0, 2)];
+
+        let mut sequential = ctx.cache.as_new();
+        let mut expected = x.clone();
+        for (index_pos, block_idx) in ops {
+            expected = block
+                .forward(&expected, index_pos, block_idx, &mut sequential)
+                .await
+                .unwrap();
+        }
+
+        let mut batched = ctx.cache.as_new();
+        let batch = ops
+            .iter()
+            .map(|&(index_pos, block_idx)| ("model.layers.0".to_string(), index_pos, block_idx))
+            .collect();
+        let y = block.forward_batch(&x, batch, &mut batched).await.unwrap();
+
+        let diff = |a: &Tensor, b: &Tensor| {
+            (a - b)
+                .unwrap()
+                .abs()
+                .unwrap()
+                .flatten_all()
+                .unwrap()
+                .max(0)
+                .unwrap()
+                .to_dtype(DType::F32)
+                .unwrap()
+                .to_scalar::<f32>()
+                .unwrap()
+        };
+        assert_eq!(diff(&y, &expected), 0.);
+        for (_, block_idx) in ops {
+            let (k, v) = batched.kv(block_idx).unwrap().unwrap();
+            let (ek, ev) = sequential.kv(block_idx).unwrap().unwrap();
+            assert_eq!(diff(&k, &ek), 0.);
+            assert_eq!(diff(&v, &ev), 0.);
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokenizers::Tokenizer;
+
+/// A single message of a chat conversation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn system(content: &str) -> Self {
+        Self {
+            role: "system".to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    pub fn user(content: &str) -> Self {
+        Self {
+            role: "user".to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    pub fn assistant(content: &str) -> Self {
+        Self {
+            role: "assistant".to_string(),
+            content: content.to_string(),
+        }
+    }
+}
+
+/// Special tokens used to assemble a Llama 3 chat prompt.
+#[derive(Debug, Clone)]
+pub struct ChatTokens {
+    pub bos: String,
+    pub start_header: String,
+    pub end_header: String,
+    pub eot: String,
+}
+
+impl Default for ChatTokens {
+    fn default() -> Self {
+        Self {
+            bos: "<|begin_of_text|>".to_string(),
+            start_header: "<|start_header_id|>".to_string(),
+            end_header: "<|end_header_id|>".to_string(),
+            eot: "<|eot_id|>".to_string(),
+        }
+    }
+}
+
+impl ChatTokens {
+    /// Resolves the chat tokens from the tokenizer_config.json file (if present) and makes sure
+    /// all of them are special tokens known to the tokenizer.
+    pub fn from_tokenizer(tokenizer: &Tokenizer, tokenizer_config: &Path) -> Result<Self> {
+        let mut tokens = Self::default();
+
+        if tokenizer_config.exists() {
+            log::info!("loading chat tokens from {}", tokenizer_config.display());
+
+            let data = std::fs::read(tokenizer_config)
+                .map_err(|e| anyhow!("can't read {}: {:?}", tokenizer_config.display(), e))?;
+            let config: serde_json::Value = serde_json::from_slice(&data)
+                .map_err(|e| anyhow!("can't parse {}: {:?}", tokenizer_config.display(), e))?;
+
+            if let Some(bos) = config.get("bos_token").and_then(|v| v.as_str()) {
+                tokens.bos = bos.to_string();
+            }
+        }
+
+        for token in [
+            &tokens.bos,
+            &tokens.start_header,
+            &tokens.end_header,
+            &tokens.eot,
+        ] {
+            if tokenizer.token_to_id(token).is_none() {
+                bail!("tokenizer has no chat token {token}");
+            }
+        }
+
+        Ok(tokens)
+    }
+
+    /// Wraps the user prompt, and the optional system prompt, with the role headers so that the
+    /// model starts generating the assistant turn.
+    pub fn build_chat_prompt(&self, system: Option<&str>, user: &str) -> String {
+        let mut messages = vec![];
+        if let Some(system) = system {
+            messages.push(ChatMessage::system(system));
+        }
+        messages.push(ChatMessage::user(user));
+        self.build_prompt(&messages)
+    }
+
+    /// Formats the next user turn of a conversation whose previous turns have already been
+    /// formatted, ending with the header of the assistant turn.
+    pub fn build_next_turn(&self, user: &str) -> String {
+        self.message("user", user) + &self.header("assistant")
+    }
+
+    /// Formats a whole conversation, ending with the header of the assistant turn.
+    pub fn build_prompt(&self, messages: &[ChatMessage]) -> String {
+        let mut prompt = self.bos.clone();
+        for message in messages {
+            prompt += &self.message(&message.role, &message.content);
+        }
+        prompt += &self.header("assistant");
+        prompt
+    }
+
+    fn header(&self, role: &str) -> String {
+        format!("{}{role}{}\n\n", &self.start_header, &self.end_header)
+    }
+
+    fn message(&self, role: &str, content: &str) -> String {
+        format!("{}{}{}", self.header(role), content.trim(), &self.eot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils;
+    use tokenizers::AddedToken;
+
+    /// Tokenizer of the test models with the Llama 3 chat tokens.
+    fn chat_tokenizer() -> Tokenizer {
+        let mut tokenizer = test_utils::tokenizer();
+        let chat = ChatTokens::default();
+        let special: Vec<AddedToken> = [chat.bos, chat.start_header, chat.end_header, chat.eot]
+            .into_iter()
+            .map(|token| AddedToken::from(token, true))
+            .collect();
+        tokenizer.add_special_tokens(&special);
+        tokenizer
+    }
+
+    #[test]
+    fn chat_prompt_with_system() {
+        assert_eq!(
+            ChatTokens::default().build_chat_prompt(Some("Be brief. "), " Hello!"),
+            "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\nBe brief.<|eot_id|>\
+             <|start_header_id|>user<|end_header_id|>\n\nHello!<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+    }
+
+    #[test]
+    fn chat_prompt_without_system() {
+        assert_eq!(
+            ChatTokens::default().build_chat_prompt(None, "Hello!"),
+            "<|begin_of_text|><|start_header_id|>user<|end_header_id|>\n\nHello!<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+        assert_eq!(
+            ChatTokens::default().build_next_turn("And?"),
+            "<|start_header_id|>user<|end_header_id|>\n\nAnd?<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+    }
+
+    #[test]
+    fn chat_tokens_from_the_tokenizer_config() {
+        let dir = test_utils::temp_dir("chat-tokens");
+        let config = dir.join("tokenizer_config.json");
+        let tokenizer = chat_tokenizer();
+
+        // no config, the Llama 3 tokens
+        let tokens = ChatTokens::from_tokenizer(&tokenizer, &config).unwrap();
+        assert_eq!(tokens.bos, "<|begin_of_text|>");
+
+        std::fs::write(&config, r#"{"bos_token": "<s>"}"#).unwrap();
+        let tokens = ChatTokens::from_tokenizer(&tokenizer, &config).unwrap();
+        assert_eq!(tokens.bos, "<s>");
+        let prompt = tokens.build_chat_prompt(None, "hello");
+        let ids = tokenizer.encode(prompt, false).unwrap().get_ids().to_vec();
+        let id = |token: &str| tokenizer.token_to_id(token).unwrap();
+        assert_eq!(
+            ids,
+            vec![
+                id("<s>"),
+                id("<|start_header_id|>"),
+                id("<unk>"),
+                id("<|end_header_id|>"),
+                id("hello"),
+                id("<|eot_id|>"),
+                id("<|start_header_id|>"),
+                id("<unk>"),
+                id("<|end_header_id|>"),
+            ]
+        );
+
+        // the chat tokens must be known to the tokenizer
+        let err = ChatTokens::from_tokenizer(&test_utils::tokenizer(), &config).unwrap_err();
+        assert!(err.to_string().contains("no chat token"), "{err}");
+    }
+}
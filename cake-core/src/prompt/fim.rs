@@ -0,0 +1,148 @@
+use std::path::Path;
+
+use anyhow::Result;
+use tokenizers::Tokenizer;
+
+/// Ids of the special tokens used to assemble a fill-in-the-middle prompt.
+#[derive(Debug, Clone)]
+pub struct FimTokens {
+    pub prefix: u32,
+    pub suffix: u32,
+    pub middle: u32,
+    /// Ends the generated middle.
+    pub end: u32,
+}
+
+impl FimTokens {
+    /// Resolves the fill-in-the-middle tokens from the tokenizer_config.json file (if present),
+    /// defaulting to the Code Llama ones, and makes sure all of them are known to the tokenizer.
+    pub fn from_tokenizer(tokenizer: &Tokenizer, tokenizer_config: &Path) -> Result<Self> {
+        let mut names = [
+            ("prefix_token", "▁<PRE>".to_string()),
+            ("suffix_token", "▁<SUF>".to_string()),
+            ("middle_token", "▁<MID>".to_string()),
+            ("eot_token", "▁<EOT>".to_string()),
+        ];
+
+        if tokenizer_config.exists() {
+            log::info!("loading fim tokens from {}", tokenizer_config.display());
+
+            let data = std::fs::read(tokenizer_config)
+                .map_err(|e| anyhow!("can't read {}: {:?}", tokenizer_config.display(), e))?;
+            let config: serde_json::Value = serde_json::from_slice(&data)
+                .map_err(|e| anyhow!("can't parse {}: {:?}", tokenizer_config.display(), e))?;
+
+            for (key, token) in &mut names {
+                if let Some(name) = config.get(*key).and_then(|v| v.as_str()) {
+                    *token = name.to_string();
+                }
+            }
+        }
+
+        let mut ids = [0; 4];
+        for (id, (_, token)) in ids.iter_mut().zip(&names) {
+            *id = tokenizer
+                .token_to_id(token)
+                .ok_or_else(|| anyhow!("tokenizer has no fim token {token}"))?;
+        }
+        let [prefix, suffix, middle, end] = ids;
+
+        Ok(Self {
+            prefix,
+            suffix,
+            middle,
+            end,
+        })
+    }
+
+    /// Lays out the prefix and the suffix of the text to fill as <PRE> prefix <SUF> suffix <MID>,
+    /// after the bos token if any, so that the model generates the middle.
+    pub fn build_fim_prompt(
+        &self,
+        tokenizer: &Tokenizer,
+        bos: Option<u32>,
+        prefix: &str,
+        suffix: &str,
+    ) -> Result<Vec<u32>> {
+        let encode = |text: &str| -> Result<Vec<u32>> {
+            Ok(tokenizer
+                .encode(text, false)
+                .map_err(anyhow::Error::msg)?
+                .get_ids()
+                .to_vec())
+        };
+
+        let mut tokens: Vec<u32> = bos.into_iter().collect();
+        tokens.push(self.prefix);
+        tokens.extend(encode(prefix)?);
+        tokens.push(self.suffix);
+        tokens.extend(encode(suffix)?);
+        tokens.push(self.middle);
+        Ok(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils;
+    use tokenizers::AddedToken;
+
+    /// Tokenizer of the test models with the Code Llama fim tokens, and <PRE> too.
+    fn fim_tokenizer() -> Tokenizer {
+        let mut tokenizer = test_utils::tokenizer();
+        let special: Vec<AddedToken> = ["▁<PRE>", "▁<SUF>", "▁<MID>", "▁<EOT>", "<PRE>"]
+            .into_iter()
+            .map(|token| AddedToken::from(token, true))
+            .collect();
+        tokenizer.add_special_tokens(&special);
+        tokenizer
+    }
+
+    #[test]
+    fn fim_prompt_layout() {
+        let dir = test_utils::temp_dir("fim-tokens");
+        let config = dir.join("tokenizer_config.json");
+        let tokenizer = fim_tokenizer();
+        let id = |token: &str| tokenizer.token_to_id(token).unwrap();
+
+        // no config, the Code Llama tokens
+        let tokens = FimTokens::from_tokenizer(&tokenizer, &config).unwrap();
+        assert_eq!(
+            (tokens.prefix, tokens.suffix, tokens.middle, tokens.end),
+            (id("▁<PRE>"), id("▁<SUF>"), id("▁<MID>"), id("▁<EOT>"))
+        );
+        let prompt = tokens
+            .build_fim_prompt(&tokenizer, Some(1), "the cat", "on the mat")
+            .unwrap();
+        assert_eq!(
+            prompt,
+            [
+                1,
+                id("▁<PRE>"),
+                id("the"),
+                id("cat"),
+                id("▁<SUF>"),
+                id("on"),
+                id("the"),
+                id("mat"),
+                id("▁<MID>"),
+            ]
+        );
+        // no bos
+        let prompt = tokens.build_fim_prompt(&tokenizer, None, "", "").unwrap();
+        assert_eq!(prompt, [id("▁<PRE>"), id("▁<SUF>"), id("▁<MID>")]);
+
+        std::fs::write(&config, r#"{"prefix_token": "<PRE>"}"#).unwrap();
+        let tokens = FimTokens::from_tokenizer(&tokenizer, &config).unwrap();
+        assert_eq!((tokens.prefix, tokens.end), (id("<PRE>"), id("▁<EOT>")));
+
+        // the fim tokens must be known to the tokenizer
+        std::fs::write(&config, r#"{"eot_token": "<EOT>"}"#).unwrap();
+        let e = FimTokens::from_tokenizer(&tokenizer, &config).unwrap_err();
+        assert!(e.to_string().contains("no fim token <EOT>"), "{e}");
+        let e = FimTokens::from_tokenizer(&test_utils::tokenizer(), &dir.join("none")).unwrap_err();
+        assert!(e.to_string().contains("no fim token ▁<PRE>"), "{e}");
+    }
+}
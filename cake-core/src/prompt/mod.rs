@@ -0,0 +1,5 @@
+mod chat;
+mod fim;
+
+pub use chat::*;
+pub use fim::*;
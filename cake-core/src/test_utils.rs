@@ -0,0 +1,442 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use clap::Parser;
+use rand::{distributions::Uniform, Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::{
+    cake::{
+        CakeError, ConnectionOptions, Context, Master, Message, Worker, WorkerInfo,
+        MESSAGE_MAX_SIZE,
+    },
+    Args,
+};
+
+/// Words of the vocabulary of the test models, one token each.
+pub const WORDS: [&str; 32] = [
+    "<unk>", "<s>", "</s>", "the", "a", "cat", "dog", "sat", "on", "mat", "and", "ran", "to",
+    "home", "big", "small", "red", "blue", "is", "was", "hello", "world", "one", "two", "three",
+    "four", "five", "six", "seven", "eight", "nine", "ten",
+];
+
+/// Configuration of the tiny model of model_dir.
+pub fn config() -> serde_json::Value {
+    serde_json::json!({
+        "hidden_size": 64,
+        "intermediate_size": 128,
+        "vocab_size": WORDS.len(),
+        "num_hidden_layers": 4,
+        "num_attention_heads": 4,
+        "num_key_value_heads": 2,
+        "rms_norm_eps": 1e-5,
+        "rope_theta": 10000.0,
+        "max_position_embeddings": 256,
+        "bos_token_id": 1,
+        "eos_token_id": 2,
+    })
+}
+
+/// Directory of a tiny model with random weights, the configuration of config and a word level
+/// tokenizer of WORDS, written once for all the tests.
+pub fn model_dir() -> &'static Path {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| model_dir_with("tiny", serde_json::json!({})))
+}
+
+/// Writes a tiny model like the one of model_dir to a directory of its own, with the entries of
+/// overrides replacing the ones of its configuration. The weights only depend on the shapes.
+pub fn model_dir_with(name: &str, overrides: serde_json::Value) -> PathBuf {
+    let dir = temp_dir(name);
+    let mut config = config();
+    for (key, value) in overrides.as_object().unwrap() {
+        config[key] = value.clone();
+    }
+    write_model(&dir, &config).unwrap();
+    dir
+}
+
+/// Directory of a tiny model like the one of model_dir whose tokenizer also has the special
+/// tokens of the Llama 3 chat template, written once for all the tests.
+pub fn chat_model_dir() -> &'static Path {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let chat = crate::prompt::ChatTokens::default();
+        let chat = [chat.bos, chat.start_header, chat.end_header, chat.eot];
+        let dir = model_dir_with(
+            "chat",
+            serde_json::json!({ "vocab_size": WORDS.len() + chat.len() }),
+        );
+        let mut tokenizer = tokenizer_json();
+        let added = tokenizer["added_tokens"].as_array_mut().unwrap();
+        for (i, token) in chat.iter().enumerate() {
+            added.push(serde_json::json!({
+                "id": WORDS.len() + i, "content": token, "single_word": false, "lstrip": false,
+                "rstrip": false, "normalized": false, "special": true,
+            }));
+        }
+        std::fs::write(dir.join("tokenizer.json"), tokenizer.to_string()).unwrap();
+        dir
+    })
+}
+
+/// Empty directory for a test, under a directory of the test process.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir()
+        .join(format!("cake-tests-{}", std::process::id()))
+        .join(name);
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn write_model(dir: &Path, config: &serde_json::Value) -> Result<()> {
+    let dim = |key: &str| config[key].as_u64().unwrap() as usize;
+    let (hidden, intermediate, vocab) = (
+        dim("hidden_size"),
+        dim("intermediate_size"),
+        dim("vocab_size"),
+    );
+    let kv = dim("num_key_value_heads") * hidden / dim("num_attention_heads");
+
+    // the same weights for every model of the same shapes
+    let mut rng = ChaCha20Rng::seed_from_u64(0);
+    let mut random = |shape: (usize, usize)| -> Result<Tensor> {
+        let values: Vec<f32> = (&mut rng)
+            .sample_iter(Uniform::new(-0.35, 0.35))
+            .take(shape.0 * shape.1)
+            .collect();
+        Ok(Tensor::from_vec(values, shape, &Device::Cpu)?)
+    };
+    let ones = |size: usize| Tensor::ones(size, DType::F32, &Device::Cpu);
+
+    let mut tensors = HashMap::new();
+    tensors.insert(
+        "model.embed_tokens.weight".to_string(),
+        random((vocab, hidden))?,
+    );
+    tensors.insert("lm_head.weight".to_string(), random((vocab, hidden))?);
+    tensors.insert("model.norm.weight".to_string(), ones(hidden)?);
+    for layer_idx in 0..dim("num_hidden_layers") {
+        let mut insert = |name: &str, tensor: Tensor| {
+            tensors.insert(format!("model.layers.{layer_idx}.{name}"), tensor);
+        };
+        insert("self_attn.q_proj.weight", random((hidden, hidden))?);
+        insert("self_attn.k_proj.weight", random((kv, hidden))?);
+        insert("self_attn.v_proj.weight", random((kv, hidden))?);
+        insert("self_attn.o_proj.weight", random((hidden, hidden))?);
+        insert("mlp.gate_proj.weight", random((intermediate, hidden))?);
+        insert("mlp.up_proj.weight", random((intermediate, hidden))?);
+        insert("mlp.down_proj.weight", random((hidden, intermediate))?);
+        insert("input_layernorm.weight", ones(hidden)?);
+        insert("post_attention_layernorm.weight", ones(hidden)?);
+    }
+    candle_core::safetensors::save(&tensors, dir.join("model.safetensors"))?;
+
+    let weight_map: HashMap<_, _> = tensors
+        .keys()
+        .map(|name| (name.clone(), "model.safetensors"))
+        .collect();
+    std::fs::write(
+        dir.join("model.safetensors.index.json"),
+        serde_json::json!({ "metadata": {}, "weight_map": weight_map }).to_string(),
+    )?;
+    std::fs::write(dir.join("config.json"), config.to_string())?;
+    std::fs::write(dir.join("tokenizer.json"), tokenizer_json().to_string())?;
+    std::fs::write(dir.join("topology.yml"), "{}\n")?;
+
+    Ok(())
+}
+
+/// Word level tokenizer of WORDS, adding <s> first.
+pub fn tokenizer_json() -> serde_json::Value {
+    let special = |id: usize| {
+        serde_json::json!({
+            "id": id, "content": WORDS[id], "single_word": false, "lstrip": false,
+            "rstrip": false, "normalized": false, "special": true,
+        })
+    };
+    let vocab: HashMap<_, _> = WORDS.iter().enumerate().map(|(id, w)| (*w, id)).collect();
+    serde_json::json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [special(0), special(1), special(2)],
+        "normalizer": null,
+        "pre_tokenizer": { "type": "WhitespaceSplit" },
+        "post_processor": {
+            "type": "TemplateProcessing",
+            "single": [
+                { "SpecialToken": { "id": "<s>", "type_id": 0 } },
+                { "Sequence": { "id": "A", "type_id": 0 } },
+            ],
+            "pair": [{ "Sequence": { "id": "A", "type_id": 0 } }],
+            "special_tokens": { "<s>": { "id": "<s>", "ids": [1], "tokens": ["<s>"] } },
+        },
+        "decoder": { "type": "WordPiece", "prefix": "##", "cleanup": false },
+        "model": { "type": "WordLevel", "vocab": vocab, "unk_token": "<unk>" },
+    })
+}
+
+/// Byte level BPE tokenizer without merges, a token per byte.
+pub fn byte_level_tokenizer_json() -> serde_json::Value {
+    let mut alphabet: Vec<char> = tokenizers::pre_tokenizers::byte_level::ByteLevel::alphabet()
+        .into_iter()
+        .collect();
+    alphabet.sort();
+    let vocab: HashMap<String, usize> = alphabet
+        .iter()
+        .enumerate()
+        .map(|(id, c)| (c.to_string(), id))
+        .collect();
+    let byte_level = serde_json::json!({
+        "type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true,
+    });
+    serde_json::json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [],
+        "normalizer": null,
+        "pre_tokenizer": byte_level,
+        "post_processor": null,
+        "decoder": byte_level,
+        "model": {
+            "type": "BPE", "dropout": null, "unk_token": null, "continuing_subword_prefix": null,
+            "end_of_word_suffix": null, "fuse_unk": false, "byte_fallback": false,
+            "vocab": vocab, "merges": [],
+        },
+    })
+}
+
+/// The tokenizer of the test models.
+pub fn tokenizer() -> tokenizers::Tokenizer {
+    tokenizers::Tokenizer::from_bytes(tokenizer_json().to_string()).unwrap()
+}
+
+/// Arguments running the model of model_dir on the CPU in f32 with every layer local, followed by
+/// the given command line arguments.
+pub fn args(extra: &[&str]) -> Args {
+    args_for(model_dir(), extra)
+}
+
+/// Same as args, for the model in dir.
+pub fn args_for(dir: &Path, extra: &[&str]) -> Args {
+    let model = dir.display().to_string();
+    let topology = dir.join("topology.yml").display().to_string();
+    let common = [
+        "cake",
+        "--model",
+        &model,
+        "--topology",
+        &topology,
+        "--cpu",
+        "--dtype",
+        "f32",
+    ];
+    Args::parse_from(common.iter().chain(extra))
+}
+
+/// Configuration of the model of model_dir, as the library loads it.
+pub fn model_config() -> crate::model::Config {
+    Context::from_args(args(&[])).unwrap().config
+}
+
+/// Writes a topology file to the model directory and returns its path.
+pub fn topology(name: &str, yaml: &str) -> String {
+    let path = model_dir().join(name);
+    std::fs::write(&path, yaml).unwrap();
+    path.display().to_string()
+}
+
+/// Master of args.
+pub async fn master(args: Args) -> Master {
+    Master::new(Context::from_args(args).unwrap())
+        .await
+        .unwrap()
+}
+
+/// Address on the loopback interface with a port nothing listens on.
+pub fn free_address() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Waits for something to listen on address.
+pub async fn wait_listening(address: &str) {
+    for _ in 0..500 {
+        if tokio::net::TcpStream::connect(address).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    panic!("nothing listens on {address}");
+}
+
+/// Worker serving its clients in the background until dropped.
+pub struct TestWorker {
+    task: JoinHandle<Result<(), CakeError>>,
+}
+
+impl TestWorker {
+    /// Starts the worker of args, listening on its --address.
+    pub async fn start(args: Args) -> Result<Self> {
+        let address = args.address.clone();
+        let mut worker = Worker::new(Context::from_args(args)?).await?;
+        let task = tokio::spawn(async move { worker.run_until(std::future::pending()).await });
+        wait_listening(&address).await;
+        Ok(Self { task })
+    }
+}
+
+impl Drop for TestWorker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Starts a worker named name serving the layers of its node in the topology file.
+pub async fn worker(name: &str, topology: &str, extra: &[&str]) -> TestWorker {
+    let node_address = crate::cake::Topology::from_path(topology)
+        .unwrap()
+        .get(name)
+        .unwrap()
+        .host
+        .clone();
+    let mut worker_args = vec![
+        "--mode",
+        "worker",
+        "--name",
+        name,
+        "--address",
+        &node_address,
+    ];
+    worker_args.extend_from_slice(extra);
+    let mut args = args(&worker_args);
+    args.topology = topology.to_string();
+    TestWorker::start(args).await.unwrap()
+}
+
+/// What a MockWorker does with a message of the master.
+pub enum Reply {
+    /// Sends this response.
+    Message(Message),
+    /// Expects no response, such as for a ResetCache.
+    Nothing,
+    /// Sends heartbeats at the interval for the duration, then the response.
+    Busy(Duration, Duration, Message),
+    /// Never answers, keeping the connection open.
+    Silent,
+    /// Closes the connection.
+    Drop,
+}
+
+/// Worker speaking the protocol with the replies of a closure, given the index of the connection
+/// and the message. The connections are served concurrently, like by a worker.
+pub struct MockWorker {
+    pub address: String,
+    task: JoinHandle<()>,
+}
+
+impl MockWorker {
+    pub async fn start<F>(respond: F) -> Self
+    where
+        F: FnMut(usize, Message) -> Reply + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let respond = Arc::new(Mutex::new(respond));
+        let task = tokio::spawn(async move {
+            // the connections are aborted along with the worker
+            let mut connections = JoinSet::new();
+            for connection in 0.. {
+                let (stream, _) = listener.accept().await.unwrap();
+                connections.spawn(Self::serve(stream, connection, respond.clone()));
+            }
+        });
+        Self { address, task }
+    }
+
+    async fn serve<F>(mut stream: TcpStream, connection: usize, respond: Arc<Mutex<F>>)
+    where
+        F: FnMut(usize, Message) -> Reply + Send + 'static,
+    {
+        if !matches!(
+            Message::from_reader(&mut stream, MESSAGE_MAX_SIZE).await,
+            Ok(Message::Hello { .. })
+        ) {
+            return;
+        }
+        let info = Message::WorkerInfo(WorkerInfo {
+            name: "mock".to_string(),
+            device: "cpu".to_string(),
+            dtype: "f32".to_string(),
+            ..Default::default()
+        });
+        if info.to_writer(&mut stream).await.is_err() {
+            return;
+        }
+
+        while let Ok(msg) = Message::from_reader(&mut stream, MESSAGE_MAX_SIZE).await {
+            let reply = respond.lock().unwrap()(connection, msg);
+            let reply = match reply {
+                Reply::Message(reply) => reply,
+                Reply::Nothing => continue,
+                Reply::Busy(interval, duration, reply) => {
+                    let start = Instant::now();
+                    while start.elapsed() < duration {
+                        tokio::time::sleep(interval).await;
+                        if Message::Heartbeat.to_writer(&mut stream).await.is_err() {
+                            break;
+                        }
+                    }
+                    reply
+                }
+                Reply::Silent => std::future::pending().await,
+                Reply::Drop => break,
+            };
+            if reply.to_writer(&mut stream).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+impl Drop for MockWorker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Frame of the message with a byte of the data of one of its tensors flipped.
+pub async fn corrupted_frame(message: &Message, data: &[u8]) -> Vec<u8> {
+    let mut frame = vec![];
+    message.to_writer(&mut frame).await.unwrap();
+    let offset = frame
+        .windows(data.len())
+        .position(|window| window == data)
+        .unwrap();
+    frame[offset + data.len() / 2] ^= 0x10;
+    frame
+}
+
+/// Options of the connections to the workers for the tests, failing fast.
+pub fn connection_options() -> ConnectionOptions {
+    ConnectionOptions {
+        backoff_base: Duration::from_millis(10),
+        backoff_max: Duration::from_millis(50),
+        ..ConnectionOptions::from_args(&args(&[])).unwrap()
+    }
+}
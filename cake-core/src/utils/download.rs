@@ -0,0 +1,408 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+
+/// Attempts to download a file, each one resuming where the previous one stopped.
+const ATTEMPTS: usize = 5;
+
+/// Delay before resuming an interrupted download.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Model files that are used if present.
+const OPTIONAL_FILES: [&str; 2] = ["generation_config.json", "tokenizer_config.json"];
+
+/// Downloads the files of a model from url, a directory of the Hugging Face hub such as
+/// https://huggingface.co/meta-llama/Meta-Llama-3-8B/resolve/main, to data_path. Only the
+/// safetensors shards holding tensors for which needed returns true are downloaded, along with the
+/// configuration and the tokenizer if requested.
+///
+/// Files are downloaded next to their destination with a .part extension and renamed once complete,
+/// an interrupted download is resumed with a range request. Shards are verified against the sha256
+/// hashes of the index, if it has any, and downloaded again if they don't match. The token of the
+/// HF_TOKEN environment variable is sent if set.
+pub fn download_model<F>(url: &str, data_path: &Path, tokenizer: bool, needed: F) -> Result<()>
+where
+    F: Fn(&str) -> bool,
+{
+    std::fs::create_dir_all(data_path)
+        .map_err(|e| anyhow!("can't create {}: {:?}", data_path.display(), e))?;
+
+    let downloader = Downloader::new(url);
+
+    downloader.fetch("config.json", data_path, None)?;
+    if tokenizer {
+        downloader.fetch("tokenizer.json", data_path, None)?;
+    }
+    for name in OPTIONAL_FILES {
+        downloader.fetch_optional(name, data_path)?;
+    }
+
+    let index_name = "model.safetensors.index.json";
+    if !downloader.fetch_optional(index_name, data_path)? {
+        // a single shard
+        return downloader.fetch("model.safetensors", data_path, None);
+    }
+
+    let index_path = data_path.join(index_name);
+    let index: serde_json::Value = serde_json::from_slice(
+        &std::fs::read(&index_path)
+            .map_err(|e| anyhow!("can't read {}: {:?}", index_path.display(), e))?,
+    )
+    .map_err(|e| anyhow!("can't parse {}: {:?}", index_path.display(), e))?;
+
+    let weight_map = match index.get("weight_map") {
+        Some(serde_json::Value::Object(map)) => map,
+        _ => bail!("no weight map in {}", index_path.display()),
+    };
+    let mut shards: Vec<&str> = weight_map
+        .iter()
+        .filter(|(tensor, _)| needed(tensor))
+        .filter_map(|(_, shard)| shard.as_str())
+        .collect();
+    shards.sort();
+    shards.dedup();
+
+    log::info!(
+        "downloading {} of {} shards ...",
+        shards.len(),
+        weight_map
+            .values()
+            .filter_map(|shard| shard.as_str())
+            .collect::<std::collections::HashSet<_>>()
+            .len()
+    );
+
+    for shard in shards {
+        let sha256 = index
+            .get("sha256")
+            .and_then(|hashes| hashes.get(shard))
+            .and_then(|hash| hash.as_str());
+        downloader.fetch(shard, data_path, sha256)?;
+    }
+
+    Ok(())
+}
+
+struct Downloader {
+    agent: ureq::Agent,
+    url: String,
+    token: Option<String>,
+}
+
+impl Downloader {
+    fn new(url: &str) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(30))
+                .timeout_read(Duration::from_secs(60))
+                .build(),
+            url: url.trim_end_matches('/').to_string(),
+            token: std::env::var("HF_TOKEN").ok(),
+        }
+    }
+
+    fn request(&self, method: &str, name: &str) -> ureq::Request {
+        let req = self.agent.request(method, &format!("{}/{name}", &self.url));
+        match &self.token {
+            Some(token) => req.set("Authorization", &format!("Bearer {token}")),
+            None => req,
+        }
+    }
+
+    /// Downloads a file that the model may not have, returns whether it exists.
+    fn fetch_optional(&self, name: &str, data_path: &Path) -> Result<bool> {
+        if data_path.join(name).exists() {
+            return Ok(true);
+        }
+        match self.request("HEAD", name).call() {
+            Err(ureq::Error::Status(404, _)) => Ok(false),
+            _ => self.fetch(name, data_path, None).map(|_| true),
+        }
+    }
+
+    /// Downloads a file unless it's already complete, verifying it against sha256 if set.
+    fn fetch(&self, name: &str, data_path: &Path, sha256: Option<&str>) -> Result<()> {
+        let path = data_path.join(name);
+        if path.exists() {
+            match sha256 {
+                Some(expected) if !Self::verify(&path, expected)? => {
+                    log::warn!("{} doesn't match its hash, downloading it again", &name);
+                    std::fs::remove_file(&path)
+                        .map_err(|e| anyhow!("can't remove {}: {:?}", path.display(), e))?;
+                }
+                _ => return Ok(()),
+            }
+        }
+
+        let part = data_path.join(format!("{name}.part"));
+        let mut reason = String::new();
+        for attempt in 1..=ATTEMPTS {
+            if attempt > 1 {
+                log::warn!(
+                    "downloading {name} failed: {reason}, resuming in {:?} ({attempt}/{ATTEMPTS}) ...",
+                    RETRY_DELAY
+                );
+                std::thread::sleep(RETRY_DELAY);
+            }
+
+            if let Err(e) = self.resume(name, &part) {
+                reason = e.to_string();
+                continue;
+            }
+
+            if let Some(expected) = sha256 {
+                if !Self::verify(&part, expected)? {
+                    // the partial content can't be trusted either
+                    reason = "sha256 mismatch".to_string();
+                    std::fs::remove_file(&part)
+                        .map_err(|e| anyhow!("can't remove {}: {:?}", part.display(), e))?;
+                    continue;
+                }
+            }
+
+            std::fs::rename(&part, &path)
+                .map_err(|e| anyhow!("can't rename {}: {:?}", part.display(), e))?;
+            return Ok(());
+        }
+
+        bail!(
+            "can't download {}/{name} after {ATTEMPTS} attempts: {reason}",
+            &self.url
+        )
+    }
+
+    /// Downloads the rest of a file to part.
+    fn resume(&self, name: &str, part: &Path) -> Result<()> {
+        let offset = std::fs::metadata(part).map(|meta| meta.len()).unwrap_or(0);
+
+        let mut req = self.request("GET", name);
+        if offset > 0 {
+            req = req.set("Range", &format!("bytes={offset}-"));
+        }
+        let resp = match req.call() {
+            Ok(resp) => resp,
+            // nothing left after offset
+            Err(ureq::Error::Status(416, _)) if offset > 0 => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut file = if resp.status() == 206 {
+            log::info!(
+                "resuming {name} from {}",
+                human_bytes::human_bytes(offset as f64)
+            );
+            OpenOptions::new().append(true).open(part)?
+        } else {
+            // the server ignored the range
+            log::info!("downloading {name} ...");
+            File::create(part)?
+        };
+        let expected = resp
+            .header("Content-Length")
+            .and_then(|len| len.parse::<u64>().ok());
+
+        let mut reader = resp.into_reader();
+        let mut buffer = vec![0u8; 1 << 20];
+        let mut received = 0;
+        loop {
+            match reader.read(&mut buffer)? {
+                0 => break,
+                n => {
+                    file.write_all(&buffer[..n])?;
+                    received += n as u64;
+                }
+            }
+        }
+        file.flush()?;
+
+        match expected {
+            Some(expected) if received < expected => {
+                bail!("connection closed after {received} of {expected} bytes")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn verify(path: &Path, expected: &str) -> Result<bool> {
+        let mut file =
+            File::open(path).map_err(|e| anyhow!("can't open {}: {:?}", path.display(), e))?;
+        let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+        let mut buffer = vec![0u8; 1 << 20];
+        loop {
+            match file.read(&mut buffer)? {
+                0 => break,
+                n => context.update(&buffer[..n]),
+            }
+        }
+        let actual: String = context
+            .finish()
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+
+        Ok(actual.eq_ignore_ascii_case(expected))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::test_utils;
+
+    fn sha256(data: &[u8]) -> String {
+        ring::digest::digest(&ring::digest::SHA256, data)
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// Serves the files over HTTP, honoring the range requests, and closes the connection
+    /// halfway through the first download of interrupted. Returns its url and the requests it
+    /// got, as "<method> <file> <offset>".
+    fn serve(
+        files: HashMap<String, Vec<u8>>,
+        interrupted: &str,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let log = requests.clone();
+        let mut interrupted = Some(interrupted.to_string());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut parts = line.split_whitespace();
+                let method = parts.next().unwrap().to_string();
+                let name = parts.next().unwrap().trim_start_matches('/').to_string();
+                let mut offset = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(range) = header.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        offset = range.trim().trim_end_matches('-').parse().unwrap();
+                    }
+                }
+                log.lock()
+                    .unwrap()
+                    .push(format!("{method} {name} {offset}"));
+
+                let Some(data) = files.get(&name) else {
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    );
+                    continue;
+                };
+                let body = &data[offset..];
+                let status = if offset > 0 {
+                    "206 Partial Content"
+                } else {
+                    "200 OK"
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                if method == "GET" {
+                    let body = match interrupted.take_if(|interrupted| *interrupted == name) {
+                        Some(_) => &body[..body.len() / 2],
+                        None => body,
+                    };
+                    let _ = stream.write_all(body);
+                }
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn interrupted_download_is_resumed() {
+        // the layers 2 and 3 in a shard of their own
+        let tensors = candle_core::safetensors::load(
+            test_utils::model_dir().join("model.safetensors"),
+            &candle_core::Device::Cpu,
+        )
+        .unwrap();
+        let shard_of = |tensor: &str| {
+            if tensor.starts_with("model.layers.2.") || tensor.starts_with("model.layers.3.") {
+                "model-00002-of-00002.safetensors"
+            } else {
+                "model-00001-of-00002.safetensors"
+            }
+        };
+        let server_dir = test_utils::temp_dir("download-server");
+        let mut files = HashMap::new();
+        for shard in [
+            "model-00001-of-00002.safetensors",
+            "model-00002-of-00002.safetensors",
+        ] {
+            let path = server_dir.join(shard);
+            let shard_tensors: HashMap<_, _> = tensors
+                .iter()
+                .filter(|(name, _)| shard_of(name) == shard)
+                .map(|(name, tensor)| (name.clone(), tensor.clone()))
+                .collect();
+            candle_core::safetensors::save(&shard_tensors, &path).unwrap();
+            files.insert(shard.to_string(), std::fs::read(&path).unwrap());
+        }
+        let weight_map: HashMap<_, _> = tensors
+            .keys()
+            .map(|name| (name.clone(), shard_of(name)))
+            .collect();
+        let sha256: HashMap<_, _> = files
+            .iter()
+            .map(|(shard, data)| (shard.clone(), sha256(data)))
+            .collect();
+        let index = serde_json::json!({ "weight_map": weight_map, "sha256": sha256 });
+        files.insert(
+            "model.safetensors.index.json".to_string(),
+            index.to_string().into_bytes(),
+        );
+        files.insert(
+            "config.json".to_string(),
+            test_utils::config().to_string().into_bytes(),
+        );
+        let shard = "model-00002-of-00002.safetensors";
+        let (url, requests) = serve(files.clone(), shard);
+
+        // a complete shard that doesn't match its hash is downloaded again
+        let dir = test_utils::temp_dir("download");
+        std::fs::write(dir.join(shard), b"corrupted").unwrap();
+        download_model(&url, &dir, false, |tensor| shard_of(tensor) == shard).unwrap();
+
+        assert_eq!(std::fs::read(dir.join(shard)).unwrap(), files[shard]);
+        assert!(!dir.join(format!("{shard}.part")).exists());
+        assert!(!dir.join("model-00001-of-00002.safetensors").exists());
+
+        let requests = requests.lock().unwrap();
+        let downloads: Vec<&String> = requests
+            .iter()
+            .filter(|request| request.starts_with(&format!("GET {shard}")))
+            .collect();
+        assert_eq!(downloads.len(), 2, "{requests:?}");
+        assert_eq!(*downloads[0], format!("GET {shard} 0"));
+        let offset: usize = downloads[1].rsplit(' ').next().unwrap().parse().unwrap();
+        assert!(offset > 0 && offset < files[shard].len(), "{offset}");
+    }
+}
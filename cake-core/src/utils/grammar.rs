@@ -0,0 +1,724 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use anyhow::{bail, Result};
+use tokenizers::Tokenizer;
+
+/// Symbol of a grammar rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Symbol {
+    /// A character of the class with this index.
+    Chars(usize),
+    /// The rule with this index.
+    Rule(usize),
+}
+
+/// Character ranges, matching the characters out of them if negated.
+#[derive(Debug, Clone)]
+struct CharClass {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+impl CharClass {
+    fn single(c: char) -> Self {
+        Self {
+            ranges: vec![(c, c)],
+            negated: false,
+        }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != self.negated
+    }
+}
+
+/// A grammar in a subset of the GBNF format of llama.cpp, enough to describe JSON documents:
+///
+/// ```text
+/// # comments run to the end of the line
+/// root  ::= "{" ws pair ("," ws pair)* "}"
+/// pair  ::= "\"" [a-z]+ "\"" ws ":" ws value
+/// value ::= [0-9]+ | "true" | "false" | ( "\"" [^"]* "\"" )
+/// ws    ::= [ \t\n]*
+/// ```
+///
+/// Rules are sequences of literals, character classes (negated with ^), . for any character,
+/// references to other rules and parenthesized groups, optionally followed by *, + or ?, with
+/// alternatives separated by |. Generation starts from the root rule.
+#[derive(Debug)]
+pub struct Grammar {
+    /// Alternatives of every rule, each a sequence of symbols.
+    rules: Vec<Vec<Vec<Symbol>>>,
+    classes: Vec<CharClass>,
+    root: usize,
+}
+
+impl Grammar {
+    /// Parses and validates a grammar, failing on undefined rules and on rules that could expand
+    /// to themselves forever without matching any character.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut parser = Parser {
+            chars: text.chars().collect(),
+            pos: 0,
+            names: vec![],
+            ids: HashMap::new(),
+            rules: vec![],
+            classes: vec![],
+        };
+        parser.parse_rules()?;
+
+        let Parser {
+            names,
+            ids,
+            rules,
+            classes,
+            ..
+        } = parser;
+        let rules = rules
+            .into_iter()
+            .enumerate()
+            .map(|(id, rule)| rule.ok_or_else(|| anyhow!("undefined rule {}", names[id])))
+            .collect::<Result<Vec<_>>>()?;
+        let root = *ids
+            .get("root")
+            .ok_or_else(|| anyhow!("the grammar has no root rule"))?;
+
+        let grammar = Self {
+            rules,
+            classes,
+            root,
+        };
+        if let Some(id) = grammar.left_recursive_rule() {
+            bail!(
+                "rule {} can expand to itself without matching any character",
+                names[id]
+            );
+        }
+
+        Ok(grammar)
+    }
+
+    /// Returns a rule that can expand to itself with nothing matched in between, if any, as
+    /// matching would never end.
+    fn left_recursive_rule(&self) -> Option<usize> {
+        let mut nullable = vec![false; self.rules.len()];
+        loop {
+            let mut changed = false;
+            for (id, alternatives) in self.rules.iter().enumerate() {
+                if !nullable[id]
+                    && alternatives.iter().any(|seq| {
+                        seq.iter().all(|symbol| match symbol {
+                            Symbol::Rule(rule) => nullable[*rule],
+                            Symbol::Chars(_) => false,
+                        })
+                    })
+                {
+                    nullable[id] = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        // rules each rule can start with
+        let leftmost: Vec<Vec<usize>> = self
+            .rules
+            .iter()
+            .map(|alternatives| {
+                let mut rules = vec![];
+                for seq in alternatives {
+                    for symbol in seq {
+                        match symbol {
+                            Symbol::Rule(rule) => {
+                                rules.push(*rule);
+                                if !nullable[*rule] {
+                                    break;
+                                }
+                            }
+                            Symbol::Chars(_) => break,
+                        }
+                    }
+                }
+                rules
+            })
+            .collect();
+
+        (0..self.rules.len()).find(|&start| {
+            let mut seen = HashSet::new();
+            let mut pending = leftmost[start].clone();
+            while let Some(rule) = pending.pop() {
+                if rule == start {
+                    return true;
+                }
+                if seen.insert(rule) {
+                    pending.extend(&leftmost[rule]);
+                }
+            }
+            false
+        })
+    }
+
+    /// Replaces the rule on top of the stack by each of its alternatives, until a character class
+    /// is on top or the stack is empty.
+    fn expand(&self, mut stack: Vec<Symbol>, out: &mut HashSet<Vec<Symbol>>) {
+        match stack.last() {
+            Some(&Symbol::Rule(rule)) => {
+                stack.pop();
+                for seq in &self.rules[rule] {
+                    let mut expanded = stack.clone();
+                    expanded.extend(seq.iter().rev());
+                    self.expand(expanded, out);
+                }
+            }
+            _ => {
+                out.insert(stack);
+            }
+        }
+    }
+
+    /// Returns the stacks left once the character is matched.
+    fn advance(&self, stacks: &[Vec<Symbol>], c: char) -> Vec<Vec<Symbol>> {
+        let mut out = HashSet::new();
+        for stack in stacks {
+            if let Some(&Symbol::Chars(class)) = stack.last() {
+                if self.classes[class].matches(c) {
+                    self.expand(stack[..stack.len() - 1].to_vec(), &mut out);
+                }
+            }
+        }
+        out.into_iter().collect()
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    /// Name of every rule, generated for the groups and repetitions.
+    names: Vec<String>,
+    ids: HashMap<String, usize>,
+    /// Alternatives of every rule, None until it's defined.
+    rules: Vec<Option<Vec<Vec<Symbol>>>>,
+    classes: Vec<CharClass>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn error(&self, msg: &str) -> anyhow::Error {
+        let line = self.chars[..self.pos.min(self.chars.len())]
+            .iter()
+            .filter(|&&c| c == '\n')
+            .count()
+            + 1;
+        anyhow!("grammar error at line {line}: {msg}")
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<()> {
+        for c in expected.chars() {
+            if self.peek() != Some(c) {
+                return Err(self.error(&format!("expected {expected}")));
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    /// Skips spaces and comments, and newlines unless they end the rule at depth 0.
+    fn skip_whitespace(&mut self, depth: usize) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\r' => self.pos += 1,
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                '\n' if depth == 0 && self.rule_follows() => return,
+                '\n' => self.pos += 1,
+                _ => return,
+            }
+        }
+    }
+
+    /// Returns true if the next rule definition, or the end of the grammar, follows the blank
+    /// lines and comments at the current position.
+    fn rule_follows(&self) -> bool {
+        let mut pos = self.pos;
+        let at = |pos: usize| self.chars.get(pos).copied();
+        loop {
+            match at(pos) {
+                Some(' ' | '\t' | '\r' | '\n') => pos += 1,
+                Some('#') => {
+                    while at(pos).is_some_and(|c| c != '\n') {
+                        pos += 1;
+                    }
+                }
+                None => return true,
+                _ => break,
+            }
+        }
+        let start = pos;
+        while at(pos).is_some_and(is_name_char) {
+            pos += 1;
+        }
+        if pos == start {
+            return false;
+        }
+        while at(pos).is_some_and(|c| c == ' ' || c == '\t') {
+            pos += 1;
+        }
+        self.chars[pos..].starts_with(&[':', ':', '='])
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_name_char) {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return Err(self.error("expected a rule name"));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        let id = self.rules.len();
+        self.names.push(name.to_string());
+        self.ids.insert(name.to_string(), id);
+        self.rules.push(None);
+        id
+    }
+
+    /// Adds a rule for a group or a repetition of the rule named parent.
+    fn generated_rule(&mut self, parent: &str) -> usize {
+        let name = format!("{parent}-{}", self.rules.len());
+        self.rule_id(&name)
+    }
+
+    fn parse_rules(&mut self) -> Result<()> {
+        loop {
+            while self.peek().is_some_and(|c| c.is_whitespace() || c == '#') {
+                self.skip_whitespace(1);
+            }
+            if self.peek().is_none() {
+                return Ok(());
+            }
+
+            let name = self.parse_name()?;
+            self.skip_whitespace(0);
+            self.expect("::=")?;
+            let alternatives = self.parse_alternatives(&name, 0)?;
+
+            let id = self.rule_id(&name);
+            if self.rules[id].is_some() {
+                return Err(self.error(&format!("rule {name} is defined twice")));
+            }
+            self.rules[id] = Some(alternatives);
+        }
+    }
+
+    fn parse_alternatives(&mut self, rule: &str, depth: usize) -> Result<Vec<Vec<Symbol>>> {
+        let mut alternatives = vec![self.parse_sequence(rule, depth)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alternatives.push(self.parse_sequence(rule, depth)?);
+        }
+        Ok(alternatives)
+    }
+
+    fn parse_sequence(&mut self, rule: &str, depth: usize) -> Result<Vec<Symbol>> {
+        let mut seq = vec![];
+        loop {
+            self.skip_whitespace(depth);
+            let mut symbols = match self.peek() {
+                None | Some('|' | ')' | '\n') => return Ok(seq),
+                Some('"') => self.parse_literal()?,
+                Some('[') => vec![self.parse_class()?],
+                Some('.') => {
+                    self.pos += 1;
+                    self.classes.push(CharClass {
+                        ranges: vec![],
+                        negated: true,
+                    });
+                    vec![Symbol::Chars(self.classes.len() - 1)]
+                }
+                Some('(') => {
+                    self.pos += 1;
+                    let alternatives = self.parse_alternatives(rule, depth + 1)?;
+                    self.skip_whitespace(depth + 1);
+                    self.expect(")")?;
+                    let id = self.generated_rule(rule);
+                    self.rules[id] = Some(alternatives);
+                    vec![Symbol::Rule(id)]
+                }
+                Some(c) if is_name_char(c) => {
+                    let name = self.parse_name()?;
+                    vec![Symbol::Rule(self.rule_id(&name))]
+                }
+                Some(c) => return Err(self.error(&format!("unexpected character {c:?}"))),
+            };
+
+            while let Some(op @ ('*' | '+' | '?')) = self.peek() {
+                self.pos += 1;
+                let item = if symbols.len() == 1 {
+                    symbols[0]
+                } else {
+                    let id = self.generated_rule(rule);
+                    self.rules[id] = Some(vec![symbols]);
+                    Symbol::Rule(id)
+                };
+                let id = self.generated_rule(rule);
+                let repeated = Symbol::Rule(id);
+                // right recursive, so that matching never expands a rule to itself
+                self.rules[id] = Some(match op {
+                    '*' => vec![vec![item, repeated], vec![]],
+                    '+' => vec![vec![item, repeated], vec![item]],
+                    _ => vec![vec![item], vec![]],
+                });
+                symbols = vec![repeated];
+            }
+
+            seq.extend(symbols);
+        }
+    }
+
+    fn parse_literal(&mut self) -> Result<Vec<Symbol>> {
+        self.expect("\"")?;
+        let mut symbols = vec![];
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated literal")),
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(symbols);
+                }
+                Some(_) => {
+                    let c = self.parse_char()?;
+                    self.classes.push(CharClass::single(c));
+                    symbols.push(Symbol::Chars(self.classes.len() - 1));
+                }
+            }
+        }
+    }
+
+    fn parse_class(&mut self) -> Result<Symbol> {
+        self.expect("[")?;
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = vec![];
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated character class")),
+                Some(']') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(_) => {
+                    let lo = self.parse_char()?;
+                    let hi =
+                        if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                            self.pos += 1;
+                            self.parse_char()?
+                        } else {
+                            lo
+                        };
+                    if hi < lo {
+                        return Err(self.error(&format!("invalid range {lo:?}-{hi:?}")));
+                    }
+                    ranges.push((lo, hi));
+                }
+            }
+        }
+        self.classes.push(CharClass { ranges, negated });
+        Ok(Symbol::Chars(self.classes.len() - 1))
+    }
+
+    /// Parses a character of a literal or a class, unescaping it.
+    fn parse_char(&mut self) -> Result<char> {
+        let c = self
+            .peek()
+            .ok_or_else(|| self.error("unexpected end of grammar"))?;
+        self.pos += 1;
+        if c != '\\' {
+            return Ok(c);
+        }
+
+        let escaped = self
+            .peek()
+            .ok_or_else(|| self.error("unexpected end of grammar"))?;
+        self.pos += 1;
+        let hex = |parser: &mut Self, len: usize| -> Result<char> {
+            let digits: String = parser.chars.iter().skip(parser.pos).take(len).collect();
+            parser.pos += len;
+            u32::from_str_radix(&digits, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or_else(|| parser.error(&format!("invalid escape code {digits:?}")))
+        };
+        match escaped {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            'x' => hex(self, 2),
+            'u' => hex(self, 4),
+            '\\' | '"' | '[' | ']' | '-' | '^' | '/' => Ok(escaped),
+            _ => Err(self.error(&format!("invalid escape \\{escaped}"))),
+        }
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+/// Text of every token of the vocabulary, stored in a trie so that the tokens sharing a prefix
+/// are matched against a grammar once for the prefix.
+#[derive(Debug)]
+pub struct TokenTrie {
+    nodes: Vec<TrieNode>,
+    texts: Vec<Option<String>>,
+}
+
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: Vec<(char, usize)>,
+    /// Tokens whose text ends at this node.
+    tokens: Vec<u32>,
+}
+
+impl TokenTrie {
+    /// Decodes every token of the vocabulary. Special tokens and the tokens that aren't valid
+    /// text on their own, like partial UTF-8 sequences, are left out.
+    pub fn new(tokenizer: &Tokenizer) -> Result<Self> {
+        let decode = |ids: &[u32]| tokenizer.decode(ids, true).map_err(anyhow::Error::msg);
+        // tokens are decoded after another one, as some decoders strip the leading space of
+        // the first token
+        let reference = tokenizer
+            .encode("a", false)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .first()
+            .copied();
+        let prefix = match reference {
+            Some(reference) => decode(&[reference])?,
+            None => String::new(),
+        };
+
+        let mut trie = Self {
+            nodes: vec![TrieNode::default()],
+            texts: vec![],
+        };
+        for id in 0..tokenizer.get_vocab_size(true) as u32 {
+            let text = match reference {
+                Some(reference) => decode(&[reference, id])?
+                    .strip_prefix(&prefix)
+                    .map(str::to_string),
+                None => Some(decode(&[id])?),
+            }
+            .filter(|text| !text.is_empty() && !text.contains('\u{FFFD}'));
+
+            if let Some(text) = &text {
+                let mut node = 0;
+                for c in text.chars() {
+                    node = match trie.nodes[node].children.iter().find(|(k, _)| *k == c) {
+                        Some(&(_, child)) => child,
+                        None => {
+                            trie.nodes.push(TrieNode::default());
+                            let child = trie.nodes.len() - 1;
+                            trie.nodes[node].children.push((c, child));
+                            child
+                        }
+                    };
+                }
+                trie.nodes[node].tokens.push(id);
+            }
+            trie.texts.push(text);
+        }
+
+        Ok(trie)
+    }
+
+    /// Returns the text of a token, if it has one.
+    pub fn text(&self, token: u32) -> Option<&str> {
+        self.texts.get(token as usize)?.as_deref()
+    }
+}
+
+/// Position of a generated text in a grammar, as the possible stacks of symbols left to match.
+#[derive(Debug, Clone)]
+pub struct GrammarState {
+    grammar: Arc<Grammar>,
+    stacks: Vec<Vec<Symbol>>,
+}
+
+impl GrammarState {
+    pub fn new(grammar: Arc<Grammar>) -> Self {
+        let mut stacks = HashSet::new();
+        grammar.expand(vec![Symbol::Rule(grammar.root)], &mut stacks);
+        Self {
+            grammar,
+            stacks: stacks.into_iter().collect(),
+        }
+    }
+
+    /// Appends text to the matched one, returns false and leaves the state unchanged if the
+    /// grammar doesn't allow it.
+    pub fn accept(&mut self, text: &str) -> bool {
+        let mut stacks = self.stacks.clone();
+        for c in text.chars() {
+            stacks = self.grammar.advance(&stacks, c);
+            if stacks.is_empty() {
+                return false;
+            }
+        }
+        self.stacks = stacks;
+        true
+    }
+
+    /// Returns true if the text matched so far is a complete string of the grammar.
+    pub fn is_accepting(&self) -> bool {
+        self.stacks.iter().any(|stack| stack.is_empty())
+    }
+
+    /// Returns true if the grammar doesn't allow anything after the text matched so far.
+    pub fn is_complete(&self) -> bool {
+        self.stacks.iter().all(|stack| stack.is_empty())
+    }
+
+    /// Returns the tokens whose text the grammar allows next.
+    pub fn allowed_tokens(&self, trie: &TokenTrie) -> Vec<u32> {
+        let mut allowed = vec![];
+        self.collect_allowed(trie, 0, &self.stacks, &mut allowed);
+        allowed
+    }
+
+    fn collect_allowed(
+        &self,
+        trie: &TokenTrie,
+        node: usize,
+        stacks: &[Vec<Symbol>],
+        allowed: &mut Vec<u32>,
+    ) {
+        for &(c, child) in &trie.nodes[node].children {
+            let stacks = self.grammar.advance(stacks, c);
+            if !stacks.is_empty() {
+                allowed.extend(&trie.nodes[child].tokens);
+                self.collect_allowed(trie, child, &stacks, allowed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    const JSON: &str = r#"
+# a JSON document
+root   ::= value
+value  ::= object | array | string | number | ("true" | "false" | "null")
+object ::= "{" ws ( string ws ":" ws value ws ( "," ws string ws ":" ws value ws )* )? "}"
+array  ::= "[" ws ( value ws ( "," ws value ws )* )? "]"
+string ::= "\"" ( [^"\\] | "\\" ["\\/bfnrt] )* "\""
+number ::= "-"? [0-9]+ ( "." [0-9]+ )?
+ws     ::= [ \t\n]*
+"#;
+
+    /// Returns whether the grammar accepts the text as a whole.
+    fn matches(grammar: &Arc<Grammar>, text: &str) -> bool {
+        let mut state = GrammarState::new(grammar.clone());
+        state.accept(text) && state.is_accepting()
+    }
+
+    #[test]
+    fn json_documents() {
+        let grammar = Arc::new(Grammar::parse(JSON).unwrap());
+        for text in [
+            r#"{"a": 1, "b": [true, false, null], "c": {"d": "e\"f"}}"#,
+            "[]",
+            "-12.5",
+            r#""""#,
+        ] {
+            assert!(matches(&grammar, text), "{text}");
+        }
+        for text in [r#"{"a" 1}"#, "[1,]", "1.", r#"{"a": 1"#, "truth"] {
+            assert!(!matches(&grammar, text), "{text}");
+        }
+
+        // prefixes are accepted without being complete, the state is left as is on a mismatch
+        let mut state = GrammarState::new(grammar);
+        assert!(state.accept(r#"{"a": [1"#));
+        assert!(!state.is_accepting());
+        assert!(!state.accept("}"));
+        assert!(state.accept("]}"));
+        assert!(state.is_complete());
+    }
+
+    #[test]
+    fn invalid_grammars_fail_to_parse() {
+        for (text, error) in [
+            ("root ::= value", "undefined rule value"),
+            ("value ::= \"a\"", "the grammar has no root rule"),
+            (
+                "root ::= \"a\"\nroot ::= \"b\"",
+                "grammar error at line 2: rule root is defined twice",
+            ),
+            (
+                "root ::= \"a",
+                "grammar error at line 1: unterminated literal",
+            ),
+            (
+                "root ::= [z-a]",
+                "grammar error at line 1: invalid range 'z'-'a'",
+            ),
+            (
+                "root ::= list\nlist ::= item? list \",\"\nitem ::= \"a\"",
+                "rule list can expand to itself without matching any character",
+            ),
+        ] {
+            let e = Grammar::parse(text).unwrap_err();
+            assert_eq!(e.to_string(), error, "{text}");
+        }
+    }
+
+    #[test]
+    fn allowed_tokens_continue_the_text() {
+        let tokenizer = test_utils::tokenizer();
+        let trie = TokenTrie::new(&tokenizer).unwrap();
+        let id = |word: &str| tokenizer.token_to_id(word).unwrap();
+        // tokens are decoded after another one, with the space separating them
+        assert_eq!(trie.text(id("cat")), Some(" cat"));
+        assert_eq!(trie.text(id("</s>")), None);
+
+        let grammar = Grammar::parse(r#"root ::= " the" (" cat" | " cat" " and" | " dog")"#);
+        let mut state = GrammarState::new(Arc::new(grammar.unwrap()));
+        let allowed = |state: &GrammarState| {
+            let mut tokens = state.allowed_tokens(&trie);
+            tokens.sort();
+            tokens
+        };
+        assert_eq!(allowed(&state), [id("the")]);
+        assert!(state.accept(" the"));
+        assert_eq!(allowed(&state), [id("cat"), id("dog")]);
+        assert!(state.accept(" cat"));
+        assert!(state.is_accepting() && !state.is_complete());
+        // a token may match the beginning of the text the grammar allows
+        assert_eq!(allowed(&state), [id("a"), id("and")]);
+        assert!(state.accept(" and"));
+        assert!(state.is_complete());
+        assert!(allowed(&state).is_empty());
+    }
+}
@@ -1,38 +1,251 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use candle_core::{
     utils::{cuda_is_available, metal_is_available},
-    Device,
+    DType, Device, Tensor,
 };
 
 use anyhow::{bail, Result};
 
+mod download;
+mod grammar;
+mod sampler;
+mod shard;
+mod stop_sequences;
 mod token_output_stream;
 
+pub use download::*;
+pub use grammar::*;
+pub use sampler::*;
+pub use shard::*;
+pub use stop_sequences::*;
 pub use token_output_stream::*;
 
-pub fn get_inference_device(force_cpu: bool, ordinal: usize) -> Result<Device> {
-    if force_cpu {
-        log::debug!("device is forced cpu");
-        Ok(Device::Cpu)
-    } else if cuda_is_available() {
-        log::debug!("device is cuda {ordinal}");
-        Ok(Device::new_cuda(ordinal)?)
-    } else if metal_is_available() {
-        log::debug!("device is metal {ordinal}");
-        Ok(Device::new_metal(ordinal)?)
-    } else {
-        log::debug!("device is cpu");
-        // fallback to cpu if nothing else available
-        Ok(Device::Cpu)
+/// Kind of device the inference can run on.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceKind {
+    Cuda,
+    Metal,
+    Cpu,
+}
+
+/// Order the devices are tried in when no preference is given.
+pub const DEFAULT_DEVICE_PREFERENCE: [DeviceKind; 3] =
+    [DeviceKind::Cuda, DeviceKind::Metal, DeviceKind::Cpu];
+
+/// Returns the device with the given ordinal, of the first kind of the preference list that can
+/// be initialized.
+pub fn get_inference_device(preference: &[DeviceKind], ordinal: usize) -> Result<Device> {
+    first_device(preference, |kind| match kind {
+        DeviceKind::Cuda if cuda_is_available() => Ok(Device::new_cuda(ordinal)?),
+        DeviceKind::Metal if metal_is_available() => Ok(Device::new_metal(ordinal)?),
+        DeviceKind::Cpu => Ok(Device::Cpu),
+        _ => bail!("not supported by this build"),
+    })
+}
+
+/// Returns the devices with the given ordinals.
+pub fn get_inference_devices(preference: &[DeviceKind], ordinals: &[usize]) -> Result<Vec<Device>> {
+    ordinals
+        .iter()
+        .map(|ordinal| get_inference_device(preference, *ordinal))
+        .collect()
+}
+
+/// Returns the first device of the preference list that init manages to create.
+fn first_device<F>(preference: &[DeviceKind], init: F) -> Result<Device>
+where
+    F: Fn(DeviceKind) -> Result<Device>,
+{
+    let mut failures = vec![];
+    for &kind in preference {
+        match init(kind) {
+            Ok(device) => {
+                log::info!("using device {:?}", device.location());
+                return Ok(device);
+            }
+            Err(e) => {
+                log::debug!("{kind:?} device unavailable: {e}");
+                failures.push(format!("{kind:?}: {e}"));
+            }
+        }
+    }
+
+    bail!("no device available ({})", failures.join(", "))
+}
+
+/// Limits the threads used by the CPU operations if a count is provided, returns the number of
+/// threads in use.
+pub fn init_threads(threads: Option<usize>) -> Result<usize> {
+    if let Some(threads) = threads {
+        if threads == 0 {
+            bail!("the number of threads must be greater than zero");
+        }
+        // read by candle for its matrix multiplications
+        std::env::set_var("RAYON_NUM_THREADS", threads.to_string());
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
+
+    Ok(rayon::current_num_threads())
+}
+
+/// Parses a JSON object of token ids and biases, the biases can be numbers or strings such as
+/// "-inf".
+pub fn parse_logit_bias(json: &str) -> Result<HashMap<u32, f32>> {
+    let raw: HashMap<u32, serde_json::Value> =
+        serde_json::from_str(json).map_err(|e| anyhow!("invalid logit bias: {e}"))?;
+
+    raw.into_iter()
+        .map(|(token, bias)| {
+            let bias = match &bias {
+                serde_json::Value::Number(n) => n.as_f64().map(|n| n as f32),
+                serde_json::Value::String(s) => s.parse::<f32>().ok(),
+                _ => None,
+            }
+            .ok_or_else(|| anyhow!("invalid logit bias for token {token}: {bias}"))?;
+            Ok((token, bias))
+        })
+        .collect()
+}
+
+/// Parses one of the dtypes the model can run in.
+pub fn parse_dtype(dtype: &str) -> Result<DType> {
+    match dtype {
+        "f16" => Ok(DType::F16),
+        "bf16" => Ok(DType::BF16),
+        "f32" => Ok(DType::F32),
+        dtype => Err(crate::cake::CakeError::DtypeUnsupported {
+            got: dtype.to_string(),
+        }
+        .into()),
+    }
+}
+
+/// Returns the system memory available for new allocations, in bytes.
+pub fn available_memory() -> u64 {
+    let mut sys = sysinfo::System::new();
+    sys.refresh_memory();
+    sys.available_memory()
+}
+
+/// Returns the memory available for new allocations on the device, in bytes. Metal devices share
+/// the memory of the system.
+pub fn device_memory(device: &Device) -> Result<u64> {
+    match device {
+        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        Device::Cuda(cuda) => {
+            use candle_core::cuda_backend::cudarc::driver::result;
+
+            // the query applies to the device of the current context
+            cuda.cuda_device().bind_to_thread()?;
+            let (free, _total) = result::mem_get_info()?;
+            Ok(free as u64)
+        }
+        _ => Ok(available_memory()),
+    }
+}
+
+/// Compares two secrets in a time that doesn't depend on where they differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Returns the GGUF file in the model directory, if any.
+pub fn find_gguf(data_path: &Path) -> Result<Option<PathBuf>> {
+    let mut found = vec![];
+    for entry in std::fs::read_dir(data_path)
+        .map_err(|e| anyhow!("can't read {}: {:?}", data_path.display(), e))?
+    {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "gguf") {
+            found.push(path);
+        }
+    }
+
+    match found.len() {
+        0 => Ok(None),
+        1 => Ok(found.pop()),
+        _ => bail!(
+            "found multiple gguf files in {}: {:?}",
+            data_path.display(),
+            found
+        ),
+    }
+}
+
+/// Returns the dtype of the safetensors checkpoint in the model directory, the one of the first
+/// tensor of its first file by name. GGUF models have no dtype to match and get None.
+pub fn checkpoint_dtype(data_path: &Path) -> Result<Option<DType>> {
+    if find_gguf(data_path)?.is_some() {
+        return Ok(None);
+    }
+
+    let mut filenames = load_safetensors_from_index(data_path.join("model.safetensors.index.json"))
+        .map_err(|e| anyhow!("can't find the model tensors: {:?}", e))?;
+    filenames.sort();
+    // the shards a node doesn't need may not have been downloaded
+    let filename = filenames
+        .iter()
+        .find(|filename| filename.exists())
+        .ok_or_else(|| anyhow!("no safetensors file in {}", data_path.display()))?;
+
+    let file = std::fs::File::open(filename)
+        .map_err(|e| anyhow!("can't open {}: {:?}", filename.display(), e))?;
+    // only the header is read
+    let buffer = unsafe { memmap2::MmapOptions::new().map(&file)? };
+    let (_, metadata) = safetensors::SafeTensors::read_metadata(&buffer)
+        .map_err(|e| anyhow!("can't read {}: {:?}", filename.display(), e))?;
+    let tensors = metadata.tensors();
+    let dtype = match tensors.keys().min() {
+        Some(name) => tensors[name].dtype,
+        None => bail!("no tensors in {}", filename.display()),
+    };
+
+    match dtype {
+        safetensors::Dtype::F16 => Ok(Some(DType::F16)),
+        safetensors::Dtype::BF16 => Ok(Some(DType::BF16)),
+        safetensors::Dtype::F32 => Ok(Some(DType::F32)),
+        dtype => Err(crate::cake::CakeError::DtypeUnsupported {
+            got: format!("{dtype:?} (from the checkpoint)"),
+        }
+        .into()),
     }
 }
 
-/// Loads the safetensors files for a model from the hub based on a json index file.
+/// Writes the activations to {dir}/{name}.safetensors, replacing the ones of a previous pass.
+pub fn dump_activations(dir: &Path, name: &str, x: &Tensor) -> Result<()> {
+    let path = dir.join(format!("{name}.safetensors"));
+    x.save_safetensors("activations", &path)
+        .map_err(|e| anyhow!("can't dump activations to {}: {:?}", path.display(), e))
+}
+
+/// Loads the safetensors files for a model from the hub based on a json index file, or from the
+/// files of its directory if there's no index.
 pub fn load_safetensors_from_index(
     tensors_index_json_filename: PathBuf,
 ) -> Result<Vec<std::path::PathBuf>> {
+    load_safetensors_for(tensors_index_json_filename, |_| true)
+}
+
+/// Same as load_safetensors_from_index, keeping only the files of the index holding at least one
+/// of the tensors for which needed returns true. All the files are returned if there's no index.
+pub fn load_safetensors_for<F>(
+    tensors_index_json_filename: PathBuf,
+    needed: F,
+) -> Result<Vec<std::path::PathBuf>>
+where
+    F: Fn(&str) -> bool,
+{
     let parent_dir = tensors_index_json_filename.parent().unwrap();
+    if !tensors_index_json_filename.exists() {
+        return find_safetensors(parent_dir);
+    }
+
     let json_file = std::fs::File::open(&tensors_index_json_filename).map_err(|e| {
         anyhow!(
             "can't open {}: {:?}",
@@ -53,15 +266,287 @@ pub fn load_safetensors_from_index(
         Some(_) => bail!("weight map in {json_file:?} is not a map"),
     };
     let mut safetensors_files = std::collections::HashSet::new();
-    for value in weight_map.values() {
+    for (tensor, value) in weight_map {
         if let Some(file) = value.as_str() {
-            safetensors_files.insert(file.to_string());
+            if needed(tensor) {
+                safetensors_files.insert(file.to_string());
+            }
         }
     }
-    let safetensors_files = safetensors_files
+    let mut safetensors_files = safetensors_files
         .iter()
         .map(|v| parent_dir.join(v))
         .collect::<Vec<std::path::PathBuf>>();
+    safetensors_files.sort();
 
     Ok(safetensors_files)
 }
+
+/// Returns the model-{index}-of-{count}.safetensors shards of the directory sorted by index, or
+/// its single model.safetensors file.
+fn find_safetensors(data_path: &Path) -> Result<Vec<PathBuf>> {
+    let mut shards = vec![];
+    let mut count = 0;
+    for entry in std::fs::read_dir(data_path)
+        .map_err(|e| anyhow!("can't read {}: {:?}", data_path.display(), e))?
+    {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        if let Some((index, of)) = parse_shard_name(name) {
+            shards.push((index, path));
+            count = count.max(of);
+        }
+    }
+
+    if !shards.is_empty() {
+        if shards.len() != count {
+            bail!(
+                "found {} of the {count} safetensors shards in {}",
+                shards.len(),
+                data_path.display()
+            );
+        }
+        shards.sort();
+        return Ok(shards.into_iter().map(|(_, path)| path).collect());
+    }
+
+    let single = data_path.join("model.safetensors");
+    if single.exists() {
+        return Ok(vec![single]);
+    }
+
+    bail!(
+        "no model.safetensors.index.json, model-*-of-*.safetensors or model.safetensors in {}",
+        data_path.display()
+    )
+}
+
+/// Parses the (index, count) of a model-{index}-of-{count}.safetensors file name.
+fn parse_shard_name(name: &str) -> Option<(usize, usize)> {
+    let (index, count) = name
+        .strip_prefix("model-")?
+        .strip_suffix(".safetensors")?
+        .split_once("-of-")?;
+    Some((index.parse().ok()?, count.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    use crate::{test_utils, Args};
+
+    /// Directory of empty files of the names.
+    fn files(name: &str, files: &[&str]) -> PathBuf {
+        let dir = test_utils::temp_dir(name);
+        for file in files {
+            std::fs::write(dir.join(file), b"").unwrap();
+        }
+        dir
+    }
+
+    fn names(paths: Vec<PathBuf>) -> Vec<String> {
+        paths
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn safetensors_of_the_index() {
+        let dir = files(
+            "index",
+            &["a.safetensors", "b.safetensors", "c.safetensors"],
+        );
+        let index = serde_json::json!({"weight_map": {
+            "model.norm.weight": "b.safetensors",
+            "model.layers.0.mlp.up_proj.weight": "a.safetensors",
+            "model.layers.1.mlp.up_proj.weight": "b.safetensors",
+        }});
+        let index_path = dir.join("model.safetensors.index.json");
+        std::fs::write(&index_path, index.to_string()).unwrap();
+
+        assert_eq!(
+            names(load_safetensors_from_index(index_path.clone()).unwrap()),
+            ["a.safetensors", "b.safetensors"]
+        );
+        assert_eq!(
+            names(load_safetensors_for(index_path, |name| name.contains("layers.1.")).unwrap()),
+            ["b.safetensors"]
+        );
+    }
+
+    #[test]
+    fn numbered_safetensors_shards() {
+        let shards: Vec<String> = (1..=11)
+            .map(|i| format!("model-{i}-of-11.safetensors"))
+            .collect();
+        let mut listed: Vec<&str> = shards.iter().map(String::as_str).collect();
+        listed.push("model.safetensors");
+        let dir = files("shards", &listed);
+
+        // sorted by index rather than by name, and preferred to a single file
+        let index_path = dir.join("model.safetensors.index.json");
+        assert_eq!(
+            names(load_safetensors_from_index(index_path).unwrap()),
+            shards
+        );
+
+        std::fs::remove_file(dir.join("model-5-of-11.safetensors")).unwrap();
+        let err = load_safetensors_from_index(dir.join("model.safetensors.index.json"));
+        assert!(err.unwrap_err().to_string().contains("found 10 of the 11"));
+    }
+
+    #[test]
+    fn single_safetensors_file() {
+        let dir = files("single", &["model.safetensors", "config.json"]);
+        let index_path = dir.join("model.safetensors.index.json");
+        assert_eq!(
+            names(load_safetensors_from_index(index_path).unwrap()),
+            ["model.safetensors"]
+        );
+
+        std::fs::remove_file(dir.join("model.safetensors")).unwrap();
+        assert!(load_safetensors_from_index(dir.join("model.safetensors.index.json")).is_err());
+    }
+
+    /// Tiny model whose tensors are stored in dtype.
+    fn model_in(name: &str, dtype: DType) -> PathBuf {
+        let dir = test_utils::model_dir_with(name, serde_json::json!({}));
+        let path = dir.join("model.safetensors");
+        let tensors: HashMap<String, Tensor> = candle_core::safetensors::load(&path, &Device::Cpu)
+            .unwrap()
+            .into_iter()
+            .map(|(name, tensor)| (name, tensor.to_dtype(dtype).unwrap()))
+            .collect();
+        candle_core::safetensors::save(&tensors, &path).unwrap();
+        dir
+    }
+
+    #[test]
+    fn dtype_of_the_checkpoint() {
+        let dir = model_in("bf16", DType::BF16);
+        assert_eq!(checkpoint_dtype(&dir).unwrap(), Some(DType::BF16));
+
+        let args = crate::Args {
+            dtype: None,
+            ..test_utils::args_for(&dir, &[])
+        };
+        let ctx = crate::cake::Context::from_args(args).unwrap();
+        assert_eq!(ctx.cache.cos.dtype(), DType::BF16);
+        let embeddings = ctx.var_builder.get((32, 64), "model.embed_tokens.weight");
+        assert_eq!(embeddings.unwrap().dtype(), DType::BF16);
+
+        // converted to an explicit dtype
+        let ctx = crate::cake::Context::from_args(test_utils::args_for(&dir, &[])).unwrap();
+        assert_eq!(ctx.cache.cos.dtype(), DType::F32);
+        assert_eq!(
+            checkpoint_dtype(test_utils::model_dir()).unwrap(),
+            Some(DType::F32)
+        );
+    }
+
+    #[test]
+    fn unsupported_dtype_of_the_checkpoint() {
+        let dir = model_in("f64", DType::F64);
+        let args = crate::Args {
+            dtype: None,
+            ..test_utils::args_for(&dir, &[])
+        };
+        match crate::cake::Context::from_args(args) {
+            Err(crate::cake::CakeError::DtypeUnsupported { got }) => {
+                assert_eq!(got, "F64 (from the checkpoint)")
+            }
+            res => panic!("unexpected result {:?}", res.err()),
+        }
+    }
+
+    #[test]
+    fn secrets_comparison() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn logit_bias() {
+        let bias = parse_logit_bias(r#"{"3": 2.5, "7": "-inf", "9": -1}"#).unwrap();
+        assert_eq!(bias.len(), 3);
+        assert_eq!(bias[&3], 2.5);
+        assert_eq!(bias[&7], f32::NEG_INFINITY);
+        assert_eq!(bias[&9], -1.);
+
+        assert!(parse_logit_bias(r#"{"3": true}"#).is_err());
+        assert!(parse_logit_bias(r#"{"a": 1}"#).is_err());
+    }
+
+    #[test]
+    fn threads_limit_the_pool() {
+        // the global pool can only be configured once per process, the test runs in a child one
+        if std::env::var_os("CAKE_TEST_THREADS").is_none() {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "utils::tests::threads_limit_the_pool"])
+                .env("CAKE_TEST_THREADS", "1")
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stdout)
+            );
+            return;
+        }
+
+        assert!(init_threads(Some(0)).is_err());
+        assert_eq!(init_threads(Some(3)).unwrap(), 3);
+        assert_eq!(rayon::current_num_threads(), 3);
+        assert_eq!(candle_core::utils::get_num_threads(), 3);
+        assert_eq!(rayon::broadcast(|ctx| ctx.num_threads()), [3, 3, 3]);
+        // unset, the pool is left as it is
+        assert_eq!(init_threads(None).unwrap(), 3);
+    }
+
+    #[test]
+    fn device_fallback() {
+        let tried = std::cell::RefCell::new(vec![]);
+        let init = |kind| {
+            tried.borrow_mut().push(kind);
+            match kind {
+                DeviceKind::Cpu => Ok(Device::Cpu),
+                _ => bail!("absent"),
+            }
+        };
+
+        let device = first_device(&DEFAULT_DEVICE_PREFERENCE, init).unwrap();
+        assert!(device.is_cpu());
+        assert_eq!(*tried.borrow(), DEFAULT_DEVICE_PREFERENCE);
+
+        let e = first_device(&[DeviceKind::Metal, DeviceKind::Cuda], init).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "no device available (Metal: absent, Cuda: absent)"
+        );
+    }
+
+    #[test]
+    fn device_preference_of_the_arguments() {
+        let preference = |extra: &[&str]| test_utils::args(extra).device_preference();
+        // --cpu is part of the test arguments
+        assert_eq!(preference(&[]), [DeviceKind::Cpu]);
+
+        let args = Args::parse_from(["cake"]);
+        assert_eq!(args.device_preference(), DEFAULT_DEVICE_PREFERENCE);
+        let args = Args::parse_from(["cake", "--device-preference", "metal,cpu"]);
+        assert_eq!(
+            args.device_preference(),
+            [DeviceKind::Metal, DeviceKind::Cpu]
+        );
+        assert!(Args::try_parse_from(["cake", "--cpu", "--device-preference", "cuda"]).is_err());
+    }
+}
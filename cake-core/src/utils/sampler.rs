@@ -0,0 +1,169 @@
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use candle_transformers::generation::Sampling;
+use rand::{distributions::Distribution, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+/// Samples tokens from logits like the LogitsProcessor of candle, only on the host: the logits are
+/// copied to the CPU before the softmax and the draws come from a ChaCha20 generator, so that a
+/// seed gives the same tokens whatever the device the model runs on and the platform.
+pub struct Sampler {
+    rng: ChaCha20Rng,
+    sampling: Sampling,
+}
+
+impl Sampler {
+    pub fn new(seed: u64, sampling: Sampling) -> Self {
+        Self {
+            rng: ChaCha20Rng::seed_from_u64(seed),
+            sampling,
+        }
+    }
+
+    /// Samples a token from logits of shape (vocab_size,).
+    pub fn sample(&mut self, logits: &Tensor) -> Result<u32> {
+        let probs = filtered_probs(logits, &self.sampling)?;
+        match self.sampling {
+            // the one token left, without a draw
+            Sampling::ArgMax => Ok(probs
+                .iter()
+                .position(|p| *p > 0.)
+                .map(|token| token as u32)
+                .unwrap_or_default()),
+            _ => sample_with(&probs, &mut self.rng),
+        }
+    }
+}
+
+/// Probabilities tokens are sampled with from logits of shape (vocab_size,), once the
+/// temperature, top-k and top-p of the sampling are applied. They're not renormalized after the
+/// filtering.
+pub fn filtered_probs(logits: &Tensor, sampling: &Sampling) -> Result<Vec<f32>> {
+    let logits = logits.to_device(&Device::Cpu)?.to_dtype(DType::F32)?;
+    let softmax = |temperature: f64| -> Result<Vec<f32>> {
+        let logits = (&logits / temperature)?;
+        Ok(candle_nn::ops::softmax_last_dim(&logits)?.to_vec1()?)
+    };
+
+    match *sampling {
+        Sampling::ArgMax => {
+            let logits: Vec<f32> = logits.to_vec1()?;
+            let mut probs = vec![0.; logits.len()];
+            if let Some((token, _)) = logits
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            {
+                probs[token] = 1.;
+            }
+            Ok(probs)
+        }
+        Sampling::All { temperature } => softmax(temperature),
+        Sampling::TopP { p, temperature } => {
+            let mut probs = softmax(temperature)?;
+            if p > 0. && p < 1. {
+                top_p(&mut probs, p as f32);
+            }
+            Ok(probs)
+        }
+        Sampling::TopK { k, temperature } => {
+            let mut probs = softmax(temperature)?;
+            top_k(&mut probs, k, None);
+            Ok(probs)
+        }
+        Sampling::TopKThenTopP { k, p, temperature } => {
+            let mut probs = softmax(temperature)?;
+            top_k(&mut probs, k, Some(p as f32));
+            Ok(probs)
+        }
+    }
+}
+
+/// Keeps the smallest set of most likely tokens whose probabilities reach p.
+fn top_p(probs: &mut [f32], p: f32) {
+    let mut sorted: Vec<usize> = (0..probs.len()).collect();
+    sorted.sort_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+
+    let mut sum = 0.;
+    for token in sorted {
+        if sum >= p {
+            probs[token] = 0.;
+        } else {
+            sum += probs[token];
+        }
+    }
+}
+
+/// Keeps the k most likely tokens, all of them if k is 0, then the ones reaching p among them
+/// if set.
+fn top_k(probs: &mut [f32], k: usize, p: Option<f32>) {
+    if k > 0 && k < probs.len() {
+        let mut sorted: Vec<usize> = (0..probs.len()).collect();
+        let (_, _, rest) =
+            sorted.select_nth_unstable_by(k - 1, |&a, &b| probs[b].total_cmp(&probs[a]));
+        for &token in rest.iter() {
+            probs[token] = 0.;
+        }
+    }
+    let sum: f32 = probs.iter().sum();
+    match p {
+        Some(p) if p > 0. && p < sum => top_p(probs, p),
+        _ => {}
+    }
+}
+
+/// Samples a token from the probabilities with the given generator.
+pub fn sample_with(probs: &[f32], rng: &mut ChaCha20Rng) -> Result<u32> {
+    let distr = rand::distributions::WeightedIndex::new(probs).map_err(|e| anyhow!(e))?;
+    Ok(distr.sample(rng) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tokens sampled from the logits with a sampler of the seed.
+    fn sampled(logits: &Tensor, seed: u64, n: usize) -> Vec<u32> {
+        let mut sampler = Sampler::new(seed, Sampling::All { temperature: 1. });
+        (0..n).map(|_| sampler.sample(logits).unwrap()).collect()
+    }
+
+    fn logits(device: &Device) -> Tensor {
+        Tensor::new(&[0.5f32, 1.5, -1., 0., 1., 2., -0.5, 0.25], device).unwrap()
+    }
+
+    #[test]
+    fn seeded_draws_are_the_same_everywhere() {
+        let tokens = sampled(&logits(&Device::Cpu), 42, 16);
+        // a golden stream, the same on every platform
+        assert_eq!(tokens, [5, 4, 5, 4, 0, 1, 5, 1, 5, 1, 7, 3, 1, 5, 6, 4]);
+
+        // the draws only depend on the ChaCha20 stream of the seed
+        let probs: Vec<f32> = candle_nn::ops::softmax_last_dim(&logits(&Device::Cpu))
+            .unwrap()
+            .to_vec1()
+            .unwrap();
+        let mut rng = ChaCha20Rng::seed_from_u64(42);
+        let expected: Vec<u32> = (0..16)
+            .map(|_| sample_with(&probs, &mut rng).unwrap())
+            .collect();
+        assert_eq!(tokens, expected);
+
+        // whatever the dtype and the layout the logits come in
+        let half = logits(&Device::Cpu).to_dtype(DType::F16).unwrap();
+        assert_eq!(sampled(&half, 42, 16), tokens);
+        assert_ne!(sampled(&logits(&Device::Cpu), 43, 16), tokens);
+    }
+
+    /// Runs on the first CUDA device, if any.
+    #[test]
+    fn seeded_draws_on_cuda_like_on_the_cpu() {
+        let Ok(device) = Device::new_cuda(0) else {
+            return;
+        };
+        assert_eq!(
+            sampled(&logits(&device), 42, 16),
+            sampled(&logits(&Device::Cpu), 42, 16)
+        );
+    }
+}
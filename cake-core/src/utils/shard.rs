@@ -0,0 +1,236 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Result;
+use safetensors::{SafeTensors, View};
+
+use super::{find_gguf, load_safetensors_for};
+use crate::cake::Topology;
+
+/// File the tensors of a worker are written to in its directory.
+const SHARD_FILENAME: &str = "model.safetensors";
+
+/// Writes a model directory to output/{name} for every worker of the topology, holding the
+/// tensors of the layers it serves, with their names and dtypes unchanged, in a single
+/// model.safetensors file along with its index and the config.json of the model. Each worker can
+/// then be started with its own directory as --model rather than the whole checkpoint.
+pub fn shard_checkpoint(data_path: &Path, topology: &Topology, output: &Path) -> Result<()> {
+    if find_gguf(data_path)?.is_some() {
+        bail!(
+            "can't shard {}, only safetensors checkpoints can be sharded",
+            data_path.display()
+        );
+    }
+
+    let mut workers: Vec<_> = topology
+        .iter()
+        .filter(|(_, node)| !node.is_local())
+        .collect();
+    workers.sort_by_key(|(name, _)| name.as_str());
+    if workers.is_empty() {
+        bail!("the topology has no worker to shard the checkpoint for");
+    }
+
+    for (name, node) in workers {
+        // only the shards of the checkpoint holding tensors of the worker are read
+        let filenames =
+            load_safetensors_for(data_path.join("model.safetensors.index.json"), |tensor| {
+                node.is_layer_owner(tensor)
+            })
+            .map_err(|e| anyhow!("can't find the model tensors: {:?}", e))?;
+
+        let mut buffers = vec![];
+        for filename in &filenames {
+            let file = std::fs::File::open(filename)
+                .map_err(|e| anyhow!("can't open {}: {:?}", filename.display(), e))?;
+            let buffer = unsafe { memmap2::MmapOptions::new().map(&file) }
+                .map_err(|e| anyhow!("can't map {}: {:?}", filename.display(), e))?;
+            buffers.push(buffer);
+        }
+
+        let mut shards = vec![];
+        for (filename, buffer) in filenames.iter().zip(&buffers) {
+            shards.push(
+                SafeTensors::deserialize(buffer)
+                    .map_err(|e| anyhow!("can't parse {}: {:?}", filename.display(), e))?,
+            );
+        }
+
+        let tensors: Vec<_> = shards
+            .iter()
+            .flat_map(|shard| shard.tensors())
+            .filter(|(tensor, _)| node.is_layer_owner(tensor))
+            .collect();
+        if tensors.is_empty() {
+            bail!("{name} serves no tensor of the checkpoint");
+        }
+
+        let total_size: usize = tensors.iter().map(|(_, tensor)| tensor.data_len()).sum();
+        let index = serde_json::json!({
+            "metadata": { "total_size": total_size },
+            "weight_map": tensors
+                .iter()
+                .map(|(tensor, _)| (tensor.clone(), SHARD_FILENAME.to_string()))
+                .collect::<HashMap<_, _>>(),
+        });
+
+        let dir = output.join(name);
+        log::info!(
+            "writing the {} tensors of {name} ({}) to {} ...",
+            tensors.len(),
+            human_bytes::human_bytes(total_size as f64),
+            dir.display()
+        );
+
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| anyhow!("can't create {}: {:?}", dir.display(), e))?;
+        safetensors::serialize_to_file(tensors, &None, &dir.join(SHARD_FILENAME))
+            .map_err(|e| anyhow!("can't write the tensors of {name}: {:?}", e))?;
+        std::fs::write(
+            dir.join("model.safetensors.index.json"),
+            serde_json::to_string_pretty(&index)?,
+        )?;
+
+        // the workers read the configuration of the model too
+        let config = data_path.join("config.json");
+        if config.exists() {
+            std::fs::copy(&config, dir.join("config.json"))
+                .map_err(|e| anyhow!("can't copy {}: {:?}", config.display(), e))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{cake::CancellationToken, test_utils};
+    use candle_core::{DType, Device, Tensor};
+
+    /// Text generated by a master of args.
+    async fn generate(args: crate::Args) -> String {
+        let mut text = String::new();
+        test_utils::master(args)
+            .await
+            .generate(&CancellationToken::default(), |t| text.push_str(t))
+            .await
+            .unwrap();
+        text
+    }
+
+    #[tokio::test]
+    async fn shards_hold_the_tensors_of_their_worker() {
+        // the tensors of the test model in two files, the first layers in f16
+        let dir = test_utils::model_dir_with("shard-source", serde_json::json!({}));
+        let tensors =
+            candle_core::safetensors::load(dir.join("model.safetensors"), &Device::Cpu).unwrap();
+        std::fs::remove_file(dir.join("model.safetensors")).unwrap();
+        let file_of = |name: &str| {
+            if name.starts_with("model.layers.0.") || name.starts_with("model.layers.1.") {
+                "model-00001.safetensors"
+            } else {
+                "model-00002.safetensors"
+            }
+        };
+        let mut files: HashMap<&str, HashMap<String, Tensor>> = HashMap::new();
+        for (name, tensor) in &tensors {
+            let tensor = match file_of(name) {
+                "model-00001.safetensors" => tensor.to_dtype(DType::F16).unwrap(),
+                _ => tensor.clone(),
+            };
+            files
+                .entry(file_of(name))
+                .or_default()
+                .insert(name.clone(), tensor);
+        }
+        for (file, tensors) in &files {
+            candle_core::safetensors::save(tensors, dir.join(file)).unwrap();
+        }
+        let weight_map: HashMap<_, _> = tensors.keys().map(|name| (name, file_of(name))).collect();
+        std::fs::write(
+            dir.join("model.safetensors.index.json"),
+            serde_json::json!({ "metadata": {}, "weight_map": weight_map }).to_string(),
+        )
+        .unwrap();
+
+        let address = test_utils::free_address();
+        let yaml = format!(
+            "first: {{ host: '{}', layers: [0, 1] }}\nlast: {{ host: '{address}', layers: [2, 3] }}",
+            test_utils::free_address()
+        );
+        let topology_path = test_utils::topology("shard.yml", &yaml);
+        let topology = Topology::from_path(&topology_path).unwrap();
+        let output = test_utils::temp_dir("shards");
+        shard_checkpoint(&dir, &topology, &output).unwrap();
+
+        for (worker, layers) in [("first", [0, 1]), ("last", [2, 3])] {
+            let shard_dir = output.join(worker);
+            let shard =
+                candle_core::safetensors::load(shard_dir.join(SHARD_FILENAME), &Device::Cpu)
+                    .unwrap();
+            let mut names: Vec<&String> = shard.keys().collect();
+            names.sort();
+            let mut expected: Vec<&String> = tensors
+                .keys()
+                .filter(|name| {
+                    layers
+                        .iter()
+                        .any(|layer| name.starts_with(&format!("model.layers.{layer}.")))
+                })
+                .collect();
+            expected.sort();
+            assert_eq!(names, expected);
+            assert_eq!(names.len(), 2 * 9);
+
+            for name in names {
+                let source = &files[file_of(name)][name];
+                assert_eq!(shard[name].dtype(), source.dtype(), "{name}");
+                assert_eq!(
+                    shard[name]
+                        .to_dtype(DType::F32)
+                        .unwrap()
+                        .flatten_all()
+                        .unwrap()
+                        .to_vec1::<f32>()
+                        .unwrap(),
+                    source
+                        .to_dtype(DType::F32)
+                        .unwrap()
+                        .flatten_all()
+                        .unwrap()
+                        .to_vec1::<f32>()
+                        .unwrap(),
+                );
+            }
+
+            let index: serde_json::Value = serde_json::from_slice(
+                &std::fs::read(shard_dir.join("model.safetensors.index.json")).unwrap(),
+            )
+            .unwrap();
+            let weight_map = index["weight_map"].as_object().unwrap();
+            assert_eq!(weight_map.len(), shard.len());
+            assert!(weight_map.values().all(|file| file == SHARD_FILENAME));
+            assert!(shard_dir.join("config.json").exists());
+        }
+
+        // a worker serves its layers from its shard alone
+        let mut args = test_utils::args_for(
+            &output.join("last"),
+            &["--mode", "worker", "--name", "last", "--address", &address],
+        );
+        args.topology = topology_path;
+        let _worker = test_utils::TestWorker::start(args).await.unwrap();
+        let prompt = ["--prompt", "the cat", "--max-tokens", "4", "--ignore-eos"];
+        let local = test_utils::args_for(&dir, &prompt);
+        // the first layers stay on the master, which loads them from the whole checkpoint
+        let remote = crate::Args {
+            topology: test_utils::topology(
+                "shard-last.yml",
+                &format!("last: {{ host: '{address}', layers: [2, 3] }}"),
+            ),
+            ..local.clone()
+        };
+        assert_eq!(generate(remote).await, generate(local).await);
+    }
+}
@@ -0,0 +1,121 @@
+/// Buffers streamed text so that stop sequences can be detected even when they span multiple
+/// tokens, without ever emitting (part of) a matched stop sequence.
+pub struct StopSequences {
+    stop: Vec<String>,
+    pending: String,
+    matched: bool,
+}
+
+impl StopSequences {
+    pub fn new(stop: Vec<String>) -> Self {
+        let stop = stop.into_iter().filter(|s| !s.is_empty()).collect();
+        Self {
+            stop,
+            pending: String::new(),
+            matched: false,
+        }
+    }
+
+    /// Returns true once any of the stop sequences has been matched.
+    pub fn matched(&self) -> bool {
+        self.matched
+    }
+
+    /// Appends text to the buffer and returns the part of it that can be safely emitted.
+    pub fn push(&mut self, text: &str) -> String {
+        if self.matched {
+            return String::new();
+        }
+
+        self.pending.push_str(text);
+
+        // earliest full match of any stop sequence
+        if let Some(pos) = self
+            .stop
+            .iter()
+            .filter_map(|stop| self.pending.find(stop.as_str()))
+            .min()
+        {
+            self.matched = true;
+            let text = self.pending[..pos].to_string();
+            self.pending.clear();
+            return text;
+        }
+
+        // hold back the longest suffix that could still be the beginning of a stop sequence
+        let held = self
+            .pending
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| {
+                let suffix = &self.pending[i..];
+                self.stop.iter().any(|stop| stop.starts_with(suffix))
+            })
+            .unwrap_or(self.pending.len());
+
+        let rest = self.pending.split_off(held);
+        std::mem::replace(&mut self.pending, rest)
+    }
+
+    /// Returns whatever text is still buffered, unless a stop sequence was matched.
+    pub fn finish(&mut self) -> String {
+        if self.matched {
+            String::new()
+        } else {
+            std::mem::take(&mut self.pending)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pushes the pieces of text and returns what's emitted, along with whether a stop sequence
+    /// matched.
+    fn stream(stop: &[&str], pieces: &[&str]) -> (String, bool) {
+        let mut stop = StopSequences::new(stop.iter().map(|s| s.to_string()).collect());
+        let mut text: String = pieces.iter().map(|piece| stop.push(piece)).collect();
+        text += &stop.finish();
+        (text, stop.matched())
+    }
+
+    #[test]
+    fn stop_sequence_within_a_token() {
+        assert_eq!(
+            stream(&["END"], &["one ", "twoENDthree", " four"]),
+            ("one two".to_string(), true)
+        );
+    }
+
+    #[test]
+    fn stop_sequence_spanning_tokens() {
+        assert_eq!(
+            stream(&["</s>"], &["one <", "/", "s> two"]),
+            ("one ".to_string(), true)
+        );
+    }
+
+    #[test]
+    fn partial_match_is_held_back_until_ruled_out() {
+        let mut stop = StopSequences::new(vec!["END".to_string()]);
+        assert_eq!(stop.push("one E"), "one ");
+        assert_eq!(stop.push("N"), "");
+        assert_eq!(stop.push("x"), "ENx");
+        assert!(!stop.matched());
+        assert_eq!(stop.push("E"), "");
+        assert_eq!(stop.finish(), "E");
+    }
+
+    #[test]
+    fn earliest_stop_sequence_wins() {
+        assert_eq!(
+            stream(&["three", "two"], &["one two three"]),
+            ("one ".to_string(), true)
+        );
+        assert_eq!(
+            stream(&[""], &["one", " two"]),
+            ("one two".to_string(), false)
+        );
+    }
+}
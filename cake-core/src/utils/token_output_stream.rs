@@ -31,39 +31,44 @@ impl TokenOutputStream {
         }
     }
 
+    /// Returns the text of the last streamed tokens, and the text of the same tokens followed
+    /// by the pending ones.
+    fn pending_text(&self) -> Result<(String, String)> {
+        let prev_text = self.decode(&self.tokens[self.prev_index..self.current_index])?;
+        let text = self.decode(&self.tokens[self.prev_index..])?;
+        Ok((prev_text, text))
+    }
+
     // https://github.com/huggingface/text-generation-inference/blob/5ba53d44a18983a4de32d122f4cb46f4a17d9ef6/server/text_generation_server/models/model.py#L68
+    /// Adds a token, returns the text of the pending tokens once it only holds complete
+    /// characters. The pending tokens are decoded after the last streamed ones so that the
+    /// pieces continuing a word decode as they would within the whole text.
     pub fn next_token(&mut self, token: u32) -> Result<Option<String>> {
-        let prev_text = if self.tokens.is_empty() {
-            String::new()
-        } else {
-            let tokens = &self.tokens[self.prev_index..self.current_index];
-            self.decode(tokens)?
-        };
         self.tokens.push(token);
-        let text = self.decode(&self.tokens[self.prev_index..])?;
-        if text.len() > prev_text.len() && text.chars().last().unwrap().is_alphanumeric() {
-            let text = text.split_at(prev_text.len());
-            self.prev_index = self.current_index;
-            self.current_index = self.tokens.len();
-            Ok(Some(text.1.to_string()))
-        } else {
-            Ok(None)
+        let (prev_text, text) = self.pending_text()?;
+        // the bytes of an incomplete character are decoded as U+FFFD until the rest arrives
+        match text.strip_prefix(prev_text.as_str()) {
+            Some(new) if !new.is_empty() && !new.ends_with('\u{FFFD}') => {
+                let new = new.to_string();
+                self.prev_index = self.current_index;
+                self.current_index = self.tokens.len();
+                Ok(Some(new))
+            }
+            _ => Ok(None),
         }
     }
 
+    /// Returns the text of the pending tokens, whether complete or not.
     pub fn decode_rest(&self) -> Result<Option<String>> {
-        let prev_text = if self.tokens.is_empty() {
-            String::new()
-        } else {
-            let tokens = &self.tokens[self.prev_index..self.current_index];
-            self.decode(tokens)?
-        };
-        let text = self.decode(&self.tokens[self.prev_index..])?;
-        if text.len() > prev_text.len() {
-            let text = text.split_at(prev_text.len());
-            Ok(Some(text.1.to_string()))
-        } else {
+        let (prev_text, text) = self.pending_text()?;
+        let rest = text
+            .strip_prefix(prev_text.as_str())
+            .or_else(|| text.get(prev_text.len()..))
+            .unwrap_or_default();
+        if rest.is_empty() {
             Ok(None)
+        } else {
+            Ok(Some(rest.to_string()))
         }
     }
 
@@ -85,3 +90,46 @@ impl TokenOutputStream {
         self.current_index = 0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn characters_split_across_tokens() {
+        // a token for each half of the bytes of the emoji
+        let mut json = test_utils::byte_level_tokenizer_json();
+        let bytes = tokenizers::Tokenizer::from_bytes(json.to_string())
+            .unwrap()
+            .encode("😀", false)
+            .unwrap()
+            .get_tokens()
+            .to_vec();
+        let halves = [bytes[..2].concat(), bytes[2..].concat()];
+        for (id, half) in (256..).zip(&halves) {
+            json["model"]["vocab"][half] = id.into();
+        }
+        let tokenizer = tokenizers::Tokenizer::from_bytes(json.to_string()).unwrap();
+        let token = |text: &str| tokenizer.token_to_id(text).unwrap();
+
+        let mut stream = TokenOutputStream::new(tokenizer.clone());
+        let mut pieces = vec![];
+        for id in [token("h"), token("i"), 256, 257, token("!"), 256] {
+            pieces.push(stream.next_token(id).unwrap());
+        }
+        assert_eq!(
+            pieces,
+            [
+                Some("h".to_string()),
+                Some("i".to_string()),
+                None,
+                Some("😀".to_string()),
+                Some("!".to_string()),
+                None
+            ]
+        );
+        // the incomplete character is only decoded at the end
+        assert_eq!(stream.decode_rest().unwrap(), Some("\u{FFFD}".to_string()));
+    }
+}
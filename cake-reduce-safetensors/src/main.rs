@@ -33,7 +33,7 @@ impl View for TensorStore {
         &self.shape
     }
 
-    fn data(&self) -> std::borrow::Cow<[u8]> {
+    fn data(&self) -> std::borrow::Cow<'_, [u8]> {
         std::borrow::Cow::from(&self.data)
     }
 
@@ -84,7 +84,7 @@ fn main() {
     println!("index has {} tensors", tensors_index.weight_map.len());
 
     for (layer_full_name, filename) in &tensors_index.weight_map {
-        if worker_node.is_layer_owner(&layer_full_name) {
+        if worker_node.is_layer_owner(layer_full_name) {
             //println!("{} {}", layer_full_name, filename);
             if let Some(layers) = reduced.get_mut(filename) {
                 layers.push(layer_full_name.to_string());